@@ -0,0 +1,315 @@
+//! BLAKE3 hashing, following the portable reference implementation. Kept in tree because no
+//! release of the blake3 crate builds on the toolchain haumaru is pinned to. Only the plain
+//! hash is needed, so there is no keyed hashing, key derivation or extended output.
+
+use std::cmp::min;
+
+const OUT_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+const IV: [u32; 8] = [0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C,
+                      0x1F83D9AB, 0x5BE0CD19];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+/// The mixing function, mixing `mx` and `my` into a column or diagonal of `state`.
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // columns
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    // diagonals
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn permute(m: &mut [u32; 16]) {
+    let mut permuted = [0; 16];
+    for i in 0..16 {
+        permuted[i] = m[MSG_PERMUTATION[i]];
+    }
+    *m = permuted;
+}
+
+fn compress(chaining_value: &[u32; 8],
+            block_words: &[u32; 16],
+            counter: u64,
+            block_len: u32,
+            flags: u32)
+            -> [u32; 16] {
+    let mut state = [chaining_value[0],
+                     chaining_value[1],
+                     chaining_value[2],
+                     chaining_value[3],
+                     chaining_value[4],
+                     chaining_value[5],
+                     chaining_value[6],
+                     chaining_value[7],
+                     IV[0],
+                     IV[1],
+                     IV[2],
+                     IV[3],
+                     counter as u32,
+                     (counter >> 32) as u32,
+                     block_len,
+                     flags];
+    let mut block = *block_words;
+    for i in 0..7 {
+        round(&mut state, &block);
+        if i < 6 {
+            permute(&mut block);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn first_8_words(words: [u32; 16]) -> [u32; 8] {
+    let mut first = [0; 8];
+    first.copy_from_slice(&words[..8]);
+    first
+}
+
+fn words_from_le_bytes(bytes: &[u8], words: &mut [u32]) {
+    for (four, word) in bytes.chunks(4).zip(words.iter_mut()) {
+        *word = four[0] as u32 | (four[1] as u32) << 8 | (four[2] as u32) << 16 |
+                (four[3] as u32) << 24;
+    }
+}
+
+/// What a chunk or parent node compresses to, before knowing whether it is the root.
+struct Output {
+    input_chaining_value: [u32; 8],
+    block_words: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8_words(compress(&self.input_chaining_value,
+                               &self.block_words,
+                               self.counter,
+                               self.block_len,
+                               self.flags))
+    }
+
+    fn root_hash(&self) -> Hash {
+        let words = compress(&self.input_chaining_value,
+                             &self.block_words,
+                             0,
+                             self.block_len,
+                             self.flags | ROOT);
+        let mut bytes = [0; OUT_LEN];
+        for (word, out) in words.iter().zip(bytes.chunks_mut(4)) {
+            for (i, b) in out.iter_mut().enumerate() {
+                *b = (word >> (8 * i)) as u8;
+            }
+        }
+        Hash(bytes)
+    }
+}
+
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: u8,
+    blocks_compressed: u8,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> Self {
+        ChunkState {
+            chaining_value: IV,
+            chunk_counter: chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed as usize + self.block_len as usize
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // only compress a full block once more input arrives, the last one is flagged
+            if self.block_len as usize == BLOCK_LEN {
+                let mut block_words = [0; 16];
+                words_from_le_bytes(&self.block, &mut block_words);
+                self.chaining_value = first_8_words(compress(&self.chaining_value,
+                                                             &block_words,
+                                                             self.chunk_counter,
+                                                             BLOCK_LEN as u32,
+                                                             self.start_flag()));
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = min(BLOCK_LEN - self.block_len as usize, input.len());
+            let start = self.block_len as usize;
+            self.block[start..start + take].copy_from_slice(&input[..take]);
+            self.block_len += take as u8;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        let mut block_words = [0; 16];
+        words_from_le_bytes(&self.block, &mut block_words);
+        Output {
+            input_chaining_value: self.chaining_value,
+            block_words: block_words,
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block_words = [0; 16];
+    block_words[..8].copy_from_slice(&left);
+    block_words[8..].copy_from_slice(&right);
+    Output {
+        input_chaining_value: IV,
+        block_words: block_words,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+/// A finished 32 byte hash.
+pub struct Hash([u8; OUT_LEN]);
+
+impl Hash {
+    pub fn as_bytes(&self) -> &[u8; OUT_LEN] {
+        &self.0
+    }
+}
+
+/// Incremental BLAKE3 hasher.
+pub struct Hasher {
+    chunk_state: ChunkState,
+    // chaining values of complete subtrees, enough for 2^54 chunks
+    cv_stack: [[u32; 8]; 54],
+    cv_stack_len: usize,
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher {
+            chunk_state: ChunkState::new(0),
+            cv_stack: [[0; 8]; 54],
+            cv_stack_len: 0,
+        }
+    }
+
+    /// Merge the finished chunk into the subtrees it completes. Each trailing zero bit of
+    /// `total_chunks` is a subtree now complete.
+    fn add_chunk_chaining_value(&mut self, mut cv: [u32; 8], mut total_chunks: u64) {
+        while total_chunks & 1 == 0 {
+            self.cv_stack_len -= 1;
+            cv = parent_output(self.cv_stack[self.cv_stack_len], cv).chaining_value();
+            total_chunks >>= 1;
+        }
+        self.cv_stack[self.cv_stack_len] = cv;
+        self.cv_stack_len += 1;
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // only finish a full chunk once more input arrives, the last one may be the root
+            if self.chunk_state.len() == CHUNK_LEN {
+                let cv = self.chunk_state.output().chaining_value();
+                let total_chunks = self.chunk_state.chunk_counter + 1;
+                self.add_chunk_chaining_value(cv, total_chunks);
+                self.chunk_state = ChunkState::new(total_chunks);
+            }
+            let take = min(CHUNK_LEN - self.chunk_state.len(), input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(&self) -> Hash {
+        let mut output = self.chunk_state.output();
+        for i in (0..self.cv_stack_len).rev() {
+            output = parent_output(self.cv_stack[i], output.chaining_value());
+        }
+        output.root_hash()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::hex::ToHex;
+    use super::*;
+
+    /// Hash of the official test vector input of `len` bytes, counting up modulo 251.
+    fn vector(len: usize) -> String {
+        let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Hasher::new();
+        hasher.update(&input);
+        hasher.finalize().as_bytes().to_hex()
+    }
+
+    #[test]
+    fn test_vectors() {
+        assert_eq!("af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+                   vector(0));
+        assert_eq!("2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+                   vector(1));
+        assert_eq!("42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+                   vector(1024));
+        assert_eq!("d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+                   vector(1025));
+        assert_eq!("e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+                   vector(2048));
+    }
+
+    #[test]
+    fn split_updates() {
+        let input: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Hasher::new();
+        for piece in input.chunks(333) {
+            hasher.update(piece);
+        }
+        assert_eq!(vector(5000), hasher.finalize().as_bytes().to_hex());
+    }
+}
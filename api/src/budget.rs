@@ -0,0 +1,84 @@
+//! `max_daily_upload`: a cap on what is uploaded each day, for internet plans with a data cap.
+
+use engine::{Pause, PauseReason};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use time::{Timespec, at, get_time};
+
+/// A local calendar day, as year and day of the year.
+pub type Day = (i32, i32);
+
+pub fn day_of(t: Timespec) -> Day {
+    let tm = at(t);
+    (tm.tm_year, tm.tm_yday)
+}
+
+pub fn today() -> Day {
+    day_of(get_time())
+}
+
+/// Bytes uploaded today against `max_daily_upload`. Clones share the count, so one budget
+/// covers every send worker.
+#[derive(Debug, Clone)]
+pub struct UploadBudget {
+    limit: u64,
+    spent: Arc<Mutex<(Day, u64)>>,
+}
+
+impl UploadBudget {
+    pub fn new(limit: u64) -> Self {
+        UploadBudget {
+            limit: limit,
+            spent: Arc::new(Mutex::new((today(), 0))),
+        }
+    }
+
+    /// Count `bytes` uploaded on `day`, returning whether that spends the day's budget.
+    pub fn spend(&self, day: Day, bytes: u64) -> bool {
+        let mut spent = self.spent.lock().expect("budget lock");
+        if spent.0 != day {
+            *spent = (day, 0);
+        }
+        spent.1 += bytes;
+        spent.1 >= self.limit
+    }
+
+    /// Whether the budget for `day` is spent. Every day starts with a fresh one.
+    pub fn is_spent(&self, day: Day) -> bool {
+        let spent = self.spent.lock().expect("budget lock");
+        spent.0 == day && spent.1 >= self.limit
+    }
+}
+
+/// Resume uploads paused by the budget once a new day begins.
+pub fn watch_budget(pause: Pause, budget: UploadBudget) {
+    thread::spawn(move || {
+        loop {
+            if !budget.is_spent(today()) {
+                pause.resume(PauseReason::UploadBudget);
+            }
+            thread::sleep(Duration::new(60, 0));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spent_per_day() {
+        let budget = UploadBudget::new(100);
+        let day = (116, 10);
+        let next_day = (116, 11);
+        assert!(!budget.spend(day, 60));
+        assert!(!budget.is_spent(day));
+        assert!(budget.clone().spend(day, 40));
+        assert!(budget.is_spent(day));
+
+        assert!(!budget.is_spent(next_day));
+        assert!(!budget.spend(next_day, 10));
+        assert!(!budget.is_spent(day));
+    }
+}
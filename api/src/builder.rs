@@ -0,0 +1,138 @@
+//! Assembling engines from library code.
+//!
+//! ```no_run
+//! use haumaru_api::{EngineConfig, Engine, Haumaru};
+//!
+//! let config = EngineConfig::new("/var/lib/haumaru").with_path("/home".to_string());
+//! let mut engine = Haumaru::builder()
+//!     .config(config)
+//!     .default_backends()
+//!     .unwrap()
+//!     .build()
+//!     .unwrap();
+//! engine.backup_once().unwrap();
+//! ```
+//!
+//! Any `Index` and `Storage` implementation can be given instead of the defaults with `index`
+//! and `storage`.
+
+use {EngineConfig, HaumaruError, Index, Storage, build_index, build_storage};
+use engine::{DefaultEngine, EngineHooks};
+use index::SqlLightIndex;
+use std::collections::HashSet;
+use storage::AnyStorage;
+
+/// Entry point for building engines.
+pub struct Haumaru;
+
+impl Haumaru {
+    /// A builder using the SQLite index and the configured storage unless told otherwise.
+    pub fn builder() -> HaumaruBuilder<SqlLightIndex, AnyStorage> {
+        HaumaruBuilder {
+            config: None,
+            excludes: HashSet::new(),
+            index: None,
+            storage: None,
+            hooks: None,
+        }
+    }
+}
+
+/// Collects what an engine needs. `config`, an index and a storage are required.
+pub struct HaumaruBuilder<I, S> {
+    config: Option<EngineConfig>,
+    excludes: HashSet<String>,
+    index: Option<I>,
+    storage: Option<S>,
+    hooks: Option<Box<EngineHooks>>,
+}
+
+impl<I, S> HaumaruBuilder<I, S>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Never back up `path` or anything under it. The working directory is always excluded.
+    pub fn exclude(mut self, path: &str) -> Self {
+        self.excludes.insert(path.to_string());
+        self
+    }
+
+    /// See `DefaultEngine::set_hooks`.
+    pub fn hooks(mut self, hooks: Box<EngineHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    pub fn index<J>(self, index: J) -> HaumaruBuilder<J, S>
+        where J: Index + Send + Clone + 'static
+    {
+        HaumaruBuilder {
+            config: self.config,
+            excludes: self.excludes,
+            index: Some(index),
+            storage: self.storage,
+            hooks: self.hooks,
+        }
+    }
+
+    pub fn storage<T>(self, storage: T) -> HaumaruBuilder<I, T>
+        where T: Storage + 'static
+    {
+        HaumaruBuilder {
+            config: self.config,
+            excludes: self.excludes,
+            index: self.index,
+            storage: Some(storage),
+            hooks: self.hooks,
+        }
+    }
+
+    /// Start the engine's workers. The engine is driven through `Engine`.
+    pub fn build(self) -> Result<DefaultEngine<I, S>, HaumaruError> {
+        let config = match self.config {
+            Some(config) => config,
+            None => return Err(HaumaruError::Config("No engine config given".into())),
+        };
+        let index = match self.index {
+            Some(index) => index,
+            None => return Err(HaumaruError::Config("No index given".into())),
+        };
+        let storage = match self.storage {
+            Some(storage) => storage,
+            None => return Err(HaumaruError::Config("No storage given".into())),
+        };
+
+        let mut excludes = self.excludes;
+        excludes.insert(config.abs_working().to_str().unwrap().to_string());
+
+        let mut engine = DefaultEngine::new(config, excludes, index, storage)
+            .map_err(|e| HaumaruError::Engine(e))?;
+        if let Some(hooks) = self.hooks {
+            engine.set_hooks(hooks);
+        }
+        Ok(engine)
+    }
+}
+
+impl HaumaruBuilder<SqlLightIndex, AnyStorage> {
+    /// Open the SQLite index in the configured working directory and the storage named by the
+    /// config, for whichever of the two has not been given.
+    pub fn default_backends(mut self) -> Result<Self, HaumaruError> {
+        let config = match self.config {
+            Some(ref config) => config.clone(),
+            None => return Err(HaumaruError::Config("No engine config given".into())),
+        };
+        if self.index.is_none() {
+            self.index = Some(build_index(config.clone())?);
+        }
+        if self.storage.is_none() {
+            self.storage = Some(build_storage(config)?);
+        }
+        Ok(self)
+    }
+}
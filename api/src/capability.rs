@@ -0,0 +1,92 @@
+//! Linux file capabilities, kept in the `security.capability` extended attribute. They are
+//! lost when a file is written, so restores have to put them back.
+
+use libc;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+const CAPABILITY_XATTR: &'static [u8] = b"security.capability\0";
+
+/// Largest `vfs_cap_data`, version 3.
+#[cfg(target_os = "linux")]
+const MAX_CAPABILITY_LEN: usize = 24;
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> IoResult<::std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    ::std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))
+}
+
+/// The capabilities of the file at `path`, or `None` when it has none or the filesystem does
+/// not support them.
+#[cfg(target_os = "linux")]
+pub fn capability(path: &Path) -> IoResult<Option<Vec<u8>>> {
+    let c_path = c_path(path)?;
+    let mut value = vec![0u8; MAX_CAPABILITY_LEN];
+    let len = unsafe {
+        libc::lgetxattr(c_path.as_ptr(),
+                        CAPABILITY_XATTR.as_ptr() as *const libc::c_char,
+                        value.as_mut_ptr() as *mut libc::c_void,
+                        value.len())
+    };
+    if len >= 0 {
+        value.truncate(len as usize);
+        return Ok(Some(value));
+    }
+    let e = IoError::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENODATA) |
+        Some(libc::ENOTSUP) => Ok(None),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capability(_path: &Path) -> IoResult<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Give the file at `path` the capabilities `value`. Needs CAP_SETFCAP, so in practice root.
+#[cfg(target_os = "linux")]
+pub fn set_capability(path: &Path, value: &[u8]) -> IoResult<()> {
+    let c_path = c_path(path)?;
+    let rc = unsafe {
+        libc::setxattr(c_path.as_ptr(),
+                       CAPABILITY_XATTR.as_ptr() as *const libc::c_char,
+                       value.as_ptr() as *const libc::c_void,
+                       value.len(),
+                       0)
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_capability(_path: &Path, _value: &[u8]) -> IoResult<()> {
+    Err(IoError::new(ErrorKind::Other, "file capabilities are only supported on Linux"))
+}
+
+/// Whether this process may set file capabilities.
+pub fn can_set_capabilities() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn plain_file_has_none() {
+        let path = Path::new("target/capability_plain");
+        File::create(path).unwrap();
+        assert_eq!(None, capability(path).unwrap());
+        assert!(capability(Path::new("target/does/not/exist")).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Content-defined chunking, so a small change to a large file only stores the chunks around
+//! it. Boundaries fall where a rolling gear hash of the bytes before them matches a mask, so
+//! they move with the content instead of sitting at fixed offsets, and an insert only changes
+//! the chunks it lands in.
+
+/// No chunk is cut shorter than this, except the last.
+pub const MIN_CHUNK: usize = 256 * 1024;
+
+/// Chunks average about this long.
+pub const AVG_CHUNK: usize = 1024 * 1024;
+
+/// Chunks are cut here if no boundary turns up first.
+pub const MAX_CHUNK: usize = 4 * 1024 * 1024;
+
+lazy_static! {
+    /// A random value per byte, from a fixed seed so boundaries never change between runs.
+    static ref GEAR: [u64; 256] = {
+        let mut table = [0; 256];
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        for entry in table.iter_mut() {
+            // splitmix64
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *entry = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// Length of the chunk at the start of `data`.
+fn cut(data: &[u8], min: usize, avg: usize, max: usize) -> usize {
+    if data.len() <= min {
+        return data.len();
+    }
+    let end = if data.len() < max { data.len() } else { max };
+    let mask = (avg.next_power_of_two() - 1) as u64;
+    let mut hash: u64 = 0;
+    for (i, b) in data[..end].iter().enumerate().skip(min) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// The chunks of some data, each boundary found as the one before it is taken.
+pub struct Chunks<'a> {
+    rest: &'a [u8],
+    min: usize,
+    avg: usize,
+    max: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let len = cut(self.rest, self.min, self.avg, self.max);
+        let (chunk, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Some(chunk)
+    }
+}
+
+/// Split `data` into chunks of `min` to `max` bytes, averaging about `avg`.
+pub fn chunks_with(data: &[u8], min: usize, avg: usize, max: usize) -> Chunks {
+    Chunks {
+        rest: data,
+        min: min,
+        avg: avg,
+        max: max,
+    }
+}
+
+/// Split `data` into chunks of the default sizes.
+pub fn chunks(data: &[u8]) -> Chunks {
+    chunks_with(data, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Bytes that look random, so boundaries turn up as they would in real content.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn covers_everything() {
+        let data = noise(200000, 1);
+        let chunks: Vec<&[u8]> = chunks_with(&data, 1024, 4096, 16384).collect();
+        assert!(chunks.len() > 10, "chunks={}", chunks.len());
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() >= 1024 && c.len() <= 16384));
+        let joined: Vec<u8> = chunks.iter().flat_map(|c| c.iter().cloned()).collect();
+        assert_eq!(data, joined);
+        assert_eq!(chunks, chunks_with(&data, 1024, 4096, 16384).collect::<Vec<_>>());
+        assert_eq!(None, chunks_with(&[], 1024, 4096, 16384).next());
+    }
+
+    #[test]
+    fn insert_only_changes_nearby_chunks() {
+        let data = noise(200000, 2);
+        let mut changed = data[..100000].to_vec();
+        changed.extend_from_slice(b"a few new bytes");
+        changed.extend_from_slice(&data[100000..]);
+
+        let before: Vec<&[u8]> = chunks_with(&data, 1024, 4096, 16384).collect();
+        let after: Vec<&[u8]> = chunks_with(&changed, 1024, 4096, 16384).collect();
+        let new = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(new <= 2, "new={} of {}", new, after.len());
+    }
+}
@@ -0,0 +1,88 @@
+//! What `haumaru compare` reports: how the files on disk have drifted from the latest backup.
+
+use Node;
+use std::collections::HashMap;
+
+/// How a file on disk differs from its backup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Drift {
+    /// On disk but never backed up.
+    New,
+    /// Backed up, but the copy on disk has changed since.
+    Changed,
+    /// Backed up, but gone from disk.
+    Missing,
+}
+
+impl Drift {
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Drift::New => "new",
+            Drift::Changed => "changed",
+            Drift::Missing => "missing",
+        }
+    }
+}
+
+/// A file that differs, with its size on disk, or in the backup when missing from disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriftEntry {
+    pub path: String,
+    pub drift: Drift,
+    pub size: u64,
+}
+
+/// Compare the `live` files against the `stored` ones, ordered by path. `changed` decides
+/// whether a file present in both has changed.
+pub fn compare(stored: &[Node],
+               live: &[Node],
+               changed: &mut FnMut(&Node, &Node) -> bool)
+               -> Vec<DriftEntry> {
+    let mut by_path: HashMap<&str, &Node> = stored.iter().map(|n| (n.path(), n)).collect();
+    let mut drift = vec![];
+    for node in live {
+        let kind = match by_path.remove(node.path()) {
+            None => Drift::New,
+            Some(stored) if changed(node, stored) => Drift::Changed,
+            Some(_) => continue,
+        };
+        drift.push(DriftEntry {
+            path: node.path().to_string(),
+            drift: kind,
+            size: node.size(),
+        });
+    }
+    for (path, node) in by_path {
+        drift.push(DriftEntry {
+            path: path.to_string(),
+            drift: Drift::Missing,
+            size: node.size(),
+        });
+    }
+    drift.sort_by(|a, b| a.path.cmp(&b.path));
+    drift
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::Timespec;
+    use Node;
+
+    #[test]
+    fn drift() {
+        let stored = vec![Node::new_file("same", Timespec::new(10, 0), 1, 0o644),
+                          Node::new_file("edited", Timespec::new(10, 0), 1, 0o644),
+                          Node::new_file("removed", Timespec::new(10, 0), 5, 0o644)];
+        let live = vec![Node::new_file("same", Timespec::new(10, 0), 1, 0o644),
+                        Node::new_file("edited", Timespec::new(20, 0), 3, 0o644),
+                        Node::new_file("added", Timespec::new(20, 0), 7, 0o644)];
+        let drift = compare(&stored, &live, &mut |live, stored| live.mtime() != stored.mtime());
+        let summary: Vec<(&str, Drift, u64)> =
+            drift.iter().map(|d| (d.path.as_str(), d.drift, d.size)).collect();
+        assert_eq!(vec![("added", Drift::New, 7),
+                        ("edited", Drift::Changed, 3),
+                        ("removed", Drift::Missing, 5)],
+                   summary);
+    }
+}
@@ -0,0 +1,218 @@
+//! Compression of stored blobs. Compressed blobs start with a header naming how they were
+//! stored and the size of the content, which storage reads back to hand out the content as it
+//! was. Blobs without one are stored as is, so blobs from before compression still read back.
+
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fmt;
+use std::fs::File;
+use std::io::{Cursor, Error as IoError, ErrorKind, Read, Result as IoResult, Write, copy};
+use std::path::Path;
+use std::str::FromStr;
+use storage::Blob;
+
+/// Starts every blob with a header. Not valid UTF-8, so text never starts with it.
+const MAGIC: &'static [u8] = b"\x89HMZ";
+
+/// Magic, method and the content size as a big endian u64.
+const HEADER_LEN: usize = 13;
+
+/// Content as is, behind a header only because it happens to start with `MAGIC`.
+const METHOD_STORED: u8 = 0;
+const METHOD_GZIP: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("Unknown compression {:?}, expected gzip or none", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Compression::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+fn header(method: u8, size: u64) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(method);
+    for shift in (0..8).rev() {
+        header.push((size >> (shift * 8)) as u8);
+    }
+    header
+}
+
+/// What to store for `content`: compressed with `compression` when that makes it smaller,
+/// otherwise as it is. Returns whether the result starts with a header, so differs from
+/// `content`.
+pub fn encode(compression: Option<Compression>, content: Vec<u8>) -> IoResult<(Vec<u8>, bool)> {
+    let size = content.len() as u64;
+    if let Some(Compression::Gzip) = compression {
+        let mut encoder = GzEncoder::new(header(METHOD_GZIP, size), flate2::Compression::Default);
+        encoder.write_all(&content)?;
+        let compressed = encoder.finish()?;
+        if (compressed.len() as u64) < size {
+            return Ok((compressed, true));
+        }
+    }
+    if content.starts_with(MAGIC) {
+        let mut stored = header(METHOD_STORED, size);
+        stored.extend_from_slice(&content);
+        return Ok((stored, true));
+    }
+    Ok((content, false))
+}
+
+/// `encode` for content too large to hold, read from `src` holding `size` bytes. What to store
+/// is written to `dst` when it differs from the content. Returns whether it was, so `dst` is
+/// what to store rather than `src`.
+pub fn encode_file(compression: Option<Compression>,
+                   src: &Path,
+                   size: u64,
+                   dst: &Path)
+                   -> IoResult<bool> {
+    if let Some(Compression::Gzip) = compression {
+        let mut out = File::create(dst)?;
+        out.write_all(&header(METHOD_GZIP, size))?;
+        let mut encoder = GzEncoder::new(out, flate2::Compression::Default);
+        copy(&mut File::open(src)?, &mut encoder)?;
+        if encoder.finish()?.metadata()?.len() < size {
+            return Ok(true);
+        }
+    }
+    let mut content = File::open(src)?;
+    let mut head = [0; 4];
+    let read = read_up_to(&mut content, &mut head)?;
+    if &head[..read] == MAGIC {
+        let mut out = File::create(dst)?;
+        out.write_all(&header(METHOD_STORED, size))?;
+        out.write_all(&head)?;
+        copy(&mut content, &mut out)?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Fill `buf` from `reader`, short only at the end of it.
+fn read_up_to(reader: &mut Read, buf: &mut [u8]) -> IoResult<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// `blob` as the content stored in it, decompressed if it starts with a header.
+pub fn decode(mut blob: Blob) -> IoResult<Blob> {
+    let mut header = [0; HEADER_LEN];
+    let read = read_up_to(&mut blob, &mut header)?;
+    if read < HEADER_LEN || &header[..MAGIC.len()] != MAGIC {
+        let size = blob.size();
+        let head = Cursor::new(header[..read].to_vec());
+        return Ok(Blob::new(size, box head.chain(blob)));
+    }
+
+    let size = header[MAGIC.len() + 1..].iter().fold(0, |size, b| size << 8 | *b as u64);
+    match header[MAGIC.len()] {
+        METHOD_STORED => Ok(Blob::new(size, box blob)),
+        METHOD_GZIP => Ok(Blob::new(size, box GzDecoder::new(blob)?)),
+        method => {
+            Err(IoError::new(ErrorKind::InvalidData,
+                             format!("Unknown compression method {}", method)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Cursor, Read, Write};
+    use storage::Blob;
+
+    fn round_trip(compression: Option<Compression>, content: &[u8]) -> (usize, bool) {
+        let (stored, encoded) = encode(compression, content.to_vec()).unwrap();
+        let stored_len = stored.len();
+        let mut blob = decode(Blob::new(stored_len as u64, box Cursor::new(stored))).unwrap();
+        assert_eq!(content.len() as u64, blob.size());
+        let mut read = vec![];
+        blob.read_to_end(&mut read).unwrap();
+        assert_eq!(content, &read[..]);
+        (stored_len, encoded)
+    }
+
+    #[test]
+    fn compresses_text() {
+        let line = "all work and no play makes jack a dull boy\n";
+        let text: String = (0..100).map(|_| line).collect();
+        let (stored, encoded) = round_trip(Some(Compression::Gzip), text.as_bytes());
+        assert!(encoded);
+        assert!(stored < text.len() / 10, "stored={}", stored);
+    }
+
+    #[test]
+    fn stores_as_is() {
+        assert_eq!((3, false), round_trip(Some(Compression::Gzip), b"abc"));
+        assert_eq!((0, false), round_trip(None, b""));
+        let text = b"all work and no play makes jack a dull boy";
+        assert_eq!((text.len(), false), round_trip(None, text));
+    }
+
+    /// What `encode_file` stores for `content`, read back through `decode`.
+    fn round_trip_file(name: &str, compression: Option<Compression>, content: &[u8]) -> bool {
+        use std::fs::{File, create_dir_all, metadata};
+        use std::path::Path;
+
+        let dir = Path::new("target/test/compression").join(name);
+        create_dir_all(&dir).unwrap();
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        File::create(&src).and_then(|mut f| f.write_all(content)).unwrap();
+        let encoded = encode_file(compression, &src, content.len() as u64, &dst).unwrap();
+        let stored = if encoded { dst } else { src };
+        let size = metadata(&stored).unwrap().len();
+        let mut blob = decode(Blob::new(size, box File::open(&stored).unwrap())).unwrap();
+        assert_eq!(content.len() as u64, blob.size());
+        let mut read = vec![];
+        blob.read_to_end(&mut read).unwrap();
+        assert_eq!(content, &read[..]);
+        encoded
+    }
+
+    #[test]
+    fn encodes_files() {
+        let line = "all work and no play makes jack a dull boy\n";
+        let text: String = (0..100).map(|_| line).collect();
+        assert!(round_trip_file("text", Some(Compression::Gzip), text.as_bytes()));
+        assert!(!round_trip_file("short", Some(Compression::Gzip), b"abc"));
+        assert!(!round_trip_file("none", None, text.as_bytes()));
+        assert!(round_trip_file("magic", None, b"\x89HMZ\x01 looks like a header"));
+    }
+
+    #[test]
+    fn escapes_magic() {
+        let content = b"\x89HMZ\x01 looks like a header";
+        assert_eq!((HEADER_LEN + content.len(), true), round_trip(None, content));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Compression::Gzip), "gzip".parse());
+        assert!("zip".parse::<Compression>().is_err());
+        assert_eq!("gzip", Compression::Gzip.to_string());
+    }
+}
@@ -1,8 +1,25 @@
 
 
-use {EngineConfig, HaumaruError};
+use {EngineConfig, HashAlgorithm, HaumaruError};
+use engine::{BackupScripts, BackupWindow, RemoteSource, Snapshot, parse_generated_sources};
+use namespace::{DEFAULT_NAMESPACE, hostname};
+use namespace::validate as validate_namespace;
+use network::MeteredNetworks;
+use notification::SmtpSettings;
+use output::OutputFormat;
+use time_format::{TimeFormat, TimeStyle};
+use serve::parse_clients;
+use filter::parse_filters;
+use path_priority::parse_priorities;
+use retention::{RetentionPolicy, parse_age, parse_rules};
+use pattern::{Glob, parse_patterns};
+use storage;
+use units::parse_bytes;
 
+use serde_json;
+use serde_json::Value;
 use serde_yaml;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::io::Read;
@@ -10,11 +27,76 @@ use std::io::Read;
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     path: Option<String>,
+    paths: Option<Vec<String>>,
     working: Option<String>,
     period: Option<String>,
+    full_scan_interval: Option<String>,
     max_file_size: Option<String>,
+    min_file_age: Option<String>,
+    checkpoint_interval: Option<String>,
+    storage: Option<String>,
+    faults: Option<String>,
     bucket: Option<String>,
+    aws_role_arn: Option<String>,
+    server_url: Option<String>,
+    server_token: Option<String>,
+    serve_listen: Option<String>,
+    serve_clients: Option<String>,
+    serve_max_body: Option<String>,
+    web_listen: Option<String>,
     prefix: Option<String>,
+    namespace: Option<String>,
+    hash: Option<String>,
+    parallel_hash_threshold: Option<String>,
+    chunk_threshold: Option<String>,
+    hash_threads: Option<String>,
+    free_space_reserve: Option<String>,
+    storage_soft_cap: Option<String>,
+    storage_hard_cap: Option<String>,
+    pre_send_threads: Option<String>,
+    send_threads: Option<String>,
+    max_daily_upload: Option<String>,
+    scan_nice: Option<String>,
+    scan_idle_io: Option<String>,
+    scan_io_rate: Option<String>,
+    tree_hashes: Option<String>,
+    window: Option<String>,
+    battery_threshold: Option<String>,
+    metered_interfaces: Option<String>,
+    metered_ssids: Option<String>,
+    metered_command: Option<String>,
+    transient_patterns: Option<String>,
+    excludes: Option<Vec<String>>,
+    includes: Option<Vec<String>>,
+    filters: Option<String>,
+    compression: Option<String>,
+    priorities: Option<String>,
+    retention: Option<String>,
+    retention_rules: Option<String>,
+    generated_sources: Option<String>,
+    snapshot_create: Option<String>,
+    snapshot_path: Option<String>,
+    snapshot_release: Option<String>,
+    remote: Option<String>,
+    statsd_host: Option<String>,
+    statsd_port: Option<String>,
+    statsd_prefix: Option<String>,
+    log_target: Option<String>,
+    webhook_url: Option<String>,
+    healthcheck_url: Option<String>,
+    smtp_host: Option<String>,
+    smtp_port: Option<String>,
+    smtp_username: Option<String>,
+    smtp_password: Option<String>,
+    smtp_from: Option<String>,
+    smtp_to: Option<String>,
+    desktop_notifications: Option<String>,
+    output: Option<String>,
+    pre_backup: Option<String>,
+    post_backup: Option<String>,
+    time_format: Option<String>,
+    utc: Option<String>,
+    jobs: Option<BTreeMap<String, Config>>,
 }
 
 impl Config {
@@ -39,6 +121,67 @@ impl Config {
     pub fn prefix(&self) -> Option<&str> {
         self.prefix.as_ref().map(|s| s.as_str())
     }
+    /// Where the daemon logs to: stderr, syslog or journald.
+    pub fn log_target(&self) -> Option<&str> {
+        self.log_target.as_ref().map(|s| s.as_str())
+    }
+    /// Print command results as `text` or `json`.
+    pub fn set_output(&mut self, output: String) {
+        self.output = Some(output);
+    }
+    /// Show times as `iso`, `relative`, `local` or `short`.
+    pub fn set_time_format(&mut self, time_format: String) {
+        self.time_format = Some(time_format);
+    }
+    /// Show times in UTC instead of the local timezone.
+    pub fn set_utc(&mut self, utc: bool) {
+        self.utc = Some(utc.to_string());
+    }
+
+    /// Names of the jobs under `jobs:`, in order.
+    pub fn job_names(&self) -> Vec<String> {
+        self.jobs.as_ref().map(|jobs| jobs.keys().cloned().collect()).unwrap_or(vec![])
+    }
+
+    /// The config of job `name`: the settings it gives, over the shared ones at the top level.
+    /// Unless it says otherwise, a job keeps its index in `jobs/<name>` under the shared working
+    /// dir and pushes it under its own namespace, so jobs sharing storage stay apart.
+    pub fn job(&self, name: &str) -> Result<Config, HaumaruError> {
+        validate_namespace(name).map_err(|e| HaumaruError::Config(e.into()))?;
+        let job = self.jobs.as_ref().and_then(|jobs| jobs.get(name)).ok_or_else(|| {
+                HaumaruError::Config(format!("No job {:?} in the config, it has: {}",
+                                             name,
+                                             self.job_names().join(", "))
+                    .into())
+            })?;
+
+        let mut merged = match serde_json::to_value(self) {
+            Value::Object(settings) => settings,
+            _ => unreachable!("config serializes to an object"),
+        };
+        if let Value::Object(settings) = serde_json::to_value(job) {
+            for (setting, value) in settings {
+                if !value.is_null() {
+                    merged.insert(setting, value);
+                }
+            }
+        }
+        merged.remove("jobs");
+        let mut config: Config = serde_json::from_value(Value::Object(merged))
+            .map_err(|e| HaumaruError::Config(box e))?;
+
+        if job.working.is_none() {
+            config.working = self.working.as_ref().map(|w| format!("{}/jobs/{}", w, name));
+        }
+        if job.namespace.is_none() {
+            let namespace = self.namespace
+                .clone()
+                .or_else(hostname)
+                .unwrap_or(DEFAULT_NAMESPACE.to_string());
+            config.namespace = Some(format!("{}-{}", namespace, name));
+        }
+        Ok(config)
+    }
 }
 
 pub trait AsConfig {
@@ -58,33 +201,478 @@ impl<T: Read> AsConfig for T {
 impl TryFrom<Config> for EngineConfig {
     type Err = HaumaruError;
     fn try_from(c: Config) -> Result<Self, HaumaruError> {
+        if !c.job_names().is_empty() {
+            return Err(HaumaruError::Config(format!("The config has jobs, pick one with --job: \
+                                                     {}",
+                                                    c.job_names().join(", "))
+                .into()));
+        }
+
         let working = c.working.expect("working");
         let mut config = EngineConfig::new(&working);
 
+        // keys of several roots are their paths under `path`, all of it unless given
         if let Some(path) = c.path {
             config = config.with_path(path);
+        } else if c.paths.is_some() {
+            config = config.with_path("/".to_string());
         } else {
             config = config.detached();
         }
 
+        if let Some(paths) = c.paths {
+            if paths.is_empty() {
+                return Err(HaumaruError::Config("paths must not be empty".into()));
+            }
+            config = config.with_roots(paths);
+        }
+
         if let Some(period) = c.period {
             config =
                 config.with_period(period.parse::<u32>().map_err(|e| HaumaruError::Config(box e))?);
         }
 
+        if let Some(interval) = c.full_scan_interval {
+            config = config.with_full_scan_interval(interval.parse::<u32>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
         if let Some(max_file_size) = c.max_file_size {
             config = config.with_max_file_size(max_file_size.parse::<u64>()
                 .map_err(|e| HaumaruError::Config(box e))?);
         }
 
+        if let Some(age) = c.min_file_age {
+            config = config.with_min_file_age(age.parse::<u32>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(interval) = c.checkpoint_interval {
+            config = config.with_checkpoint_interval(interval.parse::<u32>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(name) = c.storage {
+            if !storage::backends().contains(&name.as_str()) {
+                return Err(HaumaruError::Config(format!("Unknown storage backend {:?}, this \
+                                                         build has: {}",
+                                                        name,
+                                                        storage::backends().join(", "))
+                    .into()));
+            }
+            config = config.with_storage(&name);
+        }
+
+        if let Some(faults) = c.faults {
+            if !cfg!(feature = "flaky") {
+                return Err(HaumaruError::Config("faults needs a build with the flaky feature"
+                    .into()));
+            }
+            config = config.with_faults(faults.parse::<storage::Faults>()
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
         if let Some(bucket) = c.bucket {
             config = config.with_bucket(&bucket);
         }
 
+        if let Some(role_arn) = c.aws_role_arn {
+            config = config.with_aws_role_arn(&role_arn);
+        }
+
+        match (c.server_url, c.server_token) {
+            (Some(url), Some(token)) => config = config.with_server(&url, &token),
+            (None, None) => (),
+            _ => {
+                return Err(HaumaruError::Config("server_url and server_token must be given \
+                                                 together"
+                    .into()))
+            }
+        }
+
+        if let Some(listen) = c.serve_listen {
+            config = config.with_serve_listen(&listen);
+        }
+
+        if let Some(clients) = c.serve_clients {
+            config = config.with_serve_clients(parse_clients(&clients)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let Some(max_body) = c.serve_max_body {
+            config = config.with_serve_max_body(max_body.parse::<u64>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(listen) = c.web_listen {
+            config = config.with_web_listen(&listen);
+        }
+
         if let Some(prefix) = c.prefix {
             config = config.with_prefix(&prefix);
         }
 
+        if let Some(namespace) = c.namespace {
+            validate_namespace(&namespace).map_err(|e| HaumaruError::Config(e.into()))?;
+            config = config.with_namespace(&namespace);
+        }
+
+        if let Some(hash) = c.hash {
+            let algorithm = HashAlgorithm::from_tag(&hash).ok_or_else(|| {
+                    HaumaruError::Config(format!("Unknown hash algorithm: {}", hash).into())
+                })?;
+            config = config.with_hash_algorithm(algorithm);
+        }
+
+        if let Some(threshold) = c.parallel_hash_threshold {
+            config = config.with_parallel_hash_threshold(threshold.parse::<u64>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(threshold) = c.chunk_threshold {
+            config = config.with_chunk_threshold(parse_bytes(&threshold)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let Some(hash_threads) = c.hash_threads {
+            config = config.with_hash_threads(hash_threads.parse::<usize>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(reserve) = c.free_space_reserve {
+            config = config.with_free_space_reserve(reserve.parse::<u64>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        let mut scripts = BackupScripts::new();
+        if let Some(pre) = c.pre_backup {
+            scripts = scripts.with_pre(&pre);
+        }
+        if let Some(post) = c.post_backup {
+            scripts = scripts.with_post(&post);
+        }
+        config = config.with_scripts(scripts);
+
+        if let Some(cap) = c.storage_soft_cap {
+            config = config.with_storage_soft_cap(parse_bytes(&cap)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let Some(cap) = c.storage_hard_cap {
+            config = config.with_storage_hard_cap(parse_bytes(&cap)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let (Some(soft), Some(hard)) = (config.storage_soft_cap(), config.storage_hard_cap()) {
+            if soft > hard {
+                return Err(HaumaruError::Config("storage_soft_cap must not be above \
+                                                 storage_hard_cap"
+                    .into()));
+            }
+        }
+
+        if let Some(threads) = c.pre_send_threads {
+            let threads = threads.parse::<usize>().map_err(|e| HaumaruError::Config(box e))?;
+            if threads == 0 {
+                return Err(HaumaruError::Config("pre_send_threads must be positive".into()));
+            }
+            config = config.with_pre_send_threads(threads);
+        }
+
+        if let Some(threads) = c.send_threads {
+            let threads = threads.parse::<usize>().map_err(|e| HaumaruError::Config(box e))?;
+            if threads == 0 {
+                return Err(HaumaruError::Config("send_threads must be positive".into()));
+            }
+            config = config.with_send_threads(threads);
+        }
+
+        if let Some(budget) = c.max_daily_upload {
+            let budget = parse_bytes(&budget).map_err(|e| HaumaruError::Config(e.into()))?;
+            if budget == 0 {
+                return Err(HaumaruError::Config("max_daily_upload must be positive".into()));
+            }
+            config = config.with_max_daily_upload(budget);
+        }
+
+        let mut retention = RetentionPolicy::new();
+        if let Some(age) = c.retention {
+            retention = retention.with_age(parse_age(&age)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+        if let Some(rules) = c.retention_rules {
+            retention = retention.with_rules(parse_rules(&rules)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+        config = config.with_retention(retention);
+
+        if let Some(nice) = c.scan_nice {
+            config = config.with_scan_nice(nice.parse::<i32>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(idle_io) = c.scan_idle_io {
+            config = config.with_scan_idle_io(idle_io.parse::<bool>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(tree_hashes) = c.tree_hashes {
+            config = config.with_tree_hashes(tree_hashes.parse::<bool>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(rate) = c.scan_io_rate {
+            let rate = rate.parse::<u64>().map_err(|e| HaumaruError::Config(box e))?;
+            if rate == 0 {
+                return Err(HaumaruError::Config("scan_io_rate must be positive".into()));
+            }
+            config = config.with_scan_io_rate(rate);
+        }
+
+        if let Some(window) = c.window {
+            config = config.with_window(window.parse::<BackupWindow>()
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let Some(threshold) = c.battery_threshold {
+            config = config.with_battery_threshold(threshold.trim_right_matches('%')
+                .parse::<u32>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        let mut metered = MeteredNetworks::new();
+        if let Some(interfaces) = c.metered_interfaces {
+            metered = metered.with_interfaces(&interfaces);
+        }
+        if let Some(ssids) = c.metered_ssids {
+            metered = metered.with_ssids(&ssids);
+        }
+        if let Some(command) = c.metered_command {
+            metered = metered.with_command(&command);
+        }
+        config = config.with_metered(metered);
+
+        if let Some(patterns) = c.transient_patterns {
+            config = config.with_transient_patterns(parse_patterns(&patterns));
+        }
+
+        if let Some(excludes) = c.excludes {
+            config = config.with_excludes(excludes.iter().map(|e| Glob::new(e)).collect());
+        }
+
+        if let Some(includes) = c.includes {
+            config = config.with_includes(includes.iter().map(|i| Glob::new(i)).collect());
+        }
+
+        if let Some(filters) = c.filters {
+            config = config.with_filters(parse_filters(&filters)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let Some(compression) = c.compression {
+            config = config.with_compression(match compression.trim() {
+                "none" => None,
+                compression => {
+                    Some(compression.parse().map_err(|e: String| HaumaruError::Config(e.into()))?)
+                }
+            });
+        }
+
+        if let Some(priorities) = c.priorities {
+            config = config.with_priorities(parse_priorities(&priorities)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        if let Some(sources) = c.generated_sources {
+            config = config.with_generated_sources(parse_generated_sources(&sources)
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        let snapshot = match (c.snapshot_create, c.snapshot_path) {
+            (Some(create), Some(path)) => {
+                let snapshot = Snapshot::new(&create, &path);
+                Some(match c.snapshot_release {
+                    Some(release) => snapshot.with_release(&release),
+                    None => snapshot,
+                })
+            }
+            (None, None) => None,
+            _ => {
+                return Err(HaumaruError::Config("snapshot_create and snapshot_path must be \
+                                                 given together"
+                    .into()))
+            }
+        };
+        config = config.with_snapshot(snapshot);
+
+        if let Some(remote) = c.remote {
+            config = config.with_remote(Some(RemoteSource::parse(&remote)
+                .map_err(|e| HaumaruError::Config(e.into()))?));
+        }
+
+        if let Some(host) = c.statsd_host {
+            let port = match c.statsd_port {
+                Some(port) => port.parse::<u16>().map_err(|e| HaumaruError::Config(box e))?,
+                None => 8125,
+            };
+            let prefix = c.statsd_prefix.unwrap_or("haumaru".to_string());
+            config = config.with_statsd(&host, port, &prefix);
+        }
+
+        if let Some(url) = c.webhook_url {
+            config = config.with_webhook_url(&url);
+        }
+
+        if let Some(url) = c.healthcheck_url {
+            config = config.with_healthcheck_url(&url);
+        }
+
+        if let Some(host) = c.smtp_host {
+            let port = match c.smtp_port {
+                Some(port) => port.parse::<u16>().map_err(|e| HaumaruError::Config(box e))?,
+                None => 25,
+            };
+            let to: Vec<String> = c.smtp_to
+                .unwrap_or(String::new())
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+            if to.is_empty() {
+                return Err(HaumaruError::Config("smtp_to is required with smtp_host".into()));
+            }
+            let from = c.smtp_from.unwrap_or("haumaru@localhost".to_string());
+            let mut smtp = SmtpSettings::new(&host, port, &from, to);
+            if let Some(username) = c.smtp_username {
+                smtp = smtp.with_credentials(&username, &c.smtp_password.unwrap_or(String::new()));
+            }
+            config = config.with_smtp(smtp);
+        }
+
+        if let Some(desktop) = c.desktop_notifications {
+            config = config.with_desktop_notifications(desktop.parse::<bool>()
+                .map_err(|e| HaumaruError::Config(box e))?);
+        }
+
+        if let Some(output) = c.output {
+            config = config.with_output(output.parse::<OutputFormat>()
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+
+        let mut times = TimeStyle::new();
+        if let Some(time_format) = c.time_format {
+            times = times.with_format(time_format.parse::<TimeFormat>()
+                .map_err(|e| HaumaruError::Config(e.into()))?);
+        }
+        if let Some(utc) = c.utc {
+            times = times.with_utc(utc.parse::<bool>().map_err(|e| HaumaruError::Config(box e))?);
+        }
+        config = config.with_times(times);
+
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use EngineConfig;
+    use std::convert::TryFrom;
+    use super::*;
+
+    #[test]
+    fn jobs() {
+        let mut yaml = "
+working: /var/lib/haumaru
+storage: s3
+bucket: shared
+jobs:
+  photos:
+    path: /home/photos
+    period: \"3600\"
+  scratch:
+    path: /scratch
+    storage: local
+    namespace: scratch
+"
+            .as_bytes();
+        let config = yaml.as_config().unwrap();
+        assert_eq!(vec!["photos".to_string(), "scratch".to_string()], config.job_names());
+
+        let photos = config.job("photos").unwrap();
+        assert_eq!(Some("/home/photos".to_string()), photos.path());
+        assert_eq!(Some("/var/lib/haumaru/jobs/photos".to_string()), photos.working());
+        assert_eq!(Some("shared"), photos.bucket());
+        assert_eq!("3600".to_string(), photos.period());
+        assert!(photos.job_names().is_empty());
+
+        let scratch = config.job("scratch").unwrap();
+        assert_eq!(Some("local".to_string()), scratch.storage);
+        assert_eq!(Some("scratch".to_string()), scratch.namespace);
+
+        assert!(config.job("music").is_err());
+        assert!(EngineConfig::try_from(config).is_err());
+    }
+
+    #[test]
+    fn excludes() {
+        let mut yaml = "
+working: /var/lib/haumaru
+path: /home
+excludes:
+  - \"**/*.tmp\"
+  - \"**/node_modules/**\"
+includes:
+  - Documents/**
+"
+            .as_bytes();
+        let config = EngineConfig::try_from(yaml.as_config().unwrap()).unwrap();
+        let excludes: Vec<&str> = config.excludes().iter().map(|g| g.as_str()).collect();
+        assert_eq!(vec!["**/*.tmp", "**/node_modules/**"], excludes);
+        let includes: Vec<&str> = config.includes().iter().map(|g| g.as_str()).collect();
+        assert_eq!(vec!["Documents/**"], includes);
+    }
+
+    #[test]
+    fn paths() {
+        let mut yaml = "
+working: /var/lib/haumaru
+paths:
+  - /etc
+  - /var/www
+"
+            .as_bytes();
+        let config = EngineConfig::try_from(yaml.as_config().unwrap()).unwrap();
+        assert_eq!("/", config.path());
+        assert_eq!(vec!["/etc".to_string(), "/var/www".to_string()], config.roots().to_vec());
+
+        let mut yaml = "
+working: /var/lib/haumaru
+paths: []
+"
+            .as_bytes();
+        assert!(EngineConfig::try_from(yaml.as_config().unwrap()).is_err());
+    }
+
+    #[test]
+    fn scan_settings() {
+        let mut yaml = "
+working: /var/lib/haumaru
+path: /home
+scan_nice: \"10\"
+scan_idle_io: \"true\"
+scan_io_rate: \"1048576\"
+"
+            .as_bytes();
+        let config = EngineConfig::try_from(yaml.as_config().unwrap()).unwrap();
+        assert_eq!(Some(10), config.scan_nice());
+        assert!(config.scan_idle_io());
+        assert_eq!(Some(1048576), config.scan_io_rate());
+
+        let mut yaml = "
+working: /var/lib/haumaru
+scan_io_rate: \"0\"
+"
+            .as_bytes();
+        assert!(EngineConfig::try_from(yaml.as_config().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,773 @@
+//! Programmatic control of the engine. The same calls are described for other languages by
+//! `proto/haumaru.proto`.
+//!
+//! A running daemon also serves them on a unix socket in the working directory so CLI
+//! commands go through the daemon instead of opening the index behind its back. Each
+//! connection carries one JSON encoded `Request` line and gets back a stream of JSON
+//! encoded `Reply` lines, the last of which has `done` set.
+
+use cost::DEFAULT_STORAGE_CLASS;
+use find::FindQuery;
+use index::KeyScope;
+use libc;
+use listing::ListOptions;
+use output::OutputFormat;
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use time_format::TimeStyle;
+use time::{Timespec, at, strftime};
+
+/// What the engine is up to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    /// Whether a backup loop is running.
+    pub running: bool,
+    pub path: String,
+    pub phase: String,
+    pub paused: Vec<String>,
+    pub pre_send_queue: u64,
+    pub send_queue: u64,
+    pub sent_queue: u64,
+    pub last_backup_set: Option<u64>,
+    pub last_backup_at: Option<i64>,
+    pub next_run: Option<i64>,
+    pub pending_changes: u64,
+}
+
+fn format_time(sec: i64) -> String {
+    strftime("%Y-%m-%d %H:%M:%S %z", &at(Timespec::new(sec, 0))).expect("time format")
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.running {
+            writeln!(f, "Daemon:      running")?;
+        } else {
+            writeln!(f, "Daemon:      not running")?;
+        }
+        writeln!(f, "Path:        {}", self.path)?;
+        writeln!(f, "Phase:       {}", self.phase)?;
+        if !self.paused.is_empty() {
+            writeln!(f, "Paused:      {}", self.paused.join(", "))?;
+        }
+        writeln!(f,
+                 "Queues:      {} pre-send, {} send, {} sent",
+                 self.pre_send_queue,
+                 self.send_queue,
+                 self.sent_queue)?;
+        match (self.last_backup_set, self.last_backup_at) {
+            (Some(set), Some(at)) => {
+                writeln!(f, "Last backup: set {} at {}", set, format_time(at))?
+            }
+            _ => writeln!(f, "Last backup: none")?,
+        }
+        if let Some(next_run) = self.next_run {
+            writeln!(f, "Next run:    {}", format_time(next_run))?;
+        }
+        write!(f, "Pending:     {} change(s)", self.pending_changes)
+    }
+}
+
+/// The control API. Long running calls report progress a line at a time through
+/// `progress`.
+pub trait ControlService {
+    fn backup(&mut self, progress: &mut FnMut(&str)) -> Result<(), Box<Error>>;
+    /// Restore `key` under `target`, reading from storage at up to `limit_rate` bytes per
+    /// second, or with `dry_run` only report what would be restored.
+    fn restore(&mut self,
+               key: &str,
+               from: Option<Timespec>,
+               target: &str,
+               limit_rate: Option<u64>,
+               dry_run: bool,
+               format: OutputFormat,
+               progress: &mut FnMut(&str))
+               -> Result<(), Box<Error>>;
+    /// Restore the files below `key` deleted at or after `since` to where they were, under
+    /// `target`.
+    fn undelete(&mut self,
+                key: &str,
+                since: Timespec,
+                target: &str,
+                format: OutputFormat,
+                progress: &mut FnMut(&str))
+                -> Result<(), Box<Error>>;
+    fn list(&mut self,
+            key: &str,
+            from: Option<Timespec>,
+            options: &ListOptions,
+            format: OutputFormat,
+            progress: &mut FnMut(&str))
+            -> Result<(), Box<Error>>;
+    /// Everything below `key` matching `query`.
+    fn find(&mut self,
+            key: &str,
+            from: Option<Timespec>,
+            query: &FindQuery,
+            format: OutputFormat,
+            progress: &mut FnMut(&str))
+            -> Result<(), Box<Error>>;
+    fn verify(&mut self,
+              scope: &KeyScope,
+              format: OutputFormat,
+              progress: &mut FnMut(&str))
+              -> Result<(), Box<Error>>;
+    fn status(&mut self) -> Result<Status, Box<Error>>;
+    fn stats(&mut self, format: OutputFormat, progress: &mut FnMut(&str)) -> Result<(), Box<Error>>;
+    /// Every backup set with its size.
+    fn snapshots(&mut self,
+                 times: &TimeStyle,
+                 format: OutputFormat,
+                 progress: &mut FnMut(&str))
+                 -> Result<(), Box<Error>>;
+    fn du(&mut self,
+          key: &str,
+          from: Option<Timespec>,
+          depth: usize,
+          progress: &mut FnMut(&str))
+          -> Result<(), Box<Error>>;
+    /// Throughput, slowest files, retries and stage times of the run producing `backup_set`.
+    fn report(&mut self,
+              backup_set: u64,
+              times: &TimeStyle,
+              format: OutputFormat,
+              progress: &mut FnMut(&str))
+              -> Result<(), Box<Error>>;
+    /// Paths the run producing `backup_set` left out, and why.
+    fn skipped(&mut self,
+               backup_set: u64,
+               format: OutputFormat,
+               progress: &mut FnMut(&str))
+               -> Result<(), Box<Error>>;
+    /// Files on disk that are new, changed or missing compared to the latest backup, hashing
+    /// their content when `hash` is set.
+    fn compare(&mut self,
+               hash: bool,
+               format: OutputFormat,
+               progress: &mut FnMut(&str))
+               -> Result<(), Box<Error>>;
+    /// Estimated monthly S3 cost of the repository kept in `storage_class`.
+    fn cost(&mut self,
+            storage_class: &str,
+            format: OutputFormat,
+            progress: &mut FnMut(&str))
+            -> Result<(), Box<Error>>;
+    /// Remove what the retention policy no longer keeps, from the index and from storage.
+    fn prune(&mut self, format: OutputFormat, progress: &mut FnMut(&str)) -> Result<(), Box<Error>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    method: String,
+    key: Option<String>,
+    from: Option<i64>,
+    target: Option<String>,
+    like: Option<String>,
+    glob: Option<String>,
+    depth: Option<usize>,
+    dry_run: Option<bool>,
+    json: Option<bool>,
+    backup_set: Option<u64>,
+    list: Option<ListOptions>,
+    find: Option<FindQuery>,
+    hash: Option<bool>,
+    storage_class: Option<String>,
+    limit_rate: Option<u64>,
+    times: Option<TimeStyle>,
+}
+
+impl Request {
+    fn new(method: &str) -> Self {
+        Request {
+            method: method.to_string(),
+            key: None,
+            from: None,
+            target: None,
+            like: None,
+            glob: None,
+            depth: None,
+            dry_run: None,
+            json: None,
+            backup_set: None,
+            list: None,
+            find: None,
+            hash: None,
+            storage_class: None,
+            limit_rate: None,
+            times: None,
+        }
+    }
+    pub fn backup() -> Self {
+        Self::new("backup")
+    }
+    pub fn restore(key: &str, from: Option<Timespec>, target: &str, dry_run: bool) -> Self {
+        let mut req = Self::new("restore");
+        req.key = Some(key.to_string());
+        req.from = from.map(|t| t.sec);
+        req.target = Some(target.to_string());
+        req.dry_run = Some(dry_run);
+        req
+    }
+    pub fn undelete(key: &str, since: Timespec, target: &str) -> Self {
+        let mut req = Self::new("undelete");
+        req.key = Some(key.to_string());
+        req.from = Some(since.sec);
+        req.target = Some(target.to_string());
+        req
+    }
+    /// Read from storage at up to `rate` bytes per second while restoring.
+    pub fn with_limit_rate(mut self, rate: Option<u64>) -> Self {
+        self.limit_rate = rate;
+        self
+    }
+    pub fn list(key: &str, from: Option<Timespec>) -> Self {
+        let mut req = Self::new("list");
+        req.key = Some(key.to_string());
+        req.from = from.map(|t| t.sec);
+        req
+    }
+    pub fn with_list_options(mut self, options: ListOptions) -> Self {
+        self.list = Some(options);
+        self
+    }
+    pub fn find(key: &str, from: Option<Timespec>, query: FindQuery) -> Self {
+        let mut req = Self::new("find");
+        req.key = Some(key.to_string());
+        req.from = from.map(|t| t.sec);
+        req.find = Some(query);
+        req
+    }
+    pub fn verify(scope: &KeyScope) -> Self {
+        let mut req = Self::new("verify");
+        match *scope {
+            KeyScope::Glob(ref glob) => req.glob = Some(glob.as_str().to_string()),
+            KeyScope::Like(ref like) => req.like = Some(like.clone()),
+        }
+        req
+    }
+    pub fn status() -> Self {
+        Self::new("status")
+    }
+    pub fn stats() -> Self {
+        Self::new("stats")
+    }
+    pub fn snapshots() -> Self {
+        Self::new("snapshots")
+    }
+    pub fn report(backup_set: u64) -> Self {
+        let mut req = Self::new("report");
+        req.backup_set = Some(backup_set);
+        req
+    }
+    pub fn skipped(backup_set: u64) -> Self {
+        let mut req = Self::new("skipped");
+        req.backup_set = Some(backup_set);
+        req
+    }
+    pub fn compare(hash: bool) -> Self {
+        let mut req = Self::new("compare");
+        req.hash = Some(hash);
+        req
+    }
+    pub fn cost(storage_class: &str) -> Self {
+        let mut req = Self::new("cost");
+        req.storage_class = Some(storage_class.to_string());
+        req
+    }
+    pub fn prune() -> Self {
+        Self::new("prune")
+    }
+    /// Ask for results in `format`.
+    pub fn with_times(mut self, times: TimeStyle) -> Self {
+        self.times = Some(times);
+        self
+    }
+    fn times(&self) -> TimeStyle {
+        self.times.unwrap_or(TimeStyle::new())
+    }
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.json = Some(format == OutputFormat::Json);
+        self
+    }
+    fn format(&self) -> OutputFormat {
+        match self.json {
+            Some(true) => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+    pub fn du(key: &str, from: Option<Timespec>, depth: usize) -> Self {
+        let mut req = Self::new("du");
+        req.key = Some(key.to_string());
+        req.from = from.map(|t| t.sec);
+        req.depth = Some(depth);
+        req
+    }
+    fn key(&self) -> &str {
+        self.key.as_ref().map(|s| s.as_str()).unwrap_or("")
+    }
+    fn from(&self) -> Option<Timespec> {
+        self.from.map(|sec| Timespec::new(sec, 0))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    line: Option<String>,
+    status: Option<Status>,
+    error: Option<String>,
+    done: bool,
+}
+
+impl Reply {
+    fn progress(line: &str) -> Self {
+        Reply {
+            line: Some(line.to_string()),
+            status: None,
+            error: None,
+            done: false,
+        }
+    }
+    fn done(result: Result<Option<Status>, Box<Error>>) -> Self {
+        let (status, error) = match result {
+            Ok(status) => (status, None),
+            Err(e) => (None, Some(format!("{}", e))),
+        };
+        Reply {
+            line: None,
+            status: status,
+            error: error,
+            done: true,
+        }
+    }
+    pub fn line(&self) -> Option<&str> {
+        self.line.as_ref().map(|s| s.as_str())
+    }
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_ref().map(|s| s.as_str())
+    }
+}
+
+/// Run `request` against `service`, passing each reply to `reply`.
+pub fn dispatch(service: &mut ControlService, request: &Request, reply: &mut FnMut(Reply)) {
+    let result = {
+        let mut progress = |line: &str| reply(Reply::progress(line));
+        match request.method.as_str() {
+            "backup" => service.backup(&mut progress).map(|_| None),
+            "restore" => {
+                match request.target {
+                    Some(ref target) => {
+                        service.restore(request.key(),
+                                     request.from(),
+                                     target,
+                                     request.limit_rate,
+                                     request.dry_run.unwrap_or(false),
+                                     request.format(),
+                                     &mut progress)
+                            .map(|_| None)
+                    }
+                    None => Err("restore needs a target".into()),
+                }
+            }
+            "undelete" => {
+                match (request.from(), request.target.as_ref()) {
+                    (Some(since), Some(target)) => {
+                        service.undelete(request.key(),
+                                      since,
+                                      target,
+                                      request.format(),
+                                      &mut progress)
+                            .map(|_| None)
+                    }
+                    _ => Err("undelete needs a time and a target".into()),
+                }
+            }
+            "list" => {
+                let options = request.list.clone().unwrap_or_default();
+                service.list(request.key(),
+                          request.from(),
+                          &options,
+                          request.format(),
+                          &mut progress)
+                    .map(|_| None)
+            }
+            "find" => {
+                let query = request.find.clone().unwrap_or_default();
+                service.find(request.key(),
+                          request.from(),
+                          &query,
+                          request.format(),
+                          &mut progress)
+                    .map(|_| None)
+            }
+            "verify" => {
+                // clients from before globs only send `like`
+                let scope = match (request.like.as_ref(), request.glob.as_ref()) {
+                    (Some(like), None) => KeyScope::like(like),
+                    (_, glob) => KeyScope::glob(glob.map(|s| s.as_str()).unwrap_or("")),
+                };
+                service.verify(&scope, request.format(), &mut progress).map(|_| None)
+            }
+            "status" => service.status().map(Some),
+            "stats" => service.stats(request.format(), &mut progress).map(|_| None),
+            "snapshots" => {
+                service.snapshots(&request.times(), request.format(), &mut progress)
+                    .map(|_| None)
+            }
+            "du" => {
+                let depth = request.depth.unwrap_or(1);
+                service.du(request.key(), request.from(), depth, &mut progress).map(|_| None)
+            }
+            "report" => {
+                match request.backup_set {
+                    Some(backup_set) => {
+                        service.report(backup_set,
+                                    &request.times(),
+                                    request.format(),
+                                    &mut progress)
+                            .map(|_| None)
+                    }
+                    None => Err("report needs a backup set".into()),
+                }
+            }
+            "skipped" => {
+                match request.backup_set {
+                    Some(backup_set) => {
+                        service.skipped(backup_set, request.format(), &mut progress).map(|_| None)
+                    }
+                    None => Err("skipped needs a backup set".into()),
+                }
+            }
+            "compare" => {
+                let hash = request.hash.unwrap_or(false);
+                service.compare(hash, request.format(), &mut progress).map(|_| None)
+            }
+            "cost" => {
+                let class = request.storage_class
+                    .as_ref()
+                    .map(|s| s.as_str())
+                    .unwrap_or(DEFAULT_STORAGE_CLASS);
+                service.cost(class, request.format(), &mut progress).map(|_| None)
+            }
+            "prune" => service.prune(request.format(), &mut progress).map(|_| None),
+            method => Err(format!("Unknown method {:?}", method).into()),
+        }
+    };
+    reply(Reply::done(result));
+}
+
+pub fn socket_path(working: &Path) -> PathBuf {
+    working.join("haumaru.sock")
+}
+
+/// Serve `service` on a unix socket at `path`, one connection at a time. Only the owner of
+/// the daemon (or root) may connect. Fails if another daemon is already serving `path`.
+pub fn serve<C>(path: &Path, mut service: C) -> io::Result<()>
+    where C: ControlService + Send + 'static
+{
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(ErrorKind::AddrInUse,
+                                      format!("{:?} is in use by another daemon", path)));
+        }
+        // a socket left behind by a previous daemon
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {:?}", path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle(&mut service, stream));
+            if let Err(e) = result {
+                warn!("Control connection failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Whether the process at the other end of `stream` runs as root or as our own user.
+fn trusted_peer(stream: &UnixStream) -> io::Result<bool> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid == 0 || cred.uid == unsafe { libc::geteuid() })
+}
+
+fn handle(service: &mut ControlService, stream: UnixStream) -> io::Result<()> {
+    // the socket mode is only set after bind, so check who is calling as well
+    if !trusted_peer(&stream)? {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                  "Control connection from another user"));
+    }
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+    let mut writer = stream;
+
+    let request: Request = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => {
+            let reply = Reply::done(Err(format!("Bad request: {}", e).into()));
+            return write_reply(&mut writer, &reply);
+        }
+    };
+    debug!("Control request {:?}", request);
+
+    let mut result = Ok(());
+    dispatch(service,
+             &request,
+             &mut |reply| if result.is_ok() {
+                 result = write_reply(&mut writer, &reply);
+             });
+    result
+}
+
+fn write_reply(writer: &mut Write, reply: &Reply) -> io::Result<()> {
+    let json = serde_json::to_string(reply)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+    writeln!(writer, "{}", json)
+}
+
+/// Connection to a running daemon.
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// Connect to the daemon using `working`, or None when no daemon is running.
+    pub fn connect(working: &Path) -> Option<Client> {
+        let path = socket_path(working);
+        if !path.exists() {
+            return None;
+        }
+        match UnixStream::connect(&path) {
+            Ok(stream) => Some(Client { stream: stream }),
+            Err(e) => {
+                debug!("No daemon on {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Send `request`, passing progress lines to `progress`.
+    pub fn call(mut self,
+                request: &Request,
+                progress: &mut FnMut(&str))
+                -> Result<Option<Status>, Box<Error>> {
+        writeln!(self.stream, "{}", serde_json::to_string(request)?)?;
+        for line in BufReader::new(self.stream).lines() {
+            let reply: Reply = serde_json::from_str(&line?)?;
+            if let Some(ref line) = reply.line {
+                progress(line);
+            }
+            if reply.done {
+                return match reply.error {
+                    Some(e) => Err(e.into()),
+                    None => Ok(reply.status),
+                };
+            }
+        }
+        Err("Daemon closed the control connection".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use find::FindQuery;
+    use index::KeyScope;
+    use listing::ListOptions;
+    use output::OutputFormat;
+    use std::error::Error;
+    use time::Timespec;
+    use time_format::TimeStyle;
+    use super::*;
+
+    struct Fake {
+        listed: Vec<(String, Option<Timespec>)>,
+    }
+
+    impl ControlService for Fake {
+        fn backup(&mut self, _progress: &mut FnMut(&str)) -> Result<(), Box<Error>> {
+            Err("no".into())
+        }
+        fn restore(&mut self,
+                   _key: &str,
+                   _from: Option<Timespec>,
+                   _target: &str,
+                   _limit_rate: Option<u64>,
+                   _dry_run: bool,
+                   _format: OutputFormat,
+                   _progress: &mut FnMut(&str))
+                   -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn undelete(&mut self,
+                    _key: &str,
+                    _since: Timespec,
+                    _target: &str,
+                    _format: OutputFormat,
+                    _progress: &mut FnMut(&str))
+                    -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn list(&mut self,
+                key: &str,
+                from: Option<Timespec>,
+                _options: &ListOptions,
+                _format: OutputFormat,
+                progress: &mut FnMut(&str))
+                -> Result<(), Box<Error>> {
+            self.listed.push((key.to_string(), from));
+            progress("a");
+            progress("b");
+            Ok(())
+        }
+        fn find(&mut self,
+                _key: &str,
+                _from: Option<Timespec>,
+                _query: &FindQuery,
+                _format: OutputFormat,
+                _progress: &mut FnMut(&str))
+                -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn verify(&mut self,
+                  _scope: &KeyScope,
+                  _format: OutputFormat,
+                  _progress: &mut FnMut(&str))
+                  -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn status(&mut self) -> Result<Status, Box<Error>> {
+            Ok(Status {
+                running: true,
+                path: "/data".into(),
+                phase: "watching".into(),
+                paused: vec![],
+                pre_send_queue: 0,
+                send_queue: 0,
+                sent_queue: 0,
+                last_backup_set: Some(3),
+                last_backup_at: Some(600),
+                next_run: None,
+                pending_changes: 2,
+            })
+        }
+        fn stats(&mut self,
+                 _format: OutputFormat,
+                 _progress: &mut FnMut(&str))
+                 -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn snapshots(&mut self,
+                     _times: &TimeStyle,
+                     _format: OutputFormat,
+                     _progress: &mut FnMut(&str))
+                     -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn du(&mut self,
+              _key: &str,
+              _from: Option<Timespec>,
+              _depth: usize,
+              _progress: &mut FnMut(&str))
+              -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn report(&mut self,
+                  _backup_set: u64,
+                  _times: &TimeStyle,
+                  _format: OutputFormat,
+                  _progress: &mut FnMut(&str))
+                  -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn skipped(&mut self,
+                   _backup_set: u64,
+                   _format: OutputFormat,
+                   _progress: &mut FnMut(&str))
+                   -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn compare(&mut self,
+                   _hash: bool,
+                   _format: OutputFormat,
+                   _progress: &mut FnMut(&str))
+                   -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn cost(&mut self,
+                _storage_class: &str,
+                _format: OutputFormat,
+                _progress: &mut FnMut(&str))
+                -> Result<(), Box<Error>> {
+            Ok(())
+        }
+        fn prune(&mut self,
+                 _format: OutputFormat,
+                 _progress: &mut FnMut(&str))
+                 -> Result<(), Box<Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dispatch_list() {
+        let mut fake = Fake { listed: vec![] };
+        let mut replies = vec![];
+        dispatch(&mut fake,
+                 &Request::list("x", Some(Timespec::new(5, 0))),
+                 &mut |r| replies.push(r));
+        assert_eq!(vec![("x".to_string(), Some(Timespec::new(5, 0)))], fake.listed);
+        assert_eq!(3, replies.len());
+        assert_eq!(Some("a".to_string()), replies[0].line);
+        assert!(replies[2].done);
+        assert_eq!(None, replies[2].error);
+    }
+
+    #[test]
+    fn dispatch_error() {
+        let mut fake = Fake { listed: vec![] };
+        let mut replies = vec![];
+        dispatch(&mut fake, &Request::backup(), &mut |r| replies.push(r));
+        assert_eq!(1, replies.len());
+        assert_eq!(Some("no".to_string()), replies[0].error);
+    }
+
+    #[test]
+    fn round_trip_over_socket() {
+        let dir = ::std::env::temp_dir().join("haumaru-control-test");
+        let _ = ::std::fs::create_dir_all(&dir);
+        serve(&socket_path(&dir), Fake { listed: vec![] }).unwrap();
+
+        let mut lines = vec![];
+        let status = Client::connect(&dir)
+            .expect("connect")
+            .call(&Request::list("", None), &mut |l| lines.push(l.to_string()))
+            .unwrap();
+        assert_eq!(vec!["a".to_string(), "b".to_string()], lines);
+        assert_eq!(None, status);
+
+        let status = Client::connect(&dir).expect("connect").call(&Request::status(), &mut |_| ());
+        assert_eq!("/data", status.unwrap().unwrap().path);
+    }
+}
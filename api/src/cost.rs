@@ -0,0 +1,230 @@
+//! `haumaru cost`: what the repository costs to keep in S3, worked out from the index.
+
+use index::IndexStats;
+use std::cmp::max;
+use std::fmt;
+use units::format_bytes;
+
+/// S3 prices of one storage class, in US dollars.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoragePrices {
+    pub class: &'static str,
+    /// Per GiB stored for a month.
+    pub gb_month: f64,
+    /// Per thousand PUT requests.
+    pub put_per_1000: f64,
+    /// Per thousand GET and HEAD requests.
+    pub get_per_1000: f64,
+}
+
+/// us-east-1 list prices.
+const PRICES: [StoragePrices; 3] = [StoragePrices {
+                                         class: "STANDARD",
+                                         gb_month: 0.023,
+                                         put_per_1000: 0.005,
+                                         get_per_1000: 0.0004,
+                                     },
+                                     StoragePrices {
+                                         class: "STANDARD_IA",
+                                         gb_month: 0.0125,
+                                         put_per_1000: 0.01,
+                                         get_per_1000: 0.001,
+                                     },
+                                     StoragePrices {
+                                         class: "GLACIER",
+                                         gb_month: 0.004,
+                                         put_per_1000: 0.05,
+                                         get_per_1000: 0.0004,
+                                     }];
+
+/// Storage class the S3 backend uploads with.
+pub const DEFAULT_STORAGE_CLASS: &'static str = "STANDARD_IA";
+
+const MONTH_SECS: i64 = 30 * 24 * 60 * 60;
+const DAY_SECS: i64 = 24 * 60 * 60;
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Months ahead growth is projected for.
+const PROJECTION_MONTHS: [u32; 3] = [3, 6, 12];
+
+pub fn prices(class: &str) -> Result<StoragePrices, String> {
+    let class = class.to_uppercase();
+    PRICES.iter()
+        .find(|p| p.class == class)
+        .cloned()
+        .ok_or_else(|| {
+            let known: Vec<&str> = PRICES.iter().map(|p| p.class).collect();
+            format!("Unknown storage class {}, expected one of {}",
+                    class,
+                    known.join(", "))
+        })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Projection {
+    pub months: u32,
+    pub stored_bytes: u64,
+    pub monthly_cost: f64,
+}
+
+/// Monthly cost of the repository as it is, and as it grows at its recent rate.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CostEstimate {
+    pub storage_class: String,
+    pub stored_bytes: u64,
+    pub monthly_growth_bytes: u64,
+    pub monthly_puts: u64,
+    pub monthly_gets: u64,
+    pub storage_cost: f64,
+    pub request_cost: f64,
+    pub projections: Vec<Projection>,
+}
+
+impl CostEstimate {
+    pub fn monthly_cost(&self) -> f64 {
+        self.storage_cost + self.request_cost
+    }
+}
+
+/// Estimate the monthly cost of the repository described by `stats` as of `now`.
+///
+/// Growth and requests come from the backup sets of the last month, leaving out the first
+/// set, which holds the initial upload. A younger repository has its rate scaled up to a
+/// month. Each file a run sends costs a HEAD to see whether it is stored already and a PUT
+/// when it is not; each set also PUTs the index.
+pub fn estimate(stats: &IndexStats, now: i64, prices: &StoragePrices) -> CostEstimate {
+    let (mut growth, mut puts, mut gets) = (0u64, 0u64, 0u64);
+    let mut scale = 1.0;
+    if let Some(first) = stats.backup_sets.first() {
+        let since = if now - first.at >= MONTH_SECS {
+            now - MONTH_SECS
+        } else {
+            scale = MONTH_SECS as f64 / max(now - first.at, DAY_SECS) as f64;
+            first.at
+        };
+        for set in stats.backup_sets.iter().skip(1).filter(|set| set.at >= since) {
+            growth += set.new_bytes;
+            puts += 1;
+            if let Some(ref summary) = set.summary {
+                let sent = summary.new + summary.changed;
+                gets += sent;
+                puts += sent.saturating_sub(summary.dedup_hits);
+            }
+        }
+    }
+    let growth = (growth as f64 * scale) as u64;
+    let puts = (puts as f64 * scale) as u64;
+    let gets = (gets as f64 * scale) as u64;
+
+    let storage_cost = |bytes: u64| bytes as f64 / GIB * prices.gb_month;
+    let request_cost = puts as f64 / 1000.0 * prices.put_per_1000 +
+                       gets as f64 / 1000.0 * prices.get_per_1000;
+    let projections = PROJECTION_MONTHS.iter()
+        .map(|&months| {
+            let bytes = stats.stored_bytes + growth * months as u64;
+            Projection {
+                months: months,
+                stored_bytes: bytes,
+                monthly_cost: storage_cost(bytes) + request_cost,
+            }
+        })
+        .collect();
+
+    CostEstimate {
+        storage_class: prices.class.to_string(),
+        stored_bytes: stats.stored_bytes,
+        monthly_growth_bytes: growth,
+        monthly_puts: puts,
+        monthly_gets: gets,
+        storage_cost: storage_cost(stats.stored_bytes),
+        request_cost: request_cost,
+        projections: projections,
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Storage class: {}", self.storage_class)?;
+        writeln!(f,
+                 "Storage:       {}, ${:.2} / month",
+                 format_bytes(self.stored_bytes),
+                 self.storage_cost)?;
+        writeln!(f,
+                 "Requests:      {} PUT, {} GET/HEAD, ${:.2} / month",
+                 self.monthly_puts,
+                 self.monthly_gets,
+                 self.request_cost)?;
+        writeln!(f, "Total:         ${:.2} / month", self.monthly_cost())?;
+        write!(f,
+               "Growth:        {} / month",
+               format_bytes(self.monthly_growth_bytes))?;
+        for p in &self.projections {
+            write!(f,
+                   "\nIn {:>2} months:  {}, ${:.2} / month",
+                   p.months,
+                   format_bytes(p.stored_bytes),
+                   p.monthly_cost)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use index::{BackupSetStats, IndexStats, RunSummary};
+
+    fn set(id: u64, at: i64, new_bytes: u64, summary: Option<RunSummary>) -> BackupSetStats {
+        BackupSetStats {
+            id: id,
+            at: at,
+            versions: 0,
+            bytes: 0,
+            new_bytes: new_bytes,
+            summary: summary,
+            aborted: false,
+        }
+    }
+
+    #[test]
+    fn known_classes() {
+        assert_eq!("STANDARD_IA", prices("standard_ia").unwrap().class);
+        assert!(prices("REDUCED").is_err());
+    }
+
+    #[test]
+    fn growth_over_the_last_month() {
+        let gib = 1 << 30;
+        let now = 100 * DAY_SECS;
+        let summary = RunSummary {
+            new: 1500,
+            changed: 500,
+            dedup_hits: 1000,
+            ..RunSummary::default()
+        };
+        let stats = IndexStats {
+            stored_bytes: 100 * gib,
+            backup_sets: vec![set(1, 0, 90 * gib, None),
+                              set(2, 50 * DAY_SECS, gib, None),
+                              set(3, 80 * DAY_SECS, 10 * gib, Some(summary))],
+            ..IndexStats::default()
+        };
+        let estimate = estimate(&stats, now, &prices("STANDARD").unwrap());
+        assert_eq!(10 * gib, estimate.monthly_growth_bytes);
+        assert_eq!(1001, estimate.monthly_puts);
+        assert_eq!(2000, estimate.monthly_gets);
+        assert!((estimate.storage_cost - 2.3).abs() < 1e-9);
+        assert_eq!(130 * gib, estimate.projections[0].stored_bytes);
+    }
+
+    #[test]
+    fn young_repository_is_scaled_to_a_month() {
+        let now = 15 * DAY_SECS;
+        let stats = IndexStats {
+            backup_sets: vec![set(1, 0, 1000, None), set(2, DAY_SECS, 100, None)],
+            ..IndexStats::default()
+        };
+        assert_eq!(200,
+                   estimate(&stats, now, &prices("GLACIER").unwrap()).monthly_growth_bytes);
+    }
+}
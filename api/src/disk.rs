@@ -0,0 +1,48 @@
+use libc;
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Bytes free for unprivileged use on the filesystem holding `path`.
+pub fn available_space(path: &Path) -> IoResult<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    let rc = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if rc == 0 {
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+/// Fail unless `path` has room for `needed` bytes with `reserve` left over.
+pub fn ensure_space(path: &Path, needed: u64, reserve: u64) -> Result<(), String> {
+    let available = available_space(path)
+        .map_err(|e| format!("Unable to check free space of {:?}: {}", path, e))?;
+    if available < needed.saturating_add(reserve) {
+        return Err(format!("Not enough free space in {:?}: {} bytes needed with {} reserved, {} \
+                            available",
+                           path,
+                           needed,
+                           reserve,
+                           available));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn space() {
+        assert!(available_space(Path::new(".")).unwrap() > 0);
+        assert!(ensure_space(Path::new("."), 0, 0).is_ok());
+        assert!(ensure_space(Path::new("."), u64::max_value(), 1).is_err());
+        assert!(available_space(Path::new("/does/not/exist")).is_err());
+    }
+}
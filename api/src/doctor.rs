@@ -0,0 +1,344 @@
+//! `haumaru doctor`: checks of the environment haumaru runs in, each with what to do about
+//! anything it finds wrong.
+
+use {EngineConfig, HashAlgorithm, Storage};
+use disk::available_space;
+use rusqlite::{Connection, SQLITE_OPEN_READ_ONLY};
+use std::fmt;
+use std::fs::{File, create_dir_all, read_dir, remove_file};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use storage;
+use units::format_bytes;
+
+/// Storage round trips slower than this make backups of many small files drag.
+const SLOW_STORAGE_MILLIS: u64 = 2000;
+
+/// S3 refuses requests signed more than 15 minutes off its clock, so skew is a problem well
+/// before that.
+#[cfg(feature = "s3")]
+const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Problem,
+}
+
+impl Severity {
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Problem => "problem",
+        }
+    }
+}
+
+/// What one check found.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// What to do about it, for anything but `Ok`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advice: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: String) -> Self {
+        Finding {
+            check: check,
+            severity: Severity::Ok,
+            message: message,
+            advice: None,
+        }
+    }
+    fn warning(check: &'static str, message: String, advice: &str) -> Self {
+        Finding {
+            check: check,
+            severity: Severity::Warning,
+            message: message,
+            advice: Some(advice.to_string()),
+        }
+    }
+    fn problem(check: &'static str, message: String, advice: &str) -> Self {
+        Finding {
+            check: check,
+            severity: Severity::Problem,
+            message: message,
+            advice: Some(advice.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:<9} {:<10} {}",
+               format!("[{}]", self.severity.label()),
+               self.check,
+               self.message)?;
+        if let Some(ref advice) = self.advice {
+            write!(f, "\n{:<20} {}", "", advice)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check against `config`.
+pub fn diagnose(config: &EngineConfig) -> Vec<Finding> {
+    let working = PathBuf::from(config.working());
+    let mut findings = vec![platform(config), working_dir(&working)];
+    if config.remote().is_none() {
+        let roots = if config.roots().is_empty() {
+            vec![config.path().to_string()]
+        } else {
+            config.roots().to_vec()
+        };
+        findings.push(inotify(&roots));
+    }
+    findings.push(free_space(&working, config.free_space_reserve()));
+    findings.push(index(&working.join("haumaru.idx")));
+    findings.push(storage_latency(config));
+    findings.extend(clock_skew(config));
+    findings
+}
+
+fn platform(config: &EngineConfig) -> Finding {
+    let backends = storage::backends().join(", ");
+    if cfg!(feature = "flaky") && config.faults().is_some() {
+        return Finding::warning("platform",
+                                "Storage faults are being injected".to_string(),
+                                "Remove faults: from the config outside of testing");
+    }
+    if cfg!(target_os = "linux") {
+        Finding::ok("platform", format!("Linux, storage backends: {}", backends))
+    } else {
+        Finding::warning("platform",
+                         format!("Not Linux, storage backends: {}", backends),
+                         "Idle IO priority and file capabilities are Linux only, and changes \
+                          may only be picked up by scans")
+    }
+}
+
+fn working_dir(working: &Path) -> Finding {
+    let probe = working.join(".doctor-probe");
+    let written = create_dir_all(working)
+        .and_then(|_| File::create(&probe))
+        .and_then(|mut file| file.write_all(b"haumaru"))
+        .and_then(|_| remove_file(&probe));
+    match written {
+        Ok(()) => Finding::ok("working", format!("{:?} is writable", working)),
+        Err(e) => {
+            Finding::problem("working",
+                             format!("Unable to write to {:?}: {}", working, e),
+                             "Fix the ownership or permissions of the working dir, or pick \
+                              another with --working")
+        }
+    }
+}
+
+/// Directories at or below `path`, which each take an inotify watch. Unreadable ones are
+/// not counted, as they cannot be watched anyway.
+fn count_dirs(path: &Path) -> u64 {
+    let mut count = 0;
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        count += 1;
+        if let Ok(entries) = read_dir(&dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    dirs.push(entry.path());
+                }
+            }
+        }
+    }
+    count
+}
+
+fn read_limit(path: &str) -> Option<u64> {
+    let mut limit = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut limit)) {
+        Ok(_) => limit.trim().parse::<u64>().ok(),
+        Err(_) => None,
+    }
+}
+
+fn inotify(roots: &[String]) -> Finding {
+    let limits = "/proc/sys/fs/inotify/max_user_watches";
+    let limit = match read_limit(limits) {
+        Some(limit) => limit,
+        None => return Finding::ok("inotify", format!("No limit found in {}", limits)),
+    };
+    let dirs = roots.iter().map(|root| count_dirs(Path::new(root))).sum::<u64>();
+    let message = format!("{} directories to watch, limit {}", dirs, limit);
+    let advice = format!("Raise the limit, such as with sysctl fs.inotify.max_user_watches={}",
+                         (dirs * 2).next_power_of_two());
+    if dirs > limit {
+        Finding::problem("inotify", message, &advice)
+    } else if dirs > limit / 10 * 8 {
+        Finding::warning("inotify", message, &advice)
+    } else {
+        Finding::ok("inotify", message)
+    }
+}
+
+fn free_space(working: &Path, reserve: u64) -> Finding {
+    match available_space(working) {
+        Ok(available) if available < reserve => {
+            Finding::problem("space",
+                             format!("{} free, below the {} reserve",
+                                     format_bytes(available),
+                                     format_bytes(reserve)),
+                             "Free up space, or lower free_space_reserve:")
+        }
+        Ok(available) => Finding::ok("space", format!("{} free", format_bytes(available))),
+        Err(e) => {
+            Finding::warning("space",
+                             format!("Unable to check free space: {}", e),
+                             "Check the working dir exists")
+        }
+    }
+}
+
+fn index(db_path: &Path) -> Finding {
+    if !db_path.exists() {
+        return Finding::ok("index", "No index yet".to_string());
+    }
+    let advice = "Move haumaru.idx aside and restore it from a copy";
+    let checked = Connection::open_with_flags(db_path, SQLITE_OPEN_READ_ONLY).and_then(|conn| {
+        let mut stmt = conn.prepare("PRAGMA quick_check")?;
+        let mut rows = stmt.query(&[])?;
+        let mut lines = vec![];
+        while let Some(row) = rows.next() {
+            let line: String = row?.get(0);
+            lines.push(line);
+        }
+        Ok(lines)
+    });
+    match checked {
+        Ok(ref lines) if lines.len() == 1 && lines[0] == "ok" => {
+            Finding::ok("index", format!("{:?} passed its integrity check", db_path))
+        }
+        Ok(lines) => {
+            Finding::problem("index",
+                             format!("{:?} is corrupt: {}", db_path, lines.join("; ")),
+                             advice)
+        }
+        Err(e) => Finding::problem("index", format!("Unable to open {:?}: {}", db_path, e), advice),
+    }
+}
+
+fn storage_latency(config: &EngineConfig) -> Finding {
+    let storage = match storage::build(config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            return Finding::problem("storage",
+                                    format!("Unable to set up {} storage: {}", config.storage(), e),
+                                    "Check the storage settings in the config")
+        }
+    };
+    // a blob nobody has, so only the round trip and the credentials are tested
+    let started = Instant::now();
+    let looked_up = storage.retrieve(HashAlgorithm::Sha256, &[0; 32]);
+    let elapsed = started.elapsed();
+    let millis = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+    match looked_up {
+        Err(e) => {
+            Finding::problem("storage",
+                             format!("{} storage failed a lookup: {}", config.storage(), e),
+                             "Check the storage credentials and that it can be reached")
+        }
+        Ok(_) if millis > SLOW_STORAGE_MILLIS => {
+            Finding::warning("storage",
+                             format!("{} storage took {}ms to answer", config.storage(), millis),
+                             "Raise send_threads: so more uploads overlap")
+        }
+        Ok(_) => {
+            Finding::ok("storage",
+                        format!("{} storage answered in {}ms", config.storage(), millis))
+        }
+    }
+}
+
+/// How far the local clock is from S3's, which signatures depend on, when storing in S3.
+#[cfg(feature = "s3")]
+fn clock_skew(config: &EngineConfig) -> Option<Finding> {
+    use hyper::Client;
+    use hyper::header::Date;
+    use time::get_time;
+
+    let bucket = match config.bucket() {
+        Some(bucket) if config.storage() == "s3" => bucket,
+        _ => return None,
+    };
+    let url = format!("https://{}.s3.amazonaws.com/", bucket);
+    let date = Client::new()
+        .head(&url[..])
+        .send()
+        .map_err(|e| format!("{}", e))
+        .and_then(|res| {
+            res.headers
+                .get::<Date>()
+                .map(|date| (date.0).0.to_timespec())
+                .ok_or_else(|| "no Date header".to_string())
+        });
+    let skew = match date {
+        Ok(date) => get_time().sec - date.sec,
+        Err(e) => {
+            return Some(Finding::warning("clock",
+                                         format!("Unable to get the time from {}: {}", url, e),
+                                         "Check S3 can be reached"))
+        }
+    };
+    let message = format!("{}s off the S3 clock", skew);
+    Some(if skew.abs() > MAX_CLOCK_SKEW_SECS {
+        Finding::problem("clock",
+                         message,
+                         "Sync the clock, such as with NTP, or S3 will refuse requests")
+    } else {
+        Finding::ok("clock", message)
+    })
+}
+
+#[cfg(not(feature = "s3"))]
+fn clock_skew(_config: &EngineConfig) -> Option<Finding> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::create_dir_all;
+    use std::path::Path;
+
+    #[test]
+    fn counts_dirs() {
+        let base = Path::new("target/test/doctor_dirs");
+        create_dir_all(base.join("a/b")).unwrap();
+        create_dir_all(base.join("c")).unwrap();
+        assert_eq!(4, count_dirs(base));
+    }
+
+    #[test]
+    fn writable_working() {
+        assert_eq!(Severity::Ok, working_dir(Path::new("target/test/doctor_working")).severity);
+        assert_eq!(Severity::Problem, free_space(Path::new("."), u64::max_value()).severity);
+        assert_eq!(Severity::Ok,
+                   index(Path::new("target/test/doctor_working/none.idx")).severity);
+    }
+
+    #[test]
+    fn display() {
+        let finding = Finding::warning("inotify",
+                                       "9 directories to watch, limit 10".to_string(),
+                                       "Raise the limit");
+        assert_eq!("[warning] inotify    9 directories to watch, limit 10\n                     \
+                    Raise the limit",
+                   format!("{}", finding));
+    }
+}
@@ -0,0 +1,104 @@
+use Node;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Size of everything under one directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub path: String,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Group the files under `prefix` by directory, `depth` levels below it, largest first.
+/// Files directly in `prefix` are counted against `prefix` itself.
+pub fn disk_usage(files: &[Node], prefix: &str, depth: usize) -> Vec<Usage> {
+    let prefix = prefix.trim_matches('/');
+    let mut usage: HashMap<String, Usage> = HashMap::new();
+
+    for file in files {
+        let rel = if prefix.is_empty() {
+            file.path()
+        } else if file.path().starts_with(prefix) &&
+                  file.path()[prefix.len()..].starts_with('/') {
+            &file.path()[prefix.len() + 1..]
+        } else {
+            continue;
+        };
+
+        // drop the file name, keep up to `depth` directories
+        let mut dirs: Vec<&str> = rel.split('/').collect();
+        dirs.pop();
+        dirs.truncate(depth);
+
+        let mut key = prefix.to_string();
+        for dir in dirs {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(dir);
+        }
+        if key.is_empty() {
+            key.push('.');
+        }
+
+        let entry = usage.entry(key.clone()).or_insert(Usage {
+            path: key,
+            bytes: 0,
+            files: 0,
+        });
+        entry.bytes += file.size();
+        entry.files += 1;
+    }
+
+    let mut usage: Vec<Usage> = usage.into_iter().map(|(_, u)| u).collect();
+    usage.sort_by(|a, b| match b.bytes.cmp(&a.bytes) {
+        Ordering::Equal => a.path.cmp(&b.path),
+        order => order,
+    });
+    usage
+}
+
+#[cfg(test)]
+mod test {
+    use Node;
+    use super::*;
+    use time::Timespec;
+
+    fn file(path: &str, size: u64) -> Node {
+        Node::new_file(path, Timespec::new(0, 0), size, 420)
+    }
+
+    #[test]
+    fn by_top_level_dir() {
+        let files = vec![file("a/x", 10), file("a/b/y", 20), file("c/z", 5), file("top", 1)];
+        let usage = disk_usage(&files, "", 1);
+        assert_eq!(vec![Usage {
+                            path: "a".into(),
+                            bytes: 30,
+                            files: 2,
+                        },
+                        Usage {
+                            path: "c".into(),
+                            bytes: 5,
+                            files: 1,
+                        },
+                        Usage {
+                            path: ".".into(),
+                            bytes: 1,
+                            files: 1,
+                        }],
+                   usage);
+    }
+
+    #[test]
+    fn under_prefix() {
+        let files = vec![file("a/x", 10), file("a/b/y", 20), file("ab/z", 5)];
+        let usage = disk_usage(&files, "a", 1);
+        assert_eq!(2, usage.len());
+        assert_eq!("a/b", usage[0].path);
+        assert_eq!(20, usage[0].bytes);
+        assert_eq!("a", usage[1].path);
+        assert_eq!(10, usage[1].bytes);
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Times reading a file, or recording it in the index, may fail before it is given up on.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Failed attempts at each path, shared by the workers taking items off the same queue, so a
+/// failed item can be put back and is given up on after `MAX_ATTEMPTS` whichever worker
+/// takes it next.
+#[derive(Clone)]
+pub struct Attempts {
+    failed: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl Attempts {
+    pub fn new() -> Self {
+        Attempts { failed: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Record a failed attempt at `path`, returning how many have failed so far.
+    pub fn failed(&self, path: &str) -> u32 {
+        let mut failed = self.failed.lock().expect("attempts lock");
+        let count = failed.entry(path.to_string()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Forget `path`, once it is handled or given up on.
+    pub fn done(&self, path: &str) {
+        self.failed.lock().expect("attempts lock").remove(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_until_done() {
+        let attempts = Attempts::new();
+        let other = attempts.clone();
+        assert_eq!(1, attempts.failed("a"));
+        assert_eq!(2, other.failed("a"));
+        assert_eq!(1, attempts.failed("b"));
+
+        other.done("a");
+        assert_eq!(1, attempts.failed("a"));
+    }
+}
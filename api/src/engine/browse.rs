@@ -0,0 +1,211 @@
+use std::error::Error as StdError;
+use std::io::{BufRead, Write};
+use std::result::Result as StdResult;
+use time::Timespec;
+
+use {Engine, Index, Storage};
+use control::ControlService;
+use engine::{DefaultEngine, write_file_details};
+use listing::ListOptions;
+use output::OutputFormat;
+use time_format::TimeStyle;
+
+const HELP: &'static str = "\
+snapshots          list backup sets
+at <set>|latest    browse as of a backup set
+ls [dir]           list a directory
+cd <dir>|..|/      change directory
+info <file>        show a file's size, time and hash
+mark <path>        mark a file or tree for restore
+unmark <path>      drop a mark
+marked             list marks
+restore <target>   restore everything marked under target
+quit               leave";
+
+/// State of an interactive `haumaru browse` session: where it is in the tree, which backup
+/// set it looks at, and what is marked for restore.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Browser {
+    cwd: String,
+    from: Option<(u64, Timespec)>,
+    marked: Vec<String>,
+}
+
+impl Browser {
+    pub fn new() -> Self {
+        Browser::default()
+    }
+
+    /// Read commands from `input` until it ends or `quit` is given.
+    pub fn run<I, S>(&mut self,
+                     engine: &mut DefaultEngine<I, S>,
+                     input: &mut BufRead,
+                     out: &mut Write)
+                     -> StdResult<(), Box<StdError>>
+        where I: Index + Send + Clone + 'static,
+              S: Storage + 'static
+    {
+        write!(out, "{}\n", HELP)?;
+        loop {
+            write!(out, "{}> ", self.prompt())?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                write!(out, "\n")?;
+                return Ok(());
+            }
+            match self.command(engine, line.trim(), out) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => write!(out, "{}\n", e)?,
+            }
+        }
+    }
+
+    fn prompt(&self) -> String {
+        let set = match self.from {
+            Some((id, _)) => format!("set {}", id),
+            None => "latest".to_string(),
+        };
+        format!("[{}] /{}", set, self.cwd)
+    }
+
+    /// Key of `path`, relative to the current directory unless it starts with `/`.
+    fn key(&self, path: &str) -> String {
+        let mut parts: Vec<&str> = if path.starts_with('/') {
+            vec![]
+        } else {
+            self.cwd.split('/').filter(|p| !p.is_empty()).collect()
+        };
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+        parts.join("/")
+    }
+
+    fn from(&self) -> Option<Timespec> {
+        self.from.map(|(_, at)| at)
+    }
+
+    /// Run one command, returning false once the session should end.
+    fn command<I, S>(&mut self,
+                     engine: &mut DefaultEngine<I, S>,
+                     line: &str,
+                     out: &mut Write)
+                     -> StdResult<bool, Box<StdError>>
+        where I: Index + Send + Clone + 'static,
+              S: Storage + 'static
+    {
+        let mut words = line.splitn(2, ' ');
+        let command = words.next().unwrap_or("");
+        let arg = words.next().unwrap_or("").trim();
+        match command {
+            "" => {}
+            "quit" | "exit" => return Ok(false),
+            "help" => write!(out, "{}\n", HELP)?,
+            "snapshots" => {
+                engine.snapshots(&TimeStyle::new(),
+                               OutputFormat::Text,
+                               &mut |line| {
+                                   let _ = write!(out, "{}\n", line);
+                               })?
+            }
+            "at" => {
+                if arg.is_empty() || arg == "latest" {
+                    self.from = None;
+                } else {
+                    let id = arg.parse::<u64>().map_err(|_| format!("Not a backup set: {}", arg))?;
+                    self.from = Some((id, engine.backup_set_at(id)?));
+                }
+            }
+            "ls" => {
+                let key = self.key(arg);
+                engine.list_with(&key, self.from(), &ListOptions::new(), out)?;
+            }
+            "cd" => {
+                let key = self.key(arg);
+                if !key.is_empty() {
+                    match engine.index.get(key.clone(), self.from())? {
+                        Some(ref node) if node.is_dir() && !node.deleted() => {}
+                        _ => return Err(format!("Not a directory: /{}", key).into()),
+                    }
+                }
+                self.cwd = key;
+            }
+            "info" => {
+                let key = self.key(arg);
+                match engine.index.get(key.clone(), self.from())? {
+                    Some(ref node) if node.is_file() && !node.deleted() => {
+                        write_file_details(out, node, &TimeStyle::new())
+                    }
+                    _ => return Err(format!("Not a file: /{}", key).into()),
+                }
+            }
+            "mark" => {
+                let key = self.key(arg);
+                if key.is_empty() || engine.index.get(key.clone(), self.from())?.is_none() {
+                    return Err(format!("Not Found: /{}", key).into());
+                }
+                if !self.marked.contains(&key) {
+                    self.marked.push(key);
+                }
+            }
+            "unmark" => {
+                let key = self.key(arg);
+                self.marked.retain(|k| k != &key);
+            }
+            "marked" => {
+                for key in &self.marked {
+                    write!(out, "/{}\n", key)?;
+                }
+            }
+            "restore" => {
+                if arg.is_empty() {
+                    return Err("restore needs a target directory".into());
+                }
+                if self.marked.is_empty() {
+                    return Err("Nothing marked".into());
+                }
+                for key in self.marked.clone() {
+                    write!(out, "Restoring /{}\n", key)?;
+                    Engine::restore(engine, &key, self.from(), arg)?;
+                }
+                self.marked.clear();
+            }
+            command => return Err(format!("Unknown command {:?}, try help", command).into()),
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let mut browser = Browser::new();
+        assert_eq!("etc", browser.key("etc"));
+        browser.cwd = "etc/nginx".to_string();
+        assert_eq!("etc/nginx/sites", browser.key("sites"));
+        assert_eq!("etc", browser.key(".."));
+        assert_eq!("var/log", browser.key("/var/log"));
+        assert_eq!("", browser.key("/"));
+        assert_eq!("etc/nginx", browser.key(""));
+    }
+
+    #[test]
+    fn prompt() {
+        let mut browser = Browser::new();
+        assert_eq!("[latest] /", browser.prompt());
+        browser.cwd = "etc".to_string();
+        browser.from = Some((4, Timespec::new(0, 0)));
+        assert_eq!("[set 4] /etc", browser.prompt());
+    }
+}
@@ -1,14 +1,79 @@
 use std::path::PathBuf;
 use std::fs::create_dir_all;
+use HashAlgorithm;
+use engine::{BackupScripts, BackupWindow, GeneratedSource, JobGroup, RemoteSource, Snapshot};
+use network::MeteredNetworks;
+use notification::SmtpSettings;
+use output::OutputFormat;
+use time_format::TimeStyle;
+use namespace::{DEFAULT_NAMESPACE, hostname};
+use serve::{DEFAULT_LISTEN, DEFAULT_MAX_BODY, ServeClient};
+use filter::Filter;
+use compression::Compression;
+use path_priority::PathPriority;
+use storage::Faults;
+use retention::RetentionPolicy;
+use pattern::{Glob, Pattern, default_transient_patterns};
+use num_cpus;
 
 #[derive(Debug, Clone)]
 pub struct EngineConfig {
     path: Option<String>,
     working: String,
     period: Option<u32>,
+    full_scan_interval: Option<u32>,
     max_file_size: Option<u64>,
+    min_file_age: Option<u32>,
+    checkpoint_interval: Option<u32>,
+    storage: String,
+    faults: Option<Faults>,
     bucket: Option<String>,
+    aws_role_arn: Option<String>,
+    server: Option<(String, String)>,
+    serve_listen: Option<String>,
+    serve_clients: Vec<ServeClient>,
+    serve_max_body: u64,
+    web_listen: Option<String>,
     prefix: Option<String>,
+    namespace: Option<String>,
+    hash_algorithm: HashAlgorithm,
+    parallel_hash_threshold: Option<u64>,
+    chunk_threshold: Option<u64>,
+    hash_threads: usize,
+    free_space_reserve: u64,
+    storage_soft_cap: Option<u64>,
+    storage_hard_cap: Option<u64>,
+    pre_send_threads: usize,
+    send_threads: Option<usize>,
+    max_daily_upload: Option<u64>,
+    scan_nice: Option<i32>,
+    scan_idle_io: bool,
+    scan_io_rate: Option<u64>,
+    tree_hashes: bool,
+    download_rate: Option<u64>,
+    window: Option<BackupWindow>,
+    battery_threshold: Option<u32>,
+    metered: MeteredNetworks,
+    transient_patterns: Vec<Pattern>,
+    excludes: Vec<Glob>,
+    includes: Vec<Glob>,
+    roots: Vec<String>,
+    filters: Vec<Filter>,
+    compression: Option<Compression>,
+    priorities: Vec<PathPriority>,
+    retention: RetentionPolicy,
+    generated_sources: Vec<GeneratedSource>,
+    snapshot: Option<Snapshot>,
+    remote: Option<RemoteSource>,
+    statsd: Option<(String, u16, String)>,
+    webhook_url: Option<String>,
+    healthcheck_url: Option<String>,
+    smtp: Option<SmtpSettings>,
+    desktop_notifications: bool,
+    output: OutputFormat,
+    scripts: BackupScripts,
+    times: TimeStyle,
+    job_group: Option<JobGroup>,
     detached: bool,
 }
 
@@ -19,9 +84,59 @@ impl EngineConfig {
             path: None,
             working: working.into(),
             period: None,
+            full_scan_interval: None,
             max_file_size: None,
+            min_file_age: None,
+            checkpoint_interval: None,
+            storage: "local".to_string(),
+            faults: None,
             bucket: None,
+            aws_role_arn: None,
+            server: None,
+            serve_listen: None,
+            serve_clients: vec![],
+            serve_max_body: DEFAULT_MAX_BODY,
+            web_listen: None,
             prefix: None,
+            namespace: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            parallel_hash_threshold: None,
+            chunk_threshold: None,
+            hash_threads: num_cpus::get(),
+            free_space_reserve: 256 * 1024 * 1024,
+            storage_soft_cap: None,
+            storage_hard_cap: None,
+            pre_send_threads: 4,
+            send_threads: None,
+            max_daily_upload: None,
+            scan_nice: None,
+            scan_idle_io: false,
+            scan_io_rate: None,
+            tree_hashes: false,
+            download_rate: None,
+            window: None,
+            battery_threshold: None,
+            metered: MeteredNetworks::new(),
+            transient_patterns: default_transient_patterns(),
+            excludes: vec![],
+            includes: vec![],
+            roots: vec![],
+            filters: vec![],
+            compression: None,
+            priorities: vec![],
+            retention: RetentionPolicy::new(),
+            generated_sources: vec![],
+            snapshot: None,
+            remote: None,
+            statsd: None,
+            webhook_url: None,
+            healthcheck_url: None,
+            smtp: None,
+            desktop_notifications: false,
+            output: OutputFormat::Text,
+            scripts: BackupScripts::new(),
+            times: TimeStyle::new(),
+            job_group: None,
             detached: false,
         }
     }
@@ -31,26 +146,332 @@ impl EngineConfig {
         self
     }
 
+    /// Back up these directories under `path` in place of all of it, each keyed by where it is
+    /// under `path`.
+    pub fn with_roots(mut self, roots: Vec<String>) -> Self {
+        self.roots = roots;
+        self
+    }
+
     pub fn with_period(mut self, period: u32) -> Self {
         self.period = Some(period);
         self
     }
 
+    /// Seconds between reconciliation scans, catching anything the watcher missed.
+    pub fn with_full_scan_interval(mut self, interval: u32) -> Self {
+        self.full_scan_interval = Some(interval);
+        self
+    }
+
     pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
         self.max_file_size = Some(max_file_size);
         self
     }
 
+    /// Seconds a file must go unmodified before it is backed up. Younger files are left for
+    /// the next run.
+    pub fn with_min_file_age(mut self, age: u32) -> Self {
+        self.min_file_age = Some(age);
+        self
+    }
+
+    /// Seconds between persisting what an open backup set holds so far, so a long scan that
+    /// crashes resumes from there. 0 turns checkpoints off.
+    pub fn with_checkpoint_interval(mut self, secs: u32) -> Self {
+        self.checkpoint_interval = Some(secs);
+        self
+    }
+
+    /// Storage backend by name, one of `storage::backends()`.
+    pub fn with_storage(mut self, name: &str) -> Self {
+        self.storage = name.to_string();
+        self
+    }
+
+    /// Inject `faults` into the storage backend, in builds with the `flaky` feature.
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
     pub fn with_bucket(mut self, bucket: &str) -> Self {
         self.bucket = Some(bucket.into());
         self
     }
 
+    /// Role the `s3` backend assumes through STS, signing with short-lived credentials that
+    /// are renewed before they expire.
+    pub fn with_aws_role_arn(mut self, role_arn: &str) -> Self {
+        self.aws_role_arn = Some(role_arn.into());
+        self
+    }
+
+    /// `haumaru serve` server for the `server` storage backend, and the token naming this
+    /// client.
+    pub fn with_server(mut self, url: &str, token: &str) -> Self {
+        self.server = Some((url.into(), token.into()));
+        self
+    }
+
+    /// Address for `haumaru serve` to listen on.
+    pub fn with_serve_listen(mut self, listen: &str) -> Self {
+        self.serve_listen = Some(listen.into());
+        self
+    }
+
+    /// Clients allowed to back up into `haumaru serve`.
+    pub fn with_serve_clients(mut self, clients: Vec<ServeClient>) -> Self {
+        self.serve_clients = clients;
+        self
+    }
+
+    /// Largest blob `haumaru serve` accepts from a client, in bytes.
+    pub fn with_serve_max_body(mut self, bytes: u64) -> Self {
+        self.serve_max_body = bytes;
+        self
+    }
+
+    /// Address for the daemon to serve its read-only web UI on.
+    pub fn with_web_listen(mut self, listen: &str) -> Self {
+        self.web_listen = Some(listen.into());
+        self
+    }
+
     pub fn with_prefix(mut self, prefix: &str) -> Self {
         self.prefix = Some(prefix.into());
         self
     }
 
+    /// Name this client's index copy is kept under in a storage backend shared with others.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn with_hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    /// Files of at least this size are tree hashed across `hash_threads` threads.
+    pub fn with_parallel_hash_threshold(mut self, threshold: u64) -> Self {
+        self.parallel_hash_threshold = Some(threshold);
+        self
+    }
+
+    /// Files of at least this size are stored in content-defined chunks, so a change only
+    /// stores the chunks it touches.
+    pub fn with_chunk_threshold(mut self, threshold: u64) -> Self {
+        self.chunk_threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_hash_threads(mut self, hash_threads: usize) -> Self {
+        self.hash_threads = hash_threads;
+        self
+    }
+
+    /// Bytes to leave free in the working directory and local storage. Blobs that would eat
+    /// into it fail instead of filling the disk.
+    pub fn with_free_space_reserve(mut self, bytes: u64) -> Self {
+        self.free_space_reserve = bytes;
+        self
+    }
+
+    /// Repository size past which a notification is sent.
+    pub fn with_storage_soft_cap(mut self, bytes: u64) -> Self {
+        self.storage_soft_cap = Some(bytes);
+        self
+    }
+
+    /// Repository size at which uploads are paused.
+    pub fn with_storage_hard_cap(mut self, bytes: u64) -> Self {
+        self.storage_hard_cap = Some(bytes);
+        self
+    }
+
+    /// Threads reading and hashing files ahead of sending.
+    pub fn with_pre_send_threads(mut self, threads: usize) -> Self {
+        self.pre_send_threads = threads;
+        self
+    }
+
+    /// Sends to storage in flight at once. Mostly spent waiting on the backend, so high
+    /// latency backends want more and local storage fewer. Unless set, the backend's own
+    /// `Storage::max_sends` is used.
+    pub fn with_send_threads(mut self, threads: usize) -> Self {
+        self.send_threads = Some(threads);
+        self
+    }
+
+    /// Bytes uploaded in a day after which uploads are paused until the next day.
+    pub fn with_max_daily_upload(mut self, bytes: u64) -> Self {
+        self.max_daily_upload = Some(bytes);
+        self
+    }
+
+    /// Niceness of the scan and worker threads.
+    pub fn with_scan_nice(mut self, nice: i32) -> Self {
+        self.scan_nice = Some(nice);
+        self
+    }
+
+    /// Run the scan and worker threads in the idle IO class.
+    pub fn with_scan_idle_io(mut self, idle_io: bool) -> Self {
+        self.scan_idle_io = idle_io;
+        self
+    }
+
+    /// Skip scanning directories whose tree hash is unchanged since their last complete scan.
+    pub fn with_tree_hashes(mut self, tree_hashes: bool) -> Self {
+        self.tree_hashes = tree_hashes;
+        self
+    }
+
+    /// Limit reading file content to `rate` bytes per second.
+    pub fn with_scan_io_rate(mut self, rate: u64) -> Self {
+        self.scan_io_rate = Some(rate);
+        self
+    }
+
+    /// Limit reading from storage during restores to `rate` bytes per second.
+    pub fn with_download_rate(mut self, rate: u64) -> Self {
+        self.download_rate = Some(rate);
+        self
+    }
+
+    /// Only open backup sets and upload inside `window`.
+    pub fn with_window(mut self, window: BackupWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Pause uploads while on battery with less than `threshold` percent charge.
+    pub fn with_battery_threshold(mut self, threshold: u32) -> Self {
+        self.battery_threshold = Some(threshold);
+        self
+    }
+
+    /// Suspend uploads while connected to one of these networks.
+    pub fn with_metered(mut self, metered: MeteredNetworks) -> Self {
+        self.metered = metered;
+        self
+    }
+
+    /// File name patterns dropped by the watcher, replacing the defaults.
+    pub fn with_transient_patterns(mut self, patterns: Vec<Pattern>) -> Self {
+        self.transient_patterns = patterns;
+        self
+    }
+
+    /// Globs over keys that are never backed up, along with everything below what they match.
+    pub fn with_excludes(mut self, excludes: Vec<Glob>) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    /// Globs over keys that are backed up, leaving out everything else. All are when empty.
+    pub fn with_includes(mut self, includes: Vec<Glob>) -> Self {
+        self.includes = includes;
+        self
+    }
+
+    /// Commands whose output is stored alongside the files on each full scan.
+    pub fn with_generated_sources(mut self, sources: Vec<GeneratedSource>) -> Self {
+        self.generated_sources = sources;
+        self
+    }
+
+    /// Scan a snapshot of the backup path on full scans instead of the live tree.
+    pub fn with_snapshot(mut self, snapshot: Option<Snapshot>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    /// Back up a directory on another host over SSH instead of the local backup path.
+    pub fn with_remote(mut self, remote: Option<RemoteSource>) -> Self {
+        self.remote = remote;
+        self
+    }
+
+    /// Pipe the content of matching files through a filter before storing it.
+    pub fn with_filters(mut self, filters: Vec<Filter>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Compress file content with `compression` before storing it, where that makes it smaller.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Back up subtrees in order of priority when many files change at once.
+    pub fn with_priorities(mut self, priorities: Vec<PathPriority>) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// How long pruning keeps old versions, overall and per subtree.
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Emit metrics to the StatsD server at `host:port`, names prefixed with `prefix`.
+    pub fn with_statsd(mut self, host: &str, port: u16, prefix: &str) -> Self {
+        self.statsd = Some((host.into(), port, prefix.into()));
+        self
+    }
+
+    /// POST notifications as JSON to `url`.
+    pub fn with_webhook_url(mut self, url: &str) -> Self {
+        self.webhook_url = Some(url.into());
+        self
+    }
+
+    /// Ping `url` as runs start, succeed and fail, healthchecks.io style.
+    pub fn with_healthcheck_url(mut self, url: &str) -> Self {
+        self.healthcheck_url = Some(url.into());
+        self
+    }
+
+    /// Email failures using `smtp`.
+    pub fn with_smtp(mut self, smtp: SmtpSettings) -> Self {
+        self.smtp = Some(smtp);
+        self
+    }
+
+    /// Post a desktop notification when a backup run completes or fails.
+    pub fn with_desktop_notifications(mut self, desktop: bool) -> Self {
+        self.desktop_notifications = desktop;
+        self
+    }
+
+    pub fn with_output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Commands run before each backup set opens and after it closes.
+    pub fn with_scripts(mut self, scripts: BackupScripts) -> Self {
+        self.scripts = scripts;
+        self
+    }
+
+    /// Share worker slots and cancellation with the other jobs of `group`.
+    pub fn with_job_group(mut self, group: JobGroup) -> Self {
+        self.job_group = Some(group);
+        self
+    }
+
+    /// How times are shown in `ls`, `snapshots` and `report` output.
+    pub fn with_times(mut self, times: TimeStyle) -> Self {
+        self.times = times;
+        self
+    }
+
     pub fn detached(mut self) -> Self {
         self.detached = true;
         self
@@ -66,6 +487,10 @@ impl EngineConfig {
     pub fn set_path(&mut self, path: Option<String>) {
         self.path = path;
     }
+    /// The directories backed up under `path`, all of it when empty.
+    pub fn roots(&self) -> &[String] {
+        &self.roots
+    }
     pub fn working(&self) -> &str {
         &self.working
     }
@@ -78,15 +503,168 @@ impl EngineConfig {
     pub fn period(&self) -> u32 {
         self.period.expect("period not specified")
     }
+    pub fn full_scan_interval(&self) -> Option<u32> {
+        self.full_scan_interval
+    }
     pub fn max_file_size(&self) -> Option<u64> {
         self.max_file_size.clone()
     }
+    pub fn min_file_age(&self) -> Option<u32> {
+        self.min_file_age
+    }
+    pub fn checkpoint_interval(&self) -> Option<u32> {
+        self.checkpoint_interval
+    }
+    pub fn storage(&self) -> &str {
+        &self.storage
+    }
+    pub fn faults(&self) -> Option<&Faults> {
+        self.faults.as_ref()
+    }
     pub fn bucket(&self) -> Option<&str> {
         self.bucket.as_ref().map(|s| s.as_ref())
     }
+    pub fn aws_role_arn(&self) -> Option<&str> {
+        self.aws_role_arn.as_ref().map(|s| s.as_ref())
+    }
+    pub fn server(&self) -> Option<(&str, &str)> {
+        self.server.as_ref().map(|&(ref url, ref token)| (url.as_str(), token.as_str()))
+    }
+    pub fn serve_listen(&self) -> &str {
+        self.serve_listen.as_ref().map(|s| s.as_str()).unwrap_or(DEFAULT_LISTEN)
+    }
+    pub fn serve_clients(&self) -> &[ServeClient] {
+        &self.serve_clients
+    }
+    pub fn serve_max_body(&self) -> u64 {
+        self.serve_max_body
+    }
+    pub fn web_listen(&self) -> Option<&str> {
+        self.web_listen.as_ref().map(|s| s.as_str())
+    }
     pub fn prefix(&self) -> Option<&str> {
         self.prefix.as_ref().map(|s| s.as_ref())
     }
+    /// The configured namespace, or the hostname.
+    pub fn namespace(&self) -> String {
+        self.namespace
+            .clone()
+            .or_else(hostname)
+            .unwrap_or(DEFAULT_NAMESPACE.to_string())
+    }
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+    pub fn parallel_hash_threshold(&self) -> Option<u64> {
+        self.parallel_hash_threshold
+    }
+    pub fn chunk_threshold(&self) -> Option<u64> {
+        self.chunk_threshold
+    }
+    pub fn hash_threads(&self) -> usize {
+        self.hash_threads
+    }
+    pub fn free_space_reserve(&self) -> u64 {
+        self.free_space_reserve
+    }
+    pub fn storage_soft_cap(&self) -> Option<u64> {
+        self.storage_soft_cap
+    }
+    pub fn storage_hard_cap(&self) -> Option<u64> {
+        self.storage_hard_cap
+    }
+    pub fn pre_send_threads(&self) -> usize {
+        self.pre_send_threads
+    }
+    pub fn send_threads(&self) -> Option<usize> {
+        self.send_threads
+    }
+    pub fn max_daily_upload(&self) -> Option<u64> {
+        self.max_daily_upload
+    }
+    pub fn scan_nice(&self) -> Option<i32> {
+        self.scan_nice
+    }
+    pub fn scan_idle_io(&self) -> bool {
+        self.scan_idle_io
+    }
+    pub fn tree_hashes(&self) -> bool {
+        self.tree_hashes
+    }
+    pub fn scan_io_rate(&self) -> Option<u64> {
+        self.scan_io_rate
+    }
+    pub fn download_rate(&self) -> Option<u64> {
+        self.download_rate
+    }
+    pub fn window(&self) -> Option<BackupWindow> {
+        self.window
+    }
+    pub fn battery_threshold(&self) -> Option<u32> {
+        self.battery_threshold
+    }
+    pub fn metered(&self) -> &MeteredNetworks {
+        &self.metered
+    }
+    pub fn transient_patterns(&self) -> &[Pattern] {
+        &self.transient_patterns
+    }
+    pub fn excludes(&self) -> &[Glob] {
+        &self.excludes
+    }
+    pub fn includes(&self) -> &[Glob] {
+        &self.includes
+    }
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+    pub fn priorities(&self) -> &[PathPriority] {
+        &self.priorities
+    }
+    pub fn retention(&self) -> &RetentionPolicy {
+        &self.retention
+    }
+    pub fn generated_sources(&self) -> &[GeneratedSource] {
+        &self.generated_sources
+    }
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+    pub fn remote(&self) -> Option<&RemoteSource> {
+        self.remote.as_ref()
+    }
+    pub fn statsd(&self) -> Option<(&str, u16, &str)> {
+        self.statsd
+            .as_ref()
+            .map(|&(ref host, port, ref prefix)| (host.as_str(), port, prefix.as_str()))
+    }
+    pub fn webhook_url(&self) -> Option<&str> {
+        self.webhook_url.as_ref().map(|s| s.as_ref())
+    }
+    pub fn healthcheck_url(&self) -> Option<&str> {
+        self.healthcheck_url.as_ref().map(|s| s.as_ref())
+    }
+    pub fn smtp(&self) -> Option<&SmtpSettings> {
+        self.smtp.as_ref()
+    }
+    pub fn desktop_notifications(&self) -> bool {
+        self.desktop_notifications
+    }
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+    pub fn scripts(&self) -> &BackupScripts {
+        &self.scripts
+    }
+    pub fn times(&self) -> TimeStyle {
+        self.times
+    }
+    pub fn job_group(&self) -> Option<&JobGroup> {
+        self.job_group.as_ref()
+    }
     pub fn is_detached(&self) -> bool {
         self.detached
     }
@@ -0,0 +1,465 @@
+use std::error::Error as StdError;
+use std::result::Result as StdResult;
+use std::sync::TryLockError;
+use time::{Timespec, now_utc};
+
+use {Engine, Index, Storage};
+use control::{ControlService, Status};
+use engine::{DefaultEngine, Phase};
+use compare::Drift;
+use cost::{estimate, prices};
+use du::disk_usage;
+use index::{BackupSetStats, IndexStats, KeyScope, RunReport};
+use find::FindQuery;
+use listing::{ListOptions, write_deleted_marker, write_long_node};
+use output::{DriftRecord, NodeRecord, OutputFormat, RestoreRecord, SkippedRecord, VerifyRecord,
+             json_line};
+use time_format::{TimeFormat, TimeStyle};
+use units::format_bytes;
+
+#[derive(Serialize)]
+struct StatsRecord {
+    stats: IndexStats,
+    storage_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PruneRecord {
+    versions: u64,
+    backup_sets: u64,
+    /// Blobs storage deleted. Backends that cannot delete leave theirs in place.
+    blobs: u64,
+}
+
+#[derive(Serialize)]
+struct ReportRecord {
+    backup_set: BackupSetStats,
+    report: RunReport,
+}
+
+impl<I, S> ControlService for DefaultEngine<I, S>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    fn backup(&mut self, progress: &mut FnMut(&str)) -> StdResult<(), Box<StdError>> {
+        // a detached engine has no workers, hand the run over to the daemon
+        if self.config.is_detached() {
+            self.request_run();
+            progress("Backup run requested");
+            return Ok(());
+        }
+        progress(&format!("Backing up {}", self.config.path()));
+        self.backup_once()?;
+        progress("Backup complete");
+        Ok(())
+    }
+
+    fn restore(&mut self,
+               key: &str,
+               from: Option<Timespec>,
+               target: &str,
+               limit_rate: Option<u64>,
+               dry_run: bool,
+               format: OutputFormat,
+               progress: &mut FnMut(&str))
+               -> StdResult<(), Box<StdError>> {
+        if !dry_run {
+            progress(&format!("Restoring {:?} to {}", key, target));
+            let rate = limit_rate.or(self.config.download_rate());
+            self.restore_limited(key, from, target, rate)?;
+            progress("Restore complete");
+            return Ok(());
+        }
+
+        for (node, path) in self.restore_plan(key, from, target)? {
+            let path = path.to_string_lossy().into_owned();
+            match format {
+                OutputFormat::Json => {
+                    progress(&json_line(&RestoreRecord {
+                        path: node.path().to_string(),
+                        target: path,
+                        kind: if node.is_dir() { "dir" } else { "file" }.to_string(),
+                        size: node.size(),
+                    }))
+                }
+                OutputFormat::Text => {
+                    progress(&format!("would restore {} -> {} ({}B)",
+                                      node.path(),
+                                      path,
+                                      node.size()))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&mut self,
+            key: &str,
+            from: Option<Timespec>,
+            options: &ListOptions,
+            format: OutputFormat,
+            progress: &mut FnMut(&str))
+            -> StdResult<(), Box<StdError>> {
+        if format == OutputFormat::Text {
+            let mut out = vec![];
+            self.list_with(key, from, options, &mut out)?;
+            for line in String::from_utf8_lossy(&out).lines() {
+                progress(line);
+            }
+            return Ok(());
+        }
+
+        for entry in self.ls_nodes(key, from, options)? {
+            let mut record = NodeRecord::from(&entry.node);
+            record.deleted_at = entry.deleted_at.map(|t| t.sec);
+            progress(&json_line(&record));
+        }
+        Ok(())
+    }
+
+    fn undelete(&mut self,
+                key: &str,
+                since: Timespec,
+                target: &str,
+                format: OutputFormat,
+                progress: &mut FnMut(&str))
+                -> StdResult<(), Box<StdError>> {
+        let restored = DefaultEngine::undelete(self, key, since, target)?;
+        for &(ref node, ref path) in &restored {
+            let path = path.to_string_lossy().into_owned();
+            match format {
+                OutputFormat::Json => {
+                    progress(&json_line(&RestoreRecord {
+                        path: node.path().to_string(),
+                        target: path,
+                        kind: "file".to_string(),
+                        size: node.size(),
+                    }))
+                }
+                OutputFormat::Text => {
+                    progress(&format!("undeleted {} -> {} ({}B)", node.path(), path, node.size()))
+                }
+            }
+        }
+        if format == OutputFormat::Text {
+            progress(&format!("{} file(s) undeleted", restored.len()));
+        }
+        Ok(())
+    }
+
+    fn find(&mut self,
+            key: &str,
+            from: Option<Timespec>,
+            query: &FindQuery,
+            format: OutputFormat,
+            progress: &mut FnMut(&str))
+            -> StdResult<(), Box<StdError>> {
+        let options = ListOptions::new().with_recursive(true).with_deleted(query.deleted());
+        for entry in self.ls_nodes(key, from, &options)? {
+            if !query.matches(&entry) {
+                continue;
+            }
+            match format {
+                OutputFormat::Json => {
+                    let mut record = NodeRecord::from(&entry.node);
+                    record.deleted_at = entry.deleted_at.map(|t| t.sec);
+                    progress(&json_line(&record));
+                }
+                OutputFormat::Text => {
+                    let mut line = vec![];
+                    if query.deleted() {
+                        write_deleted_marker(&mut line, entry.deleted_at, options.times());
+                    }
+                    write_long_node(&mut line, &entry.node, options.times());
+                    progress(String::from_utf8_lossy(&line).trim_right());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(&mut self,
+              scope: &KeyScope,
+              format: OutputFormat,
+              progress: &mut FnMut(&str))
+              -> StdResult<(), Box<StdError>> {
+        self.verify_with(scope,
+                         &mut |node, valid| match format {
+                             OutputFormat::Json => {
+                                 progress(&json_line(&VerifyRecord {
+                                     path: node.path().to_string(),
+                                     backup_set: node.backup_set(),
+                                     ok: valid,
+                                 }))
+                             }
+                             OutputFormat::Text => {
+                                 progress(&format!("{:4} {} {}",
+                                                   node.backup_set().expect("backup set"),
+                                                   node.path(),
+                                                   if valid { "OK" } else { "FAILED" }))
+                             }
+                         })
+    }
+
+    fn stats(&mut self,
+             format: OutputFormat,
+             progress: &mut FnMut(&str))
+             -> StdResult<(), Box<StdError>> {
+        let mut stats = self.index.stats()?;
+        stats.compression = self.config.compression().map(|c| c.to_string());
+        let usage = self.storage.usage()?;
+        if format == OutputFormat::Json {
+            progress(&json_line(&StatsRecord {
+                stats: stats,
+                storage_bytes: usage,
+            }));
+            return Ok(());
+        }
+
+        for line in format!("{}", stats).lines() {
+            progress(line);
+        }
+        if let Some(usage) = usage {
+            progress("");
+            progress(&format!("Storage:      {}", format_bytes(usage)));
+        }
+        Ok(())
+    }
+
+    fn snapshots(&mut self,
+                 times: &TimeStyle,
+                 format: OutputFormat,
+                 progress: &mut FnMut(&str))
+                 -> StdResult<(), Box<StdError>> {
+        for set in self.index.stats()?.backup_sets {
+            match format {
+                OutputFormat::Json => progress(&json_line(&set)),
+                OutputFormat::Text => {
+                    let tm = times.show(Timespec::new(set.at, 0), TimeFormat::Local);
+                    let mut line = format!("{:>6}  {}  {:>8} version(s)  {:>10}",
+                                           set.id,
+                                           tm,
+                                           set.versions,
+                                           format_bytes(set.bytes));
+                    if let Some(ref s) = set.summary {
+                        line.push_str(&format!("  +{} ~{} -{}  {} uploaded in {:.1}s",
+                                               s.new,
+                                               s.changed,
+                                               s.deleted,
+                                               format_bytes(s.bytes_uploaded),
+                                               s.wall_time_ms as f64 / 1000.0));
+                    }
+                    if set.aborted {
+                        line.push_str("  aborted");
+                    }
+                    progress(&line)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn du(&mut self,
+          key: &str,
+          from: Option<Timespec>,
+          depth: usize,
+          progress: &mut FnMut(&str))
+          -> StdResult<(), Box<StdError>> {
+        let files = self.index.files(from)?;
+        for usage in disk_usage(&files, key, depth) {
+            progress(&format!("{:>10}  {:>7} files  {}",
+                              format_bytes(usage.bytes),
+                              usage.files,
+                              usage.path));
+        }
+        Ok(())
+    }
+
+    fn report(&mut self,
+              backup_set: u64,
+              times: &TimeStyle,
+              format: OutputFormat,
+              progress: &mut FnMut(&str))
+              -> StdResult<(), Box<StdError>> {
+        let set = self.index
+            .stats()?
+            .backup_sets
+            .into_iter()
+            .find(|set| set.id == backup_set);
+        let set = match set {
+            Some(set) => set,
+            None => return Err(format!("Backup set {} not found", backup_set).into()),
+        };
+        let report = match self.index.report(backup_set)? {
+            Some(report) => report,
+            None => return Err(format!("No report recorded for backup set {}", backup_set).into()),
+        };
+
+        if format == OutputFormat::Json {
+            progress(&json_line(&ReportRecord {
+                backup_set: set,
+                report: report,
+            }));
+            return Ok(());
+        }
+
+        let tm = times.show(Timespec::new(set.at, 0), TimeFormat::Local);
+        progress(&format!("Backup set {} at {}", set.id, tm));
+        if let Some(ref summary) = set.summary {
+            progress(&format!("Summary:    {}", summary));
+        }
+        for line in format!("{}", report).lines() {
+            progress(line);
+        }
+        Ok(())
+    }
+
+    fn skipped(&mut self,
+               backup_set: u64,
+               format: OutputFormat,
+               progress: &mut FnMut(&str))
+               -> StdResult<(), Box<StdError>> {
+        let known = self.index.stats()?.backup_sets.iter().any(|set| set.id == backup_set);
+        if !known {
+            return Err(format!("Backup set {} not found", backup_set).into());
+        }
+        let skipped = self.index.skipped(backup_set)?;
+        if format == OutputFormat::Json {
+            for skip in skipped {
+                progress(&json_line(&SkippedRecord {
+                    path: skip.path,
+                    reason: skip.reason.label().to_string(),
+                    size: skip.size,
+                }));
+            }
+            return Ok(());
+        }
+
+        for skip in &skipped {
+            let size = skip.size.map(format_bytes).unwrap_or(String::new());
+            progress(&format!("{:9} {:>10}  {}", skip.reason.label(), size, skip.path));
+        }
+        progress(&format!("{} path(s) skipped from backup set {}", skipped.len(), backup_set));
+        Ok(())
+    }
+
+    fn compare(&mut self,
+               hash: bool,
+               format: OutputFormat,
+               progress: &mut FnMut(&str))
+               -> StdResult<(), Box<StdError>> {
+        let drift = self.compare_live(hash)?;
+        if format == OutputFormat::Json {
+            for entry in drift {
+                progress(&json_line(&DriftRecord {
+                    path: entry.path,
+                    drift: entry.drift.label().to_string(),
+                    size: entry.size,
+                }));
+            }
+            return Ok(());
+        }
+
+        let (mut new, mut changed, mut missing, mut at_risk) = (0, 0, 0, 0);
+        for entry in &drift {
+            match entry.drift {
+                Drift::New => new += 1,
+                Drift::Changed => changed += 1,
+                Drift::Missing => missing += 1,
+            }
+            if entry.drift != Drift::Missing {
+                at_risk += entry.size;
+            }
+            progress(&format!("{:8} {:>10}  {}",
+                              entry.drift.label(),
+                              format_bytes(entry.size),
+                              entry.path));
+        }
+        progress(&format!("{} new, {} changed, {} missing: {} not in the latest backup",
+                          new,
+                          changed,
+                          missing,
+                          format_bytes(at_risk)));
+        Ok(())
+    }
+
+    fn cost(&mut self,
+            storage_class: &str,
+            format: OutputFormat,
+            progress: &mut FnMut(&str))
+            -> StdResult<(), Box<StdError>> {
+        let prices = prices(storage_class)?;
+        let stats = self.index.stats()?;
+        let estimate = estimate(&stats, now_utc().to_timespec().sec, &prices);
+        match format {
+            OutputFormat::Json => progress(&json_line(&estimate)),
+            OutputFormat::Text => {
+                for line in format!("{}", estimate).lines() {
+                    progress(line);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn prune(&mut self,
+             format: OutputFormat,
+             progress: &mut FnMut(&str))
+             -> StdResult<(), Box<StdError>> {
+        // a blob a run found already stored could be deleted before its record is inserted,
+        // so no run may start until the prune is done
+        let run_lock = self.run_lock.clone();
+        let _pruning = match run_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                return Err("A backup run is in progress, prune once it has finished".into())
+            }
+        };
+        if self.pre_send_queue.len() + self.send_queue.len() > 0 {
+            return Err("Files are still queued for storage, prune once they are stored".into());
+        }
+        if self.config.retention().is_keep_forever() {
+            progress("No retention: or retention_rules: configured, every version is kept");
+            return Ok(());
+        }
+
+        let (pruned, blobs) = self.prune(now_utc().to_timespec().sec)?;
+        if format == OutputFormat::Json {
+            progress(&json_line(&PruneRecord {
+                versions: pruned.versions,
+                backup_sets: pruned.backup_sets,
+                blobs: blobs,
+            }));
+            return Ok(());
+        }
+        progress(&format!("Removed {} version(s) and {} backup set(s)",
+                          pruned.versions,
+                          pruned.backup_sets));
+        progress(&format!("Deleted {} of {} unreferenced blob(s) from storage",
+                          blobs,
+                          pruned.orphans.len()));
+        Ok(())
+    }
+
+    fn status(&mut self) -> StdResult<Status, Box<StdError>> {
+        let running = self.state.phase() != Phase::Stopped;
+        let last = match self.state.last_backup_set() {
+            Some(last) => Some(last),
+            None => self.index.latest_backup_set()?,
+        };
+        Ok(Status {
+            running: running,
+            path: self.config.path().to_string(),
+            phase: format!("{}", self.state.phase()),
+            paused: self.pause.reasons().iter().map(|r| format!("{}", r)).collect(),
+            pre_send_queue: self.pre_send_queue.len(),
+            send_queue: self.send_queue.len(),
+            sent_queue: self.sent_queue.len(),
+            last_backup_set: last.map(|(set, _)| set),
+            last_backup_at: last.map(|(_, at)| at),
+            next_run: if running { self.state.next_run() } else { None },
+            pending_changes: self.state.pending_changes(),
+        })
+    }
+}
@@ -1,19 +1,31 @@
-use {Engine, Index, Storage};
-use filesystem::Change;
-use index::IndexError;
+use {Engine, Index, Storage, get_key};
+use filesystem::{Change, ChangeSource, add_change};
+use index::KeyScope;
+use listing::ListOptions;
 use std::collections::HashSet;
+use std::fs::remove_file;
 use std::error::Error as StdError;
-use std::fs::create_dir_all;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{Read, Write, copy};
 use std::result::Result as StdResult;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use control;
+use libc;
+use metrics;
+use network;
+use notification::Notification;
+use path_priority::priority_of;
+use power;
+use signals;
+use storage::retrieve_node;
+use systemd;
 use super::*;
+use super::web;
 use time;
-use time::{Timespec, at, strftime};
+use time::Timespec;
 
 impl<I, S> Engine for DefaultEngine<I, S>
     where I: Index + Send + Clone + 'static,
@@ -22,24 +34,70 @@ impl<I, S> Engine for DefaultEngine<I, S>
     fn run(&mut self) -> StdResult<(), Box<StdError>> {
 
         info!("Starting backup engine on {}", self.config.path());
+        self.state.set_phase(Phase::Starting);
 
         let changes = Arc::new(Mutex::new(HashSet::new()));
 
-        {
-            let watcher =
-                self.backup_path().watcher().map_err(|e| DefaultEngineError::StartWatcher(e))?;
+        signals::watch_pause_signals(self.pause.clone());
+        signals::watch_stop_signals(self.cancel.clone());
+        if let Some(threshold) = self.config.battery_threshold() {
+            power::watch_power(self.pause.clone(), threshold);
+        }
+        if !self.config.metered().is_empty() {
+            network::watch_metered(self.pause.clone(), self.config.metered().clone());
+        }
+
+        // serve CLI requests through this process rather than letting them open the index
+        let socket = control::socket_path(&self.config.abs_working());
+        if let Err(e) = control::serve(&socket, self.control_handle()?) {
+            warn!("Unable to start control socket {:?}: {}", socket, e);
+        }
+        if let Some(listen) = self.config.web_listen() {
+            if let Err(e) = web::serve(listen, self.control_handle()?) {
+                warn!("Unable to start web UI on {}: {}", listen, e);
+            }
+        }
+
+        let source_ended = Arc::new(AtomicBool::new(false));
+        // a remote source has nothing local to watch, it is only picked up by full scans
+        let remote = self.config.remote().is_some();
+        if remote && self.config.full_scan_interval().is_none() {
+            warn!("No full_scan_interval set, the remote source is only scanned once");
+        }
+        if !remote || self.change_source.is_some() {
+            let mut source: Box<ChangeSource> = match self.change_source.take() {
+                Some(source) => source,
+                None => {
+                    box self.backup_path()
+                        .watcher()
+                        .map_err(|e| DefaultEngineError::StartWatcher(e))?
+                }
+            };
             let changes = changes.clone();
+            let source_ended = source_ended.clone();
             let local_excludes = self.excludes.clone();
+            let exclude_globs = self.config.excludes().to_vec();
+            let ignores = self.ignores.clone();
             let local_path = self.config.path().to_string();
+            let transient_patterns = self.config.transient_patterns().to_vec();
             thread::spawn(move || {
-                match watcher.watch(move |change| {
-                    if is_excluded(&local_excludes, &change, &local_path) {
+                match source.watch(&mut |change| {
+                    if is_excluded(&local_excludes, &exclude_globs, &change, &local_path) {
                         trace!("Skipping excluded path: {:?}", change.path());
                         return;
                     }
+                    ignores.changed(change.path());
+                    if ignores.is_ignored(change.path(), &local_path) {
+                        trace!("Skipping ignored path: {:?}", change.path());
+                        return;
+                    }
+                    if is_transient(&transient_patterns, &change) {
+                        trace!("Skipping transient path: {:?}", change.path());
+                        return;
+                    }
 
                     let mut changes = changes.lock().unwrap();
-                    changes.insert(change);
+                    add_change(&mut changes, change);
                 }) {
                     Ok(_) => {
                         warn!("Watch ended");
@@ -48,44 +106,214 @@ impl<I, S> Engine for DefaultEngine<I, S>
                         error!("Watch ended: {}", e);
                     }
                 };
+                source_ended.store(true, Ordering::SeqCst);
             });
         }
 
+        // started up, the rest can take a long time so is reported through the status
+        systemd::ready();
+        let _watchdog = systemd::Watchdog::start();
+
         // full scan into backup set
+        if let Some(window) = self.config.window() {
+            systemd::status(&format!("Waiting for backup window {}", window));
+            self.state.set_phase(Phase::OutsideWindow);
+            window.wait_until_open();
+        }
+        self.check_storage_caps();
+        if self.pause.reasons().contains(&PauseReason::StorageCap) {
+            systemd::status("Storage hard cap reached, waiting for space");
+            self.state.set_phase(Phase::Paused);
+            let mut waited = 0;
+            while self.pause.reasons().contains(&PauseReason::StorageCap) {
+                if self.cancel.is_cancelled() {
+                    info!("Cancelled, stopping backup engine");
+                    self.state.set_phase(Phase::Stopped);
+                    return Ok(());
+                }
+                sleep(Duration::new(1, 0));
+                waited += 1;
+                if waited % 60 == 0 {
+                    self.check_storage_caps();
+                }
+            }
+        }
+        systemd::status("Initial scan");
+        self.state.set_phase(Phase::Scanning);
+        self.notify(Notification::BackupStarted);
         let now = time::now_utc().to_timespec();
-        self.scan_as_backup_set(now.sec)?;
+        match self.scan_as_backup_set(now.sec) {
+            Err(_) if self.cancel.is_cancelled() => {
+                info!("Cancelled, stopping backup engine");
+                self.state.set_phase(Phase::Stopped);
+                return Ok(());
+            }
+            scanned => scanned?,
+        }
+        let mut last_full_scan = now.sec;
 
         // start long running backup loop
         loop {
             let now = time::now_utc().to_timespec();
             let seconds_div = (now.sec / self.config.period() as i64) as i64;
             let seconds = (seconds_div + 1) * self.config.period() as i64;
-            let next_time = Timespec::new(seconds, 0);
+            let mut next_time = Timespec::new(seconds, 0);
 
+            systemd::status(&format!("Idle, {} change(s) pending",
+                                     changes.lock().unwrap().len()));
+            self.state.set_phase(Phase::Watching);
+            self.state.set_next_run(next_time.sec);
             loop {
+                self.state.set_pending_changes(changes.lock().unwrap().len() as u64);
                 let now = time::now_utc().to_timespec();
                 if now >= next_time {
                     break;
                 }
+                if self.cancel.is_cancelled() {
+                    info!("Cancelled, stopping backup engine");
+                    self.state.set_phase(Phase::Stopped);
+                    return Ok(());
+                }
+                if self.run_requested.swap(false, Ordering::SeqCst) {
+                    info!("Backup run requested");
+                    next_time = now;
+                    break;
+                }
                 sleep(Duration::new(1, 0));
             }
 
+            self.check_storage_caps();
+            if self.pause.is_paused() {
+                debug!("Paused, holding changes");
+                let reasons: Vec<String> =
+                    self.pause.reasons().iter().map(|r| format!("{}", r)).collect();
+                systemd::status(&format!("Paused ({})", reasons.join(", ")));
+                self.state.set_phase(Phase::Paused);
+                continue;
+            }
+
+            // hold changes until the window opens
+            if let Some(window) = self.config.window() {
+                if !window.is_open() {
+                    debug!("Outside backup window {}, holding changes", window);
+                    systemd::status(&format!("Outside backup window {}", window));
+                    self.state.set_phase(Phase::OutsideWindow);
+                    continue;
+                }
+            }
+
             info!("Beginning backup run");
+            self.notify(Notification::BackupStarted);
+            let last_backup_set = self.state.last_backup_set();
 
             let mut work_queue = vec![];
             {
                 let mut changes = changes.lock().unwrap();
+                // along with files that were too young last run
+                for change in self.deferred.drain() {
+                    add_change(&mut changes, change);
+                }
                 for c in changes.drain() {
                     // drain changes into the work queue
                     work_queue.push(c);
                 }
             }
+            systemd::status(&format!("Backing up {} change(s), queues {}/{}/{}",
+                                     work_queue.len(),
+                                     self.pre_send_queue.len(),
+                                     self.send_queue.len(),
+                                     self.sent_queue.len()));
+            self.state.set_phase(Phase::Uploading);
+            self.state.set_pending_changes(0);
 
-            self.process_changes(next_time.sec, work_queue)?;
+            // unwatched roots are walked for changes, unless a full scan covers them anyway
+            let full_scan = match self.config.full_scan_interval() {
+                Some(interval) => next_time.sec - last_full_scan >= interval as i64,
+                None => false,
+            };
+            let unwatched = match full_scan || remote {
+                true => Ok(vec![]),
+                false => self.unwatched_changes(),
+            };
+            let run = unwatched.and_then(|changes| {
+                work_queue.extend(changes);
+                self.process_changes(next_time.sec, work_queue)
+            });
+            let run = run.and_then(|_| match full_scan {
+                true => {
+                    info!("Beginning reconciliation scan");
+                    self.state.set_phase(Phase::Scanning);
+                    last_full_scan = next_time.sec;
+                    self.scan_as_backup_set(next_time.sec)
+                }
+                false => Ok(()),
+            });
+            if run.is_err() && self.cancel.is_cancelled() {
+                info!("Cancelled, stopping backup engine");
+                self.state.set_phase(Phase::Stopped);
+                return Ok(());
+            }
+            if let Err(e) = run {
+                self.state.set_phase(Phase::Stopped);
+                systemd::status(&format!("Backup failed: {}", e));
+                self.backup_failed(format!("{}", e));
+                return Err(e);
+            }
+            if self.state.last_backup_set() == last_backup_set {
+                self.notify(Notification::BackupUpToDate);
+            }
+
+            // finished once the source has ended and everything it sent is backed up
+            if source_ended.load(Ordering::SeqCst) && changes.lock().unwrap().is_empty() &&
+               self.deferred.is_empty() {
+                info!("Change source ended, stopping backup engine");
+                self.state.set_phase(Phase::Stopped);
+                return Ok(());
+            }
             info!("Backup run complete");
         }
     }
 
+    fn backup_once(&mut self) -> StdResult<(), Box<StdError>> {
+        info!("Starting one-shot backup of {}", self.config.path());
+        signals::watch_stop_signals(self.cancel.clone());
+
+        self.check_storage_caps();
+        if self.pause.reasons().contains(&PauseReason::StorageCap) {
+            return Err(box DefaultEngineError::Other("Storage hard cap reached, not backing up"
+                .to_string()));
+        }
+
+        self.notify(Notification::BackupStarted);
+        let now = time::now_utc().to_timespec();
+        self.scan_and_drain(now.sec)?;
+        if !self.deferred.is_empty() {
+            warn!("Left out {} file(s) modified within min_file_age or stuck on the queues, they \
+                   are picked up by the next backup",
+                  self.deferred.len());
+            self.deferred.clear();
+        }
+
+        info!("Backup complete");
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StdResult<(), Box<StdError>> {
+        self.wait_for_queue_drain();
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> StdResult<(), Box<StdError>> {
+        info!("Shutting down engine");
+        self.cancel.cancel();
+        self.flush()?;
+        self.pre_send_queue.close();
+        self.send_queue.close();
+        self.sent_queue.close();
+        self.state.set_phase(Phase::Stopped);
+        Ok(())
+    }
+
     fn process_changes(&mut self,
                        next_time: i64,
                        work_queue: Vec<Change>)
@@ -93,42 +321,57 @@ impl<I, S> Engine for DefaultEngine<I, S>
         if work_queue.is_empty() {
             return Ok(());
         }
-        let backup_set = self.index.create_backup_set(next_time)?;
+        let run_lock = self.run_lock.clone();
+        let _running = run_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let started = Instant::now();
+        let backup_set = self.open_backup_set(next_time)?;
+
+        // renames first, while the old path is still live in the index, then by priority
+        let mut work_queue = work_queue;
+        {
+            let base = self.read_root.path();
+            let priorities = self.config.priorities();
+            work_queue.sort_by_key(|c| {
+                let key = get_key(&base, c.path().to_str().unwrap());
+                (c.renamed_from().is_none(), priority_of(priorities, &key))
+            });
+        }
+
         for change in work_queue {
+            // the rest are picked up again by the next scan
+            if self.cancel.is_cancelled() {
+                break;
+            }
             self.process_change(backup_set, change).unwrap();
+            self.checkpoint_if_due()?;
+            metrics::gauge("queue.pre_send", self.pre_send_queue.len() as u64);
+            metrics::gauge("queue.send", self.send_queue.len() as u64);
+            metrics::gauge("queue.sent", self.sent_queue.len() as u64);
         }
+        self.run_stats.add_scan_time(started.elapsed());
         self.wait_for_queue_drain();
         self.index.close_backup_set()?;
+        self.backup_set_closed(backup_set, next_time);
+        self.cancel.check()?;
+
+        metrics::incr("backup_runs");
+        metrics::timing("backup_run.time", started.elapsed());
         Ok(())
     }
 
-    fn verify_store(&mut self, like: String) -> StdResult<(), Box<StdError>> {
+    fn verify_store(&mut self, scope: &KeyScope) -> StdResult<(), Box<StdError>> {
         info!("Verifying store");
-        let mut failed = vec![];
-        let storage = &self.storage;
-
-        self.index
-            .visit_all_hashable(like,
-                                &mut |node| {
-                let (node, valid) = storage.verify(node)
-                    .map_err(|e| IndexError::Fatal(format!("Verify error: {}", e), None))?;
-                if valid {
-                    if valid {
-                        info!("{:4} {} OK",
-                              node.backup_set().expect("backup set"),
-                              node.path());
-                    } else {
-                        error!("Verification failed for {}", node.hash_string());
-                        failed.push(node);
-                    }
-                }
-                Ok(())
-            })?;
-
-        if failed.is_empty() {
-            info!("Verification OK");
-        }
-
+        self.verify_with(scope,
+                         &mut |node, valid| if valid {
+                             info!("{:4} {} OK",
+                                   node.backup_set().expect("backup set"),
+                                   node.path());
+                         } else {
+                             error!("Verification failed for {} ({})",
+                                    node.path(),
+                                    node.hash_string());
+                         })?;
+        info!("Verification OK");
         Ok(())
     }
 
@@ -137,33 +380,8 @@ impl<I, S> Engine for DefaultEngine<I, S>
                from: Option<Timespec>,
                target: &str)
                -> StdResult<(), Box<StdError>> {
-
-        if key.is_empty() {
-            info!("Performing full restore to {}", target);
-
-            create_dir_all(target)?;
-            for node in self.index.list("".to_string(), from)? {
-                self.restore_node(node, "", from, target)?;
-            }
-            Ok(())
-
-        } else {
-
-            info!("Restoring {} to {}", key, target);
-            let node = match self.index.get(key.to_string(), from)? {
-                Some(n) => n,
-                None => {
-                    return Err(box DefaultEngineError::Other(format!("Not Found: {:?}", key)));
-                }
-            };
-
-            let mut tmp = PathBuf::new();
-            tmp.push(key);
-            let parent = tmp.parent().expect("restore.parent").to_str().expect("UTF-8 validity");
-            debug!("Parent of key is {:?}", parent);
-
-            self.restore_node(node, parent, from, target)
-        }
+        let rate = self.config.download_rate();
+        self.restore_limited(key, from, target, rate)
     }
 
     fn list(&mut self,
@@ -171,35 +389,54 @@ impl<I, S> Engine for DefaultEngine<I, S>
             from: Option<Timespec>,
             out: &mut Write)
             -> StdResult<(), Box<StdError>> {
+        self.list_with(key, from, &ListOptions::new(), out)
+    }
 
-        if key == "" {
-            for node in self.index.list("".to_string(), from)? {
-                write_ls_node(out, &node);
-            }
-            return Ok(());
+    fn cat(&mut self,
+           key: &str,
+           from: Option<Timespec>,
+           out: &mut Write)
+           -> StdResult<(), Box<StdError>> {
+        // a deleted file was not there at the time asked about
+        let node = self.index
+            .get(key.to_string(), from)?
+            .and_then(|n| if n.deleted() { None } else { Some(n) });
+        let node = match node {
+            Some(n) => n,
+            None => return Err(box DefaultEngineError::Other(format!("Not Found: {}", key))),
+        };
+        if !node.is_file() {
+            return Err(box DefaultEngineError::Other(format!("Not a file: {}", key)));
         }
 
-        let node = match self.index.get(key.to_string(), from)? {
-            Some(n) => n,
+        let mut blob = match retrieve_node(&self.storage, &node)? {
+            Some(blob) => blob,
             None => {
-                return Err(box DefaultEngineError::Other(format!("Not Found: {}", key)));
+                let msg = format!("Unable to read {}, hash is missing from storage", key);
+                return Err(box DefaultEngineError::GeneralWithNode(msg, node));
             }
         };
-
-        if node.is_file() {
-            let t = at(node.mtime().clone());
-            let tm = strftime("%b %e %H:%M %z", &t).expect("mtime format");
-            write!(out, "Name:   {}\n", node.path()).expect("write");
-            write!(out, "Size:   {} bytes\n", node.size()).expect("write");
-            write!(out, "Time:   {}\n", tm).expect("write");
-            write!(out, "SHA256: {}\n", node.hash_string()).expect("write");
-
-        } else if node.is_dir() {
-            for node in self.index.list(node.path().to_string(), from)? {
-                write_ls_node(out, &node);
-            }
+        let written = copy(&mut blob, out)?;
+        if written != blob.size() {
+            let msg = format!("Read {} of {} bytes of {}", written, blob.size(), key);
+            return Err(box DefaultEngineError::GeneralWithNode(msg, node));
         }
-
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn put(&mut self,
+           key: &str,
+           current: bool,
+           input: &mut Read)
+           -> StdResult<(), Box<StdError>> {
+        let key = virtual_key(key).map_err(|e| DefaultEngineError::Other(e))?;
+        let working = self.config.abs_working();
+        let pid = unsafe { libc::getpid() };
+        let spool = working.join(format!("put-{}.tmp", pid));
+        let body = working.join(format!("put-{}.body.tmp", pid));
+        let stored = self.put_spooled(key, current, input, &spool, &body);
+        let _ = remove_file(&spool);
+        let _ = remove_file(&body);
+        stored
+    }
+}
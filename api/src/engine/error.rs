@@ -16,6 +16,8 @@ pub enum DefaultEngineError {
     Storage(String, Box<StdError>),
     Other(String),
     GeneralWithNode(String, Node),
+    /// Stopped early through a `CancelToken`.
+    Cancelled,
 }
 
 impl StdError for DefaultEngineError {
@@ -44,6 +46,7 @@ impl Display for DefaultEngineError {
             }
             DefaultEngineError::Other(ref s) => write!(f, "Engine error: {}", s).unwrap(),
             DefaultEngineError::GeneralWithNode(ref s, ref _n) => write!(f, "{}", s).unwrap(),
+            DefaultEngineError::Cancelled => write!(f, "Cancelled").unwrap(),
         }
         Ok(())
     }
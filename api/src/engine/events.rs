@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
+
+use engine::FailureKind;
+
+/// Something that happened in the engine, for applications building their own view of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A new or changed file is waiting to be read and sent.
+    FileQueued { path: String, bytes: u64 },
+    /// A file's content has been sent to storage.
+    FileUploaded {
+        path: String,
+        bytes: u64,
+        duration: Duration,
+    },
+    SetOpened { backup_set: u64 },
+    /// A backup set was closed with `failures` files left out of it.
+    SetClosed { backup_set: u64, failures: usize },
+    /// A file could not be backed up.
+    Error {
+        kind: FailureKind,
+        path: String,
+        error: String,
+    },
+}
+
+/// Fans engine events out to every subscriber. Subscribers that hang up are forgotten.
+#[derive(Clone)]
+pub struct Events {
+    senders: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Events { senders: Arc::new(Mutex::new(vec![])) }
+    }
+
+    /// Receive every event sent from now on.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.senders.lock().expect("events lock").push(tx);
+        rx
+    }
+
+    pub fn send(&self, event: Event) {
+        let mut senders = self.senders.lock().expect("events lock");
+        senders.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fan_out() {
+        let events = Events::new();
+        let a = events.subscribe();
+        let b = events.subscribe();
+        events.send(Event::SetOpened { backup_set: 1 });
+        assert_eq!(Event::SetOpened { backup_set: 1 }, a.recv().unwrap());
+        assert_eq!(Event::SetOpened { backup_set: 1 }, b.recv().unwrap());
+
+        drop(a);
+        events.send(Event::SetOpened { backup_set: 2 });
+        assert_eq!(1, events.senders.lock().unwrap().len());
+        assert_eq!(Event::SetOpened { backup_set: 2 }, b.recv().unwrap());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use engine::{Event, Events};
+
+/// Where in the pipeline a file failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FailureKind {
+    /// Reading or hashing the file.
+    Read,
+    /// Sending the content to storage.
+    Upload,
+    /// Recording the file in the index.
+    Index,
+    /// Still queued when the backup set had to close.
+    Timeout,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FailureKind::Read => write!(f, "read"),
+            FailureKind::Upload => write!(f, "upload"),
+            FailureKind::Index => write!(f, "index"),
+            FailureKind::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub key: String,
+    pub error: String,
+}
+
+/// Per-file failures collected by the workers during a backup run.
+#[derive(Clone)]
+pub struct Failures {
+    failures: Arc<Mutex<Vec<Failure>>>,
+    events: Option<Events>,
+}
+
+impl Failures {
+    pub fn new() -> Self {
+        Failures {
+            failures: Arc::new(Mutex::new(vec![])),
+            events: None,
+        }
+    }
+
+    /// Also send each failure to `events` as it is recorded.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn record(&self, kind: FailureKind, key: &str, error: &str) {
+        if let Some(ref events) = self.events {
+            events.send(Event::Error {
+                kind: kind,
+                path: key.to_string(),
+                error: error.to_string(),
+            });
+        }
+        self.failures.lock().expect("failures lock").push(Failure {
+            kind: kind,
+            key: key.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Everything recorded since the last call.
+    pub fn take(&self) -> FailureSummary {
+        let mut failures = self.failures.lock().expect("failures lock");
+        FailureSummary { failures: failures.drain(..).collect() }
+    }
+}
+
+/// Failures from one backup set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailureSummary {
+    failures: Vec<Failure>,
+}
+
+/// Failed keys listed in the summary before eliding the rest.
+const LISTED_FAILURES: usize = 50;
+
+impl FailureSummary {
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.failures.len()
+    }
+    pub fn failures(&self) -> &[Failure] {
+        &self.failures
+    }
+    pub fn counts(&self) -> BTreeMap<FailureKind, usize> {
+        let mut counts = BTreeMap::new();
+        for failure in &self.failures {
+            *counts.entry(failure.kind).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+impl fmt::Display for FailureSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counts: Vec<String> =
+            self.counts().iter().map(|(kind, n)| format!("{} {}", n, kind)).collect();
+        write!(f, "{} file(s) failed ({})", self.len(), counts.join(", "))?;
+        for failure in self.failures.iter().take(LISTED_FAILURES) {
+            write!(f, "\n  {:<6} {}: {}", failure.kind, failure.key, failure.error)?;
+        }
+        if self.len() > LISTED_FAILURES {
+            write!(f, "\n  ... and {} more", self.len() - LISTED_FAILURES)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let failures = Failures::new();
+        failures.record(FailureKind::Upload, "a", "timeout");
+        failures.record(FailureKind::Read, "b", "permission denied");
+        failures.record(FailureKind::Upload, "c", "timeout");
+
+        let summary = failures.take();
+        assert_eq!(3, summary.len());
+        assert_eq!(Some(&2), summary.counts().get(&FailureKind::Upload));
+        assert_eq!("3 file(s) failed (1 read, 2 upload)\n  upload a: timeout\n  read   b: \
+                    permission denied\n  upload c: timeout",
+                   format!("{}", summary));
+
+        assert!(failures.take().is_empty());
+    }
+}
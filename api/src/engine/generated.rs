@@ -0,0 +1,86 @@
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::process::Command;
+
+/// Key of the directory holding generated sources in each full backup set.
+pub const GENERATED_DIR: &'static str = ".haumaru-generated";
+
+/// A command, such as `pg_dump`, whose output is stored as `GENERATED_DIR/name` on each full
+/// scan instead of a file read from disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedSource {
+    name: String,
+    command: String,
+}
+
+impl GeneratedSource {
+    pub fn new(name: &str, command: &str) -> Self {
+        GeneratedSource {
+            name: name.to_string(),
+            command: command.to_string(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn key(&self) -> String {
+        format!("{}/{}", GENERATED_DIR, self.name)
+    }
+
+    /// Run the command with `sh -c`, returning its stdout.
+    pub fn capture(&self) -> IoResult<Vec<u8>> {
+        let output = Command::new("sh").arg("-c").arg(&self.command).output()?;
+        if !output.status.success() {
+            return Err(IoError::new(ErrorKind::Other,
+                                    format!("{:?} failed: {}: {}",
+                                            self.command,
+                                            output.status,
+                                            String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Parse `name=command` pairs separated by `;`.
+pub fn parse_generated_sources(s: &str) -> Result<Vec<GeneratedSource>, String> {
+    let mut sources: Vec<GeneratedSource> = vec![];
+    for entry in s.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let command = parts.next().unwrap_or("").trim();
+        if name.is_empty() || command.is_empty() {
+            return Err(format!("Generated source must be name=command: {}", entry));
+        }
+        if name.contains('/') {
+            return Err(format!("Generated source name can not contain '/': {}", name));
+        }
+        if sources.iter().any(|s| s.name == name) {
+            return Err(format!("Generated source {} given twice", name));
+        }
+        sources.push(GeneratedSource::new(name, command));
+    }
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let sources = parse_generated_sources("db.sql=pg_dump app; users=echo a=b").unwrap();
+        assert_eq!(vec![GeneratedSource::new("db.sql", "pg_dump app"),
+                        GeneratedSource::new("users", "echo a=b")],
+                   sources);
+        assert_eq!(".haumaru-generated/db.sql", sources[0].key());
+        assert!(parse_generated_sources("a/b=true").is_err());
+        assert!(parse_generated_sources("a=true;a=false").is_err());
+        assert!(parse_generated_sources("a").is_err());
+    }
+
+    #[test]
+    fn capture() {
+        assert_eq!(b"hi\n".to_vec(),
+                   GeneratedSource::new("a", "echo hi").capture().unwrap());
+        assert!(GeneratedSource::new("a", "echo no >&2; exit 1").capture().is_err());
+    }
+}
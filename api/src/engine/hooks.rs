@@ -0,0 +1,87 @@
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use engine::{Event, FailureKind};
+
+/// Callbacks for applications embedding the engine. Every method does nothing by default.
+///
+/// Hooks are called in order from a thread of their own, so a slow hook delays later hooks
+/// but never the backup.
+pub trait EngineHooks: Send {
+    fn on_backup_start(&mut self, _backup_set: u64) {}
+
+    fn on_file_backed_up(&mut self, _path: &str, _bytes: u64, _duration: Duration) {}
+
+    /// `failures` files could not be included in `backup_set`.
+    fn on_backup_complete(&mut self, _backup_set: u64, _failures: usize) {}
+
+    fn on_error(&mut self, _kind: FailureKind, _path: &str, _error: &str) {}
+}
+
+fn dispatch(hooks: &mut EngineHooks, event: Event) {
+    match event {
+        Event::SetOpened { backup_set } => hooks.on_backup_start(backup_set),
+        Event::FileUploaded { path, bytes, duration } => {
+            hooks.on_file_backed_up(&path, bytes, duration)
+        }
+        Event::SetClosed { backup_set, failures } => {
+            hooks.on_backup_complete(backup_set, failures)
+        }
+        Event::Error { kind, path, error } => hooks.on_error(kind, &path, &error),
+        Event::FileQueued { .. } => {}
+    }
+}
+
+/// Call `hooks` for each event from `events` until the engine goes away.
+pub fn spawn(mut hooks: Box<EngineHooks>, events: Receiver<Event>) {
+    thread::spawn(move || for event in events {
+        dispatch(&mut *hooks, event);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use engine::{Event, FailureKind};
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+    }
+
+    impl EngineHooks for Recorder {
+        fn on_backup_start(&mut self, backup_set: u64) {
+            self.calls.push(format!("start {}", backup_set));
+        }
+        fn on_backup_complete(&mut self, backup_set: u64, failures: usize) {
+            self.calls.push(format!("complete {} {}", backup_set, failures));
+        }
+        fn on_error(&mut self, _kind: FailureKind, path: &str, error: &str) {
+            self.calls.push(format!("error {} {}", path, error));
+        }
+    }
+
+    #[test]
+    fn dispatch_events() {
+        let mut hooks = Recorder::default();
+        dispatch(&mut hooks, Event::SetOpened { backup_set: 3 });
+        dispatch(&mut hooks,
+                 Event::FileQueued {
+                     path: "a".to_string(),
+                     bytes: 1,
+                 });
+        dispatch(&mut hooks,
+                 Event::Error {
+                     kind: FailureKind::Read,
+                     path: "a".to_string(),
+                     error: "gone".to_string(),
+                 });
+        dispatch(&mut hooks,
+                 Event::SetClosed {
+                     backup_set: 3,
+                     failures: 1,
+                 });
+        assert_eq!(vec!["start 3", "error a gone", "complete 3 1"], hooks.calls);
+    }
+}
@@ -0,0 +1,141 @@
+//! `.haumaruignore` files: globs in a directory's ignore file leave out matching paths in the
+//! subtree below it, much as `.gitignore` does. A pattern without a `/` matches at any depth,
+//! one with a `/` is relative to the directory holding the file.
+
+use pattern::{Glob, glob_covers};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const IGNORE_FILE: &'static str = ".haumaruignore";
+
+/// The patterns of each ignore file read so far, by the directory holding it. Clones share
+/// them, so the watcher sees what scans read.
+#[derive(Debug, Clone)]
+pub struct IgnoreFiles {
+    dirs: Arc<Mutex<HashMap<PathBuf, Vec<Glob>>>>,
+}
+
+impl IgnoreFiles {
+    pub fn new() -> Self {
+        IgnoreFiles { dirs: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Forget every file read, so a scan picks up edits the watcher did not see.
+    pub fn clear(&self) {
+        self.dirs.lock().expect("ignore files lock").clear();
+    }
+
+    /// Note a change at `path`. An ignore file that changed is read again when next needed.
+    pub fn changed(&self, path: &Path) {
+        if path.file_name().and_then(|n| n.to_str()) == Some(IGNORE_FILE) {
+            if let Some(dir) = path.parent() {
+                self.dirs.lock().expect("ignore files lock").remove(dir);
+            }
+        }
+    }
+
+    /// Is `path` left out by the ignore file of a directory from `base` down to its parent.
+    pub fn is_ignored(&self, path: &Path, base: &str) -> bool {
+        let base = Path::new(base);
+        if !path.starts_with(base) || path == base {
+            return false;
+        }
+        let mut dirs = self.dirs.lock().expect("ignore files lock");
+        let mut dir = path.parent();
+        while let Some(d) = dir {
+            let globs = dirs.entry(d.to_path_buf()).or_insert_with(|| read(d));
+            if let Ok(rest) = path.strip_prefix(d) {
+                if glob_covers(globs, rest.to_str().unwrap_or("")) {
+                    return true;
+                }
+            }
+            if d == base {
+                break;
+            }
+            dir = d.parent();
+        }
+        false
+    }
+}
+
+/// The patterns of the ignore file in `dir`, none if it has none.
+fn read(dir: &Path) -> Vec<Glob> {
+    let path = dir.join(IGNORE_FILE);
+    let mut content = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => parse(&content),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => {
+            warn!("Unable to read {:?}: {}", path, e);
+            vec![]
+        }
+    }
+}
+
+/// One pattern a line. Blank lines and lines starting with `#` are skipped, as are negations,
+/// which are not supported.
+pub fn parse(content: &str) -> Vec<Glob> {
+    let mut globs = vec![];
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('!') {
+            warn!("Skipping ignore pattern {:?}, negations are not supported", line);
+            continue;
+        }
+        let anchored = line.trim_right_matches('/').contains('/');
+        globs.push(if anchored {
+            Glob::new(line)
+        } else {
+            Glob::new(&format!("**/{}", line))
+        });
+    }
+    globs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn patterns() {
+        let globs = parse("# build output\n\n*.log\n/build\ncache/\ndocs/*.pdf\n!keep.log\n");
+        let patterns: Vec<&str> = globs.iter().map(|g| g.as_str()).collect();
+        assert_eq!(vec!["**/*.log", "build", "**/cache", "docs/*.pdf"], patterns);
+    }
+
+    #[test]
+    fn applies_to_subtree() {
+        let base = "target/test/ignore_files";
+        let _ = remove_dir_all(base);
+        create_dir_all(format!("{}/web/cache", base)).unwrap();
+        File::create(format!("{}/{}", base, IGNORE_FILE))
+            .and_then(|mut f| f.write_all(b"*.log\n"))
+            .unwrap();
+        File::create(format!("{}/web/{}", base, IGNORE_FILE))
+            .and_then(|mut f| f.write_all(b"cache\n"))
+            .unwrap();
+
+        let ignores = IgnoreFiles::new();
+        let ignored = |path: &str| ignores.is_ignored(&Path::new(base).join(path), base);
+        assert!(ignored("a.log"));
+        assert!(ignored("web/b.log"));
+        assert!(ignored("web/cache"));
+        assert!(ignored("web/cache/c"));
+        assert!(!ignored("cache"));
+        assert!(!ignored("web/index.html"));
+        assert!(!ignored(IGNORE_FILE));
+
+        File::create(format!("{}/web/{}", base, IGNORE_FILE)).unwrap();
+        assert!(ignored("web/cache"));
+        ignores.changed(&Path::new(base).join("web").join(IGNORE_FILE));
+        assert!(!ignored("web/cache"));
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+
+use engine::CancelToken;
+
+/// A number of slots workers take turns holding, limiting how many of them work at once.
+#[derive(Clone)]
+pub struct Slots {
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Slots {
+    pub fn new(count: usize) -> Self {
+        Slots { state: Arc::new((Mutex::new(count), Condvar::new())) }
+    }
+
+    /// Wait for a free slot, held until the returned guard is dropped.
+    pub fn acquire(&self) -> SlotGuard {
+        let &(ref free, ref cvar) = &*self.state;
+        let mut free = free.lock().expect("slots lock");
+        while *free == 0 {
+            free = cvar.wait(free).expect("slots cvar");
+        }
+        *free -= 1;
+        SlotGuard { slots: self.clone() }
+    }
+}
+
+pub struct SlotGuard {
+    slots: Slots,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let &(ref free, ref cvar) = &*self.slots.state;
+        *free.lock().expect("slots lock") += 1;
+        cvar.notify_one();
+    }
+}
+
+/// What the jobs of one config share when they run in one process: pools of pre-send and
+/// send slots, so adding jobs does not multiply the files read or the uploads in flight, and
+/// for one-shot runs a cancel token, so the first job to fail stops the rest.
+#[derive(Clone)]
+pub struct JobGroup {
+    pre_send: Slots,
+    send: Slots,
+    cancel: Option<CancelToken>,
+}
+
+impl JobGroup {
+    pub fn new(pre_send: usize, send: usize) -> Self {
+        JobGroup {
+            pre_send: Slots::new(pre_send),
+            send: Slots::new(send),
+            cancel: None,
+        }
+    }
+    /// Share a cancel token, so the jobs can be stopped together.
+    pub fn with_shared_cancel(mut self) -> Self {
+        self.cancel = Some(CancelToken::new());
+        self
+    }
+    pub fn pre_send(&self) -> &Slots {
+        &self.pre_send
+    }
+    pub fn send(&self) -> &Slots {
+        &self.send
+    }
+    pub fn cancel(&self) -> Option<&CancelToken> {
+        self.cancel.as_ref()
+    }
+}
+
+impl fmt::Debug for JobGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JobGroup")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn slots_limit_workers() {
+        let slots = Slots::new(2);
+        let busy = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(Mutex::new(0));
+        let workers: Vec<_> = (0..6)
+            .map(|_| {
+                let slots = slots.clone();
+                let busy = busy.clone();
+                let most = most.clone();
+                thread::spawn(move || {
+                    let _slot = slots.acquire();
+                    let now = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                    {
+                        let mut most = most.lock().unwrap();
+                        if now > *most {
+                            *most = now;
+                        }
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().expect("worker");
+        }
+        assert!(*most.lock().unwrap() <= 2);
+    }
+}
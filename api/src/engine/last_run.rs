@@ -0,0 +1,78 @@
+use index::RunSummary;
+use serde_json;
+use std::fs::{File, rename};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+
+/// Written to the working dir after every run, for monitoring that checks backups are fresh
+/// without talking to the daemon.
+pub const LAST_RUN_FILE: &'static str = "last-run.json";
+
+/// How the latest backup run ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    /// When the run finished, in seconds since the epoch.
+    pub finished_at: i64,
+    /// The set the run closed. A run that failed before closing one has none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_set: Option<u64>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Files that failed to make it into the set.
+    pub failures: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RunSummary>,
+}
+
+impl LastRun {
+    /// Replace the file in `working`, writing it aside first so readers never see half of it.
+    pub fn write(&self, working: &Path) -> IoResult<()> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| IoError::new(ErrorKind::Other, format!("{}", e)))?;
+        let tmp = working.join(format!("{}.tmp", LAST_RUN_FILE));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(json.as_bytes())?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+        }
+        rename(&tmp, working.join(LAST_RUN_FILE))
+    }
+
+    /// The file in `working`, or `None` before the first run.
+    pub fn read(working: &Path) -> IoResult<Option<LastRun>> {
+        let mut json = String::new();
+        match File::open(working.join(LAST_RUN_FILE)) {
+            Ok(mut file) => file.read_to_string(&mut json)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| IoError::new(ErrorKind::InvalidData, format!("{}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::create_dir_all;
+    use std::path::Path;
+
+    #[test]
+    fn round_trip() {
+        let dir = Path::new("target/test/last_run");
+        create_dir_all(dir).unwrap();
+        let run = LastRun {
+            finished_at: 1500000000,
+            backup_set: None,
+            success: false,
+            error: Some("Storage hard cap reached".to_string()),
+            failures: 0,
+            summary: None,
+        };
+        run.write(dir).unwrap();
+        assert_eq!(Some(run), LastRun::read(dir).unwrap());
+    }
+}
@@ -1,19 +1,49 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::collections::HashSet;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::fs::create_dir_all;
-use std::io::{Write, Cursor, copy};
-use std::fs::File;
-use time::{Timespec, at, strftime};
+use std::io::{Read, Write, copy};
+use std::fs::{File, Permissions, hard_link, metadata, read_dir, remove_file, set_permissions};
+use std::os::unix::fs::PermissionsExt;
+use time::{Timespec, now_utc};
+use time_format::{TimeFormat, TimeStyle};
+use rusqlite::Connection;
 use rustc_serialize::hex::ToHex;
+use digest::Digest;
+use md5::Md5;
 use std::error::Error as StdError;
 
 use {Node, Index, Storage, get_key};
-use filesystem::{Change, BackupPath};
+use compare::{DriftEntry, compare};
+use control::Client as ControlClient;
+use estimate::ScanEstimate;
+use filesystem::{Change, ChangeKind, ChangeSource, BackupPath, add_change};
+use index::{BlobRef, IndexError, KeyScope, Pruned, SkipReason, SqlLightIndex};
+use listing::{ListEntry, ListOptions, write_deleted_marker, write_long_node, write_tree_node};
 use queue::Queue;
 use engine::pre_send::PreSendWorker;
-use storage::SendRequest;
+use disk::ensure_space;
+use filter::Filter;
+use hasher::Hasher;
+use metrics;
+use notification::{Notification, Notifiers};
+use pattern::{Glob, Pattern, glob_covers, glob_may_contain, matches_any};
+use priority;
+use path_priority::subtree_priority;
+use throttle::{Throttle, ThrottledRead};
+use budget::{UploadBudget, day_of, today, watch_budget};
+use capability::{can_set_capabilities, set_capability};
+use compression::encode_file;
+use owner::{can_chown, chown};
+use file_times::set_mtime;
+use units::format_bytes;
+use storage::{SendRequest, retrieve_node, send_request, verify_node};
 
 mod config;
 mod pre_send;
@@ -22,13 +52,96 @@ pub use self::config::EngineConfig;
 mod error;
 pub use self::error::DefaultEngineError;
 
+mod window;
+pub use self::window::BackupWindow;
+
+mod pause;
+pub use self::pause::{Pause, PauseReason};
+
+mod state;
+pub use self::state::{Phase, RunState};
+
+mod failures;
+pub use self::failures::{Failure, FailureKind, FailureSummary, Failures};
+
+mod progress;
+pub use self::progress::{Progress, ProgressSnapshot};
+
+mod events;
+pub use self::events::{Event, Events};
+
+mod run_stats;
+pub use self::run_stats::RunStats;
+
+mod cancel;
+pub use self::cancel::CancelToken;
+
+mod attempts;
+pub use self::attempts::{Attempts, MAX_ATTEMPTS};
+
+mod job_group;
+pub use self::job_group::{JobGroup, SlotGuard, Slots};
+
+mod hooks;
+pub use self::hooks::EngineHooks;
+
+mod scripts;
+pub use self::scripts::{BackupScripts, Outcome};
+
+mod generated;
+pub use self::generated::{GENERATED_DIR, GeneratedSource, parse_generated_sources};
+
+mod stream;
+pub use self::stream::{VIRTUAL_DIR, VIRTUAL_PREFIX, resolve_key, virtual_key};
+
+mod remote;
+pub use self::remote::RemoteSource;
+
+mod snapshot;
+pub use self::snapshot::{ReadRoot, Snapshot};
+
+mod browse;
+pub use self::browse::Browser;
+
+mod last_run;
+pub use self::last_run::{LAST_RUN_FILE, LastRun};
+
+mod tree_hash;
+pub use self::tree_hash::TreeHasher;
+
+mod ignore;
+pub use self::ignore::{IGNORE_FILE, IgnoreFiles};
+
+mod web;
+
 mod engine;
+mod control;
 
 #[cfg(test)]
 mod test;
 
 pub type Result<T> = StdResult<T, DefaultEngineError>;
 
+/// Sends of a single file that may fail before it is given up on until the next scan.
+const MAX_SEND_ATTEMPTS: u32 = 5;
+
+/// Milliseconds a send thread waits before taking another request after putting back a file
+/// whose chunks are still being stored.
+const PARTS_WAIT_MS: u64 = 10;
+
+/// Send threads when neither the config nor the backend says how many.
+pub const DEFAULT_SEND_THREADS: usize = 12;
+
+/// Seconds between checkpoints of an open backup set when the config does not say.
+const DEFAULT_CHECKPOINT_INTERVAL: u32 = 5 * 60;
+
+/// How long closing a backup set waits on the queues making no progress, while not paused,
+/// before giving up on what is left in them.
+const DRAIN_TIMEOUT_SECS: u64 = 60 * 60;
+
+/// How long a dropped engine waits for its notifications to be delivered.
+const NOTIFY_FLUSH_SECS: u64 = 30;
+
 pub struct DefaultEngine<I, S>
     where I: Index + Send + Clone,
           S: Storage
@@ -41,6 +154,42 @@ pub struct DefaultEngine<I, S>
     pre_send_queue: Queue<Node>,
     send_queue: Queue<SendRequest>,
     sent_queue: Queue<Node>,
+    pause: Pause,
+    change_source: Option<Box<ChangeSource>>,
+    notifiers: Notifiers,
+    run_requested: Arc<AtomicBool>,
+    /// Held by a backup run from opening its backup set until what it queued is recorded, and
+    /// by prune, so prune cannot delete a blob a run found already stored. It guards no data,
+    /// so a run that panicked while holding it leaves nothing to recover.
+    run_lock: Arc<Mutex<()>>,
+    state: RunState,
+    failures: Failures,
+    progress: Progress,
+    events: Events,
+    run_stats: RunStats,
+    cancel: CancelToken,
+    read_root: ReadRoot,
+    /// Whether the repository was over its soft cap when last checked, so crossing it is only
+    /// notified once.
+    soft_cap_crossed: bool,
+    /// Changes to files younger than `min_file_age`, held for the next run.
+    deferred: HashSet<Change>,
+    /// When the open backup set was last checkpointed, or opened.
+    last_checkpoint: Instant,
+    ignores: IgnoreFiles,
+}
+
+impl<I, S> Drop for DefaultEngine<I, S>
+    where I: Index + Send + Clone,
+          S: Storage
+{
+    /// Deliver what was notified before the process can exit and take the delivering thread
+    /// with it.
+    fn drop(&mut self) {
+        if !self.notifiers.flush(Duration::from_secs(NOTIFY_FLUSH_SECS)) {
+            warn!("Gave up waiting for notifications to be delivered");
+        }
+    }
 }
 
 impl<I, S> DefaultEngine<I, S>
@@ -56,8 +205,17 @@ impl<I, S> DefaultEngine<I, S>
         let pre_send_queue = Queue::new("pre-process").with_max_len(4);
         let send_queue = Queue::new("send").with_max_len(4);
         let sent_queue = Queue::new("sent").with_max_len(4);
+        let pause = Pause::new();
+        let notifiers = Notifiers::from_config(&config);
+        let events = Events::new();
+        // jobs run together once stop together
+        let cancel = config.job_group()
+            .and_then(|g| g.cancel())
+            .cloned()
+            .unwrap_or_else(CancelToken::new);
 
         if config.is_detached() {
+            let read_root = ReadRoot::new(config.path());
             Ok(DefaultEngine {
                 config: config,
                 excludes: excludes,
@@ -67,10 +225,46 @@ impl<I, S> DefaultEngine<I, S>
                 pre_send_queue: pre_send_queue,
                 send_queue: send_queue,
                 sent_queue: sent_queue,
+                pause: pause,
+                change_source: None,
+                notifiers: notifiers,
+                run_requested: Arc::new(AtomicBool::new(false)),
+                run_lock: Arc::new(Mutex::new(())),
+                state: RunState::new(),
+                failures: Failures::new().with_events(events.clone()),
+                progress: Progress::new(),
+                events: events,
+                run_stats: RunStats::new(),
+                cancel: cancel,
+                read_root: read_root,
+                soft_cap_crossed: false,
+                deferred: HashSet::new(),
+                last_checkpoint: Instant::now(),
+                ignores: IgnoreFiles::new(),
             })
 
         } else {
 
+            // sets still open were being written by a run that died, unless a daemon answering
+            // on the working directory is still writing them
+            let mut index = index;
+            if ControlClient::connect(&config.abs_working()).is_some() {
+                debug!("A daemon is running, leaving its open backup sets alone");
+            } else {
+                for backup_set in index.abort_open_sets()? {
+                    warn!("Backup set {} was left open by a run that did not finish, marked it \
+                           aborted",
+                          backup_set);
+                }
+                match storage.sweep_temp_files() {
+                    Ok(0) => {}
+                    Ok(removed) => {
+                        info!("Removed {} temp file(s) left by sends that died", removed)
+                    }
+                    Err(e) => warn!("Unable to remove temp files left by sends: {}", e),
+                }
+            }
+
             let mut config = config;
             let path_buf = PathBuf::from(config.path()).canonicalize()
                 .map_err(|e| {
@@ -81,11 +275,33 @@ impl<I, S> DefaultEngine<I, S>
             let abs_path = path_buf.to_str().unwrap().to_string();
             config.set_path(Some(abs_path.clone()));
 
+            let mut roots = vec![];
+            for root in config.roots() {
+                let root_buf = PathBuf::from(root).canonicalize().map_err(|e| {
+                        DefaultEngineError::Other(format!("Unable to canonicalize backup root \
+                                                           {}: {}",
+                                                          root,
+                                                          e))
+                    })?;
+                if !root_buf.starts_with(&path_buf) {
+                    return Err(box DefaultEngineError::Other(format!("Backup root {} is not \
+                                                                      under {}",
+                                                                     root,
+                                                                     abs_path)));
+                }
+                roots.push(root_buf.to_str().unwrap().to_string());
+            }
+            let config = config.with_roots(roots);
+
             debug!("Base path: {}", config.path());
+            debug!("Backup roots: {:?}", config.roots());
             debug!("Exclude paths: {:?}", excludes);
 
-            let bp = try!(BackupPath::new(abs_path.clone())
+            let mut bp = try!(BackupPath::new(abs_path.clone())
                 .map_err(|e| DefaultEngineError::CreateBackupPath(e)));
+            if !config.roots().is_empty() {
+                bp.set_roots(config.roots().iter().map(PathBuf::from).collect());
+            }
 
             let de = DefaultEngine {
                 config: config,
@@ -96,33 +312,168 @@ impl<I, S> DefaultEngine<I, S>
                 pre_send_queue: pre_send_queue.clone(),
                 send_queue: send_queue.clone(),
                 sent_queue: sent_queue.clone(),
+                pause: pause.clone(),
+                change_source: None,
+                notifiers: notifiers,
+                run_requested: Arc::new(AtomicBool::new(false)),
+                run_lock: Arc::new(Mutex::new(())),
+                state: RunState::new(),
+                failures: Failures::new().with_events(events.clone()),
+                progress: Progress::new(),
+                events: events,
+                run_stats: RunStats::new(),
+                cancel: cancel,
+                read_root: ReadRoot::new(&abs_path),
+                soft_cap_crossed: false,
+                deferred: HashSet::new(),
+                last_checkpoint: Instant::now(),
+                ignores: IgnoreFiles::new(),
             };
 
+            // one bucket shared by all workers reading file content
+            let throttle = de.config.scan_io_rate().map(Throttle::new);
+
+            let budget = de.config.max_daily_upload().map(UploadBudget::new);
+            if let Some(ref budget) = budget {
+                // what runs earlier today uploaded counts against the budget too
+                let mut index = index.clone();
+                for set in index.stats()?.backup_sets {
+                    if day_of(Timespec::new(set.at, 0)) == today() {
+                        budget.spend(today(), set.summary.map(|s| s.bytes_uploaded).unwrap_or(0));
+                    }
+                }
+                if budget.is_spent(today()) {
+                    pause.pause(PauseReason::UploadBudget);
+                }
+                watch_budget(pause.clone(), budget.clone());
+            }
+
             // pre-processing worker threads that [pre_send -> send] queues
-            for _ in 0..4 {
+            let read_attempts = Attempts::new();
+            for _ in 0..de.config.pre_send_threads() {
                 let worker = PreSendWorker::new(de.config.clone(),
                                                 pre_send_queue.clone(),
-                                                send_queue.clone());
+                                                send_queue.clone())
+                    .with_throttle(throttle.clone())
+                    .with_failures(de.failures.clone())
+                    .with_progress(de.progress.clone())
+                    .with_run_stats(de.run_stats.clone())
+                    .with_read_root(de.read_root.clone())
+                    .with_attempts(read_attempts.clone());
                 thread::spawn(move || {
                     worker.run();
                 });
             }
 
-            // sending worker threads that [send -> sent]
-            for _ in 0..12 {
+            // sending worker threads that [send -> sent], as many as the backend copes with
+            let send_threads = de.config
+                .send_threads()
+                .or(storage.max_sends())
+                .unwrap_or(DEFAULT_SEND_THREADS);
+            debug!("Starting {} send threads", send_threads);
+            for _ in 0..send_threads {
                 let mut send_queue = send_queue.clone();
                 let mut sent_queue = sent_queue.clone();
                 let storage = storage.clone();
+                let window = de.config.window();
+                let pause = pause.clone();
+                let notifiers = de.notifiers.clone();
+                let failures = de.failures.clone();
+                let progress = de.progress.clone();
+                let events = de.events.clone();
+                let run_stats = de.run_stats.clone();
+                let budget = budget.clone();
+                let job_group = de.config.job_group().cloned();
                 thread::spawn(move || {
                     loop {
-                        let mut item = send_queue.pop();
+                        // only upload inside the backup window
+                        if let Some(window) = window {
+                            window.wait_until_open();
+                        }
+                        pause.wait_while_paused();
+                        let mut item = match send_queue.pop_or_closed() {
+                            Some(item) => item,
+                            None => return,
+                        };
+                        // a chunked file is recorded once the chunks queued ahead of it are stored
+                        if let Some(parts) = item.as_ref().parts().cloned() {
+                            if !parts.is_done() {
+                                item.requeue();
+                                thread::sleep(Duration::from_millis(PARTS_WAIT_MS));
+                                continue;
+                            }
+                            if parts.is_failed() {
+                                // the chunk given up on is recorded, the next scan tries again
+                                progress.done(item.as_ref().node().size());
+                                item.success();
+                                continue;
+                            }
+                        }
                         let path = item.as_ref().node().path().to_string();
-                        match storage.send(item.as_mut()) {
-                            Ok(()) => {
-                                sent_queue.push(item.as_ref().node().clone());
+                        let started = Instant::now();
+                        let sent = {
+                            // jobs run together take turns with the same slots
+                            let _slot = job_group.as_ref().map(|g| g.send().acquire());
+                            send_request(&storage, item.as_mut())
+                        };
+                        match sent {
+                            Ok(receipt) => {
+                                let stored = receipt.stored_bytes();
+                                if item.as_ref().parts().is_none() {
+                                    metrics::timing("send.time", started.elapsed());
+                                    metrics::count("send.bytes", stored as i64);
+                                }
+                                if let Some(parts) = item.as_ref().part_of().cloned() {
+                                    parts.stored(stored);
+                                    item.success();
+                                    continue;
+                                }
+                                if receipt.deduplicated() {
+                                    run_stats.update(|s| s.dedup_hits += 1);
+                                } else {
+                                    run_stats.update(|s| s.bytes_uploaded += stored);
+                                    run_stats.uploaded(&path,
+                                                       stored,
+                                                       started.elapsed(),
+                                                       item.as_ref().attempts());
+                                    // queued files wait for tomorrow's budget
+                                    if let Some(ref budget) = budget {
+                                        if budget.spend(today(), stored) {
+                                            pause.pause(PauseReason::UploadBudget);
+                                        }
+                                    }
+                                }
+                                events.send(Event::FileUploaded {
+                                    path: path.clone(),
+                                    bytes: item.as_ref().size(),
+                                    duration: started.elapsed(),
+                                });
+                                let mut node = item.as_ref().node().clone();
+                                node.set_stored_size(Some(item.as_ref().size()));
+                                sent_queue.push(node);
+                                progress.done(item.as_ref().node().size());
                                 item.success();
                             }
-                            Err(e) => error!("Failing sending {}: {}", path, e),
+                            Err(e) => {
+                                metrics::incr("send.errors");
+                                error!("Failing sending {}: {}", path, e);
+                                if item.as_mut().failed() >= MAX_SEND_ATTEMPTS {
+                                    error!("Giving up on {} after {} attempts",
+                                           path,
+                                           MAX_SEND_ATTEMPTS);
+                                    failures.record(FailureKind::Upload, &path, &format!("{}", e));
+                                    run_stats.gave_up(&path, MAX_SEND_ATTEMPTS);
+                                    notifiers.send(Notification::UploadFailed {
+                                        path: path,
+                                        error: format!("{}", e),
+                                    });
+                                    match item.as_ref().part_of() {
+                                        Some(parts) => parts.gave_up(),
+                                        None => progress.done(item.as_ref().node().size()),
+                                    }
+                                    item.success();
+                                }
+                            }
                         }
                     }
                 });
@@ -132,16 +483,36 @@ impl<I, S> DefaultEngine<I, S>
             {
                 let mut sent_queue = sent_queue.clone();
                 let mut index = index;
+                let failures = de.failures.clone();
+                let run_stats = de.run_stats.clone();
+                let attempts = Attempts::new();
                 thread::spawn(move || {
                     loop {
-                        let item = sent_queue.pop();
+                        let item = match sent_queue.pop_or_closed() {
+                            Some(item) => item,
+                            None => return,
+                        };
                         let path = item.as_ref().path().to_string();
-                        match index.insert(item.as_ref().clone()) {
+                        let started = Instant::now();
+                        let inserted = index.insert(item.as_ref().clone());
+                        run_stats.add_index_time(started.elapsed());
+                        match inserted {
                             Ok(n) => {
                                 debug!("Inserted {} - {:?}", path, n);
+                                attempts.done(&path);
+                                item.success();
+                            }
+                            Err(e) => {
+                                error!("Failed to insert {}: {}", path, e);
+                                if attempts.failed(&path) < MAX_ATTEMPTS {
+                                    item.requeue();
+                                    continue;
+                                }
+                                error!("Giving up on {} after {} attempts", path, MAX_ATTEMPTS);
+                                failures.record(FailureKind::Index, &path, &format!("{}", e));
+                                attempts.done(&path);
                                 item.success();
                             }
-                            Err(e) => error!("Failed to insert {}: {}", path, e),
                         }
                     }
                 });
@@ -151,129 +522,1526 @@ impl<I, S> DefaultEngine<I, S>
         }
     }
 
+    /// Drive `run` from `source` instead of watching the backup path.
+    pub fn with_change_source<C>(mut self, source: C) -> Self
+        where C: ChangeSource + 'static
+    {
+        self.change_source = Some(box source);
+        self
+    }
+
+    pub fn notify(&self, notification: Notification) {
+        self.notifiers.send(notification);
+    }
+
+    /// Tell monitoring that a run failed before it could close its backup set.
+    fn backup_failed(&mut self, error: String) {
+        self.post_backup(&Outcome::Failed { error: &error });
+        self.record_last_run(LastRun {
+            finished_at: now_utc().to_timespec().sec,
+            backup_set: None,
+            success: false,
+            error: Some(error.clone()),
+            failures: 0,
+            summary: None,
+        });
+        self.notify(Notification::BackupFailed { error: error });
+    }
+
+    fn record_last_run(&self, run: LastRun) {
+        if let Err(e) = run.write(&self.config.abs_working()) {
+            warn!("Unable to write {}: {}", LAST_RUN_FILE, e);
+        }
+    }
+
+    /// Record a closed backup set and what its run did, returning the files that failed to
+    /// make it in.
+    fn backup_set_closed(&mut self, backup_set: u64, at: i64) -> FailureSummary {
+        let (summary, report, skipped) = self.run_stats.take();
+        info!("Backup set {} summary: {}", backup_set, summary);
+        if !skipped.is_empty() {
+            info!("Backup set {} skipped {} path(s)", backup_set, skipped.len());
+        }
+        if let Err(e) = self.index.set_summary(backup_set, &summary) {
+            warn!("Unable to store summary of backup set {}: {}", backup_set, e);
+        }
+        if let Err(e) = self.index.set_report(backup_set, &report) {
+            warn!("Unable to store report of backup set {}: {}", backup_set, e);
+        }
+        if let Err(e) = self.index.set_skipped(backup_set, &skipped) {
+            warn!("Unable to store skipped paths of backup set {}: {}", backup_set, e);
+        }
+
+        self.state.set_last_backup_set(backup_set, at);
+        let failures = self.failures.take();
+        if failures.is_empty() {
+            self.notify(Notification::BackupComplete { backup_set: backup_set });
+        } else {
+            self.notify(Notification::BackupPartial {
+                backup_set: backup_set,
+                failures: failures.len() as u64,
+            });
+        }
+
+        // pushed from a copy, as the insert thread may write to the index while it uploads
+        let snapshot = self.config.abs_working().join("haumaru.idx.push");
+        match self.index.copy_to(&snapshot) {
+            Ok(true) => {
+                if let Err(e) = self.storage.push_index(&self.config.namespace(), &snapshot) {
+                    warn!("Unable to push index after backup set {}: {}", backup_set, e);
+                }
+                let _ = remove_file(&snapshot);
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Unable to copy index after backup set {}: {}", backup_set, e),
+        }
+
+        self.post_backup(&Outcome::Closed {
+            backup_set: backup_set,
+            summary: &summary,
+            failures: failures.len(),
+        });
+        self.record_last_run(LastRun {
+            finished_at: now_utc().to_timespec().sec,
+            backup_set: Some(backup_set),
+            success: failures.is_empty(),
+            error: None,
+            failures: failures.len() as u64,
+            summary: Some(summary),
+        });
+        self.events.send(Event::SetClosed {
+            backup_set: backup_set,
+            failures: failures.len(),
+        });
+        if !failures.is_empty() {
+            error!("Backup set {}: {}", backup_set, failures);
+        }
+        failures
+    }
+
+    /// Run the post backup script. It failing is only logged.
+    fn post_backup(&self, outcome: &Outcome) {
+        if let Err(e) = self.config.scripts().post(outcome) {
+            warn!("Post backup script failed: {}", e);
+        }
+    }
+
+    /// Check every stored blob in `scope`, reporting each result to `f`. Fails, and
+    /// notifies, if any blob is missing or corrupt.
+    pub fn verify_with(&mut self,
+                       scope: &KeyScope,
+                       f: &mut FnMut(&Node, bool))
+                       -> StdResult<(), Box<StdError>> {
+        self.progress.reset();
+        {
+            let progress = &self.progress;
+            self.index
+                .visit_all_hashable(scope,
+                                    &mut |node| {
+                    progress.queued(node.size());
+                    Ok(())
+                })?;
+        }
+
+        let mut failed = vec![];
+        let visited = {
+            let storage = &self.storage;
+            let progress = &self.progress;
+            let cancel = &self.cancel;
+            self.index
+                .visit_all_hashable(scope,
+                                    &mut |node| {
+                    if cancel.is_cancelled() {
+                        return Err(IndexError::Fatal("Cancelled".to_string(), None));
+                    }
+                    progress.started(node.path());
+                    let size = node.size();
+                    let (node, valid) = verify_node(storage, node)
+                        .map_err(|e| IndexError::Fatal(format!("Verify error: {}", e), None))?;
+                    progress.done(size);
+                    f(&node, valid);
+                    if !valid {
+                        failed.push(node.path().to_string());
+                    }
+                    Ok(())
+                })
+        };
+        self.cancel.check()?;
+        visited?;
+
+        for row in self.index.corrupt_rows()? {
+            error!("Corrupt index row: {}", row);
+            failed.push(format!("index {}", row));
+        }
+
+        if !failed.is_empty() {
+            let count = failed.len();
+            self.notify(Notification::VerifyFailed { failed: failed });
+            return Err(format!("Verification failed for {} file(s)", count).into());
+        }
+        Ok(())
+    }
+
+    /// The nodes `restore` would write and where, without touching storage or disk.
+    pub fn restore_plan(&mut self,
+                        key: &str,
+                        from: Option<Timespec>,
+                        target: &str)
+                        -> StdResult<Vec<(Node, PathBuf)>, Box<StdError>> {
+        let mut plan = vec![];
+        if key.is_empty() {
+            for node in self.list_root(from)? {
+                self.plan_node(node, "", from, target, &mut plan)?;
+            }
+        } else {
+            let node = match self.index.get(key.to_string(), from)? {
+                Some(n) => n,
+                None => {
+                    return Err(box DefaultEngineError::Other(format!("Not Found: {:?}", key)));
+                }
+            };
+            let mut tmp = PathBuf::new();
+            tmp.push(key);
+            let parent = tmp.parent().expect("restore.parent").to_str().expect("UTF-8 validity");
+            self.plan_node(node, parent, from, target, &mut plan)?;
+        }
+        Ok(plan)
+    }
+
+    /// Restore `key` under `target`, reading from storage at up to `rate` bytes per second.
+    pub fn restore_limited(&mut self,
+                           key: &str,
+                           from: Option<Timespec>,
+                           target: &str,
+                           rate: Option<u64>)
+                           -> StdResult<(), Box<StdError>> {
+
+        self.progress.reset();
+        for (node, _) in self.restore_plan(key, from, target)? {
+            if node.is_file() {
+                self.progress.queued(node.size());
+            }
+        }
+        let throttle = rate.map(Throttle::new);
+
+        if key.is_empty() {
+            info!("Performing full restore to {}", target);
+
+            create_dir_all(target)?;
+            let mut links = RestoredLinks::new();
+            for node in self.list_root(from)? {
+                self.restore_node(node, "", from, target, throttle.as_ref(), &mut links)?;
+            }
+            Ok(())
+
+        } else {
+
+            info!("Restoring {} to {}", key, target);
+            let node = match self.index.get(key.to_string(), from)? {
+                Some(n) => n,
+                None => {
+                    return Err(box DefaultEngineError::Other(format!("Not Found: {:?}", key)));
+                }
+            };
+
+            let mut tmp = PathBuf::new();
+            tmp.push(key);
+            let parent = tmp.parent().expect("restore.parent").to_str().expect("UTF-8 validity");
+            debug!("Parent of key is {:?}", parent);
+
+            self.restore_node(node,
+                              parent,
+                              from,
+                              target,
+                              throttle.as_ref(),
+                              &mut RestoredLinks::new())
+        }
+    }
+
+    /// Restore the last version of every file below `key` deleted at or after `since` to its
+    /// path under `target`, leaving alone files that are back there already. Returns what was
+    /// restored, and where.
+    pub fn undelete(&mut self,
+                    key: &str,
+                    since: Timespec,
+                    target: &str)
+                    -> StdResult<Vec<(Node, PathBuf)>, Box<StdError>> {
+        let options = ListOptions::new().with_recursive(true).with_deleted(true);
+        let deleted: Vec<Node> = self.ls_nodes(key, None, &options)?
+            .into_iter()
+            .filter(|e| e.node.is_file() && e.deleted_at.map(|at| at >= since).unwrap_or(false))
+            .map(|e| e.node)
+            .collect();
+
+        self.progress.reset();
+        for node in &deleted {
+            self.progress.queued(node.size());
+        }
+
+        let mut restored = vec![];
+        let mut links = RestoredLinks::new();
+        for node in deleted {
+            let path = Path::new(target).join(node.path());
+            if path.exists() {
+                info!("Not undeleting {}, {:?} exists", node.path(), path);
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            self.restore_node(node.clone(), "", None, target, None, &mut links)?;
+            restored.push((node, path));
+        }
+        Ok(restored)
+    }
+
+    /// Top level of the backup path, leaving out data stored with `put`, which is only reached
+    /// through `VIRTUAL_PREFIX` keys, and generated sources, listed by their own key.
+    fn list_root(&mut self, from: Option<Timespec>) -> StdResult<Vec<Node>, IndexError> {
+        let nodes = self.index.list("".to_string(), from)?;
+        Ok(nodes.into_iter()
+            .filter(|n| n.path() != VIRTUAL_DIR && n.path() != GENERATED_DIR)
+            .collect())
+    }
+
+    /// When backup set `id` was taken, for looking at the tree as of that set.
+    pub fn backup_set_at(&mut self, id: u64) -> StdResult<Timespec, Box<StdError>> {
+        match self.index.stats()?.backup_sets.into_iter().find(|set| set.id == id) {
+            Some(set) => Ok(Timespec::new(set.at, 0)),
+            None => Err(format!("Backup set {} not found", id).into()),
+        }
+    }
+
+    /// Remove the versions the retention policy no longer keeps as of `now`, then the blobs
+    /// only they referenced from storage. Returns what left the index and how many blobs
+    /// storage let go of.
+    pub fn prune(&mut self, now: i64) -> StdResult<(Pruned, u64), Box<StdError>> {
+        let pruned = self.index.prune(self.config.retention(), now)?;
+        let mut deleted = 0;
+        // the storage may be shared, so a blob is only ours to delete when no other
+        // namespace's index references it
+        let shared = match self.blobs_of_other_namespaces()? {
+            Some(shared) => shared,
+            None => {
+                warn!("{} storage cannot list the namespaces sharing it, keeping {} unreferenced \
+                       blob(s)",
+                      self.config.storage(),
+                      pruned.orphans.len());
+                self.notify(Notification::PruneComplete { removed: pruned.versions });
+                return Ok((pruned, 0));
+            }
+        };
+        for &(algorithm, ref hash) in pruned.orphans.iter().filter(|b| !shared.contains(*b)) {
+            match self.storage.delete(algorithm, hash) {
+                Ok(true) => deleted += 1,
+                Ok(false) => {}
+                Err(e) => warn!("Unable to delete blob {}: {}", hash.to_hex(), e),
+            }
+        }
+        self.notify(Notification::PruneComplete { removed: pruned.versions });
+        Ok((pruned, deleted))
+    }
+
+    /// Every blob the index copies other namespaces pushed to storage reference, or `None`
+    /// when storage cannot list its namespaces. The copies are fetched into
+    /// `working/namespaces`.
+    fn blobs_of_other_namespaces(&self) -> StdResult<Option<HashSet<BlobRef>>, Box<StdError>> {
+        let namespaces = match self.storage.namespaces()? {
+            Some(namespaces) => namespaces,
+            None => return Ok(None),
+        };
+        let own = self.config.namespace();
+        let dir = self.config.abs_working().join("namespaces");
+        let mut blobs = HashSet::new();
+        for namespace in namespaces.into_iter().filter(|n| *n != own) {
+            let mut blob = match self.storage.fetch_index(&namespace)? {
+                Some(blob) => blob,
+                None => return Ok(None),
+            };
+            create_dir_all(&dir)?;
+            let db_path = dir.join(format!("{}.idx", namespace));
+            copy(&mut blob, &mut File::create(&db_path)?)?;
+            let mut index = SqlLightIndex::new(Connection::open(&db_path)?)?;
+            blobs.extend(index.blobs()?);
+        }
+        Ok(Some(blobs))
+    }
+
+    /// What `ls` shows for `key`. Directories are walked when `options` ask for a recursive
+    /// listing, and deleted entries left out unless they ask for those.
+    pub fn ls_nodes(&mut self,
+                    key: &str,
+                    from: Option<Timespec>,
+                    options: &ListOptions)
+                    -> StdResult<Vec<ListEntry>, Box<StdError>> {
+        let nodes = if key.is_empty() {
+            self.list_root(from)?
+        } else {
+            match self.index.get(key.to_string(), from)? {
+                Some(ref node) if node.is_file() => vec![node.clone()],
+                Some(node) => self.index.list(node.path().to_string(), from)?,
+                None => return Err(box DefaultEngineError::Other(format!("Not Found: {}", key))),
+            }
+        };
+        let mut listing = vec![];
+        self.walk_listing(nodes, 0, from, options, &mut listing)?;
+        Ok(listing)
+    }
+
+    fn walk_listing(&mut self,
+                    nodes: Vec<Node>,
+                    depth: usize,
+                    from: Option<Timespec>,
+                    options: &ListOptions,
+                    listing: &mut Vec<ListEntry>)
+                    -> StdResult<(), IndexError> {
+        let mut nodes: Vec<Node> = nodes.into_iter()
+            .filter(|n| options.deleted() || !n.deleted())
+            .collect();
+        options.sort(&mut nodes);
+        for node in nodes {
+            let children = if options.recursive() && node.is_dir() {
+                Some(self.index.list(node.path().to_string(), from)?)
+            } else {
+                None
+            };
+            let entry = self.list_entry(depth, node)?;
+            listing.push(entry);
+            if let Some(children) = children {
+                self.walk_listing(children, depth + 1, from, options, listing)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `node` as `ls` shows it: deleted nodes are shown as their last version, from the index
+    /// history, along with when they were deleted.
+    fn list_entry(&mut self, depth: usize, node: Node) -> StdResult<ListEntry, IndexError> {
+        if !node.deleted() {
+            return Ok(ListEntry {
+                depth: depth,
+                node: node,
+                deleted_at: None,
+            });
+        }
+        let deleted_at = node.mtime().clone();
+        let backup_set = node.backup_set();
+        let last = self.index
+            .versions(node.path().to_string())?
+            .into_iter()
+            .filter(|v| !v.deleted() && v.backup_set() <= backup_set)
+            .last();
+        let mut shown = last.unwrap_or(node);
+        shown.set_deleted(true);
+        Ok(ListEntry {
+            depth: depth,
+            node: shown,
+            deleted_at: Some(deleted_at),
+        })
+    }
+
+    /// Write what `ls` shows for `key` to `out`: the details of a file, otherwise a line for
+    /// each entry.
+    pub fn list_with(&mut self,
+                     key: &str,
+                     from: Option<Timespec>,
+                     options: &ListOptions,
+                     out: &mut Write)
+                     -> StdResult<(), Box<StdError>> {
+        if !key.is_empty() {
+            match self.index.get(key.to_string(), from)? {
+                Some(ref node) if node.deleted() && !options.deleted() => {
+                    return Err(box DefaultEngineError::Other(format!("Not Found: {}", key)));
+                }
+                Some(ref node) if node.is_file() && !node.deleted() => {
+                    write_file_details(out, node, options.times());
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        for entry in self.ls_nodes(key, from, options)? {
+            if options.deleted() {
+                write_deleted_marker(out, entry.deleted_at, options.times());
+            }
+            if options.tree() {
+                write_tree_node(out, entry.depth, &entry.node);
+            } else if options.long() {
+                write_long_node(out, &entry.node, options.times());
+            } else {
+                write_ls_node(out, &entry.node, options.times());
+            }
+        }
+        Ok(())
+    }
+
+    fn plan_node(&mut self,
+                 node: Node,
+                 node_base: &str,
+                 from: Option<Timespec>,
+                 target: &str,
+                 plan: &mut Vec<(Node, PathBuf)>)
+                 -> StdResult<(), Box<StdError>> {
+        let n = match node_base.is_empty() {
+            true => 0,
+            false => node_base.len() + 1,
+        };
+        let mut restore_path = PathBuf::new();
+        restore_path.push(target);
+        restore_path.push(&node.path()[n..]);
+
+        if node.is_dir() {
+            let children = self.index.list(node.path().to_string(), from)?;
+            plan.push((node, restore_path));
+            for child in children {
+                self.plan_node(child, node_base, from, target, plan)?;
+            }
+        } else {
+            plan.push((node, restore_path));
+        }
+        Ok(())
+    }
+
+    /// Progress of the backup loop.
+    pub fn state(&self) -> RunState {
+        self.state.clone()
+    }
+
+    /// Receive the engine's events from now on. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Call `hooks` as backups start and finish, files are backed up and errors happen.
+    pub fn set_hooks(&mut self, hooks: Box<EngineHooks>) {
+        hooks::spawn(hooks, self.events.subscribe());
+    }
+
+    /// Start a backup set at `at`.
+    fn open_backup_set(&mut self, at: i64) -> StdResult<u64, Box<StdError>> {
+        // the index grows with every set
+        ensure_space(&self.config.abs_working(), 0, self.config.free_space_reserve())?;
+        self.config
+            .scripts()
+            .pre(at)
+            .map_err(|e| DefaultEngineError::Other(format!("Pre backup script failed: {}", e)))?;
+        let backup_set = self.index.create_backup_set(at).map_err(|e| box e)?;
+        self.last_checkpoint = Instant::now();
+        self.run_stats.take();
+        self.events.send(Event::SetOpened { backup_set: backup_set });
+        Ok(backup_set)
+    }
+
+    /// Persist what the open backup set holds so far once the checkpoint interval has passed.
+    /// Files still queued are left for the next checkpoint, or the close of the set.
+    fn checkpoint_if_due(&mut self) -> StdResult<(), Box<StdError>> {
+        let interval = self.config.checkpoint_interval().unwrap_or(DEFAULT_CHECKPOINT_INTERVAL);
+        if interval == 0 || self.last_checkpoint.elapsed() < Duration::from_secs(interval as u64) {
+            return Ok(());
+        }
+        self.index.checkpoint().map_err(|e| box e)?;
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+
+    /// Token stopping `run`, scans, `restore` and `verify_store` at the next file boundary.
+    /// Backup sets being written are closed with what was done so far.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Files and bytes worked through by the current backup, restore or verify.
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// Compare the repository size against the configured caps. Crossing the soft cap is
+    /// notified; reaching the hard cap is notified and pauses uploads until the repository
+    /// shrinks back under it, or the cap is raised.
+    pub fn check_storage_caps(&mut self) {
+        let soft = self.config.storage_soft_cap();
+        let hard = self.config.storage_hard_cap();
+        if soft.is_none() && hard.is_none() {
+            return;
+        }
+        // the index knows the stored size without walking or listing the backend
+        let used = match self.index.stats() {
+            Ok(stats) => stats.stored_bytes,
+            Err(e) => {
+                warn!("Unable to measure the repository: {}", e);
+                return;
+            }
+        };
+
+        let over_soft = soft.map(|cap| used >= cap).unwrap_or(false);
+        if over_soft && !self.soft_cap_crossed {
+            let cap = soft.expect("soft cap");
+            warn!("Repository holds {}, over its soft cap of {}",
+                  format_bytes(used),
+                  format_bytes(cap));
+            self.notify(Notification::StorageSoftCap {
+                used: used,
+                cap: cap,
+            });
+        }
+        self.soft_cap_crossed = over_soft;
+
+        match hard {
+            Some(cap) if used >= cap => {
+                if !self.pause.reasons().contains(&PauseReason::StorageCap) {
+                    error!("Repository holds {}, reaching its hard cap of {}",
+                           format_bytes(used),
+                           format_bytes(cap));
+                    self.notify(Notification::StorageHardCap {
+                        used: used,
+                        cap: cap,
+                    });
+                    self.pause.pause(PauseReason::StorageCap);
+                }
+            }
+            _ => self.pause.resume(PauseReason::StorageCap),
+        }
+    }
+
+    /// A detached engine sharing this engine's index, storage and pause state, for serving
+    /// requests from other processes while this one runs.
+    pub fn control_handle(&self) -> StdResult<Self, Box<StdError>> {
+        let mut handle = DefaultEngine::new(self.config.clone().detached(),
+                                            self.excludes.clone(),
+                                            self.index.clone(),
+                                            self.storage.clone())?;
+        handle.pause = self.pause.clone();
+        handle.run_requested = self.run_requested.clone();
+        handle.run_lock = self.run_lock.clone();
+        handle.state = self.state.clone();
+        handle.progress = self.progress.clone();
+        handle.events = self.events.clone();
+        handle.pre_send_queue = self.pre_send_queue.clone();
+        handle.send_queue = self.send_queue.clone();
+        handle.sent_queue = self.sent_queue.clone();
+        Ok(handle)
+    }
+
+    /// Ask `run` to start a backup run now instead of at the next period.
+    pub fn request_run(&self) {
+        self.run_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Handle for pausing and resuming this engine.
+    pub fn pause(&self) -> Pause {
+        self.pause.clone()
+    }
+
     pub fn backup_path(&mut self) -> &mut BackupPath {
         self.backup_path.as_mut().expect("some BackupPath")
     }
 
     pub fn scan_as_backup_set(&mut self, now: i64) -> StdResult<(), Box<StdError>> {
-        let backup_set = self.index.create_backup_set(now).map_err(|e| box e)?;
-        self.scan(backup_set)?;
+        let run_lock = self.run_lock.clone();
+        let _running = run_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let backup_set = self.open_backup_set(now)?;
+        let scanned = self.scan(backup_set);
+        if scanned.is_err() && !self.cancel.is_cancelled() {
+            return scanned;
+        }
+        // a cancelled scan still stores what it got through
         self.index.close_backup_set()?;
+        self.backup_set_closed(backup_set, now);
+        scanned
+    }
+
+    /// Like `scan_as_backup_set`, but waits for every queued file to be sent before closing
+    /// the backup set. Monitoring hears once how it went: the run failed, or the set closed
+    /// with or without failed files.
+    fn scan_and_drain(&mut self, now: i64) -> StdResult<(), Box<StdError>> {
+        self.progress.reset();
+        let (backup_set, scanned) = match self.scan_and_close(now) {
+            Ok(closed) => closed,
+            Err(e) => {
+                self.backup_failed(format!("{}", e));
+                return Err(e);
+            }
+        };
+        let failures = self.backup_set_closed(backup_set, now);
+        scanned?;
+        if !failures.is_empty() {
+            return Err(format!("Backup set {} completed with {} failed file(s)",
+                               backup_set,
+                               failures.len())
+                .into());
+        }
         Ok(())
     }
 
-    pub fn wait_for_queue_drain(&mut self) {
-        self.pre_send_queue.wait();
-        self.send_queue.wait();
-        self.sent_queue.wait();
+    /// Open a backup set, scan into it and close it once everything queued is sent. A
+    /// cancelled scan still closes the set, so its result is returned alongside.
+    fn scan_and_close(&mut self,
+                      now: i64)
+                      -> StdResult<(u64, StdResult<(), Box<StdError>>), Box<StdError>> {
+        let run_lock = self.run_lock.clone();
+        let _running = run_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let backup_set = self.open_backup_set(now)?;
+        let scanned = self.scan(backup_set);
+        let scanned = if self.cancel.is_cancelled() {
+            scanned
+        } else {
+            Ok(scanned?)
+        };
+        self.wait_for_queue_drain();
+        self.index.close_backup_set()?;
+        Ok((backup_set, scanned))
+    }
+
+    /// Wait for queued files to be stored and indexed. When the queues make no progress for
+    /// `DRAIN_TIMEOUT_SECS`, not counting time paused, the files still waiting are taken off
+    /// them and recorded as failures, so the backup set closes as partial. They are handed to
+    /// the next run like deferred files. Returns how many were given up on.
+    pub fn wait_for_queue_drain(&mut self) -> usize {
+        let timeout = Duration::from_secs(DRAIN_TIMEOUT_SECS);
+        let poll = Duration::from_secs(1);
+        // files only leave the queues once indexed or given up on, so a new low is progress
+        let mut least = self.queued();
+        let mut idle_since = Instant::now();
+        loop {
+            if self.pre_send_queue.wait_timeout(poll) && self.send_queue.wait_timeout(poll) &&
+               self.sent_queue.wait_timeout(poll) && self.queued() == 0 {
+                return 0;
+            }
+            let queued = self.queued();
+            if queued < least || self.pause.is_paused() {
+                least = cmp::min(least, queued);
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= timeout {
+                break;
+            }
+        }
 
-        let pre_send_len = self.pre_send_queue.len();
-        let send_queue_len = self.send_queue.len();
-        let sent_queue_len = self.sent_queue.len();
+        let mut stuck: Vec<String> = vec![];
+        stuck.extend(self.pre_send_queue
+            .take_pending()
+            .iter()
+            .map(|n| n.path().to_string()));
+        stuck.extend(self.send_queue
+            .take_pending()
+            .iter()
+            .map(|r| r.node().path().to_string()));
+        stuck.extend(self.sent_queue
+            .take_pending()
+            .iter()
+            .map(|n| n.path().to_string()));
+
+        let in_progress = self.pre_send_queue.in_progress() + self.send_queue.in_progress() +
+                          self.sent_queue.in_progress();
+        if in_progress > 0 {
+            warn!("{} file(s) still being worked on after waiting for the queues",
+                  in_progress);
+        }
 
-        if pre_send_len + send_queue_len + sent_queue_len > 0 {
-            let queue_stats = format!("{}/{}/{}", pre_send_len, send_queue_len, sent_queue_len);
-            error!("Items still in queue: {}", queue_stats);
-            panic!("Items still in queue");
+        if !stuck.is_empty() {
+            let queue_stats = format!("{}/{}/{}",
+                                      self.pre_send_queue.len(),
+                                      self.send_queue.len(),
+                                      self.sent_queue.len());
+            error!("Gave up on {} queued file(s), still in queue: {}",
+                   stuck.len(),
+                   queue_stats);
+            for path in &stuck {
+                self.failures.record(FailureKind::Timeout,
+                                     path,
+                                     "Still queued when the backup set closed");
+                // streams cannot be read again, and remote files are fetched by full scans
+                if !stream::is_reserved(path) && self.config.remote().is_none() {
+                    self.deferred.insert(Change::new(Path::new(self.config.path()).join(path)));
+                }
+            }
         }
+        stuck.len()
+    }
 
+    /// Files on the queues, waiting or being worked on.
+    fn queued(&self) -> u64 {
+        self.pre_send_queue.len() + self.send_queue.len() + self.sent_queue.len()
     }
 
     pub fn scan(&mut self, backup_set: u64) -> StdResult<(), Box<StdError>> {
         info!("Beginning full scan");
+        self.create_snapshot()?;
+        self.ignores.clear();
+        let scanned = match self.config.remote().cloned() {
+            Some(remote) => self.scan_remote(backup_set, &remote),
+            None => self.scan_roots(backup_set),
+        };
+        if scanned.is_ok() {
+            self.capture_generated(backup_set);
+        }
+        self.wait_for_queue_drain();
+        self.release_snapshot();
+        scanned?;
+        info!("Full scan complete");
+        Ok(())
+    }
+
+    /// Take the configured snapshot, if any, and read from it until `release_snapshot`.
+    fn create_snapshot(&mut self) -> StdResult<(), Box<StdError>> {
+        let snapshot = match self.config.snapshot() {
+            Some(snapshot) => snapshot.clone(),
+            None => return Ok(()),
+        };
+        info!("Creating snapshot at {}", snapshot.path());
+        snapshot.create()
+            .map_err(|e| DefaultEngineError::Other(format!("Unable to create snapshot: {}", e)))?;
+        self.read_root.set_snapshot(Some(snapshot.path().to_string()));
+        Ok(())
+    }
+
+    /// Go back to reading the backup path and release the snapshot. Only called once the queues
+    /// have drained, so nothing is still reading from it.
+    fn release_snapshot(&mut self) {
+        if self.read_root.snapshot().is_none() {
+            return;
+        }
+        self.read_root.set_snapshot(None);
+        if let Some(snapshot) = self.config.snapshot() {
+            info!("Releasing snapshot at {}", snapshot.path());
+            if let Err(e) = snapshot.release() {
+                error!("Unable to release snapshot at {}: {}", snapshot.path(), e);
+            }
+        }
+    }
+
+    /// The excluded paths, moved under the snapshot while reading from one so they still
+    /// match what is read.
+    fn read_excludes(&self) -> HashSet<String> {
+        self.excludes.iter().map(|e| self.read_root.rebase(e)).collect()
+    }
+
+    /// Where each backup root is under `base`, the backup path or a snapshot of it. Just `base`
+    /// when no roots are configured.
+    fn roots_under(&self, base: &str) -> Vec<String> {
+        if self.config.roots().is_empty() {
+            return vec![base.to_string()];
+        }
+        let path = Path::new(self.config.path());
+        self.config
+            .roots()
+            .iter()
+            .map(|root| match Path::new(root).strip_prefix(path) {
+                Ok(key) if key.as_os_str().is_empty() => base.to_string(),
+                Ok(key) => Path::new(base).join(key).to_str().expect("UTF-8 root").to_string(),
+                Err(_) => root.clone(),
+            })
+            .collect()
+    }
+
+    /// Scan every backup root into `backup_set`, along with the directories leading to it.
+    fn scan_roots(&mut self, backup_set: u64) -> StdResult<(), Box<StdError>> {
+        let base = self.read_root.path();
+        for root in self.roots_under(&base) {
+            let mut dirs = vec![];
+            {
+                let mut dir = Path::new(&root);
+                while dir != Path::new(&base) {
+                    dirs.push(dir.to_path_buf());
+                    dir = match dir.parent() {
+                        Some(parent) => parent,
+                        None => break,
+                    };
+                }
+            }
+            for dir in dirs.into_iter().rev() {
+                self.process_change(backup_set, Change::new(dir))?;
+            }
+            self.scan_path(backup_set, &root)?;
+        }
+        self.delete_dropped_roots(backup_set)
+    }
+
+    /// Record whatever is beside the directories leading to the backup roots as deleted, such
+    /// as a root since dropped from `paths:`. Nothing else scans those directories, so their
+    /// other entries would otherwise stay live.
+    fn delete_dropped_roots(&mut self, backup_set: u64) -> StdResult<(), Box<StdError>> {
+        if self.config.roots().is_empty() {
+            return Ok(());
+        }
+        let path = self.config.path().to_string();
+        let roots: Vec<String> = self.config.roots().iter().map(|r| get_key(&path, r)).collect();
+        let mut leading = HashSet::new();
+        leading.insert(String::new());
+        for root in &roots {
+            let mut dir = Path::new(root.as_str());
+            while let Some(parent) = dir.parent() {
+                leading.insert(parent.to_str().expect("UTF-8 root").to_string());
+                dir = parent;
+            }
+        }
+
+        // directories inside a root are scanned with it
+        let mut dirs: Vec<String> = leading.iter()
+            .filter(|dir| !roots.iter().any(|root| key_holds(root, dir)))
+            .cloned()
+            .collect();
+        dirs.sort();
+        for dir in dirs {
+            for node in self.index.list(dir, None)? {
+                let key = node.path().to_string();
+                if node.deleted() || leading.contains(&key) ||
+                   roots.iter().any(|root| key_holds(root, &key)) ||
+                   key == GENERATED_DIR || key == VIRTUAL_DIR {
+                    continue;
+                }
+                info!("{} is no longer under a backup root", key);
+                self.delete_subtree(backup_set, node)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record `node`, and everything still live under it, as deleted in `backup_set`.
+    fn delete_subtree(&mut self, backup_set: u64, node: Node) -> StdResult<(), Box<StdError>> {
+        if node.is_dir() {
+            for child in self.index.list(node.path().to_string(), None)? {
+                if !child.deleted() {
+                    self.delete_subtree(backup_set, child)?;
+                }
+            }
+        }
+        debug!("Deleting {}", node.path());
+        self.run_stats.update(|s| s.deleted += 1);
+        self.index.insert(node.as_deleted().with_backup_set(backup_set))?;
+        Ok(())
+    }
+
+    /// Scan the subtree at `root` into `backup_set`.
+    pub fn scan_path(&mut self, backup_set: u64, root: &str) -> StdResult<(), Box<StdError>> {
+        priority::lower_current_thread(self.config.scan_nice(), self.config.scan_idle_io());
 
         use std::collections::VecDeque;
-        use std::fs::read_dir;
         use std::fs::DirEntry;
+        use std::io::Result as IoResult;
+
+        let started = Instant::now();
+        let base = self.read_root.path();
+        let root_key = get_key(&base, root);
+
+        // directories unchanged since their last complete scan are skipped, whole subtrees
+        // at a time
+        let (mut tree_hasher, stored_hashes) = if self.config.tree_hashes() {
+            let excludes = self.read_excludes();
+            let globs = self.config.excludes().to_vec();
+            let includes = self.config.includes().to_vec();
+            let ignores = self.ignores.clone();
+            let skip_base = base.clone();
+            let skipped = move |path: &Path| {
+                let change = Change::new(path.to_path_buf());
+                is_excluded(&excludes, &globs, &change, &skip_base) ||
+                ignores.is_ignored(path, &skip_base) ||
+                !is_included(&includes, &change, &skip_base)
+            };
+            let rules = self.scan_rules();
+            (Some(TreeHasher::new(&base, rules.as_bytes(), skipped)), self.index.tree_hashes()?)
+        } else {
+            (None, HashMap::new())
+        };
+        // directories holding files deferred to a later run, whose tree hashes must not be
+        // stored
+        let mut deferred_dirs = HashSet::new();
 
         let mut queue = VecDeque::new();
-        queue.push_back(self.config.path().to_string());
+        queue.push_back(root.to_string());
 
         while let Some(p) = queue.pop_front() {
+            if let Some(ref mut tree_hasher) = tree_hasher {
+                let live = tree_hasher.hash(Path::new(&p));
+                if live.is_some() && live.as_ref() == stored_hashes.get(&get_key(&base, &p)) {
+                    debug!("Skipping unchanged {:?}", p);
+                    continue;
+                }
+            }
             debug!("Scanning {:?}", p);
 
-            let mut ls: Vec<DirEntry> = vec![];
-            for entry in read_dir(&p)? {
-                ls.push(entry?);
-            }
-            let known_nodes = self.index.list(get_key(self.config.path(), &p), None)?;
+            // known nodes not seen on disk by the end of the listing have been deleted
+            let mut known_nodes: HashSet<String> = self.index
+                .list(get_key(&base, &p), None)?
+                .into_iter()
+                .map(|n| n.path().to_string())
+                .collect();
+
+            // entries are streamed, unless they have to be ordered so that the most important
+            // subtrees are queued for upload first
+            let entries: Box<Iterator<Item = IoResult<DirEntry>>> = if self.config
+                .priorities()
+                .is_empty() {
+                box read_dir(&p)?
+            } else {
+                let priorities = self.config.priorities();
+                let mut ls = vec![];
+                for entry in read_dir(&p)? {
+                    ls.push(entry?);
+                }
+                ls.sort_by_key(|entry| {
+                    subtree_priority(priorities, &get_key(&base, entry.path().to_str().unwrap()))
+                });
+                box ls.into_iter().map(Ok)
+            };
 
             // process each item that exists
-            for entry in &ls {
+            let mut dirs = vec![];
+            for entry in entries {
+                let entry = entry?;
+                if self.cancel.is_cancelled() {
+                    self.wait_for_queue_drain();
+                    return Err(box DefaultEngineError::Cancelled);
+                }
+
+                let entry_path = entry.path();
+                known_nodes.remove(&get_key(&base, entry_path.to_str().unwrap()));
 
                 let ftype = entry.file_type()?;
                 if ftype.is_symlink() {
                     // TODO handle symlinks
                     debug!("Skipping symlink {:?}", entry.file_name());
+                    let key = get_key(&base, entry_path.to_str().unwrap());
+                    self.run_stats.skipped(&key, SkipReason::Symlink, None);
                     continue;
                 }
 
-                let entry_path = entry.path();
-
+                let deferred = self.deferred.len();
                 self.process_change(backup_set, Change::new(entry_path.clone()))?;
+                if self.deferred.len() > deferred {
+                    deferred_dirs.insert(get_key(&base, &p));
+                }
+                self.checkpoint_if_due()?;
 
                 if entry_path.is_dir() {
                     debug!("Scan dir  {:?}", entry_path);
-                    queue.push_front(entry_path.to_str().unwrap().to_string());
-                }
-
-            }
-
-            // check each item we know about still exists
-            // i.e. check for deleted ndoes
-            debug!("known_nodes.len={}", known_nodes.len());
-            for known_node in known_nodes {
-                debug!("Checking {}", known_node.path());
-                let mut found = false;
-                let mut found_at = 0;
-                for i in 0..ls.len() {
-                    let entry = &ls.get(i).unwrap();
-                    let entry_key = get_key(self.config.path(), entry.path().to_str().unwrap());
-                    // debug!("Compare {} and {:?}", known_node.path, entry_key);
-                    if known_node.path() == entry_key {
-                        found = true;
-                        found_at = i;
-                        break;
-                    }
+                    dirs.push(entry_path.to_str().unwrap().to_string());
                 }
-                if found {
-                    // remove from search list to speed up iteration
-                    let removed = ls.remove(found_at);
-                    assert_eq!(&get_key(self.config.path(), removed.path().to_str().unwrap()),
-                               known_node.path());
-                } else {
-                    debug!("Found node no longer on disk: {}", known_node.path());
-                    let mut change_path = PathBuf::new();
-                    change_path.push(self.config.path());
-                    change_path.push(&known_node.path());
-                    self.process_change(backup_set, Change::new(change_path))?;
+
+            }
+            // depth first, in the order listed
+            for dir in dirs.into_iter().rev() {
+                queue.push_front(dir);
+            }
+
+            // whatever we know about that was not listed has been deleted
+            let mut deleted: Vec<String> = known_nodes.into_iter().collect();
+            deleted.sort();
+            debug!("deleted nodes={}", deleted.len());
+            for path in deleted {
+                if path == GENERATED_DIR || path == VIRTUAL_DIR {
+                    continue;
                 }
+                debug!("Found node no longer on disk: {}", path);
+                let mut change_path = PathBuf::new();
+                change_path.push(&base);
+                change_path.push(&path);
+                self.process_change(backup_set, Change::new(change_path))?;
             }
 
         }
 
+        self.run_stats.add_scan_time(started.elapsed());
         self.wait_for_queue_drain();
-        info!("Full scan complete");
+
+        // a directory holding a file that failed or was deferred has to be scanned again next
+        // time, and so has every directory above it
+        if let Some(tree_hasher) = tree_hasher {
+            if self.failures.is_empty() {
+                let mut hashes = tree_hasher.into_hashes();
+                hashes.retain(|key, _| !deferred_dirs.iter().any(|dir| key_holds(key, dir)));
+                self.index.set_tree_hashes(&root_key, &hashes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured rules picking what scans back up, for tree hashes to change with.
+    fn scan_rules(&self) -> String {
+        let mut excludes: Vec<&String> = self.excludes.iter().collect();
+        excludes.sort();
+        let globs = |globs: &[Glob]| globs.iter().map(|g| g.as_str()).collect::<Vec<_>>();
+        format!("excludes={:?} exclude_globs={:?} includes={:?}",
+                excludes,
+                globs(self.config.excludes()),
+                globs(self.config.includes()))
+    }
+
+    /// How the files under the backup path differ from the latest backup. Files whose size and
+    /// mtime match their backup are taken as unchanged, unless `hash` asks for their content to
+    /// be hashed and checked.
+    pub fn compare_live(&mut self, hash: bool) -> StdResult<Vec<DriftEntry>, Box<StdError>> {
+        let base = self.live_root("compare")?;
+        let live = self.live_files(&base)?;
+        let generated = format!("{}/", GENERATED_DIR);
+        let virtual_prefix = format!("{}/", VIRTUAL_DIR);
+        let stored: Vec<Node> = self.index
+            .files(None)?
+            .into_iter()
+            .filter(|n| !n.path().starts_with(&generated) && !n.path().starts_with(&virtual_prefix))
+            .collect();
+
+        let filters = self.config.filters();
+        Ok(compare(&stored, &live, &mut |live, stored| {
+            // filtered content is stored at its filtered size
+            if stored.filter().is_none() && live.size() != stored.size() {
+                return true;
+            }
+            if !hash {
+                return live.mtime() != stored.mtime();
+            }
+            match content_hash(&base, stored, filters) {
+                Ok(content) => Some(content) != *stored.hash(),
+                Err(e) => {
+                    warn!("Unable to hash {}: {}", stored.path(), e);
+                    true
+                }
+            }
+        }))
+    }
+
+    /// The absolute backup path, for `command`s that read it directly rather than scan it.
+    fn live_root(&self, command: &str) -> StdResult<String, Box<StdError>> {
+        if self.config.remote().is_some() {
+            return Err(format!("{} needs a local backup path, not a remote source", command)
+                .into());
+        }
+        let root = PathBuf::from(self.config.path()).canonicalize()?;
+        Ok(root.to_str().expect("UTF-8 backup path").to_string())
+    }
+
+    /// Every file under `root` a scan would back up, keyed as in the index.
+    fn live_files(&self, root: &str) -> StdResult<Vec<Node>, Box<StdError>> {
+        let mut files = vec![];
+        self.walk_live(root,
+                       &mut |entry| if let LiveEntry::File(node) = entry {
+                           files.push(node);
+                       })?;
+        Ok(files)
+    }
+
+    /// Walk the backup roots under `root` as a scan would, without reading any content.
+    /// Symlinks are skipped as scans skip them.
+    fn walk_live(&self, root: &str, f: &mut FnMut(LiveEntry)) -> StdResult<(), Box<StdError>> {
+        let mut queue: Vec<PathBuf> =
+            self.roots_under(root).into_iter().map(PathBuf::from).collect();
+        while let Some(dir) = queue.pop() {
+            for entry in read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let change = Change::new(path.clone());
+                if is_excluded(&self.excludes, self.config.excludes(), &change, root) ||
+                   self.ignores.is_ignored(&path, root) ||
+                   !is_included(self.config.includes(), &change, root) ||
+                   is_transient(self.config.transient_patterns(), &change) {
+                    f(LiveEntry::Excluded);
+                    continue;
+                }
+                let ftype = entry.file_type()?;
+                if ftype.is_dir() {
+                    f(LiveEntry::Dir);
+                    queue.push(path);
+                } else if ftype.is_file() {
+                    let metadata = entry.metadata()?;
+                    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+                    f(LiveEntry::File(Node::new_file(get_key(root, path.to_str().unwrap()),
+                                                     Timespec::new(mtime as i64, 0),
+                                                     metadata.len(),
+                                                     metadata.permissions().mode())));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Scan the backup path without uploading anything, to see what a first backup would hold.
+    pub fn estimate_scan(&self) -> StdResult<ScanEstimate, Box<StdError>> {
+        let root = self.live_root("estimate")?;
+        let max_file_size = self.config.max_file_size();
+        let mut estimate = ScanEstimate::default();
+        self.walk_live(&root, &mut |entry| match entry {
+                LiveEntry::Excluded => estimate.excluded += 1,
+                LiveEntry::Dir => estimate.dirs += 1,
+                LiveEntry::File(node) => {
+                    if max_file_size.map(|max| node.size() > max).unwrap_or(false) {
+                        estimate.over_limit += 1;
+                        estimate.over_limit_bytes += node.size();
+                    } else {
+                        estimate.files += 1;
+                        estimate.bytes += node.size();
+                    }
+                }
+            })?;
+        Ok(estimate)
+    }
+
+    /// Scan `remote` over SSH into `backup_set`. New and changed files are fetched whole and
+    /// queued straight for the send workers.
+    fn scan_remote(&mut self,
+                   backup_set: u64,
+                   remote: &RemoteSource)
+                   -> StdResult<(), Box<StdError>> {
+        info!("Scanning {}:{}", remote.host(), remote.path());
+        let started = Instant::now();
+        let entries = remote.scan()?;
+
+        let mut dirs = vec!["".to_string()];
+        let mut seen = HashSet::new();
+        for entry in entries {
+            if self.cancel.is_cancelled() {
+                return Err(box DefaultEngineError::Cancelled);
+            }
+            let key = entry.path().to_string();
+            seen.insert(key.clone());
+            if stream::is_reserved(&key) {
+                warn!("Skipping {}, the name is reserved by haumaru", key);
+                self.run_stats.skipped(&key, SkipReason::Reserved, Some(entry.size()));
+                continue;
+            }
+            self.run_stats.update(|s| s.scanned += 1);
+
+            let existing = self.index
+                .get(key.clone(), None)
+                .map_err(|e| DefaultEngineError::Index(box e))?;
+
+            if entry.is_dir() {
+                dirs.push(key);
+                let changed = match existing {
+                    Some(ref e) => !e.is_dir() || e.mode() != entry.mode(),
+                    None => true,
+                };
+                if changed {
+                    self.index
+                        .insert(entry.with_backup_set(backup_set))
+                        .map_err(|e| DefaultEngineError::Index(box e))?;
+                }
+                continue;
+            }
+
+            let unchanged = match existing {
+                Some(ref e) => {
+                    e.is_file() && e.size() == entry.size() && e.mtime() == entry.mtime() &&
+                    e.mode() == entry.mode()
+                }
+                None => false,
+            };
+            if unchanged {
+                continue;
+            }
+            if let Some(size) = self.config.max_file_size() {
+                if entry.size() > size {
+                    debug!("Skipping large file {}", key);
+                    self.run_stats.skipped(&key, SkipReason::OverSize, Some(entry.size()));
+                    continue;
+                }
+            }
+            // not indexed, so the next scan finds it again
+            if too_young(&entry, self.config.min_file_age()) {
+                debug!("Deferring {}, modified within min_file_age", key);
+                continue;
+            }
+
+            if existing.is_some() {
+                info!("{}:{} . {}", remote.host(), remote.path(), key);
+                self.run_stats.update(|s| s.changed += 1);
+            } else {
+                info!("{}:{} + {}", remote.host(), remote.path(), key);
+                self.run_stats.update(|s| s.new += 1);
+            }
+            let content = match remote.read(&key) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("Failed reading {} from {}: {}", key, remote.host(), e);
+                    self.failures.record(FailureKind::Read, &key, &format!("{}", e));
+                    continue;
+                }
+            };
+            self.queue_content(backup_set, key, content, *entry.mtime(), entry.mode());
+        }
+
+        // anything indexed in a listed directory that is no longer there was deleted
+        for dir in dirs {
+            let known_nodes = self.index
+                .list(dir, None)
+                .map_err(|e| DefaultEngineError::Index(box e))?;
+            for known in known_nodes {
+                if known.deleted() || seen.contains(known.path()) ||
+                   known.path() == GENERATED_DIR || known.path() == VIRTUAL_DIR {
+                    continue;
+                }
+                info!("{}:{} - {}", remote.host(), remote.path(), known.path());
+                self.run_stats.update(|s| s.deleted += 1);
+                self.index
+                    .insert(known.as_deleted().with_backup_set(backup_set))
+                    .map_err(|e| DefaultEngineError::Index(box e))?;
+            }
+        }
+
+        self.run_stats.add_scan_time(started.elapsed());
         Ok(())
     }
 
+    /// Run each generated source and queue its output for `backup_set`. Sources that fail are
+    /// recorded as read failures.
+    fn capture_generated(&mut self, backup_set: u64) {
+        let sources = self.config.generated_sources().to_vec();
+        if sources.is_empty() {
+            return;
+        }
+
+        let now = now_utc().to_timespec();
+        let dir = Node::new_dir(GENERATED_DIR, now, 0o700).with_backup_set(backup_set);
+        if let Err(e) = self.index.insert(dir) {
+            error!("Failed to add {}: {}", GENERATED_DIR, e);
+            return;
+        }
+
+        for source in sources {
+            info!("Capturing generated source {}", source.name());
+            let content = match source.capture() {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("Failed to capture {}: {}", source.name(), e);
+                    self.failures.record(FailureKind::Read, &source.key(), &format!("{}", e));
+                    continue;
+                }
+            };
+
+            self.queue_content(backup_set, source.key(), content, now, 0o600);
+        }
+    }
+
+    /// Hash `content` and queue it to be stored as the file `key` in `backup_set`. There is
+    /// nothing to read from disk, so it goes straight to the send workers.
+    fn queue_content(&mut self,
+                     backup_set: u64,
+                     key: String,
+                     content: Vec<u8>,
+                     mtime: Timespec,
+                     mode: u32)
+                     -> Node {
+        let mut hasher = Hasher::with_algorithm(self.config.hash_algorithm());
+        hasher.write_all(&content).expect("hash content");
+        let (md5, hash) = hasher.result();
+
+        let size = content.len() as u64;
+        let mut node = Node::new_file(key, mtime, size, mode)
+            .with_backup_set(backup_set)
+            .with_hash_algorithm(hasher.algorithm());
+        node.set_hash(hash.clone());
+
+        let compression = self.config.compression();
+        match SendRequest::for_content(compression, md5, hash, node.clone(), content) {
+            Ok(req) => self.queue_send(req),
+            Err(e) => error!("Failed to compress {}: {}", node.path(), e),
+        }
+        node
+    }
+
+    /// Queue `req` to be stored, then recorded in the open backup set.
+    fn queue_send(&mut self, req: SendRequest) {
+        let size = req.node().size();
+        self.progress.queued(size);
+        self.events.send(Event::FileQueued {
+            path: req.node().path().to_string(),
+            bytes: size,
+        });
+        self.send_queue.push(req);
+    }
+
+    /// Store everything read from `input` as the file `key`, as `put` does. The stream is
+    /// copied to `spool` as it is read, and compressed into `body`, so it is never held in
+    /// memory whatever its size.
+    fn put_spooled(&mut self,
+                   key: String,
+                   current: bool,
+                   input: &mut Read,
+                   spool: &Path,
+                   body: &Path)
+                   -> StdResult<(), Box<StdError>> {
+        let mut hasher = Hasher::with_algorithm(self.config.hash_algorithm());
+        let mut size = 0;
+        {
+            let mut file = File::create(spool)?;
+            let mut buf = [0; 64 * 1024];
+            loop {
+                let n = input.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.write_all(&buf[..n])?;
+                file.write_all(&buf[..n])?;
+                size += n as u64;
+            }
+        }
+        let (md5, hash) = hasher.result();
+
+        let encoded = encode_file(self.config.compression(), spool, size, body)?;
+        let (stored, md5) = if encoded {
+            let mut digest = Md5::default();
+            let mut file = File::open(body)?;
+            let mut buf = [0; 64 * 1024];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                digest.input(&buf[..n]);
+            }
+            (body, digest.result().to_vec())
+        } else {
+            (spool, md5)
+        };
+        let stored_size = metadata(stored)?.len();
+
+        let run_lock = self.run_lock.clone();
+        let _running = run_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let now = now_utc().to_timespec();
+        // the latest set is closed, so it is opened again for the write
+        let latest = self.index.latest_backup_set()?;
+        let (backup_set, opened) = match latest {
+            Some((backup_set, _)) if current => {
+                self.index.reopen_backup_set(backup_set)?;
+                (backup_set, false)
+            }
+            _ => (self.open_backup_set(now.sec)?, true),
+        };
+        info!("Storing {} bytes as {} in backup set {}", size, key, backup_set);
+
+        // so the key can be listed and restored like any other file
+        for dir in stream::parents(&key) {
+            self.index.insert(Node::new_dir(dir, now, 0o700).with_backup_set(backup_set))?;
+        }
+        let mut node = Node::new_file(key.clone(), now, size, 0o600)
+            .with_backup_set(backup_set)
+            .with_hash_algorithm(hasher.algorithm());
+        node.set_hash(hash.clone());
+        let file = File::open(stored)?;
+        self.queue_send(SendRequest::for_file(md5, hash, node.clone(), file, stored_size, encoded));
+        self.wait_for_queue_drain();
+        self.index.close_backup_set()?;
+        if opened {
+            self.backup_set_closed(backup_set, now.sec);
+        }
+
+        let stored = self.index.get(key.clone(), None)?;
+        match stored {
+            Some(ref stored) if stored.hash() == node.hash() => Ok(()),
+            _ => Err(box DefaultEngineError::Other(format!("Failed to store {}", key))),
+        }
+    }
+
+    /// Walk the roots that could not be watched, standing in for the missing watcher events.
+    /// Only paths that differ from the index are returned, so a run with nothing else to do
+    /// opens no backup set for them.
+    pub fn unwatched_changes(&mut self) -> StdResult<Vec<Change>, Box<StdError>> {
+        let base = self.read_root.path();
+        let excludes = self.read_excludes();
+        let mut queue = self.backup_path().unwatched().to_vec();
+        let mut changes = vec![];
+        while let Some(dir) = queue.pop() {
+            self.cancel.check()?;
+            debug!("Scanning unwatched {:?}", dir);
+
+            // known nodes not seen on disk by the end of the listing have been deleted
+            let mut known_nodes: HashSet<String> = self.index
+                .list(get_key(&base, dir.to_str().unwrap()), None)?
+                .into_iter()
+                .map(|n| n.path().to_string())
+                .collect();
+
+            for entry in read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                known_nodes.remove(&get_key(&base, path.to_str().unwrap()));
+                let change = Change::new(path.clone());
+                if entry.file_type()?.is_symlink() ||
+                   is_excluded(&excludes, self.config.excludes(), &change, &base) ||
+                   self.ignores.is_ignored(&path, &base) ||
+                   !is_included(self.config.includes(), &change, &base) {
+                    continue;
+                }
+                if path.is_dir() {
+                    queue.push(path);
+                }
+                if self.differs(&change, &base)? {
+                    changes.push(change);
+                }
+            }
+
+            for path in known_nodes {
+                if path != GENERATED_DIR && path != VIRTUAL_DIR {
+                    changes.push(Change::new(Path::new(&base).join(path)));
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Would `process_change` record anything for `change`: is its path new, gone, or
+    /// different from its backup in kind, size, mtime, mode or owner.
+    fn differs(&mut self, change: &Change, base: &str) -> StdResult<bool, Box<StdError>> {
+        let key = get_key(base, change.path().to_str().unwrap());
+        if stream::is_reserved(&key) {
+            return Ok(false);
+        }
+        let node = self.index.get(key, None)?;
+        let file = self.backup_path()
+            .get_file(change.path())
+            .map_err(|e| DefaultEngineError::GetFile(e))?;
+        Ok(match (node, file) {
+            (None, None) => false,
+            (Some(_), None) => true,
+            (None, Some(file)) => {
+                self.config.max_file_size().map(|max| file.size() <= max).unwrap_or(true)
+            }
+            (Some(node), Some(file)) => {
+                node.kind() != file.kind() ||
+                !file.is_dir() && (file.size() != node.size() || file.mtime() != node.mtime()) ||
+                file.mode() != node.mode() || file.owner() != node.owner()
+            }
+        })
+    }
+
     fn process_change(&mut self, backup_set: u64, change: Change) -> StdResult<(), Box<StdError>> {
-        if is_excluded(&self.excludes, &change, self.config.path()) {
+        let base = self.read_root.path();
+        let excludes = self.read_excludes();
+        if is_excluded(&excludes, self.config.excludes(), &change, &base) {
             trace!("Skipping excluded path: {:?}", change.path());
+            let working = self.config.abs_working();
+            let own = change.path().starts_with(self.read_root.rebase(working.to_str().unwrap()));
+            if !own && is_excluded_top(&excludes, self.config.excludes(), &change, &base) {
+                let key = get_key(&base, change.path().to_str().unwrap());
+                self.run_stats.skipped(&key, SkipReason::Excluded, None);
+            }
+            return Ok(());
+        }
+        if self.ignores.is_ignored(change.path(), &base) {
+            trace!("Skipping ignored path: {:?}", change.path());
+            return Ok(());
+        }
+        if !is_included(self.config.includes(), &change, &base) {
+            trace!("Skipping path not included: {:?}", change.path());
             return Ok(());
         }
 
         debug!("Received {:?}", change);
+        self.run_stats.update(|s| s.scanned += 1);
 
-        let change_path_str = change.path().to_str().unwrap();
-        let key = get_key(self.config.path(), change_path_str);
+        let key = get_key(&base, change.path().to_str().unwrap());
         debug!("Change key = {}", key);
+        if stream::is_reserved(&key) {
+            warn!("Skipping {}, the name is reserved by haumaru", key);
+            self.run_stats.skipped(&key, SkipReason::Reserved, None);
+            return Ok(());
+        }
 
         let node = self.index
             .get(key.clone(), None)
             .map_err(|e| DefaultEngineError::Index(box e))?;
-        let file = self.backup_path()
-            .get_file(change.path())
+        let file = match self.read_root.snapshot() {
+                Some(snapshot) => self.backup_path().get_file_at(&snapshot, change.path()),
+                None => self.backup_path().get_file(change.path()),
+            }
             .map_err(|e| DefaultEngineError::GetFile(e))?;
 
         let queue_stats = format!("{}/{}/{}",
@@ -289,6 +2057,8 @@ impl<I, S> DefaultEngine<I, S>
                     }
                     Some(existing_node) => {
                         info!("{} - {}", queue_stats, key);
+                        metrics::incr("files.deleted");
+                        self.run_stats.update(|s| s.deleted += 1);
                         debug!("Detected DELETE on {:?}, {:?}", change, existing_node);
                         self.index
                             .insert(existing_node.as_deleted().with_backup_set(backup_set))
@@ -301,20 +2071,97 @@ impl<I, S> DefaultEngine<I, S>
                 if let Some(size) = self.config.max_file_size() {
                     if new_node.size() > size {
                         debug!("Skipping large file {}", key);
+                        self.run_stats.skipped(&key, SkipReason::OverSize, Some(new_node.size()));
                         return Ok(());
                     }
                 }
 
+                if new_node.is_file() && too_young(&new_node, self.config.min_file_age()) {
+                    debug!("Deferring {}, modified within min_file_age", key);
+                    add_change(&mut self.deferred, change);
+                    return Ok(());
+                }
+
                 match node {
+                    None if change.renamed_from().is_some() => {
+                        match self.renamed_node(&change, &new_node)? {
+                            Some(renamed) => {
+                                info!("{} > {}", queue_stats, key);
+                                metrics::incr("files.renamed");
+                                self.run_stats.update(|s| s.changed += 1);
+                                debug!("Detected RENAME on {:?}, {:?}", change, renamed);
+                                self.sent_queue.push(renamed.with_backup_set(backup_set));
+                            }
+                            None => {
+                                info!("{} + {}", queue_stats, key);
+                                metrics::incr("files.new");
+                                self.run_stats.update(|s| s.new += 1);
+                                debug!("Detected NEW (renamed) on {:?}, {:?}", change, new_node);
+                                if let Err(e) =
+                                       self.queue_for_send(new_node.with_backup_set(backup_set)) {
+                                    error!("Failed queuing renamed {}: {}", key, e);
+                                }
+                            }
+                        }
+                    }
                     None => {
                         info!("{} + {}", queue_stats, key);
+                        metrics::incr("files.new");
+                        self.run_stats.update(|s| s.new += 1);
                         debug!("Detected NEW on {:?}, {:?}", change, new_node);
-                        if let Err(e) = self.queue_for_send(new_node.with_backup_set(backup_set)) {
+                        if let Some(linked) = self.linked_node(&new_node)? {
+                            metrics::incr("files.linked");
+                            debug!("{} is a hard link, reusing its recorded content", key);
+                            self.sent_queue.push(linked.with_backup_set(backup_set));
+                        } else if let Err(e) =
+                               self.queue_for_send(new_node.with_backup_set(backup_set)) {
                             error!("Failed queuing new {}: {}", key, e);
                         }
                     }
                     Some(existing_node) => {
 
+                        // attribute events leave content alone whatever the mtime says, unless
+                        // the size shows a write was coalesced into the same event
+                        let content_match = existing_node.kind() == new_node.kind() &&
+                                            (new_node.is_dir() ||
+                                             new_node.size() == existing_node.size() &&
+                                             (change.kind() == ChangeKind::Metadata ||
+                                              new_node.mtime() == existing_node.mtime()));
+
+                        // content unchanged, but permissions, ownership (uid and gid) or, going by
+                        // attribute events, the mtime may have been
+                        if content_match &&
+                           (new_node.mode() != existing_node.mode() ||
+                            new_node.owner() != existing_node.owner() ||
+                            change.kind() == ChangeKind::Metadata &&
+                            new_node.mtime() != existing_node.mtime()) {
+                            info!("{} ~ {}", queue_stats, key);
+                            metrics::incr("files.metadata");
+                            self.run_stats.update(|s| s.changed += 1);
+                            debug!("Detected METADATA ({:?}) on {:?},\n{:?},\n{:?}",
+                                   change.kind(),
+                                   change,
+                                   existing_node,
+                                   new_node);
+                            let mut node = match *existing_node.hash() {
+                                Some(ref hash) => {
+                                    new_node.with_hash(hash.clone())
+                                        .with_hash_algorithm(existing_node.hash_algorithm())
+                                }
+                                None => new_node,
+                            };
+                            node.set_chunks(existing_node.chunks().to_vec());
+                            let node = node.with_backup_set(backup_set);
+                            if node.is_file() {
+                                self.sent_queue.push(node);
+                            } else {
+                                self.index
+                                    .insert(node)
+                                    .map_err(|e| DefaultEngineError::Index(box e))?;
+                            }
+                            return Ok(());
+                        }
+
                         // no need to update directory
                         if existing_node.is_dir() && new_node.is_dir() {
                             debug!("  {} (skipping dir)", key);
@@ -322,18 +2169,24 @@ impl<I, S> DefaultEngine<I, S>
                         }
 
                         // size and mtime match, skip.
-                        if new_node.size() == existing_node.size() &&
-                           new_node.mtime() == existing_node.mtime() {
+                        if content_match {
                             debug!("  {} (assume match)", key);
                             return Ok(());
                         }
 
                         info!("{} . {}", queue_stats, key);
+                        metrics::incr("files.updated");
+                        self.run_stats.update(|s| s.changed += 1);
                         debug!("Detected UPDATE on {:?},\n{:?},\n{:?}",
                                change,
                                existing_node,
                                new_node);
-                        if let Err(e) = self.queue_for_send(new_node.with_backup_set(backup_set)) {
+                        if let Some(linked) = self.linked_node(&new_node)? {
+                            metrics::incr("files.linked");
+                            debug!("{} is a hard link, reusing its recorded content", key);
+                            self.sent_queue.push(linked.with_backup_set(backup_set));
+                        } else if let Err(e) =
+                               self.queue_for_send(new_node.with_backup_set(backup_set)) {
                             error!("Failed queuing updated {}: {}", key, e);
                         }
                     }
@@ -344,11 +2197,72 @@ impl<I, S> DefaultEngine<I, S>
         Ok(())
     }
 
+    /// A renamed file whose size and mtime are unchanged keeps the content hash of its old path,
+    /// so it does not need to be read and sent again.
+    fn renamed_node(&mut self, change: &Change, new_node: &Node) -> Result<Option<Node>> {
+        let from = match change.renamed_from() {
+            Some(from) => from,
+            None => return Ok(None),
+        };
+        if !new_node.is_file() {
+            return Ok(None);
+        }
+
+        let from_key = get_key(&self.read_root.path(), from.to_str().unwrap());
+        let old_node = self.index
+            .get(from_key, None)
+            .map_err(|e| DefaultEngineError::Index(box e))?;
+
+        Ok(old_node.and_then(|old| {
+            if old.is_file() && !old.deleted() && old.size() == new_node.size() &&
+               old.mtime() == new_node.mtime() {
+                old.hash().clone().map(|hash| {
+                    let mut node =
+                        new_node.clone().with_hash(hash).with_hash_algorithm(old.hash_algorithm());
+                    node.set_capability(old.capability().map(|c| c.to_vec()));
+                    node.set_chunks(old.chunks().to_vec());
+                    node
+                })
+            } else {
+                None
+            }
+        }))
+    }
+
+    /// A file with more than one hard link, another of which is already recorded with the same
+    /// size and mtime, keeps the content hash recorded for that link, so the shared content is
+    /// not read and sent again.
+    fn linked_node(&mut self, new_node: &Node) -> Result<Option<Node>> {
+        let inode = match new_node.inode() {
+            Some(inode) if new_node.is_file() => inode,
+            _ => return Ok(None),
+        };
+        let linked = self.index
+            .linked(inode)
+            .map_err(|e| DefaultEngineError::Index(box e))?;
+
+        Ok(linked.into_iter()
+            .find(|link| {
+                link.path() != new_node.path() && link.has_hash() &&
+                link.size() == new_node.size() && link.mtime() == new_node.mtime()
+            })
+            .map(|link| {
+                let mut node = new_node.clone()
+                    .with_hash(link.hash().clone().expect("linked hash"))
+                    .with_hash_algorithm(link.hash_algorithm());
+                node.set_capability(link.capability().map(|c| c.to_vec()));
+                node.set_chunks(link.chunks().to_vec());
+                node
+            }))
+    }
+
     fn restore_node(&mut self,
                     node: Node,
                     node_base: &str,
                     from: Option<Timespec>,
-                    target: &str)
+                    target: &str,
+                    throttle: Option<&Throttle>,
+                    links: &mut RestoredLinks)
                     -> StdResult<(), Box<StdError>> {
 
         debug!("node_base={}", node_base);
@@ -364,17 +2278,42 @@ impl<I, S> DefaultEngine<I, S>
         restore_path.push(target);
         restore_path.push(node_restore_path);
 
+        self.cancel.check()?;
         if node.is_dir() {
             debug!("Creating dir {:?}", restore_path);
-            create_dir_all(restore_path)?;
-            for node in self.index.list(node.path().to_string(), from)? {
-                self.restore_node(node, node_base, from, target)?;
+            create_dir_all(&restore_path)?;
+            restore_owner(&node, &restore_path);
+            for child in self.index.list(node.path().to_string(), from)? {
+                self.restore_node(child, node_base, from, target, throttle, links)?;
             }
+            // after the children, so a read-only dir can still be written into and their
+            // writes do not move its mtime
+            restore_mode_and_mtime(&node, &restore_path);
         } else if node.is_file() {
             let hash = node.hash().as_ref().expect("File must have hash");
+            self.progress.started(node.path());
+            if let Some(filter) = node.filter() {
+                warn!("{} was stored through filter {}, restoring the filtered content",
+                      node.path(),
+                      filter);
+            }
+
+            if let Some(inode) = node.inode() {
+                if let Some(&(ref linked_hash, ref linked_path)) = links.get(&inode) {
+                    if linked_hash == hash {
+                        debug!("Linking {:?} to {:?}", restore_path, linked_path);
+                        if restore_path.exists() {
+                            remove_file(&restore_path)?;
+                        }
+                        hard_link(linked_path, &restore_path)?;
+                        self.progress.done(node.size());
+                        return Ok(());
+                    }
+                }
+            }
 
             debug!("Retrieving hash {}", hash.as_slice().to_hex());
-            let mut ingest = match self.storage.retrieve(hash.as_slice())? {
+            let mut blob = match retrieve_node(&self.storage, &node)? {
                 None => {
                     let msg = format!("Unable to restore {}, hash is missing from storage",
                                       node.path());
@@ -391,12 +2330,41 @@ impl<I, S> DefaultEngine<I, S>
                     let msg = format!("Unable to create file  {}: {}", node.path(), e);
                     box DefaultEngineError::GeneralWithNode(msg, node.clone())
                 })?;
-            copy(&mut ingest, &mut outgest).map_err(|e| {
+            let size = blob.size();
+            let copied = match throttle {
+                Some(throttle) => {
+                    copy(&mut ThrottledRead::new(blob, throttle.clone()), &mut outgest)
+                }
+                None => copy(&mut blob, &mut outgest),
+            };
+            let written = copied.map_err(|e| {
                     DefaultEngineError::GeneralWithNode(format!("Failed writing {}: {}",
                                                                 restore_path_str,
                                                                 e),
                                                         node.clone())
                 })?;
+            if written != size {
+                let msg = format!("Restored {} of {} bytes of {}",
+                                  written,
+                                  size,
+                                  node.path());
+                return Err(box DefaultEngineError::GeneralWithNode(msg, node.clone()));
+            }
+            // writing the file cleared any capabilities it had, and so would a chown
+            drop(outgest);
+            restore_owner(&node, &restore_path);
+            if let Some(cap) = node.capability() {
+                if !can_set_capabilities() {
+                    warn!("Not restoring the capabilities of {}, that needs root", node.path());
+                } else if let Err(e) = set_capability(&restore_path, cap) {
+                    warn!("Unable to restore the capabilities of {}: {}", node.path(), e);
+                }
+            }
+            restore_mode_and_mtime(&node, &restore_path);
+            if let Some(inode) = node.inode() {
+                links.insert(inode, (hash.clone(), restore_path.clone()));
+            }
+            self.progress.done(node.size());
         }
 
         Ok(())
@@ -404,6 +2372,11 @@ impl<I, S> DefaultEngine<I, S>
 
     fn queue_for_send(&mut self, n: Node) -> Result<()> {
         Ok(if n.is_file() {
+            self.progress.queued(n.size());
+            self.events.send(Event::FileQueued {
+                path: n.path().to_string(),
+                bytes: n.size(),
+            });
             self.pre_send_queue.push(n);
         } else {
             self.index.insert(n).map_err(|e| DefaultEngineError::Index(box e))?;
@@ -412,54 +2385,80 @@ impl<I, S> DefaultEngine<I, S>
     }
 }
 
+/// Files restored so far that were recorded with more than one hard link, by device and inode,
+/// with their content hash, so the other links are restored as links to them.
+type RestoredLinks = HashMap<(u64, u64), (Vec<u8>, PathBuf)>;
+
+/// Give the restored `path` back to the owner of `node`, when running as root.
+fn restore_owner(node: &Node, path: &Path) {
+    if let Some((uid, gid)) = node.owner() {
+        if !can_chown() {
+            debug!("Not restoring the owner of {}, that needs root", node.path());
+        } else if let Err(e) = chown(path, uid, gid) {
+            warn!("Unable to restore the owner of {}: {}", node.path(), e);
+        }
+    }
+}
+
+/// Give the restored `path` the permissions and mtime of `node`. The mode goes on after any
+/// chown, which clears setuid and setgid bits.
+fn restore_mode_and_mtime(node: &Node, path: &Path) {
+    if let Err(e) = set_permissions(path, Permissions::from_mode(node.mode() & 0o7777)) {
+        warn!("Unable to restore the mode of {}: {}", node.path(), e);
+    }
+    if let Err(e) = set_mtime(path, *node.mtime()) {
+        warn!("Unable to restore the mtime of {}: {}", node.path(), e);
+    }
+}
+
+/// What `walk_live` finds at each path.
+enum LiveEntry {
+    File(Node),
+    Dir,
+    Excluded,
+}
+
+/// Hash the file on disk behind `stored` the way it was hashed when it was backed up.
+fn content_hash(base: &str,
+                stored: &Node,
+                filters: &[Filter])
+                -> StdResult<Vec<u8>, Box<StdError>> {
+    let mut content = vec![];
+    File::open(Path::new(base).join(stored.path()))?.read_to_end(&mut content)?;
+    if let Some(id) = stored.filter() {
+        match filters.iter().find(|f| f.id() == id) {
+            Some(filter) => content = filter.apply(content)?,
+            None => return Err(format!("Filter {} is no longer configured", id).into()),
+        }
+    }
+    let mut hasher = Hasher::with_algorithm(stored.hash_algorithm());
+    hasher.write_all(&content)?;
+    Ok(hasher.result().1)
+}
+
+/// The `rwxr-xr-x` part of an `ls` mode column. Setuid, setgid and sticky bits show as
+/// `s`/`t` in place of the execute bit they share a column with, or `S`/`T` when that execute
+/// bit is not set.
 pub fn perms_string(mode: u32) -> String {
-    let mut out = Cursor::new(Vec::new());
-    if mode & 2u32.pow(8) == 2u32.pow(8) {
-        write!(out, "r").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(7) == 2u32.pow(7) {
-        write!(out, "w").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(6) == 2u32.pow(6) {
-        write!(out, "x").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(5) == 2u32.pow(5) {
-        write!(out, "r").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(4) == 2u32.pow(4) {
-        write!(out, "w").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(3) == 2u32.pow(3) {
-        write!(out, "x").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(2) == 2u32.pow(2) {
-        write!(out, "r").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(1) == 2u32.pow(1) {
-        write!(out, "w").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    if mode & 2u32.pow(0) == 2u32.pow(0) {
-        write!(out, "x").expect("write");
-    } else {
-        write!(out, "-").expect("write");
-    }
-    String::from_utf8(out.into_inner()).expect("from_utf8")
+    let classes = [(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')];
+    let mut out = String::new();
+    for &(shift, special, set, unset) in classes.iter() {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        out.push(match (mode & special != 0, bits & 0o1 != 0) {
+            (true, true) => set,
+            (true, false) => unset,
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    out
+}
+
+/// The full `ls` mode column of `node`, its type character followed by its permissions.
+pub fn mode_string(node: &Node) -> String {
+    format!("{}{}", node.kind().type_char(), perms_string(node.mode()))
 }
 
 #[test]
@@ -469,9 +2468,27 @@ fn test_perms_string() {
     assert_eq!("rw-r--r--", &perms_string(420));
     assert_eq!("rw-------", &perms_string(384));
     assert_eq!("------rwx", &perms_string(7));
+    assert_eq!("rwsr-xr-x", &perms_string(0o104755));
+    assert_eq!("rwxr-Sr--", &perms_string(0o2744));
+    assert_eq!("rwxrwxrwt", &perms_string(0o41777));
+    assert_eq!("rw-rw-rwT", &perms_string(0o1666));
 }
 
-pub fn is_excluded(excludes: &HashSet<String>, change: &Change, base_path: &str) -> bool {
+#[test]
+fn test_mode_string() {
+    assert_eq!("drwxr-xr-x",
+               mode_string(&Node::new_dir("a", Timespec::new(0, 0), 0o40755)));
+    assert_eq!("-rw-r--r--",
+               mode_string(&Node::new_file("a", Timespec::new(0, 0), 0, 0o100644)));
+}
+
+/// Is the change under one of the absolute `excludes`, or has a key under `base_path` matched
+/// by one of the `globs`.
+pub fn is_excluded(excludes: &HashSet<String>,
+                   globs: &[Glob],
+                   change: &Change,
+                   base_path: &str)
+                   -> bool {
     let change_path_str = change.path().to_str().unwrap();
     for exclude in excludes {
         if change_path_str.starts_with(exclude) {
@@ -481,21 +2498,87 @@ pub fn is_excluded(excludes: &HashSet<String>, change: &Change, base_path: &str)
     if change_path_str == base_path {
         return true;
     }
-    false
+    change.path().starts_with(base_path) &&
+    glob_covers(globs, &get_key(base_path, change_path_str))
+}
+
+/// Is the change under `base_path` picked by the `includes` globs, which pick everything when
+/// there are none. Anything but a file is also picked when it could hold a picked path, so
+/// scans reach it.
+pub fn is_included(includes: &[Glob], change: &Change, base_path: &str) -> bool {
+    if includes.is_empty() || !change.path().starts_with(base_path) ||
+       change.path().to_str() == Some(base_path) {
+        return true;
+    }
+    let key = get_key(base_path, change.path().to_str().unwrap());
+    glob_covers(includes, &key) || (!change.path().is_file() && glob_may_contain(includes, &key))
+}
+
+/// Is the change the top of an excluded tree: excluded itself, but under a parent that is not.
+pub fn is_excluded_top(excludes: &HashSet<String>,
+                       globs: &[Glob],
+                       change: &Change,
+                       base_path: &str)
+                       -> bool {
+    if change.path().to_str() == Some(base_path) ||
+       !is_excluded(excludes, globs, change, base_path) {
+        return false;
+    }
+    match change.path().parent() {
+        Some(parent) if parent.to_str() != Some(base_path) => {
+            !is_excluded(excludes, globs, &Change::new(parent.to_path_buf()), base_path)
+        }
+        _ => true,
+    }
+}
+
+/// Is the index key `key` the directory `dir` or somewhere below it. The empty key is the
+/// backup path, which holds every key.
+pub fn key_holds(dir: &str, key: &str) -> bool {
+    dir.is_empty() || key == dir || key.starts_with(dir) && key[dir.len()..].starts_with('/')
+}
+
+/// Was `node` modified less than `min_age` seconds ago. Times in the future count as old, so
+/// a skewed clock cannot hold a file back forever.
+pub fn too_young(node: &Node, min_age: Option<u32>) -> bool {
+    let age = now_utc().to_timespec().sec - node.mtime().sec;
+    match min_age {
+        Some(min_age) => age >= 0 && age < min_age as i64,
+        None => false,
+    }
+}
+
+/// Is the change to an editor or build tool scratch file.
+pub fn is_transient(patterns: &[Pattern], change: &Change) -> bool {
+    match change.path().file_name().and_then(|n| n.to_str()) {
+        Some(name) => matches_any(patterns, name),
+        None => false,
+    }
+}
+
+/// Write the name, size, mtime and hash of the file `node`.
+pub fn write_file_details(out: &mut Write, node: &Node, times: &TimeStyle) {
+    write!(out, "Name:   {}\n", node.path()).expect("write");
+    write!(out, "Size:   {} bytes\n", node.size()).expect("write");
+    write!(out,
+           "Time:   {}\n",
+           times.show(node.mtime().clone(), TimeFormat::Local))
+        .expect("write");
+    write!(out,
+           "{}: {}\n",
+           node.hash_algorithm().label(),
+           node.hash_string())
+        .expect("write");
+    if node.inconsistent() {
+        write!(out, "Note:   changed while it was read, may be inconsistent\n").expect("write");
+    }
 }
 
-pub fn write_ls_node(out: &mut Write, node: &Node) {
-    let d = match node.is_dir() {
-        true => "d",
-        false => "-",
-    };
-    let mode = perms_string(node.mode());
-    let t = at(node.mtime().clone());
-    let tm = strftime("%b %e %H:%M", &t).expect("mtime format");
+pub fn write_ls_node(out: &mut Write, node: &Node, times: &TimeStyle) {
+    let tm = times.show(node.mtime().clone(), TimeFormat::Short);
     write!(out,
-           "{}{} {}B {} {}\n",
-           d,
-           mode,
+           "{} {}B {} {}\n",
+           mode_string(node),
            node.size(),
            tm,
            node.path())
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Why the engine is paused. The engine runs again once every reason has been cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PauseReason {
+    /// Requested by the user (signal or control socket).
+    User,
+    /// Running on battery below the configured threshold.
+    Battery,
+    /// Connected to a metered network.
+    Metered,
+    /// The repository has reached its configured hard cap.
+    StorageCap,
+    /// Today's `max_daily_upload` has been uploaded.
+    UploadBudget,
+}
+
+impl fmt::Display for PauseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PauseReason::User => write!(f, "user"),
+            PauseReason::Battery => write!(f, "battery"),
+            PauseReason::Metered => write!(f, "metered network"),
+            PauseReason::StorageCap => write!(f, "storage hard cap reached"),
+            PauseReason::UploadBudget => write!(f, "daily upload budget spent"),
+        }
+    }
+}
+
+/// Shared pause state. While paused the send workers stop consuming the send queue and the
+/// backup loop stops opening new backup sets.
+#[derive(Clone)]
+pub struct Pause {
+    state: Arc<(Mutex<HashSet<PauseReason>>, Condvar)>,
+}
+
+impl Pause {
+    pub fn new() -> Self {
+        Pause { state: Arc::new((Mutex::new(HashSet::new()), Condvar::new())) }
+    }
+
+    pub fn pause(&self, reason: PauseReason) {
+        let &(ref reasons, _) = &*self.state;
+        if reasons.lock().expect("pause lock").insert(reason) {
+            info!("Paused ({})", reason);
+        }
+    }
+
+    pub fn resume(&self, reason: PauseReason) {
+        let &(ref reasons, ref cvar) = &*self.state;
+        let mut reasons = reasons.lock().expect("pause lock");
+        if reasons.remove(&reason) {
+            if reasons.is_empty() {
+                info!("Resumed");
+            } else {
+                info!("Cleared pause reason {}, still paused", reason);
+            }
+            cvar.notify_all();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let &(ref reasons, _) = &*self.state;
+        !reasons.lock().expect("pause lock").is_empty()
+    }
+
+    pub fn reasons(&self) -> Vec<PauseReason> {
+        let &(ref reasons, _) = &*self.state;
+        reasons.lock().expect("pause lock").iter().cloned().collect()
+    }
+
+    /// Block until no pause reasons remain.
+    pub fn wait_while_paused(&self) {
+        let &(ref reasons, ref cvar) = &*self.state;
+        let mut reasons = reasons.lock().expect("pause lock");
+        while !reasons.is_empty() {
+            reasons = cvar.wait(reasons).expect("pause cvar");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn pause_and_resume() {
+        let pause = Pause::new();
+        assert!(!pause.is_paused());
+        pause.pause(PauseReason::User);
+        assert!(pause.is_paused());
+        assert_eq!(vec![PauseReason::User], pause.reasons());
+        pause.resume(PauseReason::User);
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn wait_while_paused() {
+        let pause = Pause::new();
+        pause.pause(PauseReason::User);
+
+        let waiter = {
+            let pause = pause.clone();
+            thread::spawn(move || pause.wait_while_paused())
+        };
+
+        pause.resume(PauseReason::User);
+        waiter.join().expect("waiter");
+    }
+}
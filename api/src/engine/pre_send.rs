@@ -1,16 +1,106 @@
-use std::path::PathBuf;
-use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::fs::{File, metadata};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use time::Timespec;
 
-use engine::{EngineConfig, DefaultEngineError};
-use Node;
+use engine::{Attempts, EngineConfig, DefaultEngineError, FailureKind, Failures, MAX_ATTEMPTS,
+             Progress, ReadRoot, RunStats};
+use {Chunk, HashAlgorithm, Node};
+use chunker;
+use compression::Compression;
+use capability::capability;
+use filter::filter_for;
 use queue::Queue;
-use hasher::Hasher;
-use storage::{SendRequest, SendRequestReader};
+use hasher::{Hasher, parallel_hash};
+use priority;
+use storage::{Parts, SendRequest, SendRequestReader};
+use throttle::{Throttle, ThrottledRead};
+
+/// Reads of a file that keeps changing before it is stored as it was last read.
+const READ_ATTEMPTS: u32 = 3;
+
+/// Size and mtime, to tell whether a file changed while it was read.
+fn stat(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = metadata(path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+/// Read `path` with `read`, again if its size or mtime changed while it was read. Returns the
+/// content, the size and mtime from the stat after the last read, and whether it was read
+/// without the file changing.
+fn read_consistent<F>(path: &Path,
+                      mut read: F)
+                      -> Result<(Vec<u8>, (u64, SystemTime), bool), DefaultEngineError>
+    where F: FnMut(&Path) -> Result<Vec<u8>, DefaultEngineError>
+{
+    let stat_of = |path: &Path| {
+        stat(path).map_err(|e| {
+            DefaultEngineError::Storage(format!("Failed to stat {:?}", path), box e)
+        })
+    };
+    let mut attempt = 1;
+    loop {
+        let before = stat_of(path)?;
+        let content = read(path)?;
+        let after = stat_of(path)?;
+        let consistent = before == after && after.0 == content.len() as u64;
+        if consistent || attempt >= READ_ATTEMPTS {
+            return Ok((content, after, consistent));
+        }
+        debug!("{:?} changed while it was read, reading it again", path);
+        attempt += 1;
+        sleep(Duration::from_secs(1));
+    }
+}
+
+/// Queue a request on `outgest` for each content-defined chunk of `content`, stored under its
+/// hash with `algorithm`, as each boundary is found. The send queue holds only a few, so a
+/// large file is not held twice over. Returns the chunks for `node` to record and the bytes queued.
+fn queue_chunks(compression: Option<Compression>,
+                algorithm: HashAlgorithm,
+                node: &Node,
+                content: &[u8],
+                parts: &Parts,
+                outgest: &mut Queue<SendRequest>)
+                -> io::Result<(Vec<Chunk>, u64)> {
+    let mut chunks = vec![];
+    let mut queued = 0;
+    for piece in chunker::chunks(content) {
+        let mut hasher = Hasher::with_algorithm(algorithm);
+        hasher.write_all(piece)?;
+        let (md5, hash) = hasher.result();
+        let size = piece.len() as u64;
+        let part = Node::new_file(node.path(), *node.mtime(), size, node.mode())
+            .with_hash(hash.clone())
+            .with_hash_algorithm(algorithm);
+        let req = SendRequest::for_content(compression, md5, hash.clone(), part, piece.to_vec())?
+            .with_part_of(parts.clone());
+        queued += req.size();
+        parts.add();
+        outgest.push(req);
+        chunks.push(Chunk {
+            algorithm: algorithm,
+            hash: hash,
+            size: size,
+        });
+    }
+    Ok((chunks, queued))
+}
 
 pub struct PreSendWorker {
     config: EngineConfig,
     ingest: Queue<Node>,
     outgest: Queue<SendRequest>,
+    throttle: Option<Throttle>,
+    failures: Option<Failures>,
+    progress: Option<Progress>,
+    run_stats: Option<RunStats>,
+    read_root: Option<ReadRoot>,
+    attempts: Attempts,
 }
 
 impl PreSendWorker {
@@ -19,25 +109,117 @@ impl PreSendWorker {
             config: config,
             ingest: ingest,
             outgest: outgest,
+            throttle: None,
+            failures: None,
+            progress: None,
+            run_stats: None,
+            read_root: None,
+            attempts: Attempts::new(),
         }
     }
+    /// Share `throttle` for reading file content.
+    pub fn with_throttle(mut self, throttle: Option<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+    /// Record files that cannot be read in `failures`.
+    pub fn with_failures(mut self, failures: Failures) -> Self {
+        self.failures = Some(failures);
+        self
+    }
+    /// Report files as they are read, and as done when they cannot be.
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+    /// Count the bytes read, and the time taken, into `run_stats`.
+    pub fn with_run_stats(mut self, run_stats: RunStats) -> Self {
+        self.run_stats = Some(run_stats);
+        self
+    }
+    /// Read file content from `read_root`, following any snapshot in use, instead of the
+    /// backup path.
+    pub fn with_read_root(mut self, read_root: ReadRoot) -> Self {
+        self.read_root = Some(read_root);
+        self
+    }
+    /// Share the count of failed reads with the other workers on the same queue.
+    pub fn with_attempts(mut self, attempts: Attempts) -> Self {
+        self.attempts = attempts;
+        self
+    }
     pub fn run(mut self) {
+        priority::lower_current_thread(self.config.scan_nice(), self.config.scan_idle_io());
+
         loop {
-            let item = self.ingest.pop();
+            let item = match self.ingest.pop_or_closed() {
+                Some(item) => item,
+                None => return,
+            };
+            if let Some(ref progress) = self.progress {
+                progress.started(item.as_ref().path());
+            }
 
-            match self.process(item.as_ref()) {
+            let started = Instant::now();
+            let processed = {
+                // jobs run together take turns with the same slots
+                let _slot = self.config.job_group().map(|g| g.pre_send().acquire());
+                self.process(item.as_ref())
+            };
+            if let Some(ref run_stats) = self.run_stats {
+                run_stats.add_read_time(started.elapsed());
+            }
+            match processed {
                 Ok(req) => {
+                    if let Some(ref run_stats) = self.run_stats {
+                        let size = req.size();
+                        run_stats.update(|s| s.bytes_read += size);
+                    }
                     self.outgest.push(req);
+                    self.attempts.done(item.as_ref().path());
                     item.success();
                 }
                 Err(e) => {
                     error!("Failed processing: {}", e);
-                    continue;
+                    if self.attempts.failed(item.as_ref().path()) < MAX_ATTEMPTS {
+                        item.requeue();
+                        continue;
+                    }
+                    // given up on until the file changes again or the next scan
+                    error!("Giving up on {} after {} attempts",
+                           item.as_ref().path(),
+                           MAX_ATTEMPTS);
+                    self.attempts.done(item.as_ref().path());
+                    if let Some(ref failures) = self.failures {
+                        failures.record(FailureKind::Read, item.as_ref().path(), &format!("{}", e));
+                    }
+                    if let Some(ref progress) = self.progress {
+                        progress.done(item.as_ref().size());
+                    }
+                    item.success();
                 }
             }
         }
     }
 
+    fn read(&self, path: &Path) -> Result<Vec<u8>, DefaultEngineError> {
+        use std::io::copy;
+
+        let mut buffer = vec![];
+        let mut src_file = File::open(path)
+            .map_err(|e| DefaultEngineError::Storage(format!("Failed opening {:?}", path), box e))?;
+        let copied = match self.throttle {
+            Some(ref throttle) => {
+                copy(&mut ThrottledRead::new(src_file, throttle.clone()), &mut buffer)
+            }
+            None => copy(&mut src_file, &mut buffer),
+        };
+        copied.map_err(|e| {
+                DefaultEngineError::Storage(format!("Failed reading {:?}", path), box e)
+            })?;
+        Ok(buffer)
+    }
+
     fn process(&self, node: &Node) -> Result<SendRequest, DefaultEngineError> {
         use std::io::{Cursor, copy};
 
@@ -46,42 +228,179 @@ impl PreSendWorker {
         debug!("Processing {}", node.path());
 
         let mut path = PathBuf::new();
-        path.push(self.config.path());
+        match self.read_root {
+            Some(ref read_root) => path.push(read_root.path()),
+            None => path.push(self.config.path()),
+        }
         path.push(node.path());
 
-        let mut buffer = Cursor::new(vec![]);
+        let (content, (size, mtime), consistent) = read_consistent(&path, |p| self.read(p))?;
+        let mut buffer = Cursor::new(content);
 
-        let mut src_file = File::open(&path)
-            .map_err(|e| DefaultEngineError::Storage(format!("Failed opening {:?}", path), box e))?;
+        // the node describes the file as it was last read, not as the scan found it
+        let mut node = node.clone();
+        node.set_size(size);
+        if let Ok(since_epoch) = mtime.duration_since(UNIX_EPOCH) {
+            node.set_mtime(Timespec::new(since_epoch.as_secs() as i64, 0));
+        }
+        if !consistent {
+            warn!("{} kept changing while it was read, storing it marked inconsistent",
+                  node.path());
+            node.set_inconsistent(true);
+        }
+        match capability(&path) {
+            Ok(cap) => node.set_capability(cap),
+            Err(e) => warn!("Unable to read capabilities of {:?}: {}", path, e),
+        }
+        if let Some(filter) = filter_for(self.config.filters(), node.path()) {
+            debug!("Filtering {} through {}", node.path(), filter.id());
+            let filtered = filter.apply(buffer.into_inner()).map_err(|e| {
+                    DefaultEngineError::Storage(format!("Failed to filter {:?}", path), box e)
+                })?;
+            buffer = Cursor::new(filtered);
+            node.set_filter(filter.id());
+        }
+
+        let size = buffer.get_ref().len() as u64;
+        buffer.set_position(0);
 
-        match copy(&mut src_file, &mut buffer) {
-            Err(e) => {
-                return Err(DefaultEngineError::Storage(format!("Failed reading {:?}", path),
-                                                       box e));
+        let algorithm = self.config.hash_algorithm();
+        let (md5, hash, algorithm) = match self.config.parallel_hash_threshold() {
+            Some(threshold) if size >= threshold => {
+                debug!("Hashing {} in parallel", node.path());
+                let data = Arc::new(buffer.into_inner());
+                let (md5, hash) =
+                    parallel_hash(algorithm, data.clone(), self.config.hash_threads());
+                buffer = Cursor::new(Arc::try_unwrap(data).expect("hash threads finished"));
+                (md5, hash, algorithm.tree())
+            }
+            _ => {
+                let mut hasher = Hasher::with_algorithm(algorithm);
+                match copy(&mut buffer, &mut hasher) {
+                    Err(e) => {
+                        return Err(DefaultEngineError::Storage(format!("Failed to hash {:?}",
+                                                                       path),
+                                                               box e));
+                    }
+                    _ => (),
+                };
+                let (md5, hash) = hasher.result();
+                (md5, hash, hasher.algorithm())
             }
-            _ => (),
         };
 
-        let size = buffer.position();
-        buffer.set_position(0);
+        node.set_hash(hash.clone());
+        node.set_hash_algorithm(algorithm);
 
-        let mut hasher = Hasher::new();
-        match copy(&mut buffer, &mut hasher) {
-            Err(e) => {
-                return Err(DefaultEngineError::Storage(format!("Failed to hash {:?}", path),
-                                                       box e));
+        let compression = self.config.compression();
+        let content = buffer.into_inner();
+        match self.config.chunk_threshold() {
+            Some(threshold) if size >= threshold => {
+                // the file is recorded once the chunks queued ahead of it are stored
+                let parts = Parts::new();
+                let mut outgest = self.outgest.clone();
+                // chunks are small enough to hash in one go, so never with a tree algorithm
+                let chunk_algorithm = algorithm.base();
+                let (chunks, stored) = queue_chunks(compression,
+                                                    chunk_algorithm,
+                                                    &node,
+                                                    &content,
+                                                    &parts,
+                                                    &mut outgest)
+                    .map_err(|e| {
+                        DefaultEngineError::Storage(format!("Failed to chunk {:?}", path), box e)
+                    })?;
+                debug!("Split {} into {} chunks", node.path(), chunks.len());
+                node.set_chunks(chunks);
+                let reader = SendRequestReader::InMemory(Cursor::new(vec![]));
+                return Ok(SendRequest::new(md5, hash, node, reader, stored).with_parts(parts));
             }
-            _ => (),
-        };
+            _ => {}
+        }
 
-        let (md5, sha256) = hasher.result();
-        let mut node = node.clone();
-        node.set_hash(sha256.clone());
+        let req = SendRequest::for_content(compression, md5, hash, node, content)
+            .map_err(|e| {
+                DefaultEngineError::Storage(format!("Failed to compress {:?}", path), box e)
+            })?;
+        if req.encoded() {
+            debug!("Compressed {} from {} to {} bytes", req.node().path(), size, req.size());
+        }
+        debug!("Processing {} complete", req.node().path());
+        Ok(req)
+    }
+}
 
-        buffer.set_position(0);
+#[cfg(test)]
+mod test {
+    use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all};
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    use engine::DefaultEngineError;
+    use super::{READ_ATTEMPTS, read_consistent};
+
+    fn write_file(path: &Path, content: &[u8]) {
+        File::create(path).and_then(|mut f| f.write_all(content)).expect("write");
+    }
+
+    fn append(path: &Path, content: &[u8]) {
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(content))
+            .expect("append");
+    }
+
+    fn read_file(path: &Path) -> Result<Vec<u8>, DefaultEngineError> {
+        let mut content = vec![];
+        File::open(path).and_then(|mut f| f.read_to_end(&mut content)).expect("read");
+        Ok(content)
+    }
+
+    #[test]
+    fn read_consistent_rereads_changed_file() {
+        let dir = "target/test/read_consistent_rereads_changed_file";
+        let _ = remove_dir_all(dir);
+        create_dir_all(dir).expect("create dir");
+        let path = Path::new(dir).join("file");
+        write_file(&path, b"one");
+
+        let mut reads = 0;
+        let (content, (size, _), consistent) = read_consistent(&path, |p| {
+                reads += 1;
+                let content = read_file(p);
+                if reads == 1 {
+                    append(p, b" two");
+                }
+                content
+            })
+            .expect("read");
+        assert_eq!(2, reads);
+        assert!(consistent);
+        assert_eq!(b"one two".to_vec(), content);
+        assert_eq!(7, size);
+    }
+
+    #[test]
+    fn read_consistent_gives_up_on_changing_file() {
+        let dir = "target/test/read_consistent_gives_up_on_changing_file";
+        let _ = remove_dir_all(dir);
+        create_dir_all(dir).expect("create dir");
+        let path = Path::new(dir).join("file");
+        write_file(&path, b"x");
 
-        let reader = SendRequestReader::InMemory(buffer);
-        debug!("Processing {} complete", node.path());
-        Ok(SendRequest::new(md5, sha256, node, reader, size))
+        let mut reads = 0;
+        let (content, (size, _), consistent) = read_consistent(&path, |p| {
+                reads += 1;
+                let content = read_file(p);
+                append(p, b"x");
+                content
+            })
+            .expect("read");
+        assert_eq!(READ_ATTEMPTS, reads);
+        assert!(!consistent);
+        assert_eq!(3, content.len());
+        // size is from the stat after the last read, not from the scan
+        assert_eq!(4, size);
     }
 }
@@ -0,0 +1,148 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Inner {
+    started: Instant,
+    files_total: u64,
+    files_done: u64,
+    bytes_total: u64,
+    bytes_done: u64,
+    current: Option<String>,
+}
+
+/// Files and bytes worked through by a backup, restore or verify, shared with the workers
+/// doing the work.
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// A point in time copy of `Progress`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressSnapshot {
+    pub files_total: u64,
+    pub files_done: u64,
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+    pub current: Option<String>,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress {
+            inner: Arc::new(Mutex::new(Inner {
+                started: Instant::now(),
+                files_total: 0,
+                files_done: 0,
+                bytes_total: 0,
+                bytes_done: 0,
+                current: None,
+            })),
+        }
+    }
+
+    /// Start counting again from nothing.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().expect("progress lock");
+        inner.started = Instant::now();
+        inner.files_total = 0;
+        inner.files_done = 0;
+        inner.bytes_total = 0;
+        inner.bytes_done = 0;
+        inner.current = None;
+    }
+
+    /// A file of `bytes` has been found that needs work.
+    pub fn queued(&self, bytes: u64) {
+        let mut inner = self.inner.lock().expect("progress lock");
+        inner.files_total += 1;
+        inner.bytes_total += bytes;
+    }
+
+    /// Work has started on `path`.
+    pub fn started(&self, path: &str) {
+        self.inner.lock().expect("progress lock").current = Some(path.to_string());
+    }
+
+    /// A queued file of `bytes` is finished with, whether it succeeded or not.
+    pub fn done(&self, bytes: u64) {
+        let mut inner = self.inner.lock().expect("progress lock");
+        inner.files_done += 1;
+        inner.bytes_done += bytes;
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let inner = self.inner.lock().expect("progress lock");
+        ProgressSnapshot {
+            files_total: inner.files_total,
+            files_done: inner.files_done,
+            bytes_total: inner.bytes_total,
+            bytes_done: inner.bytes_done,
+            current: inner.current.clone(),
+            elapsed: inner.started.elapsed(),
+        }
+    }
+}
+
+impl ProgressSnapshot {
+    /// Bytes per second since starting.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        if secs <= 0.0 {
+            return 0.0;
+        }
+        self.bytes_done as f64 / secs
+    }
+
+    /// Time left for the bytes queued so far at the current throughput. None until anything
+    /// has been done.
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        if throughput <= 0.0 || self.bytes_total < self.bytes_done {
+            return None;
+        }
+        let left = (self.bytes_total - self.bytes_done) as f64 / throughput;
+        Some(Duration::new(left.ceil() as u64, 0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn snapshot(bytes_total: u64, bytes_done: u64, secs: u64) -> ProgressSnapshot {
+        ProgressSnapshot {
+            files_total: 2,
+            files_done: 1,
+            bytes_total: bytes_total,
+            bytes_done: bytes_done,
+            current: None,
+            elapsed: Duration::new(secs, 0),
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let progress = Progress::new();
+        progress.queued(10);
+        progress.queued(30);
+        progress.started("a/b");
+        progress.done(10);
+
+        let s = progress.snapshot();
+        assert_eq!((2, 1, 40, 10), (s.files_total, s.files_done, s.bytes_total, s.bytes_done));
+        assert_eq!(Some("a/b".to_string()), s.current);
+
+        progress.reset();
+        assert_eq!(0, progress.snapshot().files_total);
+    }
+
+    #[test]
+    fn eta() {
+        assert_eq!(None, snapshot(100, 0, 5).eta());
+        assert_eq!(Some(Duration::new(15, 0)), snapshot(100, 25, 5).eta());
+        assert_eq!(Some(Duration::new(0, 0)), snapshot(100, 100, 5).eta());
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::process::Command;
+use std::str;
+use time::Timespec;
+
+use Node;
+
+/// A directory on another host, backed up over SSH. It is listed with GNU `find` and read with
+/// `cat`, so nothing else needs to be installed there. File content is fetched whole, which
+/// suits small servers rather than large media trees.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteSource {
+    host: String,
+    path: String,
+}
+
+impl RemoteSource {
+    pub fn new(host: &str, path: &str) -> Self {
+        RemoteSource {
+            host: host.to_string(),
+            path: path.trim_right_matches('/').to_string(),
+        }
+    }
+
+    /// Parse `[user@]host:/path`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        let host = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("");
+        if host.is_empty() || !path.starts_with('/') {
+            return Err(format!("Remote must be [user@]host:/path: {}", s));
+        }
+        Ok(RemoteSource::new(host, path))
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Files and directories under the remote path, keyed relative to it.
+    pub fn scan(&self) -> IoResult<Vec<Node>> {
+        let listing = self.ssh(&format!("find {} -mindepth 1 \\( -type f -o -type d \\) \
+                                         -printf '%y %s %T@ %m %P\\0'",
+                                        quote(&self.path)))?;
+        parse_listing(&listing)
+            .map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Content of the file at `key`.
+    pub fn read(&self, key: &str) -> IoResult<Vec<u8>> {
+        self.ssh(&format!("cat -- {}", quote(&format!("{}/{}", self.path, key))))
+    }
+
+    fn ssh(&self, command: &str) -> IoResult<Vec<u8>> {
+        let output = Command::new("ssh").arg("-o")
+            .arg("BatchMode=yes")
+            .arg(&self.host)
+            .arg(command)
+            .output()?;
+        if !output.status.success() {
+            return Err(IoError::new(ErrorKind::Other,
+                                    format!("{} on {} failed: {}: {}",
+                                            command,
+                                            self.host,
+                                            output.status,
+                                            String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Quote `s` for the remote shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+/// Parse `find -printf '%y %s %T@ %m %P\0'` output. Entries are NUL separated as names may
+/// hold newlines. Names that are not UTF-8 cannot be keyed, so they are skipped.
+fn parse_listing(listing: &[u8]) -> Result<Vec<Node>, String> {
+    let mut nodes = vec![];
+    for entry in listing.split(|b| *b == 0).filter(|e| !e.is_empty()) {
+        let line = match str::from_utf8(entry) {
+            Ok(line) => line,
+            Err(_) => {
+                warn!("Skipping remote entry that is not UTF-8: {}",
+                      String::from_utf8_lossy(entry));
+                continue;
+            }
+        };
+        let parts: Vec<&str> = line.splitn(5, ' ').collect();
+        if parts.len() != 5 {
+            return Err(format!("Unexpected listing line: {}", line));
+        }
+        let size = parts[1].parse::<u64>().map_err(|e| format!("{}: {}", line, e))?;
+        let mtime = parts[2].split('.')
+            .next()
+            .unwrap_or("")
+            .parse::<i64>()
+            .map_err(|e| format!("{}: {}", line, e))?;
+        let mode = u32::from_str_radix(parts[3], 8).map_err(|e| format!("{}: {}", line, e))?;
+        let mtime = Timespec::new(mtime, 0);
+        match parts[0] {
+            "f" => nodes.push(Node::new_file(parts[4], mtime, size, mode)),
+            "d" => nodes.push(Node::new_dir(parts[4], mtime, mode)),
+            _ => return Err(format!("Unexpected file type: {}", line)),
+        }
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::Timespec;
+    use Node;
+
+    #[test]
+    fn parse() {
+        let remote = RemoteSource::parse("backup@web1:/srv/www/").unwrap();
+        assert_eq!("backup@web1", remote.host());
+        assert_eq!("/srv/www", remote.path());
+        assert!(RemoteSource::parse("web1").is_err());
+        assert!(RemoteSource::parse("web1:srv").is_err());
+    }
+
+    #[test]
+    fn listing() {
+        let nodes = parse_listing(b"d 4096 1480000000.5 755 conf\0\
+                                    f 12 1480000001.0000000000 644 conf/site\none.conf\0\
+                                    f 1 1480000002.0 644 conf/\xff\0")
+            .unwrap();
+        assert_eq!(vec![Node::new_dir("conf", Timespec::new(1480000000, 0), 0o755),
+                        Node::new_file("conf/site\none.conf",
+                                       Timespec::new(1480000001, 0),
+                                       12,
+                                       0o644)],
+                   nodes);
+        assert!(parse_listing(b"l 1 1 777 link").is_err());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!("'it'\\''s'", quote("it's"));
+    }
+}
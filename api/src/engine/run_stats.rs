@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use index::{RunReport, RunSummary, SkipReason, SkippedPath};
+
+struct Inner {
+    started: Instant,
+    summary: RunSummary,
+    report: RunReport,
+    /// By path, so a path skipped by several events in a run is recorded once.
+    skipped: BTreeMap<String, (SkipReason, Option<u64>)>,
+}
+
+/// Counts what the current backup run has done, shared with the workers.
+#[derive(Clone)]
+pub struct RunStats {
+    inner: Arc<Mutex<Inner>>,
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        RunStats {
+            inner: Arc::new(Mutex::new(Inner {
+                started: Instant::now(),
+                summary: RunSummary::default(),
+                report: RunReport::default(),
+                skipped: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn update<F>(&self, f: F)
+        where F: FnOnce(&mut RunSummary)
+    {
+        f(&mut self.inner.lock().expect("run stats lock").summary);
+    }
+
+    /// `path` was sent to storage in `took`, after `failures` failed attempts.
+    pub fn uploaded(&self, path: &str, bytes: u64, took: Duration, failures: u32) {
+        let mut inner = self.inner.lock().expect("run stats lock");
+        let at = inner.started.elapsed().as_secs();
+        inner.report.uploaded(path, bytes, millis(took), at);
+        if failures > 0 {
+            inner.report.record_retries(path, failures);
+        }
+    }
+
+    /// `path` was given up on after `failures` failed attempts.
+    pub fn gave_up(&self, path: &str, failures: u32) {
+        self.inner.lock().expect("run stats lock").report.record_retries(path, failures);
+    }
+
+    /// `path` was left out of the run for `reason`.
+    pub fn skipped(&self, path: &str, reason: SkipReason, size: Option<u64>) {
+        let mut inner = self.inner.lock().expect("run stats lock");
+        inner.skipped.insert(path.to_string(), (reason, size));
+    }
+
+    pub fn add_scan_time(&self, took: Duration) {
+        self.inner.lock().expect("run stats lock").report.stages.scan_ms += millis(took);
+    }
+    pub fn add_read_time(&self, took: Duration) {
+        self.inner.lock().expect("run stats lock").report.stages.read_ms += millis(took);
+    }
+    pub fn add_index_time(&self, took: Duration) {
+        self.inner.lock().expect("run stats lock").report.stages.index_ms += millis(took);
+    }
+
+    /// Everything counted since the last call, timed from then until now.
+    pub fn take(&self) -> (RunSummary, RunReport, Vec<SkippedPath>) {
+        use std::mem::replace;
+
+        let mut inner = self.inner.lock().expect("run stats lock");
+        let mut summary = replace(&mut inner.summary, RunSummary::default());
+        let report = replace(&mut inner.report, RunReport::default());
+        let skipped = replace(&mut inner.skipped, BTreeMap::new())
+            .into_iter()
+            .map(|(path, (reason, size))| {
+                SkippedPath {
+                    path: path,
+                    reason: reason,
+                    size: size,
+                }
+            })
+            .collect();
+        summary.wall_time_ms = millis(inner.started.elapsed());
+        inner.started = Instant::now();
+        (summary, report, skipped)
+    }
+}
@@ -0,0 +1,126 @@
+//! Commands run around backup sets: `pre_backup` before each set opens, to quiesce
+//! applications or snapshot databases, and `post_backup` once it closes or the run fails, to
+//! resume them or start follow-up jobs. How the run went is passed in `HAUMARU_*` variables.
+
+use index::RunSummary;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::process::Command;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackupScripts {
+    pre: Option<String>,
+    post: Option<String>,
+}
+
+/// How a run ended, as told to the post script.
+#[derive(Debug)]
+pub enum Outcome<'a> {
+    /// `backup_set` was closed, with `failures` files that could not be included.
+    Closed {
+        backup_set: u64,
+        summary: &'a RunSummary,
+        failures: usize,
+    },
+    /// The run stopped with `error` before a set could be closed.
+    Failed { error: &'a str },
+}
+
+impl BackupScripts {
+    pub fn new() -> Self {
+        BackupScripts::default()
+    }
+    /// Run `command` before each backup set opens. The run is abandoned if it fails.
+    pub fn with_pre(mut self, command: &str) -> Self {
+        self.pre = Some(command.to_string());
+        self
+    }
+    /// Run `command` after each backup set closes, or the run fails.
+    pub fn with_post(mut self, command: &str) -> Self {
+        self.post = Some(command.to_string());
+        self
+    }
+
+    /// Run the pre script for a set taken at `at`.
+    pub fn pre(&self, at: i64) -> IoResult<()> {
+        match self.pre {
+            Some(ref command) => run(command, &[("HAUMARU_AT", at.to_string())]),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the post script with how the run ended.
+    pub fn post(&self, outcome: &Outcome) -> IoResult<()> {
+        match self.post {
+            Some(ref command) => run(command, &env(outcome)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The variables describing `outcome`. A set closed with failed files counts as a failure.
+fn env(outcome: &Outcome) -> Vec<(&'static str, String)> {
+    match *outcome {
+        Outcome::Closed { backup_set, summary, failures } => {
+            let status = if failures == 0 { "success" } else { "failure" };
+            vec![("HAUMARU_STATUS", status.to_string()),
+                 ("HAUMARU_BACKUP_SET", backup_set.to_string()),
+                 ("HAUMARU_FAILED_FILES", failures.to_string()),
+                 ("HAUMARU_SCANNED", summary.scanned.to_string()),
+                 ("HAUMARU_NEW", summary.new.to_string()),
+                 ("HAUMARU_CHANGED", summary.changed.to_string()),
+                 ("HAUMARU_DELETED", summary.deleted.to_string()),
+                 ("HAUMARU_BYTES_UPLOADED", summary.bytes_uploaded.to_string()),
+                 ("HAUMARU_WALL_TIME_MS", summary.wall_time_ms.to_string())]
+        }
+        Outcome::Failed { error } => {
+            vec![("HAUMARU_STATUS", "failure".to_string()),
+                 ("HAUMARU_ERROR", error.to_string())]
+        }
+    }
+}
+
+fn run(command: &str, env: &[(&str, String)]) -> IoResult<()> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    for &(name, ref value) in env {
+        cmd.env(name, value);
+    }
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(IoError::new(ErrorKind::Other,
+                                format!("{:?} failed: {}: {}",
+                                        command,
+                                        output.status,
+                                        String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use index::RunSummary;
+
+    #[test]
+    fn pre_failure() {
+        assert!(BackupScripts::new().pre(0).is_ok());
+        assert!(BackupScripts::new().with_pre("test \"$HAUMARU_AT\" = 600").pre(600).is_ok());
+        assert!(BackupScripts::new().with_pre("exit 3").pre(600).is_err());
+    }
+
+    #[test]
+    fn post_env() {
+        let summary = RunSummary { new: 2, ..RunSummary::default() };
+        let closed = Outcome::Closed {
+            backup_set: 4,
+            summary: &summary,
+            failures: 0,
+        };
+        let check = "test \"$HAUMARU_STATUS $HAUMARU_BACKUP_SET $HAUMARU_NEW\" = \"success 4 2\"";
+        assert!(BackupScripts::new().with_post(check).post(&closed).is_ok());
+
+        let failed = Outcome::Failed { error: "disk full" };
+        let check = "test \"$HAUMARU_STATUS: $HAUMARU_ERROR\" = \"failure: disk full\"";
+        assert!(BackupScripts::new().with_post(check).post(&failed).is_ok());
+    }
+}
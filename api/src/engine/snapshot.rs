@@ -0,0 +1,141 @@
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// Commands that take a filesystem snapshot (LVM, ZFS, btrfs, APFS) of the backup path for
+/// a full scan, and release it again afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    create: String,
+    path: String,
+    release: Option<String>,
+}
+
+impl Snapshot {
+    /// `create` makes the snapshot available at `path`, mirroring the backup path.
+    pub fn new(create: &str, path: &str) -> Self {
+        Snapshot {
+            create: create.to_string(),
+            path: path.to_string(),
+            release: None,
+        }
+    }
+    pub fn with_release(mut self, release: &str) -> Self {
+        self.release = Some(release.to_string());
+        self
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn create(&self) -> IoResult<()> {
+        run(&self.create)
+    }
+    pub fn release(&self) -> IoResult<()> {
+        match self.release {
+            Some(ref release) => run(release),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Run through `cmd`, as Windows has no `sh`.
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+fn run(command: &str) -> IoResult<()> {
+    let output = shell(command).output()?;
+    if !output.status.success() {
+        return Err(IoError::new(ErrorKind::Other,
+                                format!("{:?} failed: {}: {}",
+                                        command,
+                                        output.status,
+                                        String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(())
+}
+
+/// Where file content is read from: the backup path, or a snapshot of it while one is in use.
+/// Shared with the workers reading file content.
+#[derive(Debug, Clone)]
+pub struct ReadRoot {
+    base: String,
+    snapshot: Arc<Mutex<Option<String>>>,
+}
+
+impl ReadRoot {
+    pub fn new(base: &str) -> Self {
+        ReadRoot {
+            base: base.to_string(),
+            snapshot: Arc::new(Mutex::new(None)),
+        }
+    }
+    pub fn path(&self) -> String {
+        match *self.snapshot.lock().unwrap() {
+            Some(ref snapshot) => snapshot.clone(),
+            None => self.base.clone(),
+        }
+    }
+    pub fn snapshot(&self) -> Option<String> {
+        self.snapshot.lock().unwrap().clone()
+    }
+    pub fn set_snapshot(&self, snapshot: Option<String>) {
+        *self.snapshot.lock().unwrap() = snapshot;
+    }
+    /// Where `path` under the base is read from. Paths outside it stay as they are.
+    pub fn rebase(&self, path: &str) -> String {
+        match self.snapshot() {
+            Some(ref snapshot) if Path::new(path).starts_with(&self.base) => {
+                format!("{}{}", snapshot, &path[self.base.len()..])
+            }
+            _ => path.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_root_follows_snapshot() {
+        let root = ReadRoot::new("/data");
+        let worker = root.clone();
+        assert_eq!("/data", worker.path());
+        root.set_snapshot(Some("/mnt/snap".to_string()));
+        assert_eq!("/mnt/snap", worker.path());
+        root.set_snapshot(None);
+        assert_eq!("/data", worker.path());
+    }
+
+    #[test]
+    fn rebase_into_snapshot() {
+        let root = ReadRoot::new("/data");
+        assert_eq!("/data/.haumaru", root.rebase("/data/.haumaru"));
+        root.set_snapshot(Some("/mnt/snap".to_string()));
+        assert_eq!("/mnt/snap/.haumaru", root.rebase("/data/.haumaru"));
+        assert_eq!("/mnt/snap", root.rebase("/data"));
+        assert_eq!("/database", root.rebase("/database"));
+        assert_eq!("/var/cache", root.rebase("/var/cache"));
+    }
+
+    #[test]
+    fn create_and_release() {
+        let snapshot = Snapshot::new("true", "/mnt/snap").with_release("false");
+        assert!(snapshot.create().is_ok());
+        assert!(snapshot.release().is_err());
+        assert!(Snapshot::new("true", "/mnt/snap").release().is_ok());
+    }
+}
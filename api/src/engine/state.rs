@@ -0,0 +1,86 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// What the backup loop is doing right now.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Not running a backup loop.
+    Stopped,
+    Starting,
+    Scanning,
+    /// Waiting for changes until the next run.
+    Watching,
+    /// Sending a backup set's changes to storage.
+    Uploading,
+    Paused,
+    OutsideWindow,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Stopped => write!(f, "stopped"),
+            Phase::Starting => write!(f, "starting"),
+            Phase::Scanning => write!(f, "scanning"),
+            Phase::Watching => write!(f, "watching"),
+            Phase::Uploading => write!(f, "uploading"),
+            Phase::Paused => write!(f, "paused"),
+            Phase::OutsideWindow => write!(f, "outside backup window"),
+        }
+    }
+}
+
+struct Inner {
+    phase: Phase,
+    next_run: Option<i64>,
+    pending_changes: u64,
+    last_backup_set: Option<(u64, i64)>,
+}
+
+/// Progress of the backup loop, shared with anything reporting on it.
+#[derive(Clone)]
+pub struct RunState {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl RunState {
+    pub fn new() -> Self {
+        RunState {
+            inner: Arc::new(Mutex::new(Inner {
+                phase: Phase::Stopped,
+                next_run: None,
+                pending_changes: 0,
+                last_backup_set: None,
+            })),
+        }
+    }
+
+    pub fn set_phase(&self, phase: Phase) {
+        self.inner.lock().expect("state lock").phase = phase;
+    }
+    pub fn phase(&self) -> Phase {
+        self.inner.lock().expect("state lock").phase
+    }
+
+    pub fn set_next_run(&self, next_run: i64) {
+        self.inner.lock().expect("state lock").next_run = Some(next_run);
+    }
+    pub fn next_run(&self) -> Option<i64> {
+        self.inner.lock().expect("state lock").next_run
+    }
+
+    pub fn set_pending_changes(&self, pending: u64) {
+        self.inner.lock().expect("state lock").pending_changes = pending;
+    }
+    pub fn pending_changes(&self) -> u64 {
+        self.inner.lock().expect("state lock").pending_changes
+    }
+
+    /// Record the backup set closed most recently and its timestamp.
+    pub fn set_last_backup_set(&self, backup_set: u64, at: i64) {
+        self.inner.lock().expect("state lock").last_backup_set = Some((backup_set, at));
+    }
+    pub fn last_backup_set(&self) -> Option<(u64, i64)> {
+        self.inner.lock().expect("state lock").last_backup_set
+    }
+}
@@ -0,0 +1,83 @@
+use engine::GENERATED_DIR;
+
+/// Key of the directory holding data stored with `put`, kept apart from the files scanned from
+/// the backup path.
+pub const VIRTUAL_DIR: &'static str = ".haumaru-virtual";
+
+/// Prefix addressing data stored with `put`, as in `virtual:db/app.sql`.
+pub const VIRTUAL_PREFIX: &'static str = "virtual:";
+
+/// Where `put` stores `key`. The key is a relative path, without `.` or `..` components, and may
+/// start with `VIRTUAL_PREFIX`.
+pub fn virtual_key(key: &str) -> Result<String, String> {
+    let key = strip_prefix(key).unwrap_or(key).trim_matches('/');
+    if key.is_empty() {
+        return Err("Key can not be empty".to_string());
+    }
+    if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        return Err(format!("Invalid key: {}", key));
+    }
+    Ok(format!("{}/{}", VIRTUAL_DIR, key))
+}
+
+/// The index key for `key` as given to `ls`, `restore` or `cat`. Keys starting with
+/// `VIRTUAL_PREFIX` are looked up in `VIRTUAL_DIR`, everything else is a path in the backup path.
+pub fn resolve_key(key: &str) -> String {
+    match strip_prefix(key).map(|k| k.trim_matches('/')) {
+        Some("") => VIRTUAL_DIR.to_string(),
+        Some(k) => format!("{}/{}", VIRTUAL_DIR, k),
+        None => key.to_string(),
+    }
+}
+
+/// Whether `key`, scanned from the backup path, would land in `VIRTUAL_DIR` or `GENERATED_DIR`.
+pub fn is_reserved(key: &str) -> bool {
+    [VIRTUAL_DIR, GENERATED_DIR]
+        .iter()
+        .any(|dir| key == *dir || key.starts_with(dir) && key[dir.len()..].starts_with('/'))
+}
+
+fn strip_prefix(key: &str) -> Option<&str> {
+    if key.starts_with(VIRTUAL_PREFIX) {
+        Some(&key[VIRTUAL_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/// Directories above `key`, outermost first.
+pub fn parents(key: &str) -> Vec<&str> {
+    key.match_indices('/').map(|(i, _)| &key[..i]).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(Ok(".haumaru-virtual/db/dump.sql".to_string()),
+                   virtual_key("/db/dump.sql"));
+        assert_eq!(Ok(".haumaru-virtual/dump".to_string()), virtual_key("virtual:dump"));
+        assert!(virtual_key("").is_err());
+        assert!(virtual_key("db//dump").is_err());
+        assert!(virtual_key("../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(".haumaru-virtual/db/dump.sql", resolve_key("virtual:db/dump.sql"));
+        assert_eq!(".haumaru-virtual", resolve_key("virtual:"));
+        assert_eq!("db/dump.sql", resolve_key("db/dump.sql"));
+        assert!(is_reserved(".haumaru-virtual/db"));
+        assert!(!is_reserved(".haumaru-virtualenv"));
+        assert!(is_reserved(".haumaru-generated"));
+        assert!(is_reserved(".haumaru-generated/app.sql"));
+    }
+
+    #[test]
+    fn parents_outermost_first() {
+        assert_eq!(vec![".haumaru-virtual", ".haumaru-virtual/db"],
+                   parents(".haumaru-virtual/db/dump.sql"));
+    }
+}
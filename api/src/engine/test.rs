@@ -1,16 +1,34 @@
 extern crate env_logger;
 
-use std::io::Cursor;
+use std::io::{Cursor, Write};
 use std::collections::HashSet;
+use std::fs::{File, create_dir_all, remove_dir_all};
 use rusqlite::Connection;
 use time::Timespec;
 
+use compare::{Drift, DriftEntry};
+use hasher::Hasher;
 use index::SqlLightIndex;
 use storage::LocalStorage;
-use engine::DefaultEngine;
-use {Node, Index, Engine, EngineConfig};
+use engine::{Browser, DefaultEngine, PauseReason, is_excluded, is_excluded_top, is_included,
+             key_holds, too_young};
+use filesystem::Change;
+use pattern::Glob;
+use listing::ListOptions;
+use {Node, Index, Engine, EngineConfig, HashAlgorithm};
 
 fn test_list(key: &str, f: &Fn(&mut Index)) -> String {
+    test_list_with(key, &ListOptions::new(), f)
+}
+
+fn test_list_with(key: &str, options: &ListOptions, f: &Fn(&mut Index)) -> String {
+    let mut engine = test_engine(f);
+    let mut cur = Cursor::new(Vec::new());
+    engine.list_with(key, None, options, &mut cur).expect("list");
+    String::from_utf8(cur.into_inner()).expect("from_utf8")
+}
+
+fn test_engine(f: &Fn(&mut Index)) -> DefaultEngine<SqlLightIndex, LocalStorage> {
     let _ = env_logger::init();
 
     let conn = Connection::open_in_memory().expect("conn");
@@ -22,10 +40,7 @@ fn test_list(key: &str, f: &Fn(&mut Index)) -> String {
     f(&mut index);
     expect!(index.close_backup_set(), "close backup set");
 
-    let mut engine = DefaultEngine::new(config, HashSet::new(), index, store).expect("new engine");
-    let mut cur = Cursor::new(Vec::new());
-    engine.list(key, None, &mut cur).expect("list");
-    String::from_utf8(cur.into_inner()).expect("from_utf8")
+    DefaultEngine::new(config, HashSet::new(), index, store).expect("new engine")
 }
 
 #[test]
@@ -90,3 +105,258 @@ fn list_empty_dir() {
                            });
     assert_eq!("", output.as_str());
 }
+
+fn insert_tree(index: &mut Index) {
+    index.insert(Node::new_dir("a", Timespec::new(10, 0), 500).with_backup_set(5))
+        .expect("insert dir");
+    index.insert(Node::new_dir("a/dir", Timespec::new(10, 0), 488).with_backup_set(5))
+        .expect("insert dir");
+    index.insert(Node::new_file("a/dir/file", Timespec::new(10, 0), 1024, 420)
+            .with_hash(vec![0; 32])
+            .with_backup_set(5))
+        .expect("insert_file");
+}
+
+#[test]
+fn list_recursive() {
+    let output = test_list_with("", &ListOptions::new().with_recursive(true), &insert_tree);
+    assert_eq!("drwxrw-r-- 0B Dec 31 18:00 a\ndrwxr-x--- 0B Dec 31 18:00 a/dir\n-rw-r--r-- \
+                1024B Dec 31 18:00 a/dir/file\n",
+               output.as_str());
+}
+
+#[test]
+fn list_tree() {
+    let output = test_list_with("a", &ListOptions::new().with_tree(true), &insert_tree);
+    assert_eq!("dir/\n  file (1024B)\n", output.as_str());
+}
+
+#[test]
+fn list_deleted() {
+    let insert = |index: &mut Index| {
+        let file = Node::new_file("a", Timespec::new(10, 0), 1024, 420)
+            .with_hash(vec![0; 32])
+            .with_backup_set(5);
+        index.insert(file.clone()).expect("insert");
+        index.insert(file.as_deleted().with_backup_set(5)).expect("insert tombstone");
+    };
+    assert_eq!("", test_list("", &insert).as_str());
+
+    let output = test_list_with("", &ListOptions::new().with_deleted(true), &insert);
+    assert!(output.starts_with("deleted "), output);
+    assert!(output.ends_with(" -rw-r--r-- 1024B Dec 31 18:00 a\n"), output);
+}
+
+#[test]
+fn browse_session() {
+    let mut engine = test_engine(&insert_tree);
+    let mut input = Cursor::new(b"cd a\nls\ncd nope\nmark dir\nmarked\nquit\nls\n".to_vec());
+    let mut out = vec![];
+    Browser::new().run(&mut engine, &mut input, &mut out).expect("browse");
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("[latest] /a> "), out);
+    assert!(out.contains("drwxr-x--- 0B Dec 31 18:00 a/dir\n"), out);
+    assert!(out.contains("Not a directory: /a/nope\n"), out);
+    assert!(out.contains("/a/dir\n"), out);
+    // nothing runs after quit
+    assert!(out.ends_with("[latest] /a> "), out);
+}
+
+#[test]
+fn compare_live() {
+    let dir = "target/test/compare_live";
+    let _ = remove_dir_all(dir);
+    create_dir_all(dir).expect("create dir");
+    File::create(format!("{}/a", dir)).and_then(|mut f| f.write_all(b"x")).expect("write a");
+    File::create(format!("{}/b", dir)).and_then(|mut f| f.write_all(b"yy")).expect("write b");
+
+    let mut hasher = Hasher::with_algorithm(HashAlgorithm::Sha256);
+    hasher.write_all(b"x").expect("hash");
+    let hash = hasher.result().1;
+
+    let conn = Connection::open_in_memory().expect("conn");
+    let mut index = SqlLightIndex::new(conn).expect("index");
+    expect!(index.create_backup_set(0), "create backup set");
+    // same size and content as on disk, but an older mtime
+    index.insert(Node::new_file("a", Timespec::new(10, 0), 1, 0o644).with_hash(hash))
+        .expect("insert");
+    index.insert(Node::new_file("gone", Timespec::new(10, 0), 4, 0o644).with_hash(vec![0; 32]))
+        .expect("insert");
+    expect!(index.close_backup_set(), "close backup set");
+
+    let config = EngineConfig::new_detached("target/test/compare_live_working")
+        .with_path(dir.to_string());
+    let store = LocalStorage::new(&config).expect("store");
+    let mut engine = DefaultEngine::new(config, HashSet::new(), index, store).expect("engine");
+
+    let summary = |drift: Vec<DriftEntry>| -> Vec<(String, Drift)> {
+        drift.into_iter().map(|d| (d.path, d.drift)).collect()
+    };
+    assert_eq!(vec![("a".to_string(), Drift::Changed),
+                    ("b".to_string(), Drift::New),
+                    ("gone".to_string(), Drift::Missing)],
+               summary(engine.compare_live(false).expect("compare")));
+    assert_eq!(vec![("b".to_string(), Drift::New), ("gone".to_string(), Drift::Missing)],
+               summary(engine.compare_live(true).expect("compare")));
+}
+
+#[test]
+fn excluded_top() {
+    let mut excludes = HashSet::new();
+    excludes.insert("/data/cache".to_string());
+    let globs = vec![Glob::new("**/node_modules/**"), Glob::new("**/*.tmp")];
+    let top = |path: &str| is_excluded_top(&excludes, &globs, &Change::new(path.into()), "/data");
+    assert!(top("/data/cache"));
+    assert!(!top("/data/cache/a"));
+    assert!(!top("/data/docs"));
+    assert!(!top("/data"));
+    assert!(top("/data/web/node_modules"));
+    assert!(!top("/data/web/node_modules/x/index.js"));
+    assert!(top("/data/web/a.tmp"));
+
+    let excluded = |path: &str| is_excluded(&excludes, &globs, &Change::new(path.into()), "/data");
+    assert!(excluded("/data/web/node_modules/x/index.js"));
+    assert!(!excluded("/data/web/index.js"));
+    assert!(!excluded("/database/a.tmp"));
+}
+
+#[test]
+fn included() {
+    let includes = vec![Glob::new("Documents/**"), Glob::new("Projects/**")];
+    let included = |path: &str| is_included(&includes, &Change::new(path.into()), "/home");
+    assert!(included("/home/Documents/a.txt"));
+    assert!(included("/home/Projects/web/index.html"));
+    assert!(included("/home/Projects"));
+    assert!(!included("/home/Music/a.mp3"));
+    assert!(!included("/home/.bashrc"));
+    assert!(included("/home"));
+    assert!(is_included(&[], &Change::new("/home/Music/a.mp3".into()), "/home"));
+}
+
+#[test]
+fn key_holds_subtree() {
+    assert!(key_holds("", "docs/a.txt"));
+    assert!(key_holds("docs", "docs"));
+    assert!(key_holds("docs", "docs/a.txt"));
+    assert!(!key_holds("docs", "docs2/a.txt"));
+    assert!(!key_holds("docs/a", "docs"));
+}
+
+#[test]
+fn min_file_age() {
+    let now = ::time::now_utc().to_timespec().sec;
+    let file = |age: i64| Node::new_file("a", Timespec::new(now - age, 0), 1, 0o644);
+    assert!(too_young(&file(5), Some(60)));
+    assert!(!too_young(&file(120), Some(60)));
+    assert!(!too_young(&file(5), None));
+    assert!(!too_young(&file(-3600), Some(60)));
+}
+
+#[test]
+fn estimate_scan() {
+    let dir = "target/test/estimate_scan";
+    let _ = remove_dir_all(dir);
+    create_dir_all(format!("{}/sub", dir)).expect("create dir");
+    create_dir_all(format!("{}/skip", dir)).expect("create dir");
+    File::create(format!("{}/a", dir)).and_then(|mut f| f.write_all(b"x")).expect("write a");
+    File::create(format!("{}/sub/b", dir)).and_then(|mut f| f.write_all(b"yy")).expect("write b");
+    File::create(format!("{}/big", dir))
+        .and_then(|mut f| f.write_all(&[0; 100]))
+        .expect("write big");
+    File::create(format!("{}/skip/c", dir)).and_then(|mut f| f.write_all(b"z")).expect("write c");
+
+    let skip = ::std::fs::canonicalize(format!("{}/skip", dir)).expect("canonicalize");
+    let mut excludes = HashSet::new();
+    excludes.insert(skip.to_str().unwrap().to_string());
+
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).expect("index");
+    let config = EngineConfig::new_detached("target/test/estimate_scan_working")
+        .with_path(dir.to_string())
+        .with_max_file_size(10);
+    let store = LocalStorage::new(&config).expect("store");
+    let engine = DefaultEngine::new(config, excludes, index, store).expect("engine");
+
+    let estimate = engine.estimate_scan().expect("estimate").at_bandwidth(2);
+    assert_eq!((2, 3, 1, 1), (estimate.files, estimate.bytes, estimate.dirs, estimate.excluded));
+    assert_eq!((1, 100), (estimate.over_limit, estimate.over_limit_bytes));
+    assert_eq!(2, estimate.duration_secs);
+}
+
+#[test]
+fn estimate_roots() {
+    let dir = "target/test/estimate_roots";
+    let _ = remove_dir_all(dir);
+    for sub in &["etc", "var/www", "var/log"] {
+        create_dir_all(format!("{}/{}", dir, sub)).expect("create dir");
+    }
+    File::create(format!("{}/etc/hosts", dir)).and_then(|mut f| f.write_all(b"x")).expect("write");
+    File::create(format!("{}/var/www/index.html", dir))
+        .and_then(|mut f| f.write_all(b"yy"))
+        .expect("write");
+    File::create(format!("{}/var/log/syslog", dir))
+        .and_then(|mut f| f.write_all(b"zzz"))
+        .expect("write");
+
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).expect("index");
+    let config = EngineConfig::new_detached("target/test/estimate_roots_working")
+        .with_path(dir.to_string())
+        .with_roots(vec![format!("{}/etc", dir), format!("{}/var/www", dir)]);
+    let store = LocalStorage::new(&config).expect("store");
+    let engine = DefaultEngine::new(config, HashSet::new(), index, store).expect("engine");
+
+    let estimate = engine.estimate_scan().expect("estimate");
+    assert_eq!((2, 3), (estimate.files, estimate.bytes));
+}
+
+#[test]
+fn delete_dropped_roots() {
+    let conn = Connection::open_in_memory().expect("conn");
+    let mut index = SqlLightIndex::new(conn).expect("index");
+    expect!(index.create_backup_set(0), "create backup set");
+    for dir in &["etc", "var", "var/www", "var/log"] {
+        index.insert(Node::new_dir(*dir, Timespec::new(10, 0), 0o755).with_backup_set(1))
+            .expect("insert");
+    }
+    for file in &["etc/hosts", "var/www/index.html", "var/log/syslog"] {
+        index.insert(Node::new_file(*file, Timespec::new(10, 0), 1, 0o644)
+                .with_hash(vec![1; 32])
+                .with_backup_set(1))
+            .expect("insert");
+    }
+
+    let config = EngineConfig::new_detached("target/test/delete_dropped_roots")
+        .with_path("/".to_string())
+        .with_roots(vec!["/etc".to_string(), "/var/www".to_string()]);
+    let store = LocalStorage::new(&config).expect("store");
+    let mut engine = DefaultEngine::new(config, HashSet::new(), index, store).expect("engine");
+    engine.delete_dropped_roots(2).expect("delete dropped roots");
+
+    let mut deleted = |key: &str| {
+        engine.index.get(key.to_string(), None).expect("get").expect("node").deleted()
+    };
+    for key in &["etc", "etc/hosts", "var", "var/www", "var/www/index.html"] {
+        assert!(!deleted(key), "{} deleted", key);
+    }
+    assert!(deleted("var/log"));
+    assert!(deleted("var/log/syslog"));
+}
+
+#[test]
+fn storage_caps() {
+    let conn = Connection::open_in_memory().expect("conn");
+    let mut index = SqlLightIndex::new(conn).expect("index");
+    expect!(index.create_backup_set(0), "create backup set");
+    index.insert(Node::new_file("a", Timespec::new(10, 0), 1024, 0o644).with_hash(vec![1; 32]))
+        .expect("insert");
+    expect!(index.close_backup_set(), "close backup set");
+
+    let config = EngineConfig::new_detached("target/test/storage_caps")
+        .with_storage_soft_cap(512)
+        .with_storage_hard_cap(1024);
+    let store = LocalStorage::new(&config).expect("store");
+    let mut engine = DefaultEngine::new(config, HashSet::new(), index, store).expect("engine");
+    engine.check_storage_caps();
+    assert_eq!(vec![PauseReason::StorageCap], engine.pause().reasons());
+}
@@ -0,0 +1,219 @@
+//! Tree hashes: a digest of each directory's entries, rolling up the tree hashes of its
+//! subdirectories. Each entry contributes the name, kind, size, mode, mtime and ctime that
+//! scans judge changes by, so a directory whose tree hash matches the one stored by its last
+//! complete scan holds nothing a scan would pick up. The rules picking what is backed up are
+//! hashed in too, so changing them changes the tree hashes they apply to.
+
+use digest::Digest;
+use engine::ignore::IGNORE_FILE;
+use get_key;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs::{File, read_dir};
+use std::io::{ErrorKind, Read};
+use std::io::Result as IoResult;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Works out tree hashes as a scan reaches each directory, keyed relative to `base` as in the
+/// index. The directories below are remembered, so each subtree is only read once. Entries
+/// `skip` picks, such as excluded, ignored or not included paths, are left out, and symlinks
+/// are hashed as links and not followed. `rules` stands for the configured rules behind
+/// `skip`; with them each directory hashes the ignore files from `base` down to it.
+///
+/// A directory that cannot be read in full, or holds a name that is not UTF-8, has no tree
+/// hash and neither has any directory above it, so scans always go through them.
+pub struct TreeHasher<F> {
+    base: String,
+    rules: Vec<u8>,
+    skip: F,
+    hashes: HashMap<String, Option<Vec<u8>>>,
+    /// Digest of the rules applying in each directory read so far.
+    dir_rules: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl<F: Fn(&Path) -> bool> TreeHasher<F> {
+    pub fn new(base: &str, rules: &[u8], skip: F) -> Self {
+        TreeHasher {
+            base: base.to_string(),
+            rules: rules.to_vec(),
+            skip: skip,
+            hashes: HashMap::new(),
+            dir_rules: HashMap::new(),
+        }
+    }
+
+    /// The tree hash of `dir`, or None when it has to be scanned regardless.
+    pub fn hash(&mut self, dir: &Path) -> Option<Vec<u8>> {
+        let key = match dir.to_str() {
+            Some(dir) => get_key(&self.base, dir),
+            None => return None,
+        };
+        if let Some(hash) = self.hashes.get(&key) {
+            return hash.clone();
+        }
+        let hash = self.compute(dir).unwrap_or_else(|e| {
+            debug!("No tree hash for {:?}: {}", dir, e);
+            None
+        });
+        self.hashes.insert(key, hash.clone());
+        hash
+    }
+
+    /// Every tree hash worked out so far.
+    pub fn into_hashes(self) -> HashMap<String, Vec<u8>> {
+        self.hashes.into_iter().filter_map(|(key, hash)| hash.map(|hash| (key, hash))).collect()
+    }
+
+    /// Digest of `rules` and the ignore files of `dir` and each directory above it to `base`.
+    fn rules_of(&mut self, dir: &Path) -> IoResult<Vec<u8>> {
+        if let Some(rules) = self.dir_rules.get(dir) {
+            return Ok(rules.clone());
+        }
+        let mut hasher = Sha256::default();
+        match dir.parent() {
+            Some(parent) if dir != Path::new(&self.base) && parent.starts_with(&self.base) => {
+                hasher.input(&self.rules_of(parent)?)
+            }
+            _ => hasher.input(&self.rules),
+        }
+        let mut ignore = vec![];
+        match File::open(dir.join(IGNORE_FILE)).and_then(|mut f| f.read_to_end(&mut ignore)) {
+            Ok(_) => hasher.input(&ignore),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let rules = hasher.result().to_vec();
+        self.dir_rules.insert(dir.to_path_buf(), rules.clone());
+        Ok(rules)
+    }
+
+    fn compute(&mut self, dir: &Path) -> IoResult<Option<Vec<u8>>> {
+        let mut entries = vec![];
+        for entry in read_dir(dir)? {
+            entries.push(entry?);
+        }
+        entries.sort_by_key(|entry| entry.file_name());
+
+        // subdirectories are still hashed when a sibling has none, so they can be skipped
+        let mut complete = true;
+        let mut hasher = Sha256::default();
+        hasher.input(&self.rules_of(dir)?);
+        for entry in entries {
+            let path = entry.path();
+            if path.to_str().is_none() {
+                complete = false;
+                continue;
+            }
+            if (self.skip)(&path) {
+                continue;
+            }
+            let metadata = path.symlink_metadata()?;
+            let kind = if metadata.file_type().is_symlink() {
+                "l"
+            } else if metadata.is_dir() {
+                "d"
+            } else {
+                "f"
+            };
+            hasher.input(entry.file_name().as_bytes());
+            hasher.input(format!("\0{}\0{}\0{}\0{}.{}\0{}.{}\0",
+                                 kind,
+                                 metadata.size(),
+                                 metadata.mode(),
+                                 metadata.mtime(),
+                                 metadata.mtime_nsec(),
+                                 metadata.ctime(),
+                                 metadata.ctime_nsec())
+                .as_bytes());
+            if kind == "d" {
+                match self.hash(&path) {
+                    Some(hash) => hasher.input(&hash),
+                    None => complete = false,
+                }
+            }
+        }
+
+        match complete {
+            true => Ok(Some(hasher.result().to_vec())),
+            false => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[test]
+    fn changes_roll_up() {
+        let base = "target/test/tree_hash";
+        let _ = remove_dir_all(base);
+        create_dir_all(format!("{}/a/b", base)).unwrap();
+        create_dir_all(format!("{}/c", base)).unwrap();
+        File::create(format!("{}/a/b/file", base)).unwrap().write_all(b"one").unwrap();
+
+        let hashes = |skip: &Fn(&Path) -> bool| {
+            let mut hasher = TreeHasher::new(base, b"", |path: &Path| skip(path));
+            hasher.hash(Path::new(base));
+            hasher.into_hashes()
+        };
+        let before = hashes(&|_| false);
+        assert_eq!(4, before.len());
+        assert_eq!(before, hashes(&|_| false));
+
+        File::create(format!("{}/a/b/file", base)).unwrap().write_all(b"three").unwrap();
+        let after = hashes(&|_| false);
+        assert!(before[""] != after[""]);
+        assert!(before["a"] != after["a"]);
+        assert!(before["a/b"] != after["a/b"]);
+        assert_eq!(before["c"], after["c"]);
+
+        // changes under excluded paths are not seen
+        let excluded = hashes(&|path| path.ends_with("a"));
+        assert_eq!(2, excluded.len());
+        File::create(format!("{}/a/b/file", base)).unwrap().write_all(b"seven").unwrap();
+        assert_eq!(excluded, hashes(&|path| path.ends_with("a")));
+    }
+
+    #[test]
+    fn rules_change_hashes() {
+        let base = "target/test/tree_hash_rules";
+        let _ = remove_dir_all(base);
+        create_dir_all(format!("{}/a/b", base)).unwrap();
+
+        let hashes = |rules: &[u8]| {
+            let mut hasher = TreeHasher::new(base, rules, |_: &Path| false);
+            hasher.hash(Path::new(base));
+            hasher.into_hashes()
+        };
+        let before = hashes(b"");
+        assert!(before["a/b"] != hashes(b"includes")["a/b"]);
+
+        // an ignore file applies to the whole subtree below it
+        File::create(format!("{}/.haumaruignore", base)).unwrap().write_all(b"*.tmp").unwrap();
+        let ignoring = hashes(b"");
+        assert!(before["a/b"] != ignoring["a/b"]);
+        File::create(format!("{}/.haumaruignore", base)).unwrap().write_all(b"*.log").unwrap();
+        assert!(ignoring["a/b"] != hashes(b"")["a/b"]);
+    }
+
+    #[test]
+    fn not_utf8_has_no_hash() {
+        let base = "target/test/tree_hash_not_utf8";
+        let _ = remove_dir_all(base);
+        create_dir_all(Path::new(base).join("a").join(OsStr::from_bytes(b"\xff"))).unwrap();
+        create_dir_all(format!("{}/c", base)).unwrap();
+
+        let mut hasher = TreeHasher::new(base, b"", |_: &Path| false);
+        assert_eq!(None, hasher.hash(Path::new(base)));
+        let hashes = hasher.into_hashes();
+        assert_eq!(vec!["c"], hashes.keys().map(|k| k.as_str()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,386 @@
+//! A small read-only web UI the daemon serves when `web_listen` is set, for looking through
+//! backups from a browser. Pages are:
+//!
+//! - `/`: what the daemon is doing
+//! - `/snapshots`: the backup sets
+//! - `/browse/<key>?at=<set>`: a directory, as of a backup set or the latest
+//! - `/download/<key>?at=<set>`: a file's content
+//!
+//! There is no authentication, so it only listens on loopback.
+
+use std::error::Error as StdError;
+use std::fmt::Write as FmtWrite;
+use std::io::copy;
+use std::result::Result as StdResult;
+use std::str;
+use std::sync::Mutex;
+use std::thread;
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use time::{Timespec, at, strftime};
+
+use {Index, Node, Storage};
+use control::ControlService;
+use engine::DefaultEngine;
+use listing::ListOptions;
+use serve::loopback_only;
+use storage::retrieve_node;
+use units::format_bytes;
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Status,
+    Snapshots,
+    Browse(String, Option<u64>),
+    Download(String, Option<u64>),
+}
+
+fn route(method: &Method, uri: &str) -> Option<Route> {
+    if *method != Method::Get {
+        return None;
+    }
+    let mut parts = uri.splitn(2, '?');
+    let path = match percent_decode(parts.next().unwrap_or("")) {
+        Some(path) => path,
+        None => return None,
+    };
+    let mut at = None;
+    for pair in parts.next().unwrap_or("").split('&') {
+        if pair.starts_with("at=") {
+            match pair["at=".len()..].parse::<u64>() {
+                Ok(set) => at = Some(set),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Some(Route::Status);
+    }
+    if path == "snapshots" {
+        return Some(Route::Snapshots);
+    }
+    let mut parts = path.splitn(2, '/');
+    let page = parts.next().unwrap_or("");
+    let key = parts.next().unwrap_or("").trim_matches('/').to_string();
+    match page {
+        "browse" => Some(Route::Browse(key, at)),
+        "download" if !key.is_empty() => Some(Route::Download(key, at)),
+        _ => None,
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        if i + 2 >= bytes.len() {
+            return None;
+        }
+        let byte = str::from_utf8(&bytes[i + 1..i + 3])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) => out.push(byte),
+            None => return None,
+        }
+        i += 3;
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Encode `key` for a URL path, leaving its `/` separators alone.
+fn percent_encode(key: &str) -> String {
+    let mut out = String::new();
+    for b in key.bytes() {
+        match b {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            b => write!(out, "%{:02X}", b).expect("write"),
+        }
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn format_time(t: Timespec) -> String {
+    strftime("%Y-%m-%d %H:%M:%S", &at(t)).expect("time format")
+}
+
+fn at_query(set: Option<u64>) -> String {
+    set.map(|set| format!("?at={}", set)).unwrap_or(String::new())
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>haumaru: {}</title>\
+             </head>\n<body><p><a href=\"/\">status</a> | <a href=\"/snapshots\">snapshots</a> \
+             | <a href=\"/browse/\">browse</a></p>\n<h1>{}</h1>\n{}</body></html>\n",
+            escape(title),
+            escape(title),
+            body)
+}
+
+/// The file `key` as of backup set `set`, or None when it is not there then.
+fn find_file<I, S>(engine: &mut DefaultEngine<I, S>,
+                   key: &str,
+                   set: Option<u64>)
+                   -> StdResult<Option<Node>, Box<StdError>>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    let from = match set {
+        Some(set) => {
+            match engine.backup_set_at(set) {
+                Ok(from) => Some(from),
+                Err(_) => return Ok(None),
+            }
+        }
+        None => None,
+    };
+    Ok(engine.index
+        .get(key.to_string(), from)?
+        .and_then(|node| if node.is_file() && !node.deleted() { Some(node) } else { None }))
+}
+
+struct WebHandler<I, S>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    engine: Mutex<DefaultEngine<I, S>>,
+}
+
+impl<I, S> WebHandler<I, S>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    fn status(&self) -> StdResult<String, Box<StdError>> {
+        let status = self.engine.lock().unwrap().status()?;
+        Ok(page("status", &format!("<pre>{}</pre>\n", escape(&format!("{}", status)))))
+    }
+
+    fn snapshots(&self) -> StdResult<String, Box<StdError>> {
+        let mut engine = self.engine.lock().unwrap();
+        let mut body = "<table>\n<tr><th>set</th><th>taken</th><th>versions</th><th>size</th>\
+                        </tr>\n"
+            .to_string();
+        for set in engine.index.stats()?.backup_sets.into_iter().rev() {
+            write!(body,
+                   "<tr><td><a href=\"/browse/?at={}\">{}</a></td><td>{}</td><td>{}</td>\
+                    <td>{}</td></tr>\n",
+                   set.id,
+                   set.id,
+                   format_time(Timespec::new(set.at, 0)),
+                   set.versions,
+                   format_bytes(set.bytes))
+                .expect("write");
+        }
+        body.push_str("</table>\n");
+        Ok(page("snapshots", &body))
+    }
+
+    fn browse(&self, key: &str, set: Option<u64>) -> StdResult<String, Box<StdError>> {
+        let mut engine = self.engine.lock().unwrap();
+        let from = match set {
+            Some(set) => Some(engine.backup_set_at(set)?),
+            None => None,
+        };
+        let query = at_query(set);
+
+        let mut body = match set {
+            Some(set) => format!("<p>As of backup set {}</p>\n", set),
+            None => "<p>Latest</p>\n".to_string(),
+        };
+        body.push_str("<table>\n");
+        if !key.is_empty() {
+            let parent = key.rsplitn(2, '/').nth(1).unwrap_or("");
+            write!(body,
+                   "<tr><td><a href=\"/browse/{}{}\">../</a></td></tr>\n",
+                   percent_encode(parent),
+                   query)
+                .expect("write");
+        }
+        for entry in engine.ls_nodes(key, from, &ListOptions::new())? {
+            let node = entry.node;
+            let name = node.path().rsplit('/').next().unwrap_or("");
+            let (link, suffix, size) = if node.is_dir() {
+                ("browse", "/", String::new())
+            } else {
+                ("download", "", format_bytes(node.size()))
+            };
+            write!(body,
+                   "<tr><td><a href=\"/{}/{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                   link,
+                   percent_encode(node.path()),
+                   query,
+                   escape(name),
+                   suffix,
+                   size,
+                   format_time(*node.mtime()))
+                .expect("write");
+        }
+        body.push_str("</table>\n");
+        Ok(page(&format!("/{}", key), &body))
+    }
+
+    /// Stream the content of `key` to `res`. The engine is only locked while looking the file
+    /// up, so a slow download does not hold up the other pages.
+    fn download(&self, key: &str, set: Option<u64>, mut res: Response) {
+        let (node, storage) = {
+            let mut engine = self.engine.lock().unwrap();
+            match find_file(&mut engine, key, set) {
+                Ok(Some(node)) => (node, engine.storage.clone()),
+                Ok(None) => {
+                    *res.status_mut() = StatusCode::NotFound;
+                    return;
+                }
+                Err(e) => {
+                    error!("Failed to look up {}: {}", key, e);
+                    *res.status_mut() = StatusCode::InternalServerError;
+                    return;
+                }
+            }
+        };
+        let mut blob = match retrieve_node(&storage, &node) {
+            Ok(Some(blob)) => blob,
+            Ok(None) => {
+                error!("Unable to read {}, hash is missing from storage", key);
+                *res.status_mut() = StatusCode::InternalServerError;
+                return;
+            }
+            Err(e) => {
+                error!("Failed to read {}: {}", key, e);
+                *res.status_mut() = StatusCode::InternalServerError;
+                return;
+            }
+        };
+
+        let name = node.path().rsplit('/').next().unwrap_or("").replace('"', "");
+        res.headers_mut().set(ContentLength(node.size()));
+        res.headers_mut().set_raw("Content-Type", vec![b"application/octet-stream".to_vec()]);
+        res.headers_mut().set_raw("Content-Disposition",
+                                  vec![format!("attachment; filename=\"{}\"", name).into_bytes()]);
+        let mut out = match res.start() {
+            Ok(out) => out,
+            Err(e) => {
+                error!("Failed to respond: {}", e);
+                return;
+            }
+        };
+        match copy(&mut blob, &mut out) {
+            Ok(written) if written == blob.size() => {}
+            Ok(written) => {
+                error!("Failed sending {}: read {} of {} bytes", key, written, blob.size());
+                return;
+            }
+            Err(e) => {
+                error!("Failed sending {}: {}", key, e);
+                return;
+            }
+        }
+        if let Err(e) = out.end() {
+            error!("Failed sending {}: {}", key, e);
+        }
+    }
+}
+
+impl<I, S> Handler for WebHandler<I, S>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    fn handle(&self, req: Request, mut res: Response) {
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new(),
+        };
+        debug!("Web UI: {} {}", req.method, uri);
+
+        let result = match route(&req.method, &uri) {
+            None => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+            Some(Route::Status) => self.status(),
+            Some(Route::Snapshots) => self.snapshots(),
+            Some(Route::Browse(key, set)) => self.browse(&key, set),
+            Some(Route::Download(key, set)) => return self.download(&key, set, res),
+        };
+
+        let html = match result {
+            Ok(html) => html,
+            Err(e) => {
+                warn!("{} {} failed: {}", req.method, uri, e);
+                *res.status_mut() = StatusCode::NotFound;
+                page("not found", &format!("<p>{}</p>\n", escape(&format!("{}", e))))
+            }
+        };
+        res.headers_mut().set_raw("Content-Type", vec![b"text/html; charset=utf-8".to_vec()]);
+        if let Err(e) = res.send(html.as_bytes()) {
+            error!("Failed to respond: {}", e);
+        }
+    }
+}
+
+/// Serve the web UI for `engine` on `listen` from a background thread. `listen` must be a
+/// loopback address.
+pub fn serve<I, S>(listen: &str, engine: DefaultEngine<I, S>) -> StdResult<(), Box<StdError>>
+    where I: Index + Send + Clone + 'static,
+          S: Storage + 'static
+{
+    if !loopback_only(listen)? {
+        return Err(format!("the web UI has no authentication and only listens on loopback: {}",
+                           listen)
+            .into());
+    }
+    let server = Server::http(listen)?;
+    info!("Web UI listening on http://{}", listen);
+    let handler = WebHandler { engine: Mutex::new(engine) };
+    thread::spawn(move || {
+        // dropping the listener waits on the server threads, which run until the process stops
+        if let Err(e) = server.handle(handler) {
+            error!("Web UI failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Route, percent_decode, percent_encode, route};
+    use hyper::method::Method;
+
+    #[test]
+    fn routes() {
+        assert_eq!(Some(Route::Status), route(&Method::Get, "/"));
+        assert_eq!(Some(Route::Snapshots), route(&Method::Get, "/snapshots"));
+        assert_eq!(Some(Route::Browse(String::new(), None)),
+                   route(&Method::Get, "/browse/"));
+        assert_eq!(Some(Route::Browse("etc/my conf".to_string(), Some(4))),
+                   route(&Method::Get, "/browse/etc/my%20conf/?at=4"));
+        assert_eq!(Some(Route::Download("etc/hosts".to_string(), None)),
+                   route(&Method::Get, "/download/etc/hosts"));
+        assert_eq!(None, route(&Method::Get, "/download/"));
+        assert_eq!(None, route(&Method::Get, "/browse/?at=latest"));
+        assert_eq!(None, route(&Method::Post, "/snapshots"));
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!("etc/my%20conf%3F", percent_encode("etc/my conf?"));
+        assert_eq!(Some("etc/my conf?".to_string()), percent_decode("etc/my%20conf%3F"));
+        assert_eq!(None, percent_decode("bad%2"));
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
+use time;
+
+/// Time of day (local time) during which backup sets may be opened and uploads run. Windows
+/// may wrap past midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackupWindow {
+    start: u32,
+    end: u32,
+}
+
+impl BackupWindow {
+    /// Is the minute of the day (0 to 1439) inside the window.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        let now = time::now();
+        self.contains(now.tm_hour as u32 * 60 + now.tm_min as u32)
+    }
+
+    /// Block until the window opens.
+    pub fn wait_until_open(&self) {
+        if self.is_open() {
+            return;
+        }
+        info!("Outside backup window {}, waiting", self);
+        while !self.is_open() {
+            sleep(Duration::new(10, 0));
+        }
+        info!("Backup window {} open", self);
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let mut parts = s.trim().split(':');
+    let hour = parts.next().and_then(|h| h.parse::<u32>().ok());
+    let minute = parts.next().and_then(|m| m.parse::<u32>().ok());
+    match (hour, minute, parts.next()) {
+        (Some(h), Some(m), None) if h < 24 && m < 60 => Ok(h * 60 + m),
+        (Some(24), Some(0), None) => Ok(24 * 60),
+        _ => Err(format!("Invalid time of day: {}", s)),
+    }
+}
+
+impl FromStr for BackupWindow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.split('-');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(start), Some(end), None) => {
+                let start = parse_time(start)?;
+                let end = parse_time(end)?;
+                if start == end {
+                    return Err(format!("Empty backup window: {}", s));
+                }
+                Ok(BackupWindow {
+                    start: start,
+                    end: end,
+                })
+            }
+            _ => Err(format!("Invalid backup window (expected HH:MM-HH:MM): {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for BackupWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:02}:{:02}-{:02}:{:02}",
+               self.start / 60,
+               self.start % 60,
+               self.end / 60,
+               self.end % 60)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let window: BackupWindow = "01:00-06:30".parse().unwrap();
+        assert_eq!("01:00-06:30", format!("{}", window));
+        assert!("01:00".parse::<BackupWindow>().is_err());
+        assert!("1-6".parse::<BackupWindow>().is_err());
+        assert!("25:00-06:00".parse::<BackupWindow>().is_err());
+        assert!("06:00-06:00".parse::<BackupWindow>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let window: BackupWindow = "01:00-06:00".parse().unwrap();
+        assert!(!window.contains(59));
+        assert!(window.contains(60));
+        assert!(window.contains(359));
+        assert!(!window.contains(360));
+    }
+
+    #[test]
+    fn contains_over_midnight() {
+        let window: BackupWindow = "22:00-06:00".parse().unwrap();
+        assert!(window.contains(23 * 60));
+        assert!(window.contains(0));
+        assert!(window.contains(5 * 60));
+        assert!(!window.contains(12 * 60));
+    }
+}
@@ -0,0 +1,91 @@
+//! `haumaru estimate`: what a first backup of the configured path would hold, found by
+//! scanning it without uploading anything.
+
+use std::fmt;
+use units::format_bytes;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScanEstimate {
+    /// Files a backup would store.
+    pub files: u64,
+    pub bytes: u64,
+    pub dirs: u64,
+    /// Paths left out by excludes or transient patterns. An excluded directory counts once.
+    pub excluded: u64,
+    /// Files skipped for being over `max_file_size`.
+    pub over_limit: u64,
+    pub over_limit_bytes: u64,
+    /// Bytes per second the duration is projected at.
+    pub bandwidth: u64,
+    /// Seconds a first backup would take to upload at `bandwidth`.
+    pub duration_secs: u64,
+}
+
+impl ScanEstimate {
+    /// Set the bandwidth, in bytes per second, to project the upload time at.
+    pub fn at_bandwidth(mut self, bandwidth: u64) -> Self {
+        self.bandwidth = bandwidth;
+        self.duration_secs = if bandwidth == 0 {
+            0
+        } else {
+            (self.bytes + bandwidth - 1) / bandwidth
+        };
+        self
+    }
+}
+
+/// `secs` as days, hours, minutes and seconds, leaving out leading zero units.
+pub fn format_duration(secs: u64) -> String {
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, mins)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+impl fmt::Display for ScanEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "Files:       {} in {} dir(s), {}",
+                 self.files,
+                 self.dirs,
+                 format_bytes(self.bytes))?;
+        writeln!(f, "Excluded:    {} path(s)", self.excluded)?;
+        writeln!(f,
+                 "Over limit:  {} file(s), {}",
+                 self.over_limit,
+                 format_bytes(self.over_limit_bytes))?;
+        write!(f,
+               "First run:   {} at {}/s",
+               format_duration(self.duration_secs),
+               format_bytes(self.bandwidth))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!("45s", format_duration(45));
+        assert_eq!("2m 5s", format_duration(125));
+        assert_eq!("3h 0m", format_duration(3 * 3600 + 30));
+        assert_eq!("1d 2h 3m", format_duration(86400 + 2 * 3600 + 3 * 60));
+    }
+
+    #[test]
+    fn projected_duration() {
+        let estimate = ScanEstimate {
+            bytes: 10 * 1024 * 1024 + 1,
+            ..ScanEstimate::default()
+        };
+        assert_eq!(11, estimate.clone().at_bandwidth(1024 * 1024).duration_secs);
+        assert_eq!(0, estimate.at_bandwidth(0).duration_secs);
+    }
+}
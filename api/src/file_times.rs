@@ -0,0 +1,47 @@
+//! Setting file times, so restored files keep the mtime they were backed up with.
+
+use libc;
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use time::{Timespec, get_time};
+
+fn timeval(t: Timespec) -> libc::timeval {
+    libc::timeval {
+        tv_sec: t.sec as libc::time_t,
+        tv_usec: (t.nsec / 1000) as libc::suseconds_t,
+    }
+}
+
+/// Set the mtime of the file at `path` to `mtime`, and its atime to now.
+pub fn set_mtime(path: &Path, mtime: Timespec) -> IoResult<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+    let times = [timeval(get_time()), timeval(mtime)];
+    let rc = unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{File, metadata};
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+    use time::Timespec;
+
+    #[test]
+    fn mtime() {
+        let path = Path::new("target/file_times_mtime");
+        File::create(path).unwrap();
+        set_mtime(path, Timespec::new(1480000000, 0)).unwrap();
+        let modified = metadata(path).unwrap().modified().unwrap();
+        assert_eq!(1480000000, modified.duration_since(UNIX_EPOCH).unwrap().as_secs());
+        assert!(set_mtime(Path::new("target/does/not/exist"), Timespec::new(0, 0)).is_err());
+    }
+}
@@ -1,16 +1,18 @@
 mod watcher;
+mod source;
 
 use {Node, get_key};
-pub use filesystem::watcher::Change;
-pub use filesystem::watcher::Watcher;
+pub use filesystem::watcher::{Change, ChangeKind, add_change};
+pub use filesystem::watcher::{Watcher, WatcherError, root_of};
+pub use filesystem::source::{ChangeSource, ScriptStep, ScriptedChangeSource};
 use notify::Error as NotifyError;
 use notify::Event;
 use notify::RecommendedWatcher;
 use notify::Watcher as NotifyWatcher;
 use std::{fmt, fs, io};
 use std::error::Error;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::channel;
@@ -28,6 +30,15 @@ pub enum BackupPathError {
     ReadCtime(io::Error),
     Scan(String),
     UnknownFileType,
+    WatchLimit(PathBuf),
+}
+
+/// inotify reports running out of watches as ENOSPC.
+fn is_watch_limit(e: &NotifyError) -> bool {
+    match *e {
+        NotifyError::Io(ref e) => e.raw_os_error() == Some(28),
+        _ => false,
+    }
 }
 
 impl fmt::Display for BackupPathError {
@@ -46,6 +57,15 @@ impl fmt::Display for BackupPathError {
             BackupPathError::ReadCtime(ref e) => write!(f, "Unable to read ctime: {}", e).unwrap(),
             BackupPathError::Scan(ref e) => write!(f, "Failed to scan: {}", e).unwrap(),
             BackupPathError::UnknownFileType => write!(f, "Unknown file type").unwrap(),
+            BackupPathError::WatchLimit(ref p) => {
+                write!(f,
+                       "Ran out of inotify watches watching {:?}. Check the limit with `cat \
+                        /proc/sys/fs/inotify/max_user_watches` and raise it with `sudo sysctl \
+                        fs.inotify.max_user_watches=524288` (add to /etc/sysctl.conf to \
+                        persist)",
+                       p)
+                    .unwrap()
+            }
         }
         Ok(())
     }
@@ -53,6 +73,8 @@ impl fmt::Display for BackupPathError {
 
 pub struct BackupPath {
     path: String,
+    roots: Vec<PathBuf>,
+    unwatched: Vec<PathBuf>,
     watcher: RecommendedWatcher,
     rx: Option<Receiver<Event>>,
 }
@@ -65,13 +87,22 @@ impl BackupPath {
         debug!("Creating BackupPath on {}", path);
         let (tx, rx) = channel();
         Ok(BackupPath {
+            roots: vec![PathBuf::from(&path)],
+            unwatched: vec![],
             path: path,
             watcher: try!(NotifyWatcher::new(tx).map_err(|e| BackupPathError::CreateWatcher(e))),
             rx: Some(rx),
         })
     }
 
+    /// The node at `path`, keyed relative to the backup path whichever root it is under.
     pub fn get_file(&self, path: &Path) -> Result<Option<Node>> {
+        self.get_file_at(&self.path, path)
+    }
+
+    /// Like `get_file`, with the key taken relative to `root`, such as a snapshot of the backup
+    /// path.
+    pub fn get_file_at(&self, root: &str, path: &Path) -> Result<Option<Node>> {
         let metadata = match fs::metadata(path) {
             Ok(m) => m,
             Err(e) => {
@@ -104,26 +135,58 @@ impl BackupPath {
         let mtime = Timespec::new(mtime_secs as i64, 0);
 
         let mode = metadata.permissions().mode();
+        let (uid, gid) = (metadata.uid(), metadata.gid());
 
-        let key = get_key(&self.path, path.to_str().unwrap());
+        let key = get_key(root, path.to_str().unwrap());
         debug!("self.path = {}", self.path);
         debug!("get_file key = {}", key);
 
         if metadata.is_file() {
-            return Ok(Some(Node::new_file(key, mtime, metadata.len(), mode)));
+            let node = Node::new_file(key, mtime, metadata.len(), mode).with_owner(uid, gid);
+            if metadata.nlink() > 1 {
+                return Ok(Some(node.with_inode(metadata.dev(), metadata.ino())));
+            }
+            return Ok(Some(node));
         }
 
         if metadata.is_dir() {
-            return Ok(Some(Node::new_dir(key, mtime, mode)));
+            return Ok(Some(Node::new_dir(key, mtime, mode).with_owner(uid, gid)));
         }
 
         Err(BackupPathError::UnknownFileType)
     }
 
-    /// Take watcher
+    /// Watch `roots` under the primary path in place of all of it. Must be set before taking the
+    /// watcher.
+    pub fn set_roots(&mut self, roots: Vec<PathBuf>) {
+        self.roots = roots;
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Roots that could not be fully watched and must be scanned periodically instead.
+    pub fn unwatched(&self) -> &[PathBuf] {
+        &self.unwatched
+    }
+
+    /// Take watcher. Roots that exhaust the inotify watch limit are recorded as unwatched
+    /// rather than failing.
     pub fn watcher(&mut self) -> Result<Watcher> {
-        debug!("Starting watcher on {}", &self.path);
-        self.watcher.watch(&self.path).map_err(|e| BackupPathError::StartWatcher(e))?;
-        Ok(Watcher::new(self.rx.take().unwrap()))
+        self.unwatched.clear();
+        for root in &self.roots {
+            debug!("Starting watcher on {:?}", root);
+            match self.watcher.watch(root) {
+                Ok(()) => (),
+                Err(ref e) if is_watch_limit(e) => {
+                    warn!("{}", BackupPathError::WatchLimit(root.clone()));
+                    warn!("Falling back to periodic scans of {:?}", root);
+                    self.unwatched.push(root.clone());
+                }
+                Err(e) => return Err(BackupPathError::StartWatcher(e)),
+            }
+        }
+        Ok(Watcher::new(self.rx.take().unwrap(), self.roots.clone()))
     }
 }
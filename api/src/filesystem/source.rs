@@ -0,0 +1,92 @@
+use filesystem::watcher::{Change, Result, Watcher};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Anything that can drive the engine's run loop with changes. The loop finishes once the
+/// source returns.
+pub trait ChangeSource: Send {
+    /// Deliver changes to `f` until the source ends, returning the number delivered.
+    fn watch(&mut self, f: &mut FnMut(Change)) -> Result<u64>;
+}
+
+impl ChangeSource for Watcher {
+    fn watch(&mut self, f: &mut FnMut(Change)) -> Result<u64> {
+        Watcher::watch(self, |change| f(change))
+    }
+}
+
+pub enum ScriptStep {
+    Wait(Duration),
+    Emit(Change),
+    /// Run an action, e.g. to modify the filesystem before emitting a change for it.
+    Run(Box<FnMut() + Send>),
+}
+
+/// A `ChangeSource` playing back a fixed script, for driving the engine in tests.
+pub struct ScriptedChangeSource {
+    steps: Vec<ScriptStep>,
+}
+
+impl ScriptedChangeSource {
+    pub fn new() -> Self {
+        ScriptedChangeSource { steps: vec![] }
+    }
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.steps.push(ScriptStep::Wait(duration));
+        self
+    }
+    pub fn emit(mut self, change: Change) -> Self {
+        self.steps.push(ScriptStep::Emit(change));
+        self
+    }
+    pub fn run<F>(mut self, action: F) -> Self
+        where F: FnMut() + Send + 'static
+    {
+        self.steps.push(ScriptStep::Run(box action));
+        self
+    }
+}
+
+impl ChangeSource for ScriptedChangeSource {
+    fn watch(&mut self, f: &mut FnMut(Change)) -> Result<u64> {
+        let mut count = 0;
+        for step in self.steps.drain(..) {
+            match step {
+                ScriptStep::Wait(duration) => sleep(duration),
+                ScriptStep::Emit(change) => {
+                    f(change);
+                    count += 1;
+                }
+                ScriptStep::Run(mut action) => action(),
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use filesystem::Change;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    #[test]
+    fn plays_back_script() {
+        let ran = Arc::new(Mutex::new(false));
+        let ran_in_script = ran.clone();
+
+        let mut source = ScriptedChangeSource::new()
+            .run(move || *ran_in_script.lock().unwrap() = true)
+            .emit(Change::new(PathBuf::from("/a")))
+            .emit(Change::new(PathBuf::from("/b")));
+
+        let mut changes = vec![];
+        let count = source.watch(&mut |change| changes.push(change)).ok().unwrap();
+
+        assert_eq!(2, count);
+        assert!(*ran.lock().unwrap());
+        assert_eq!(vec![Change::new(PathBuf::from("/a")), Change::new(PathBuf::from("/b"))],
+                   changes);
+    }
+}
@@ -1,9 +1,17 @@
 use std::result::Result as StdResult;
-use std::sync::mpsc::{Receiver, RecvError};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError};
+use std::time::Duration;
 use notify::Event;
+use notify::op::{CHMOD, CREATE, RENAME};
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::fmt;
 
+/// How long to wait for the second half of a rename before treating the old path as moved out
+/// of the backup path.
+const RENAME_PAIR_TIMEOUT_MS: u64 = 500;
+
 pub type Result<T> = StdResult<T, WatcherError>;
 
 pub enum WatcherError {
@@ -19,19 +27,57 @@ impl fmt::Display for WatcherError {
     }
 }
 
+/// The deepest of `roots` containing `path`.
+pub fn root_of<'a>(roots: &'a [PathBuf], path: &Path) -> Option<&'a PathBuf> {
+    roots.iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+}
+
+/// Multiplexes the events of every watched root into a single stream of `Change`s, each tagged
+/// with the deepest root it falls under. The roots are the backup path itself, or the
+/// directories under it given to `BackupPath::set_roots`.
 pub struct Watcher {
     rx: Receiver<Event>,
+    roots: Vec<PathBuf>,
 }
 
 impl Watcher {
-    pub fn new(rx: Receiver<Event>) -> Self {
-        Watcher { rx: rx }
+    pub fn new(rx: Receiver<Event>, roots: Vec<PathBuf>) -> Self {
+        Watcher {
+            rx: rx,
+            roots: roots,
+        }
+    }
+    fn tag(&self, change: Change) -> Change {
+        match root_of(&self.roots, change.path()).cloned() {
+            Some(root) => change.with_root(root),
+            None => change,
+        }
     }
     pub fn watch<F>(&self, mut f: F) -> Result<u64>
         where F: FnMut(Change)
     {
+        // old path of a rename, waiting for the event with the new path
+        let mut pending_rename: Option<PathBuf> = None;
+
         loop {
-            let event = try!(self.rx.recv().map_err(|e| WatcherError::ChannelRecv(e)));
+            let event = if pending_rename.is_some() {
+                match self.rx.recv_timeout(Duration::from_millis(RENAME_PAIR_TIMEOUT_MS)) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let from = pending_rename.take().expect("pending rename");
+                        debug!("Unpaired rename of {:?}", from);
+                        f(self.tag(Change::new(from)));
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(WatcherError::ChannelRecv(RecvError));
+                    }
+                }
+            } else {
+                try!(self.rx.recv().map_err(|e| WatcherError::ChannelRecv(e)))
+            };
 
             let path = match event.path.as_ref() {
                 Some(p) => p,
@@ -51,21 +97,158 @@ impl Watcher {
 
             debug!("Received notify {:?}, {:?}", op.clone(), path);
 
-            f(Change::new(path.clone()));
+            let is_rename = op.contains(RENAME);
+
+            // a rename arrives as the old path (no longer on disk) followed by the new path
+            if let Some(from) = pending_rename.take() {
+                if (is_rename || op.contains(CREATE)) && path.exists() && *path != from {
+                    debug!("Detected rename {:?} -> {:?}", from, path);
+                    f(self.tag(Change::new(from.clone())));
+                    f(self.tag(Change::renamed(path.clone(), from)));
+                    continue;
+                }
+                f(self.tag(Change::new(from)));
+            }
+
+            if is_rename && !path.exists() {
+                pending_rename = Some(path.clone());
+                continue;
+            }
+
+            if *op == CHMOD {
+                f(self.tag(Change::metadata(path.clone())));
+            } else {
+                f(self.tag(Change::new(path.clone())));
+            }
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    /// Content or existence may have changed.
+    Content,
+    /// Only attributes (e.g. permissions) changed.
+    Metadata,
+}
+
+/// A change to `path`. Changes are equal when their paths are, so a set holds one change per
+/// path; use `add_change` to merge a later one into it.
+#[derive(Debug)]
 pub struct Change {
     path: PathBuf,
+    kind: ChangeKind,
+    renamed_from: Option<PathBuf>,
+    root: Option<PathBuf>,
 }
 
 impl Change {
     pub fn new(path: PathBuf) -> Self {
-        Change { path: path }
+        Change {
+            path: path,
+            kind: ChangeKind::Content,
+            renamed_from: None,
+            root: None,
+        }
+    }
+    /// Attribute-only change on `path`.
+    pub fn metadata(path: PathBuf) -> Self {
+        Change { kind: ChangeKind::Metadata, ..Change::new(path) }
+    }
+    /// `path` appeared by renaming `from`. A change for `from` is sent separately.
+    pub fn renamed(path: PathBuf, from: PathBuf) -> Self {
+        Change {
+            path: path,
+            kind: ChangeKind::Content,
+            renamed_from: Some(from),
+            root: None,
+        }
     }
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
+    /// Tag with the watched root the change came from.
+    pub fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+    pub fn renamed_from(&self) -> Option<&Path> {
+        self.renamed_from.as_ref().map(|p| p.as_path())
+    }
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_ref().map(|p| p.as_path())
+    }
+    /// Combine with a `later` change to the same path. Content changes outweigh attribute
+    /// ones, and a rename is remembered until a later one replaces it.
+    pub fn merge(self, later: Change) -> Change {
+        let kind = match (self.kind, later.kind) {
+            (ChangeKind::Metadata, ChangeKind::Metadata) => ChangeKind::Metadata,
+            _ => ChangeKind::Content,
+        };
+        Change {
+            path: later.path,
+            kind: kind,
+            renamed_from: later.renamed_from.or(self.renamed_from),
+            root: later.root.or(self.root),
+        }
+    }
+}
+
+impl PartialEq for Change {
+    fn eq(&self, other: &Change) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for Change {}
+
+impl Hash for Change {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
+/// Add `change` to `changes`, merged with the change already there for its path.
+pub fn add_change(changes: &mut HashSet<Change>, change: Change) {
+    let change = match changes.take(&change) {
+        Some(earlier) => earlier.merge(change),
+        None => change,
+    };
+    changes.insert(change);
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use super::*;
+
+    #[test]
+    fn root_of_deepest() {
+        let roots = vec![PathBuf::from("/home"),
+                         PathBuf::from("/home/a/work"),
+                         PathBuf::from("/etc")];
+        assert_eq!(Some(&roots[1]), root_of(&roots, Path::new("/home/a/work/x")));
+        assert_eq!(Some(&roots[0]), root_of(&roots, Path::new("/home/a/workshop")));
+        assert_eq!(Some(&roots[2]), root_of(&roots, Path::new("/etc")));
+        assert_eq!(None, root_of(&roots, Path::new("/var/log")));
+    }
+
+    #[test]
+    fn changes_merge_by_path() {
+        let mut changes = HashSet::new();
+        add_change(&mut changes, Change::metadata(PathBuf::from("/a")));
+        add_change(&mut changes, Change::metadata(PathBuf::from("/a")));
+        assert_eq!(1, changes.len());
+        assert_eq!(ChangeKind::Metadata, changes.iter().next().unwrap().kind());
+
+        add_change(&mut changes, Change::renamed(PathBuf::from("/a"), PathBuf::from("/b")));
+        add_change(&mut changes, Change::metadata(PathBuf::from("/a")));
+        assert_eq!(1, changes.len());
+        let change = changes.iter().next().unwrap();
+        assert_eq!(ChangeKind::Content, change.kind());
+        assert_eq!(Some(Path::new("/b")), change.renamed_from());
+    }
 }
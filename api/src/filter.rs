@@ -0,0 +1,149 @@
+use pattern::Pattern;
+use regex::Regex;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Built-in filter dropping lines that look like they assign a password, secret or token.
+pub const STRIP_SECRETS: &'static str = "@strip-secrets";
+
+/// Content of files matching `pattern` is piped through `command` before it is stored.
+/// `command` is run by `sh -c`, or is the name of a built-in filter starting with `@`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pattern: Pattern,
+    command: String,
+}
+
+impl Filter {
+    pub fn new(pattern: &str, command: &str) -> Self {
+        Filter {
+            pattern: Pattern::new(pattern),
+            command: command.to_string(),
+        }
+    }
+
+    /// Recorded on each node stored through this filter.
+    pub fn id(&self) -> &str {
+        &self.command
+    }
+
+    /// Whether the file at `key` goes through this filter. Matches on the key or file name.
+    pub fn matches(&self, key: &str) -> bool {
+        let name = Path::new(key).file_name().and_then(|n| n.to_str()).unwrap_or(key);
+        self.pattern.matches(key) || self.pattern.matches(name)
+    }
+
+    pub fn apply(&self, content: Vec<u8>) -> IoResult<Vec<u8>> {
+        match self.command.as_ref() {
+            STRIP_SECRETS => Ok(strip_secrets(&content)),
+            c if c.starts_with('@') => {
+                Err(IoError::new(ErrorKind::InvalidInput, format!("Unknown filter {}", c)))
+            }
+            c => run_command(c, content),
+        }
+    }
+}
+
+/// The first of `filters` that `key` goes through.
+pub fn filter_for<'a>(filters: &'a [Filter], key: &str) -> Option<&'a Filter> {
+    filters.iter().find(|f| f.matches(key))
+}
+
+/// Parse `pattern=command` pairs separated by `;`.
+pub fn parse_filters(s: &str) -> Result<Vec<Filter>, String> {
+    let mut filters = vec![];
+    for entry in s.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let pattern = parts.next().unwrap_or("").trim();
+        let command = parts.next().unwrap_or("").trim();
+        if pattern.is_empty() || command.is_empty() {
+            return Err(format!("Filter must be pattern=command: {}", entry));
+        }
+        if command.starts_with('@') && command != STRIP_SECRETS {
+            return Err(format!("Unknown filter {}", command));
+        }
+        filters.push(Filter::new(pattern, command));
+    }
+    Ok(filters)
+}
+
+fn strip_secrets(content: &[u8]) -> Vec<u8> {
+    lazy_static! {
+        static ref SECRET: Regex =
+            Regex::new(r"(?i)(password|passwd|secret|token|api_?key)\s*[=:]").unwrap();
+    }
+    let text = String::from_utf8_lossy(content);
+    let mut out = String::new();
+    for line in text.split('\n') {
+        if !SECRET.is_match(line) {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    if !text.ends_with('\n') {
+        out.pop();
+    }
+    out.into_bytes()
+}
+
+fn run_command(command: &str, content: Vec<u8>) -> IoResult<Vec<u8>> {
+    let mut child = Command::new("sh").arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    // written from another thread so a filter filling its stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().expect("filter stdin");
+    let writer = thread::spawn(move || stdin.write_all(&content));
+
+    let mut out = vec![];
+    child.stdout.take().expect("filter stdout").read_to_end(&mut out)?;
+    let status = child.wait()?;
+    writer.join().expect("filter writer")?;
+
+    if !status.success() {
+        return Err(IoError::new(ErrorKind::Other,
+                                format!("Filter {:?} failed: {}", command, status)));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let filters = parse_filters("*.sql=gzip -c; .env=@strip-secrets").unwrap();
+        assert_eq!(vec![Filter::new("*.sql", "gzip -c"), Filter::new(".env", STRIP_SECRETS)],
+                   filters);
+        assert!(parse_filters("*.sql").is_err());
+        assert!(parse_filters("*.sql=@nope").is_err());
+    }
+
+    #[test]
+    fn matching() {
+        let filters = parse_filters("*.sql=gzip -c; config/*=cat").unwrap();
+        assert_eq!(Some("gzip -c"), filter_for(&filters, "db/dump.sql").map(|f| f.id()));
+        assert_eq!(Some("cat"), filter_for(&filters, "config/app").map(|f| f.id()));
+        assert!(filter_for(&filters, "notes.txt").is_none());
+    }
+
+    #[test]
+    fn strip() {
+        let filter = Filter::new(".env", STRIP_SECRETS);
+        let out = filter.apply(b"USER=me\nPASSWORD=hunter2\napi_key: x\nend".to_vec()).unwrap();
+        assert_eq!(b"USER=me\nend".to_vec(), out);
+    }
+
+    #[test]
+    fn command() {
+        let filter = Filter::new("*", "tr a-z A-Z");
+        assert_eq!(b"ABC".to_vec(), filter.apply(b"abc".to_vec()).unwrap());
+        assert!(Filter::new("*", "exit 3").apply(vec![]).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Predicates for `haumaru find`.
+
+use listing::ListEntry;
+use pattern::Pattern;
+use time::{Timespec, strptime};
+use units::parse_bytes;
+
+/// What `find` matches. Every predicate given must hold.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FindQuery {
+    name: Option<String>,
+    larger_than: Option<u64>,
+    modified_after: Option<i64>,
+    deleted: bool,
+}
+
+impl FindQuery {
+    pub fn new() -> Self {
+        FindQuery::default()
+    }
+    /// Entries whose name, without their directory, matches the wildcard `pattern`.
+    pub fn with_name(mut self, pattern: &str) -> Self {
+        self.name = Some(pattern.to_string());
+        self
+    }
+    /// Files larger than a size such as "100M".
+    pub fn with_larger_than(mut self, size: &str) -> Result<Self, String> {
+        self.larger_than = Some(parse_bytes(size)?);
+        Ok(self)
+    }
+    /// Entries modified after a unix timestamp or a UTC `YYYY-MM-DD` date.
+    pub fn with_modified_after(mut self, time: &str) -> Result<Self, String> {
+        self.modified_after = Some(parse_time(time)?.sec);
+        Ok(self)
+    }
+    /// Only entries that have been deleted, as their last version.
+    pub fn with_deleted(mut self, deleted: bool) -> Self {
+        self.deleted = deleted;
+        self
+    }
+    pub fn deleted(&self) -> bool {
+        self.deleted
+    }
+
+    pub fn matches(&self, entry: &ListEntry) -> bool {
+        let node = &entry.node;
+        if self.deleted != entry.deleted_at.is_some() {
+            return false;
+        }
+        if let Some(ref name) = self.name {
+            let base = node.path().rsplit('/').next().unwrap_or("");
+            if !Pattern::new(name).matches(base) {
+                return false;
+            }
+        }
+        if let Some(size) = self.larger_than {
+            if !node.is_file() || node.size() <= size {
+                return false;
+            }
+        }
+        if let Some(after) = self.modified_after {
+            if node.mtime().sec <= after {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A unix timestamp, or a UTC `YYYY-MM-DD` date.
+pub fn parse_time(s: &str) -> Result<Timespec, String> {
+    if let Ok(sec) = s.parse::<i64>() {
+        return Ok(Timespec::new(sec, 0));
+    }
+    strptime(s, "%Y-%m-%d")
+        .map(|tm| tm.to_timespec())
+        .map_err(|_| format!("Expected a unix timestamp or YYYY-MM-DD: {}", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use listing::ListEntry;
+    use time::Timespec;
+    use Node;
+
+    fn entry(node: Node, deleted: bool) -> ListEntry {
+        ListEntry {
+            depth: 0,
+            node: node,
+            deleted_at: if deleted { Some(Timespec::new(1, 0)) } else { None },
+        }
+    }
+
+    #[test]
+    fn predicates() {
+        let psd = Node::new_file("art/cover.psd", Timespec::new(1480000000, 0), 200 << 20, 0o644);
+        let txt = Node::new_file("notes.txt", Timespec::new(10, 0), 10, 0o644);
+
+        let query = FindQuery::new().with_name("*.psd");
+        assert!(query.matches(&entry(psd.clone(), false)));
+        assert!(!query.matches(&entry(txt.clone(), false)));
+        assert!(!query.matches(&entry(psd.clone(), true)));
+
+        let query = FindQuery::new().with_larger_than("100M").unwrap();
+        assert!(query.matches(&entry(psd.clone(), false)));
+        assert!(!query.matches(&entry(txt.clone(), false)));
+
+        let query = FindQuery::new().with_modified_after("2016-01-01").unwrap();
+        assert!(query.matches(&entry(psd.clone(), false)));
+        assert!(!query.matches(&entry(txt.clone(), false)));
+
+        assert!(FindQuery::new().with_deleted(true).matches(&entry(txt, true)));
+        assert!(FindQuery::new().with_modified_after("last week").is_err());
+    }
+}
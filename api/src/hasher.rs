@@ -1,44 +1,401 @@
+use std::cmp::{max, min};
+use std::fmt;
 use std::io::Error as IoError;
 use std::io::Write;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
 
-use crypto::sha2::Sha256;
-use crypto::md5::Md5;
-use crypto::digest::Digest;
+use blake3;
+use digest::Digest;
+use md5::Md5;
+use sha2::Sha256;
 
+/// Content hash algorithm. Recorded against each node so blobs hashed with different
+/// algorithms can live side by side in the same index and store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    /// SHA256 over the SHA256s of fixed size chunks, so huge files can be hashed in parallel.
+    Sha256Tree,
+    /// BLAKE3 over the BLAKE3s of fixed size chunks.
+    Blake3Tree,
+}
+
+/// Chunk size of the tree algorithms. Part of their definition, so must never change.
+pub const TREE_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+impl HashAlgorithm {
+    /// Tag persisted in the index and used in the storage layout.
+    pub fn tag(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256Tree => "sha256-tree",
+            HashAlgorithm::Blake3Tree => "blake3-tree",
+        }
+    }
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag.to_lowercase().as_str() {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "sha256-tree" => Some(HashAlgorithm::Sha256Tree),
+            "blake3-tree" => Some(HashAlgorithm::Blake3Tree),
+            _ => None,
+        }
+    }
+    /// Short upper case label for listings.
+    pub fn label(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Blake3 => "BLAKE3",
+            HashAlgorithm::Sha256Tree => "SHA256T",
+            HashAlgorithm::Blake3Tree => "BLAKE3T",
+        }
+    }
+    /// Length in bytes of the digests produced.
+    pub fn digest_len(&self) -> usize {
+        match *self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha256Tree => 32,
+            HashAlgorithm::Blake3 | HashAlgorithm::Blake3Tree => 32,
+        }
+    }
+    /// The algorithm used for the chunks and root of a tree algorithm.
+    pub fn base(&self) -> HashAlgorithm {
+        match *self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha256Tree => HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3 | HashAlgorithm::Blake3Tree => HashAlgorithm::Blake3,
+        }
+    }
+    /// The tree variant of this algorithm.
+    pub fn tree(&self) -> HashAlgorithm {
+        match *self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha256Tree => HashAlgorithm::Sha256Tree,
+            HashAlgorithm::Blake3 | HashAlgorithm::Blake3Tree => HashAlgorithm::Blake3Tree,
+        }
+    }
+    pub fn is_tree(&self) -> bool {
+        self.tree() == *self
+    }
+    /// Create a fresh hasher for this algorithm.
+    pub fn hasher(&self) -> Box<ContentHasher> {
+        match *self {
+            HashAlgorithm::Sha256 => box Sha256Hasher { sha256: Sha256::default() },
+            HashAlgorithm::Blake3 => box Blake3Hasher { blake3: blake3::Hasher::new() },
+            HashAlgorithm::Sha256Tree |
+            HashAlgorithm::Blake3Tree => box TreeHasher::new(self.base(), TREE_CHUNK_SIZE),
+        }
+    }
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        HashAlgorithm::Sha256
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.tag())
+    }
+}
+
+/// A content hash implementation. New algorithms are added by implementing this trait and
+/// registering a `HashAlgorithm` variant for it.
+pub trait ContentHasher: Send {
+    fn algorithm(&self) -> HashAlgorithm;
+    fn input(&mut self, buf: &[u8]);
+    fn result(&mut self) -> Vec<u8>;
+}
+
+struct Sha256Hasher {
+    sha256: Sha256,
+}
+
+impl ContentHasher for Sha256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Sha256
+    }
+    fn input(&mut self, buf: &[u8]) {
+        self.sha256.input(buf);
+    }
+    fn result(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.sha256, Sha256::default()).result().to_vec()
+    }
+}
+
+struct Blake3Hasher {
+    blake3: blake3::Hasher,
+}
+
+impl ContentHasher for Blake3Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Blake3
+    }
+    fn input(&mut self, buf: &[u8]) {
+        self.blake3.update(buf);
+    }
+    fn result(&mut self) -> Vec<u8> {
+        self.blake3.finalize().as_bytes().to_vec()
+    }
+}
+
+/// Streaming implementation of the tree algorithms. Used when verifying, as the parallel
+/// version needs the whole content in memory.
+struct TreeHasher {
+    base: HashAlgorithm,
+    chunk_size: usize,
+    leaf: Box<ContentHasher>,
+    leaf_len: usize,
+    leaf_count: usize,
+    root: Box<ContentHasher>,
+}
+
+impl TreeHasher {
+    fn new(base: HashAlgorithm, chunk_size: usize) -> Self {
+        TreeHasher {
+            base: base,
+            chunk_size: chunk_size,
+            leaf: base.hasher(),
+            leaf_len: 0,
+            leaf_count: 0,
+            root: base.hasher(),
+        }
+    }
+    fn finish_leaf(&mut self) {
+        let mut leaf = mem::replace(&mut self.leaf, self.base.hasher());
+        self.root.input(&leaf.result());
+        self.leaf_len = 0;
+        self.leaf_count += 1;
+    }
+}
+
+impl ContentHasher for TreeHasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        self.base.tree()
+    }
+    fn input(&mut self, buf: &[u8]) {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let n = min(self.chunk_size - self.leaf_len, buf.len());
+            self.leaf.input(&buf[..n]);
+            self.leaf_len += n;
+            buf = &buf[n..];
+            if self.leaf_len == self.chunk_size {
+                self.finish_leaf();
+            }
+        }
+    }
+    fn result(&mut self) -> Vec<u8> {
+        // empty content still hashes a single empty leaf
+        if self.leaf_len > 0 || self.leaf_count == 0 {
+            self.finish_leaf();
+        }
+        self.root.result()
+    }
+}
+
+/// Hashes `data` with the tree variant of `algorithm`, spreading the chunks over `threads`
+/// threads while the MD5 is computed on another. Gives the same result as streaming the data
+/// through `Hasher::with_algorithm(algorithm.tree())`.
+pub fn parallel_hash(algorithm: HashAlgorithm,
+                     data: Arc<Vec<u8>>,
+                     threads: usize)
+                     -> (Vec<u8>, Vec<u8>) {
+    parallel_hash_chunked(algorithm, data, threads, TREE_CHUNK_SIZE)
+}
+
+fn parallel_hash_chunked(algorithm: HashAlgorithm,
+                         data: Arc<Vec<u8>>,
+                         threads: usize,
+                         chunk_size: usize)
+                         -> (Vec<u8>, Vec<u8>) {
+    let base = algorithm.base();
+
+    let md5_handle = {
+        let data = data.clone();
+        thread::spawn(move || {
+            let mut md5 = Md5::default();
+            md5.input(&data);
+            md5.result().to_vec()
+        })
+    };
+
+    let chunk_count = max(1, (data.len() + chunk_size - 1) / chunk_size);
+    let threads = max(1, min(threads, chunk_count));
+
+    let mut handles = vec![];
+    for t in 0..threads {
+        let data = data.clone();
+        handles.push(thread::spawn(move || {
+            let mut leaves = vec![];
+            let mut i = t;
+            while i < chunk_count {
+                let start = i * chunk_size;
+                let end = min(start + chunk_size, data.len());
+                let mut leaf = base.hasher();
+                leaf.input(&data[start..end]);
+                leaves.push((i, leaf.result()));
+                i += threads;
+            }
+            leaves
+        }));
+    }
+
+    let mut leaves = vec![];
+    for handle in handles {
+        leaves.extend(handle.join().expect("hash thread"));
+    }
+    leaves.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut root = base.hasher();
+    for (_, leaf) in leaves {
+        root.input(&leaf);
+    }
+
+    let md5 = md5_handle.join().expect("md5 thread");
+    (md5, root.result())
+}
+
+/// Computes the MD5 (needed for S3 Content-MD5) alongside the content hash.
 pub struct Hasher {
     md5: Md5,
-    sha256: Sha256,
+    content: Box<ContentHasher>,
 }
 
 impl Hasher {
     pub fn new() -> Self {
+        Self::with_algorithm(HashAlgorithm::Sha256)
+    }
+    pub fn with_algorithm(algorithm: HashAlgorithm) -> Self {
+        Self::with_content_hasher(algorithm.hasher())
+    }
+    pub fn with_content_hasher(content: Box<ContentHasher>) -> Self {
         Hasher {
-            md5: Md5::new(),
-            sha256: Sha256::new(),
+            md5: Md5::default(),
+            content: content,
         }
     }
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.content.algorithm()
+    }
+    /// Returns the MD5 and the content hash.
     pub fn result(&mut self) -> (Vec<u8>, Vec<u8>) {
-        let mut bytes = [0u8; 16];
-        self.md5.result(&mut bytes);
-        let mut md5_vec = Vec::with_capacity(32);
-        md5_vec.append(&mut bytes.to_vec());
-
-        let mut bytes = [0u8; 32];
-        self.sha256.result(&mut bytes);
-        let mut sha256_vec = Vec::with_capacity(32);
-        sha256_vec.append(&mut bytes.to_vec());
-
-        (md5_vec, sha256_vec)
+        let md5 = mem::replace(&mut self.md5, Md5::default()).result().to_vec();
+        (md5, self.content.result())
     }
 }
 
 impl Write for Hasher {
     fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
         self.md5.input(buf);
-        self.sha256.input(buf);
+        self.content.input(buf);
         Ok(buf.len())
     }
     fn flush(&mut self) -> Result<(), IoError> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::hex::ToHex;
+    use std::io::Write;
+    use std::sync::Arc;
+    use super::*;
+    use super::{TreeHasher, parallel_hash_chunked};
+    use test::Bencher;
+
+    fn hash_hex(algorithm: HashAlgorithm, content: &str) -> String {
+        let mut hasher = Hasher::with_algorithm(algorithm);
+        hasher.write_all(content.as_bytes()).expect("write_all");
+        let (_md5, hash) = hasher.result();
+        hash.to_hex()
+    }
+
+    #[test]
+    fn sha256_empty() {
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                   hash_hex(HashAlgorithm::Sha256, ""));
+    }
+
+    #[test]
+    fn blake3_empty() {
+        assert_eq!("af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+                   hash_hex(HashAlgorithm::Blake3, ""));
+    }
+
+    #[test]
+    fn hasher_reports_algorithm() {
+        for algorithm in &[HashAlgorithm::Sha256,
+                           HashAlgorithm::Blake3,
+                           HashAlgorithm::Sha256Tree,
+                           HashAlgorithm::Blake3Tree] {
+            let mut hasher = algorithm.hasher();
+            assert_eq!(*algorithm, hasher.algorithm());
+            assert_eq!(algorithm.digest_len(), hasher.result().len());
+        }
+    }
+
+    #[test]
+    fn parallel_matches_streaming_tree() {
+        let data: Vec<u8> = (0..10).collect();
+        for algorithm in &[HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            for threads in 1..4 {
+                let (md5, parallel) =
+                    parallel_hash_chunked(*algorithm, Arc::new(data.clone()), threads, 4);
+
+                let mut streaming = TreeHasher::new(*algorithm, 4);
+                streaming.input(&data[..3]);
+                streaming.input(&data[3..]);
+                assert_eq!(streaming.result(), parallel);
+
+                let mut hasher = Hasher::new();
+                hasher.write_all(&data).expect("write_all");
+                assert_eq!(hasher.result().0, md5);
+            }
+        }
+    }
+
+    #[test]
+    fn tree_of_empty_content() {
+        let (_md5, parallel) = parallel_hash_chunked(HashAlgorithm::Sha256, Arc::new(vec![]), 2, 4);
+        let mut streaming = TreeHasher::new(HashAlgorithm::Sha256, 4);
+        assert_eq!(streaming.result(), parallel);
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(Some(HashAlgorithm::Blake3), HashAlgorithm::from_tag("BLAKE3"));
+        assert_eq!(Some(HashAlgorithm::Sha256), HashAlgorithm::from_tag("sha256"));
+        assert_eq!(None, HashAlgorithm::from_tag("md5"));
+    }
+
+    fn bench_hasher(b: &mut Bencher, algorithm: HashAlgorithm) {
+        let data = vec![7u8; 1024 * 1024];
+        b.bytes = data.len() as u64;
+        b.iter(|| {
+            let mut hasher = Hasher::with_algorithm(algorithm);
+            hasher.write_all(&data).expect("write_all");
+            hasher.result()
+        });
+    }
+
+    #[bench]
+    fn bench_sha256(b: &mut Bencher) {
+        bench_hasher(b, HashAlgorithm::Sha256);
+    }
+
+    #[bench]
+    fn bench_blake3(b: &mut Bencher) {
+        bench_hasher(b, HashAlgorithm::Blake3);
+    }
+
+    #[bench]
+    fn bench_sha256_tree_parallel(b: &mut Bencher) {
+        let data = Arc::new(vec![7u8; 4 * 1024 * 1024]);
+        b.bytes = data.len() as u64;
+        b.iter(|| parallel_hash_chunked(HashAlgorithm::Sha256, data.clone(), 4, 1024 * 1024));
+    }
+}
@@ -1,3 +1,4 @@
+use std::mem::replace;
 use std::slice::Iter;
 
 use Node;
@@ -52,4 +53,8 @@ impl BackupSet {
     pub fn iter(&mut self) -> Iter<Node> {
         self.in_memory.iter()
     }
+    /// Hand over the records held so far, leaving the set open for more.
+    pub fn take(&mut self) -> Vec<Node> {
+        replace(&mut self.in_memory, vec![])
+    }
 }
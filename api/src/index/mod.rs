@@ -1,43 +1,130 @@
 
 use {Node, Record};
+use pattern::Glob;
+use retention::RetentionPolicy;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
+use std::path::Path;
 use time::Timespec;
 
 mod sql_light_index;
 mod backup_set;
+mod stats;
+mod summary;
+mod report;
+mod skipped;
+mod prune;
 pub use index::backup_set::{BackupSet, BackupSetController};
+pub use index::stats::{BackupSetStats, IndexStats};
+pub use index::summary::RunSummary;
+pub use index::report::{FileRetries, FileTiming, RunReport, StageTimes};
+pub use index::skipped::{SkipReason, SkippedPath};
+pub use index::prune::{BlobRef, Pruned, blobs_of};
 pub use index::sql_light_index::*;
 
 pub trait Index {
     fn get(&mut self, path: String, from: Option<Timespec>) -> Result<Option<Node>, IndexError>;
     fn list(&mut self, path: String, from: Option<Timespec>) -> Result<Vec<Node>, IndexError>;
+    /// Every recorded version of `path`, deletions included, oldest first.
+    fn versions(&mut self, path: String) -> Result<Vec<Node>, IndexError>;
+    /// The live files whose latest version was recorded with `inode`, as device and inode
+    /// number, ordered by path.
+    fn linked(&mut self, inode: (u64, u64)) -> Result<Vec<Node>, IndexError>;
     fn visit_all_hashable(&mut self,
-                          like: String,
+                          scope: &KeyScope,
                           f: &mut FnMut(Node) -> Result<(), IndexError>)
                           -> Result<(), IndexError>;
     fn insert(&mut self, Node) -> Result<(), IndexError>;
     fn create_backup_set(&mut self, timestamp: i64) -> Result<u64, IndexError>;
     fn close_backup_set(&mut self) -> Result<(), IndexError>;
+    /// Open the closed backup set `id` again, to add records to it.
+    fn reopen_backup_set(&mut self, id: u64) -> Result<(), IndexError>;
+    /// Persist what the open backup set holds so far, so it survives a crash before the set
+    /// is closed.
+    fn checkpoint(&mut self) -> Result<(), IndexError>;
+    /// Mark backup sets left open by a process that died as aborted, returning them. What
+    /// they checkpointed stays, as it was stored before the crash.
+    fn abort_open_sets(&mut self) -> Result<Vec<u64>, IndexError>;
+    /// Store what the run producing `backup_set` did.
+    fn set_summary(&mut self, backup_set: u64, summary: &RunSummary) -> Result<(), IndexError>;
+    /// Store where the time of the run producing `backup_set` went.
+    fn set_report(&mut self, backup_set: u64, report: &RunReport) -> Result<(), IndexError>;
+    fn report(&mut self, backup_set: u64) -> Result<Option<RunReport>, IndexError>;
+    /// Store the paths the run producing `backup_set` left out.
+    fn set_skipped(&mut self,
+                   backup_set: u64,
+                   skipped: &[SkippedPath])
+                   -> Result<(), IndexError>;
+    /// The paths left out of `backup_set`, ordered by path.
+    fn skipped(&mut self, backup_set: u64) -> Result<Vec<SkippedPath>, IndexError>;
+    /// The tree hash of each directory as of its last complete scan, keyed by path.
+    fn tree_hashes(&mut self) -> Result<HashMap<String, Vec<u8>>, IndexError>;
+    /// Replace the tree hashes of `root` and every directory below it with `hashes`.
+    fn set_tree_hashes(&mut self,
+                       root: &str,
+                       hashes: &HashMap<String, Vec<u8>>)
+                       -> Result<(), IndexError>;
+    /// The newest backup set holding any records, and its timestamp.
+    fn latest_backup_set(&mut self) -> Result<Option<(u64, i64)>, IndexError>;
+    fn stats(&mut self) -> Result<IndexStats, IndexError>;
+    /// Every file present as of `from`, or now.
+    fn files(&mut self, from: Option<Timespec>) -> Result<Vec<Node>, IndexError>;
+    /// Describe every stored record that cannot be read back. Other calls skip these.
+    fn corrupt_rows(&mut self) -> Result<Vec<String>, IndexError>;
+    /// Remove the versions `policy` no longer keeps as of `now`, and the backup sets and paths
+    /// left empty by that. The latest version of every live path is always kept.
+    fn prune(&mut self, policy: &RetentionPolicy, now: i64) -> Result<Pruned, IndexError>;
+    /// Every blob some version in the index references.
+    fn blobs(&mut self) -> Result<HashSet<BlobRef>, IndexError>;
+    /// Copy the index file to `dest` with no write landing part way through, so the copy can
+    /// be pushed while the index is in use. Returns false for an index kept in memory.
+    fn copy_to(&mut self, dest: &Path) -> Result<bool, IndexError>;
 
     fn dump(&self) -> Vec<Record>;
 }
 
+/// Which keys a walk over the index visits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyScope {
+    Glob(Glob),
+    /// Keys containing a raw SQL LIKE pattern, kept for `verify --like`.
+    Like(String),
+}
+
+impl KeyScope {
+    pub fn all() -> Self {
+        KeyScope::glob("")
+    }
+
+    pub fn glob(pattern: &str) -> Self {
+        KeyScope::Glob(Glob::new(pattern))
+    }
+
+    pub fn like(like: &str) -> Self {
+        KeyScope::Like(like.to_string())
+    }
+}
+
 #[derive(Debug)]
 pub enum IndexError {
     Fatal(String, Option<Box<IndexError>>),
+    /// A stored record holds values that cannot be read back.
+    CorruptRow(String),
 }
 
 impl Error for IndexError {
     fn description(&self) -> &str {
         match *self {
             IndexError::Fatal(ref _s, ref _e) => "Unrecoverable fatal error",
+            IndexError::CorruptRow(ref _s) => "Corrupt index row",
         }
     }
     fn cause(&self) -> Option<&Error> {
         match *self {
             IndexError::Fatal(ref _s, None) => None,
             IndexError::Fatal(ref _s, Some(ref e)) => Some(e.as_ref()),
+            IndexError::CorruptRow(ref _s) => None,
         }
     }
 }
@@ -47,6 +134,7 @@ impl fmt::Display for IndexError {
         match *self {
             IndexError::Fatal(ref s, None) => write!(f, "Fatal error: {}", s)?,
             IndexError::Fatal(ref s, Some(ref e)) => write!(f, "{}, caused by: {}", s, e)?,
+            IndexError::CorruptRow(ref s) => write!(f, "Corrupt index row: {}", s)?,
         }
         Ok(())
     }
@@ -0,0 +1,38 @@
+use {HashAlgorithm, Node};
+use std::fmt;
+
+/// A stored blob, by the algorithm it was hashed with and its hash.
+pub type BlobRef = (HashAlgorithm, Vec<u8>);
+
+/// The blobs the content of `node` lives in: its chunks when it was chunked, its whole hash
+/// otherwise.
+pub fn blobs_of(node: &Node) -> Vec<BlobRef> {
+    if !node.chunks().is_empty() {
+        return node.chunks().iter().map(|c| (c.algorithm, c.hash.clone())).collect();
+    }
+    match *node.hash() {
+        Some(ref hash) => vec![(node.hash_algorithm(), hash.clone())],
+        None => vec![],
+    }
+}
+
+/// What a prune took out of the index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pruned {
+    /// Versions removed.
+    pub versions: u64,
+    /// Backup sets left without any versions, removed with them.
+    pub backup_sets: u64,
+    /// Blobs only the removed versions referenced, which storage can let go of.
+    pub orphans: Vec<BlobRef>,
+}
+
+impl fmt::Display for Pruned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "versions={} backup_sets={} orphans={}",
+               self.versions,
+               self.backup_sets,
+               self.orphans.len())
+    }
+}
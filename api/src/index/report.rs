@@ -0,0 +1,146 @@
+use std::fmt;
+use units::format_bytes;
+
+/// Seconds of run time covered by each throughput sample.
+pub const THROUGHPUT_INTERVAL: u64 = 10;
+
+/// Uploads kept in `RunReport::slowest`.
+pub const SLOWEST_FILES: usize = 10;
+
+/// Where the time of a backup run went, stored with the backup set it produced.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct RunReport {
+    /// Bytes uploaded in each `THROUGHPUT_INTERVAL` seconds of the run.
+    pub throughput: Vec<u64>,
+    /// The longest uploads, slowest first.
+    pub slowest: Vec<FileTiming>,
+    /// Files that needed more than one send attempt.
+    pub retried: Vec<FileRetries>,
+    /// Time summed across the workers of each stage, so may exceed the run's wall time.
+    pub stages: StageTimes,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileTiming {
+    pub path: String,
+    pub bytes: u64,
+    pub ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileRetries {
+    pub path: String,
+    /// Failed attempts, including the last one if the file was given up on.
+    pub failures: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct StageTimes {
+    /// Walking the tree and comparing against the index.
+    pub scan_ms: u64,
+    /// Reading and hashing content.
+    pub read_ms: u64,
+    pub upload_ms: u64,
+    pub index_ms: u64,
+}
+
+impl RunReport {
+    /// Add an upload of `bytes` from `path`, finishing `at_secs` into the run.
+    pub fn uploaded(&mut self, path: &str, bytes: u64, ms: u64, at_secs: u64) {
+        let sample = (at_secs / THROUGHPUT_INTERVAL) as usize;
+        if self.throughput.len() <= sample {
+            self.throughput.resize(sample + 1, 0);
+        }
+        self.throughput[sample] += bytes;
+        self.stages.upload_ms += ms;
+
+        self.slowest.push(FileTiming {
+            path: path.to_string(),
+            bytes: bytes,
+            ms: ms,
+        });
+        self.slowest.sort_by(|a, b| b.ms.cmp(&a.ms));
+        self.slowest.truncate(SLOWEST_FILES);
+    }
+
+    pub fn record_retries(&mut self, path: &str, failures: u32) {
+        self.retried.push(FileRetries {
+            path: path.to_string(),
+            failures: failures,
+        });
+    }
+
+    /// Failed send attempts across every file.
+    pub fn retries(&self) -> u64 {
+        self.retried.iter().map(|r| r.failures as u64).sum()
+    }
+}
+
+fn secs(ms: u64) -> String {
+    format!("{:.1}s", ms as f64 / 1000.0)
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "Stages:     scan {}  read {}  upload {}  index {}",
+                 secs(self.stages.scan_ms),
+                 secs(self.stages.read_ms),
+                 secs(self.stages.upload_ms),
+                 secs(self.stages.index_ms))?;
+        write!(f,
+               "Retries:    {} over {} file(s)",
+               self.retries(),
+               self.retried.len())?;
+        for r in &self.retried {
+            write!(f, "\n  {:>4}  {}", r.failures, r.path)?;
+        }
+
+        if !self.throughput.is_empty() {
+            write!(f, "\n\nThroughput:")?;
+            for (i, bytes) in self.throughput.iter().enumerate() {
+                write!(f,
+                       "\n  {:>6}s  {:>10}/s",
+                       i as u64 * THROUGHPUT_INTERVAL,
+                       format_bytes(bytes / THROUGHPUT_INTERVAL))?;
+            }
+        }
+
+        if !self.slowest.is_empty() {
+            write!(f, "\n\nSlowest uploads:")?;
+            for t in &self.slowest {
+                write!(f, "\n  {:>8}  {:>10}  {}", secs(t.ms), format_bytes(t.bytes), t.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uploaded() {
+        let mut report = RunReport::default();
+        report.uploaded("a", 100, 50, 3);
+        report.uploaded("b", 200, 500, 25);
+        for i in 0..SLOWEST_FILES {
+            report.uploaded("c", 1, i as u64, 25);
+        }
+
+        assert_eq!(vec![100, 0, 210], report.throughput);
+        assert_eq!(SLOWEST_FILES, report.slowest.len());
+        assert_eq!("b", report.slowest[0].path);
+        assert_eq!("a", report.slowest[1].path);
+        assert_eq!(550 + 45, report.stages.upload_ms);
+    }
+
+    #[test]
+    fn retries() {
+        let mut report = RunReport::default();
+        report.record_retries("a", 2);
+        report.record_retries("b", 5);
+        assert_eq!(7, report.retries());
+    }
+}
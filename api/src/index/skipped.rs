@@ -0,0 +1,65 @@
+use std::fmt;
+
+/// Why a backup run left a path out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    Symlink,
+    /// Larger than `max_file_size`.
+    OverSize,
+    /// Under an exclude. Only the top of an excluded tree is recorded.
+    Excluded,
+    /// Named like the data stored with `haumaru put`.
+    Reserved,
+}
+
+impl SkipReason {
+    pub fn label(&self) -> &'static str {
+        match *self {
+            SkipReason::Symlink => "symlink",
+            SkipReason::OverSize => "over-size",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Reserved => "reserved",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<SkipReason> {
+        match label {
+            "symlink" => Some(SkipReason::Symlink),
+            "over-size" => Some(SkipReason::OverSize),
+            "excluded" => Some(SkipReason::Excluded),
+            "reserved" => Some(SkipReason::Reserved),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.label())
+    }
+}
+
+/// A path a backup run left out, stored with the backup set it produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedPath {
+    pub path: String,
+    pub reason: SkipReason,
+    /// Size on disk, when it was known.
+    pub size: Option<u64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        for reason in &[SkipReason::Symlink,
+                        SkipReason::OverSize,
+                        SkipReason::Excluded,
+                        SkipReason::Reserved] {
+            assert_eq!(Some(*reason), SkipReason::from_label(reason.label()));
+        }
+        assert_eq!(None, SkipReason::from_label("other"));
+    }
+}
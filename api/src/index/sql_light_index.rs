@@ -6,18 +6,30 @@
 //!
 //! `node` Table
 //! id(SERIAL), parent_id(INTEGER), path_id(INTEGER), type, mtime(INTEGER),
-//!     size, mode, deleted, hash
+//!     size, mode, deleted, hash, hash_algorithm, filter, inconsistent, capability, uid, gid,
+//!     dev, inode, chunks, chunk_algorithm, stored_size
+//!
+//! `skipped` Table
+//!  backup_set_id(INTEGER), path(TEXT), reason(TEXT), size
+//!
+//! `tree_hash` Table
+//!  path(TEXT), hash(BLOB)
 //!
 
 
-use {EngineConfig, Index, Node, NodeKind, Record};
-use index::{BackupSetController, IndexError};
+use {Chunk, EngineConfig, HashAlgorithm, Index, Node, NodeKind, Record};
+use index::{BackupSetController, BackupSetStats, BlobRef, IndexError, IndexStats, KeyScope,
+            Pruned, RunReport, RunSummary, SkipReason, SkippedPath, blobs_of};
+use retention::RetentionPolicy;
 use rusqlite::{CachedStatement, Connection, Row};
 use rusqlite::Error as SqlError;
 use rusqlite::types::Value;
+use serde_json;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::fmt;
+use std::fs::copy;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use time::Timespec;
@@ -55,7 +67,107 @@ static CREATE_TABLE_BACKUP_SET_SQL: &'static str = "
     at INTEGER NOT NULL
     )";
 
-static INSERT_BACKUP_SET_SQL: &'static str = "INSERT INTO backup_set (at) VALUES (?)";
+static INSERT_BACKUP_SET_SQL: &'static str = "
+    INSERT INTO backup_set (at, status) VALUES (?, 'open')";
+
+static CLOSE_BACKUP_SET_SQL: &'static str = "
+    UPDATE backup_set SET status = 'closed' WHERE id = ?";
+
+static REOPEN_BACKUP_SET_SQL: &'static str = "
+    UPDATE backup_set SET status = 'open' WHERE id = ?";
+
+static DATABASE_LIST_SQL: &'static str = "PRAGMA database_list";
+
+static OPEN_BACKUP_SETS_SQL: &'static str = "
+    SELECT id FROM backup_set WHERE status = 'open' ORDER BY id";
+
+static ABORT_BACKUP_SET_SQL: &'static str = "
+    UPDATE backup_set SET status = 'aborted' WHERE id = ?";
+
+static UPDATE_BACKUP_SET_SUMMARY_SQL: &'static str = "
+    UPDATE backup_set SET summary = ? WHERE id = ?";
+
+static UPDATE_BACKUP_SET_REPORT_SQL: &'static str = "
+    UPDATE backup_set SET report = ? WHERE id = ?";
+
+static SELECT_BACKUP_SET_REPORT_SQL: &'static str = "
+    SELECT report FROM backup_set WHERE id = ?";
+
+static CREATE_TABLE_SKIPPED_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS skipped (
+    backup_set_id INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    size BIGINT
+    )";
+
+static CREATE_INDEX_SKIPPED_BACKUP_SET_ID_SQL: &'static str = "
+    CREATE INDEX IF NOT EXISTS skipped_backup_set_id_index
+    ON skipped (backup_set_id);
+    ";
+
+static INSERT_SKIPPED_SQL: &'static str = "
+    INSERT INTO skipped (backup_set_id, path, reason, size) VALUES (?, ?, ?, ?)";
+
+static SELECT_SKIPPED_SQL: &'static str = "
+    SELECT path, reason, size FROM skipped WHERE backup_set_id = ? ORDER BY path";
+
+static CREATE_TABLE_TREE_HASH_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS tree_hash (
+    path TEXT PRIMARY KEY,
+    hash BLOB NOT NULL
+    )";
+
+static SELECT_TREE_HASHES_SQL: &'static str = "
+    SELECT path, hash FROM tree_hash";
+
+static DELETE_TREE_HASHES_SQL: &'static str = "
+    DELETE FROM tree_hash WHERE ? = '' OR path = ? OR path LIKE ? ESCAPE '\\'";
+
+static INSERT_TREE_HASH_SQL: &'static str = "
+    INSERT OR REPLACE INTO tree_hash (path, hash) VALUES (?, ?)";
+
+static LATEST_BACKUP_SET_SQL: &'static str = "
+    SELECT id, at FROM backup_set
+    WHERE EXISTS (SELECT 1 FROM node WHERE node.backup_set_id = backup_set.id)
+    ORDER BY id DESC
+    LIMIT 1";
+
+// a blob counts at the size it takes in storage when any version of it recorded that
+static STATS_TOTALS_SQL: &'static str = "
+    SELECT
+        (SELECT COUNT(DISTINCT path_id) FROM node WHERE kind = 'F') AS paths,
+        (SELECT COUNT(*) FROM node
+            WHERE kind = 'F' AND deleted = 0
+            AND id IN (SELECT MAX(id) FROM node GROUP BY path_id)) AS live_files,
+        (SELECT COUNT(*) FROM node WHERE hash IS NOT NULL) AS versions,
+        (SELECT COALESCE(SUM(size), 0) FROM node WHERE hash IS NOT NULL) AS logical_bytes,
+        (SELECT COUNT(*) FROM (
+            SELECT 1 FROM node WHERE hash IS NOT NULL GROUP BY hash, hash_algorithm)) AS blobs,
+        (SELECT COALESCE(SUM(size), 0) FROM (
+            SELECT COALESCE(MAX(stored_size), MAX(size)) AS size FROM node
+            WHERE hash IS NOT NULL
+            GROUP BY hash, hash_algorithm)) AS stored_bytes";
+
+static STATS_BACKUP_SETS_SQL: &'static str = "
+    SELECT backup_set.id AS id, backup_set.at AS at, backup_set.summary AS summary,
+        backup_set.status AS status, COUNT(node.id) AS versions,
+        COALESCE(SUM(node.size), 0) AS bytes
+    FROM backup_set
+    INNER JOIN node
+        ON node.backup_set_id = backup_set.id
+    WHERE node.hash IS NOT NULL
+    GROUP BY backup_set.id
+    ORDER BY backup_set.id ASC";
+
+static STATS_NEW_BYTES_SQL: &'static str = "
+    SELECT first_set, SUM(size) AS new_bytes
+    FROM (
+        SELECT MIN(backup_set_id) AS first_set, COALESCE(MAX(stored_size), MAX(size)) AS size
+        FROM node
+        WHERE hash IS NOT NULL
+        GROUP BY hash, hash_algorithm)
+    GROUP BY first_set";
 
 static CREATE_TABLE_PATH_SQL: &'static str = "
     CREATE TABLE IF NOT EXISTS path (
@@ -83,7 +195,18 @@ static CREATE_TABLE_NODE_SQL: &'static str = "
     size BIGINT,
     mode INTEGER,
     deleted BOOLEAN NOT NULL,
-    hash BLOB
+    hash BLOB,
+    hash_algorithm TEXT,
+    filter TEXT,
+    inconsistent BOOLEAN,
+    capability BLOB,
+    uid INTEGER,
+    gid INTEGER,
+    dev INTEGER,
+    inode INTEGER,
+    chunks BLOB,
+    chunk_algorithm TEXT,
+    stored_size BIGINT
     )";
 
 static CREATE_INDEX_NODE_PATH_ID_SQL: &'static str = "
@@ -101,17 +224,24 @@ static CREATE_INDEX_NODE_BACKUP_SET_ID_SQL: &'static str = "
     ON node (backup_set_id);
     ";
 
+static CREATE_INDEX_NODE_INODE_SQL: &'static str = "
+    CREATE INDEX IF NOT EXISTS node_inode_index
+    ON node (dev, inode);
+    ";
+
 static INSERT_NODE_SQL: &'static str = "
     INSERT INTO node
-    (backup_set_id, parent_id, path_id, kind, mtime, size, mode, deleted, hash)
-    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    (backup_set_id, parent_id, path_id, kind, mtime, size, mode, deleted, hash, hash_algorithm,
+        filter, inconsistent, capability, uid, gid, dev, inode, chunks, chunk_algorithm,
+        stored_size)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
 
 static GET_ALL_HASHABLE_QUERY_SQL: &'static str = "
     SELECT *
     FROM node
     INNER JOIN path
     ON path.id = node.path_id
-    WHERE node.hash is not null and path.path like ?
+    WHERE node.hash is not null and path.path like ? escape '\\'
     ORDER BY path.path, node.backup_set_id ASC";
 
 static GET_LATEST_QUERY_SQL: &'static str = "
@@ -135,9 +265,19 @@ static GET_FROM_QUERY_SQL: &'static str = "
     ORDER BY node.id DESC
     LIMIT 1";
 
+static VERSIONS_QUERY_SQL: &'static str = "
+    SELECT *
+    FROM node
+    INNER JOIN path
+    ON path.id = node.path_id
+    WHERE path.path = ?
+    ORDER BY node.id ASC";
+
 static LIST_LATEST_QUERY_SQL: &'static str = "
     SELECT node.id as id, path.path, backup_set_id, node.kind, node.mtime, node.size, node.mode,
-        node.deleted, node.hash
+        node.deleted, node.hash, node.hash_algorithm, node.filter, node.inconsistent,
+        node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
     FROM node
     INNER JOIN path
         ON path.id = node.path_id
@@ -152,7 +292,9 @@ static LIST_LATEST_QUERY_SQL: &'static str = "
 
 static LIST_FROM_QUERY_SQL: &'static str = "
     SELECT node.id as id, path.path, backup_set_id, node.kind, node.mtime, node.size, node.mode,
-        node.deleted, node.hash
+        node.deleted, node.hash, node.hash_algorithm, node.filter, node.inconsistent,
+        node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
     FROM node
     INNER JOIN path
         ON path.id = node.path_id
@@ -168,6 +310,53 @@ static LIST_FROM_QUERY_SQL: &'static str = "
     )
     ORDER BY path.path ASC";
 
+static FILES_LATEST_QUERY_SQL: &'static str = "
+    SELECT node.id as id, path.path, backup_set_id, node.kind, node.mtime, node.size, node.mode,
+        node.deleted, node.hash, node.hash_algorithm, node.filter, node.inconsistent,
+        node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
+    FROM node
+    INNER JOIN path
+        ON path.id = node.path_id
+    WHERE node.id IN (SELECT MAX(id) FROM node GROUP BY path_id)
+        AND node.kind = 'F'
+        AND node.deleted = 0
+    ORDER BY path.path ASC";
+
+static FILES_FROM_QUERY_SQL: &'static str = "
+    SELECT node.id as id, path.path, backup_set_id, node.kind, node.mtime, node.size, node.mode,
+        node.deleted, node.hash, node.hash_algorithm, node.filter, node.inconsistent,
+        node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
+    FROM node
+    INNER JOIN path
+        ON path.id = node.path_id
+    WHERE node.id IN (
+        SELECT MAX(node.id)
+        FROM node INNER JOIN backup_set
+            ON node.backup_set_id = backup_set.id
+        WHERE backup_set.at <= ?
+        GROUP BY path_id
+    )
+        AND node.kind = 'F'
+        AND node.deleted = 0
+    ORDER BY path.path ASC";
+
+static LINKED_QUERY_SQL: &'static str = "
+    SELECT node.id as id, path.path, backup_set_id, node.kind, node.mtime, node.size, node.mode,
+        node.deleted, node.hash, node.hash_algorithm, node.filter, node.inconsistent,
+        node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
+    FROM node
+    INNER JOIN path
+        ON path.id = node.path_id
+    WHERE node.dev = ?
+        AND node.inode = ?
+        AND node.deleted = 0
+        AND node.id = (SELECT MAX(latest.id) FROM node AS latest
+                       WHERE latest.path_id = node.path_id)
+    ORDER BY path.path ASC";
+
 static DUMP_NODES_QUERY_SQL: &'static str = "
     SELECT node.id as node_id, path.id as path_id,
     kind, path, mtime, size, mode, deleted, hash
@@ -176,6 +365,47 @@ static DUMP_NODES_QUERY_SQL: &'static str = "
     ON path.id = node.path_id
     ORDER BY path.path, node.id ASC";
 
+static ALL_NODES_QUERY_SQL: &'static str = "
+    SELECT node.id as node_id, path.path, backup_set_id, node.kind, node.mtime, node.size,
+        node.mode, node.deleted, node.hash, node.hash_algorithm, node.filter,
+        node.inconsistent, node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
+    FROM node
+    INNER JOIN path
+        ON path.id = node.path_id
+    ORDER BY node.id ASC";
+
+// versions of a path follow each other, oldest first. a set missing its row counts as now.
+static PRUNE_NODES_QUERY_SQL: &'static str = "
+    SELECT node.id as node_id, node.path_id, path.path, node.backup_set_id,
+        COALESCE(backup_set.at, ?) as at, node.kind, node.mtime, node.size, node.mode,
+        node.deleted, node.hash, node.hash_algorithm, node.filter, node.inconsistent,
+        node.capability, node.uid, node.gid, node.dev, node.inode, node.chunks,
+        node.chunk_algorithm, node.stored_size
+    FROM node
+    INNER JOIN path
+        ON path.id = node.path_id
+    LEFT JOIN backup_set
+        ON backup_set.id = node.backup_set_id
+    ORDER BY node.path_id, node.id ASC";
+
+static DELETE_NODE_SQL: &'static str = "
+    DELETE FROM node WHERE id = ?";
+
+static DELETE_EMPTY_BACKUP_SET_SQL: &'static str = "
+    DELETE FROM backup_set
+    WHERE id = ?
+        AND (status IS NULL OR status != 'open')
+        AND NOT EXISTS (SELECT 1 FROM node WHERE node.backup_set_id = backup_set.id)";
+
+static DELETE_BACKUP_SET_SKIPPED_SQL: &'static str = "
+    DELETE FROM skipped WHERE backup_set_id = ?";
+
+static DELETE_UNUSED_PATHS_SQL: &'static str = "
+    DELETE FROM path
+    WHERE NOT EXISTS (SELECT 1 FROM node WHERE node.path_id = path.id)
+        AND NOT EXISTS (SELECT 1 FROM node WHERE node.parent_id = path.id)";
+
 pub struct SqlLightIndex {
     conn: Arc<Mutex<Connection>>,
     controller: Arc<Mutex<BackupSetController>>,
@@ -204,6 +434,20 @@ impl SqlLightIndex {
         conn.execute(CREATE_TABLE_BACKUP_SET_SQL, &[])
             .map_err(|e| SqlLightIndexError::CreateTable("backup_set".to_string(), e))?;
 
+        add_column_if_missing(&conn, "backup_set", "summary", "TEXT")?;
+        add_column_if_missing(&conn, "backup_set", "report", "TEXT")?;
+        // open, closed or aborted; sets from before the column are all closed
+        add_column_if_missing(&conn, "backup_set", "status", "TEXT")?;
+
+        conn.execute(CREATE_TABLE_SKIPPED_SQL, &[])
+            .map_err(|e| SqlLightIndexError::CreateTable("skipped".to_string(), e))?;
+
+        conn.execute(CREATE_INDEX_SKIPPED_BACKUP_SET_ID_SQL, &[])
+            .map_err(|e| SqlLightIndexError::CreateTable("skipped_backup_set".to_string(), e))?;
+
+        conn.execute(CREATE_TABLE_TREE_HASH_SQL, &[])
+            .map_err(|e| SqlLightIndexError::CreateTable("tree_hash".to_string(), e))?;
+
         conn.execute(CREATE_TABLE_PATH_SQL, &[])
             .map_err(|e| SqlLightIndexError::CreateTable("path".to_string(), e))?;
 
@@ -213,6 +457,18 @@ impl SqlLightIndex {
         conn.execute(CREATE_TABLE_NODE_SQL, &[])
             .map_err(|e| SqlLightIndexError::CreateTable("node".to_string(), e))?;
 
+        add_column_if_missing(&conn, "node", "hash_algorithm", "TEXT")?;
+        add_column_if_missing(&conn, "node", "filter", "TEXT")?;
+        add_column_if_missing(&conn, "node", "inconsistent", "BOOLEAN")?;
+        add_column_if_missing(&conn, "node", "capability", "BLOB")?;
+        add_column_if_missing(&conn, "node", "uid", "INTEGER")?;
+        add_column_if_missing(&conn, "node", "gid", "INTEGER")?;
+        add_column_if_missing(&conn, "node", "dev", "INTEGER")?;
+        add_column_if_missing(&conn, "node", "inode", "INTEGER")?;
+        add_column_if_missing(&conn, "node", "chunks", "BLOB")?;
+        add_column_if_missing(&conn, "node", "chunk_algorithm", "TEXT")?;
+        add_column_if_missing(&conn, "node", "stored_size", "BIGINT")?;
+
         conn.execute(CREATE_INDEX_NODE_BACKUP_SET_ID_SQL, &[])
             .map_err(|e| SqlLightIndexError::CreateTable("node_backup_set".to_string(), e))?;
 
@@ -222,6 +478,9 @@ impl SqlLightIndex {
         conn.execute(CREATE_INDEX_NODE_PARENT_ID_SQL, &[])
             .map_err(|e| SqlLightIndexError::CreateTable("node_parent".to_string(), e))?;
 
+        conn.execute(CREATE_INDEX_NODE_INODE_SQL, &[])
+            .map_err(|e| SqlLightIndexError::CreateTable("node_inode".to_string(), e))?;
+
         Ok(SqlLightIndex {
             conn: Arc::new(Mutex::new(conn)),
             controller: Arc::new(Mutex::new(BackupSetController::new())),
@@ -357,7 +616,22 @@ impl SqlLightIndex {
 
             let mode = node.mode() as i64;
 
+            let hash_algorithm = node.hash().as_ref().map(|_| node.hash_algorithm().tag());
+
             let backup_set_id = node.backup_set().expect("node backup_set") as i64;
+            let filter = node.filter().map(|f| f.to_string());
+            let capability = node.capability().map(|c| c.to_vec());
+            let uid = node.owner().map(|(uid, _)| uid as i64);
+            let gid = node.owner().map(|(_, gid)| gid as i64);
+            let dev = node.inode().map(|(dev, _)| dev as i64);
+            let inode = node.inode().map(|(_, inode)| inode as i64);
+            let chunks = if node.is_chunked() {
+                Some(encode_chunks(node.chunks()))
+            } else {
+                None
+            };
+            let chunk_algorithm = node.chunks().first().map(|c| c.algorithm.tag());
+            let stored_size = node.stored_size().map(|s| s as i64);
 
             let conn = self.conn.lock().expect("conn lock");
             self.insert_node(&conn)
@@ -369,7 +643,18 @@ impl SqlLightIndex {
                            &size,
                            &mode,
                            &node.deleted(),
-                           node.hash()])
+                           node.hash(),
+                           &hash_algorithm,
+                           &filter,
+                           &node.inconsistent(),
+                           &capability,
+                           &uid,
+                           &gid,
+                           &dev,
+                           &inode,
+                           &chunks,
+                           &chunk_algorithm,
+                           &stored_size])
                 .map_err(|e| IndexError::Fatal(format!("Insert node query failed: {}", e), None))?;
         }
         Ok(())
@@ -382,39 +667,38 @@ impl SqlLightIndex {
 
         while let Some(row) = rows.next() {
             let row = row.unwrap();
-            let id = get_string_from_row(&row, "node_id");
-            let path = get_string_from_row(&row, "path");
-            let size = get_u64_from_row(&row, "size");
-            let mtime: u64 = get_u64_from_row(&row, "mtime");
-            let kind = get_string_from_row(&row, "kind");
-            let mode = get_u32_from_row(&row, "mode");
-            let deleted = get_bool_from_row(&row, "deleted");
-
-            println!("{} {} {} {} {} {} {}",
-                     id,
-                     path,
-                     size,
-                     mtime,
-                     kind,
-                     mode,
-                     deleted);
-
+            let id = get_string_from_row(&row, "node_id").unwrap_or_default();
+            match record_from_row(&row) {
+                Ok(r) => {
+                    let mtime = get_u64_from_row(&row, "mtime").unwrap_or_default();
+                    println!("{} {} {} {} {:?} {} {}",
+                             id,
+                             r.path,
+                             r.size,
+                             mtime,
+                             r.kind,
+                             r.mode,
+                             r.deleted);
+                }
+                Err(e) => println!("{} {}", id, e),
+            }
         }
     }
 }
 
 impl Index for SqlLightIndex {
     fn visit_all_hashable(&mut self,
-                          like: String,
+                          scope: &KeyScope,
                           f: &mut FnMut(Node) -> Result<(), IndexError>)
                           -> Result<(), IndexError> {
-        trace!("Listing all hashable");
-
-        let like = {
-            if like.is_empty() {
-                "%".to_owned()
-            } else {
-                format!("%{}%", like)
+        trace!("Listing all hashable in {:?}", scope);
+
+        // globs narrow the query down to their literal prefix, the rest is matched here
+        let (like, glob) = match *scope {
+            KeyScope::Like(ref like) if like.is_empty() => ("%".to_owned(), None),
+            KeyScope::Like(ref like) => (format!("%{}%", like), None),
+            KeyScope::Glob(ref glob) => {
+                (format!("{}%", escape_like(glob.literal_prefix())), Some(glob))
             }
         };
 
@@ -426,7 +710,11 @@ impl Index for SqlLightIndex {
         while let Some(row) = rows.next() {
             let row =
                 row.map_err(|e| IndexError::Fatal(format!("Failed to get next row: {}", e), None))?;
-            f(row.try_into()?)?;
+            if let Some(node) = node_or_skip(row)? {
+                if glob.map(|g| g.matches(node.path())).unwrap_or(true) {
+                    f(node)?;
+                }
+            }
         }
 
         Ok(())
@@ -452,12 +740,48 @@ impl Index for SqlLightIndex {
             debug!("No record found for key {:?}", path);
             return Ok(None);
         }
-        let row = row.unwrap().unwrap();
+        let row = row.unwrap()
+            .map_err(|e| IndexError::Fatal(format!("Failed to get {}: {}", path, e), None))?;
         let node: Node = row.try_into()?;
         node.validate();
         Ok(Some(node))
     }
 
+    fn versions(&mut self, path: String) -> Result<Vec<Node>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to get versions of {}: {}", path, e), None)
+        };
+
+        let mut stmt = conn.prepare_cached(VERSIONS_QUERY_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[&path]).map_err(&fail)?;
+        let mut versions = vec![];
+        while let Some(row) = rows.next() {
+            if let Some(node) = node_or_skip(row.map_err(&fail)?)? {
+                node.validate();
+                versions.push(node);
+            }
+        }
+        Ok(versions)
+    }
+
+    fn linked(&mut self, inode: (u64, u64)) -> Result<Vec<Node>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to get links to inode {:?}: {}", inode, e), None)
+        };
+
+        let mut stmt = conn.prepare_cached(LINKED_QUERY_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[&(inode.0 as i64), &(inode.1 as i64)]).map_err(&fail)?;
+        let mut linked = vec![];
+        while let Some(row) = rows.next() {
+            if let Some(node) = node_or_skip(row.map_err(&fail)?)? {
+                linked.push(node);
+            }
+        }
+        Ok(linked)
+    }
+
     fn create_backup_set(&mut self, timestamp: i64) -> Result<u64, IndexError> {
         let conn = self.conn.lock().expect("conn lock");
         let mut stmt = self.insert_backup_set(&conn);
@@ -487,11 +811,344 @@ impl Index for SqlLightIndex {
             self.persist(node)?;
         }
 
+        {
+            let conn = self.conn.lock().expect("conn lock");
+            conn.execute(CLOSE_BACKUP_SET_SQL, &[&(backup_set.index() as i64)])
+                .map_err(|e| {
+                    IndexError::Fatal(format!("Failed to close backup set {}: {}",
+                                              backup_set.index(),
+                                              e),
+                                      None)
+                })?;
+        }
+
         info!("Backup set {} closed", backup_set.index());
 
         Ok(())
     }
 
+    fn reopen_backup_set(&mut self, id: u64) -> Result<(), IndexError> {
+        {
+            let conn = self.conn.lock().expect("conn lock");
+            conn.execute(REOPEN_BACKUP_SET_SQL, &[&(id as i64)])
+                .map_err(|e| {
+                    IndexError::Fatal(format!("Failed to reopen backup set {}: {}", id, e), None)
+                })?;
+        }
+
+        let mut ctrl = self.controller.lock().expect("backup_set lock");
+        ctrl.open(id);
+
+        info!("Reopened backup set {}", id);
+
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<(), IndexError> {
+        let (index, nodes) = {
+            let mut ctrl = self.controller.lock().expect("backup_set lock");
+            match ctrl.get() {
+                Some(backup_set) => (backup_set.index(), backup_set.take()),
+                None => return Ok(()),
+            }
+        };
+
+        for node in &nodes {
+            self.persist(node)?;
+        }
+        info!("Checkpointed {} record(s) of backup set {}", nodes.len(), index);
+
+        Ok(())
+    }
+
+    fn abort_open_sets(&mut self) -> Result<Vec<u64>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to abort open backup sets: {}", e), None)
+        };
+        let mut open = vec![];
+        {
+            let mut stmt = conn.prepare(OPEN_BACKUP_SETS_SQL).map_err(&fail)?;
+            let mut rows = stmt.query(&[]).map_err(&fail)?;
+            while let Some(row) = rows.next() {
+                open.push(get_u64_from_row(&row.map_err(&fail)?, "id")?);
+            }
+        }
+        for id in &open {
+            conn.execute(ABORT_BACKUP_SET_SQL, &[&(*id as i64)]).map_err(&fail)?;
+        }
+        Ok(open)
+    }
+
+    fn set_summary(&mut self, backup_set: u64, summary: &RunSummary) -> Result<(), IndexError> {
+        let json = serde_json::to_string(summary)
+            .map_err(|e| IndexError::Fatal(format!("Failed to encode summary: {}", e), None))?;
+        let conn = self.conn.lock().expect("conn lock");
+        conn.execute(UPDATE_BACKUP_SET_SUMMARY_SQL, &[&json, &(backup_set as i64)])
+            .map_err(|e| {
+                IndexError::Fatal(format!("Failed to store summary of backup set {}: {}",
+                                          backup_set,
+                                          e),
+                                  None)
+            })?;
+        Ok(())
+    }
+
+    fn set_report(&mut self, backup_set: u64, report: &RunReport) -> Result<(), IndexError> {
+        let json = serde_json::to_string(report)
+            .map_err(|e| IndexError::Fatal(format!("Failed to encode report: {}", e), None))?;
+        let conn = self.conn.lock().expect("conn lock");
+        conn.execute(UPDATE_BACKUP_SET_REPORT_SQL, &[&json, &(backup_set as i64)])
+            .map_err(|e| {
+                IndexError::Fatal(format!("Failed to store report of backup set {}: {}",
+                                          backup_set,
+                                          e),
+                                  None)
+            })?;
+        Ok(())
+    }
+
+    fn report(&mut self, backup_set: u64) -> Result<Option<RunReport>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to get report of backup set {}: {}", backup_set, e),
+                              None)
+        };
+        let mut stmt = conn.prepare(SELECT_BACKUP_SET_REPORT_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[&(backup_set as i64)]).map_err(&fail)?;
+        let json = match rows.next() {
+            Some(row) => {
+                match row.map_err(&fail)?.get_checked("report") {
+                    Ok(Value::Text(json)) => json,
+                    _ => return Ok(None),
+                }
+            }
+            None => return Ok(None),
+        };
+        let report = serde_json::from_str(&json).map_err(|e| {
+                IndexError::Fatal(format!("Bad report for backup set {}: {}", backup_set, e),
+                                  None)
+            })?;
+        Ok(Some(report))
+    }
+
+    fn set_skipped(&mut self,
+                   backup_set: u64,
+                   skipped: &[SkippedPath])
+                   -> Result<(), IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to store skipped paths of backup set {}: {}",
+                                      backup_set,
+                                      e),
+                              None)
+        };
+        let mut stmt = conn.prepare_cached(INSERT_SKIPPED_SQL).map_err(&fail)?;
+        for skip in skipped {
+            let size = skip.size.map(|size| size as i64);
+            stmt.execute(&[&(backup_set as i64), &skip.path, &skip.reason.label(), &size])
+                .map_err(&fail)?;
+        }
+        Ok(())
+    }
+
+    fn skipped(&mut self, backup_set: u64) -> Result<Vec<SkippedPath>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to get skipped paths of backup set {}: {}",
+                                      backup_set,
+                                      e),
+                              None)
+        };
+        let mut stmt = conn.prepare(SELECT_SKIPPED_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[&(backup_set as i64)]).map_err(&fail)?;
+        let mut skipped = vec![];
+        while let Some(row) = rows.next() {
+            let row = row.map_err(&fail)?;
+            let reason = get_string_from_row(&row, "reason")?;
+            let reason = SkipReason::from_label(&reason)
+                .ok_or_else(|| IndexError::CorruptRow(format!("skip reason was {}", reason)))?;
+            let size = match get_value_from_row(&row, "size")? {
+                Value::Integer(i) => Some(i as u64),
+                _ => None,
+            };
+            skipped.push(SkippedPath {
+                path: get_string_from_row(&row, "path")?,
+                reason: reason,
+                size: size,
+            });
+        }
+        Ok(skipped)
+    }
+
+    fn tree_hashes(&mut self) -> Result<HashMap<String, Vec<u8>>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to get tree hashes: {}", e), None)
+        };
+        let mut stmt = conn.prepare(SELECT_TREE_HASHES_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[]).map_err(&fail)?;
+        let mut hashes = HashMap::new();
+        while let Some(row) = rows.next() {
+            let row = row.map_err(&fail)?;
+            let hash = match get_value_from_row(&row, "hash")? {
+                Value::Blob(hash) => hash,
+                n => return Err(IndexError::CorruptRow(format!("col hash was {:?}", n))),
+            };
+            hashes.insert(get_string_from_row(&row, "path")?, hash);
+        }
+        Ok(hashes)
+    }
+
+    fn set_tree_hashes(&mut self,
+                       root: &str,
+                       hashes: &HashMap<String, Vec<u8>>)
+                       -> Result<(), IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to store tree hashes under {:?}: {}", root, e),
+                              None)
+        };
+        let below = format!("{}/%", escape_like(root));
+        conn.execute(DELETE_TREE_HASHES_SQL, &[&root, &root, &below]).map_err(&fail)?;
+        let mut stmt = conn.prepare_cached(INSERT_TREE_HASH_SQL).map_err(&fail)?;
+        for (path, hash) in hashes {
+            stmt.execute(&[path, hash]).map_err(&fail)?;
+        }
+        Ok(())
+    }
+
+    fn latest_backup_set(&mut self) -> Result<Option<(u64, i64)>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let mut stmt = conn.prepare(LATEST_BACKUP_SET_SQL)
+            .map_err(|e| IndexError::Fatal(format!("latest_backup_set query: {}", e), None))?;
+        let mut rows = stmt.query(&[])
+            .map_err(|e| IndexError::Fatal(format!("Failed to get latest backup set: {}", e),
+                                           None))?;
+        let row = match rows.next() {
+            Some(row) => {
+                row.map_err(|e| {
+                        IndexError::Fatal(format!("Failed to get latest backup set: {}", e), None)
+                    })?
+            }
+            None => return Ok(None),
+        };
+        let latest = (get_u64_from_row(&row, "id")?, get_u64_from_row(&row, "at")? as i64);
+        Ok(Some(latest))
+    }
+
+    fn stats(&mut self) -> Result<IndexStats, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| IndexError::Fatal(format!("Failed to gather stats: {}", e), None);
+
+        let mut stats = IndexStats::default();
+        {
+            let mut stmt = conn.prepare(STATS_TOTALS_SQL).map_err(&fail)?;
+            let mut rows = stmt.query(&[]).map_err(&fail)?;
+            if let Some(row) = rows.next() {
+                let row = row.map_err(&fail)?;
+                stats.paths = get_u64_from_row(&row, "paths")?;
+                stats.live_files = get_u64_from_row(&row, "live_files")?;
+                stats.versions = get_u64_from_row(&row, "versions")?;
+                stats.logical_bytes = get_u64_from_row(&row, "logical_bytes")?;
+                stats.blobs = get_u64_from_row(&row, "blobs")?;
+                stats.stored_bytes = get_u64_from_row(&row, "stored_bytes")?;
+            }
+        }
+
+        let mut new_bytes = HashMap::new();
+        {
+            let mut stmt = conn.prepare(STATS_NEW_BYTES_SQL).map_err(&fail)?;
+            let mut rows = stmt.query(&[]).map_err(&fail)?;
+            while let Some(row) = rows.next() {
+                let row = row.map_err(&fail)?;
+                new_bytes.insert(get_u64_from_row(&row, "first_set")?,
+                                 get_u64_from_row(&row, "new_bytes")?);
+            }
+        }
+
+        let mut stmt = conn.prepare(STATS_BACKUP_SETS_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[]).map_err(&fail)?;
+        while let Some(row) = rows.next() {
+            let row = row.map_err(&fail)?;
+            let id = get_u64_from_row(&row, "id")?;
+            stats.backup_sets.push(BackupSetStats {
+                id: id,
+                at: get_u64_from_row(&row, "at")? as i64,
+                versions: get_u64_from_row(&row, "versions")?,
+                bytes: get_u64_from_row(&row, "bytes")?,
+                new_bytes: new_bytes.get(&id).cloned().unwrap_or(0),
+                summary: match row.get_checked("summary") {
+                    Ok(Value::Text(json)) => serde_json::from_str(&json).ok(),
+                    _ => None,
+                },
+                aborted: match row.get_checked("status") {
+                    Ok(Value::Text(status)) => status == "aborted",
+                    _ => false,
+                },
+            });
+        }
+
+        Ok(stats)
+    }
+
+    fn files(&mut self, from: Option<Timespec>) -> Result<Vec<Node>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+
+        let mut query;
+        let mut rows = match from {
+                None => {
+                    query = conn.prepare_cached(FILES_LATEST_QUERY_SQL).expect("files query");
+                    query.query(&[])
+                }
+                Some(t) => {
+                    query = conn.prepare_cached(FILES_FROM_QUERY_SQL).expect("files query");
+                    query.query(&[&t.sec])
+                }
+            }.map_err(|e| IndexError::Fatal(format!("Failed to list files: {}", e), None))?;
+
+        let mut v = vec![];
+        while let Some(row_result) = rows.next() {
+            let row = row_result.map_err(|e| {
+                    IndexError::Fatal(format!("Failed to get next row: {}", e), None)
+                })?;
+            if let Some(node) = node_or_skip(row)? {
+                node.validate();
+                v.push(node);
+            }
+        }
+
+        Ok(v)
+    }
+
+    fn copy_to(&mut self, dest: &Path) -> Result<bool, IndexError> {
+        // every clone writes through this connection, so none can while it is held
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| {
+            IndexError::Fatal(format!("Failed to find the index file: {}", e), None)
+        };
+        let file = {
+            let mut stmt = conn.prepare(DATABASE_LIST_SQL).map_err(&fail)?;
+            let mut rows = stmt.query(&[]).map_err(&fail)?;
+            match rows.next() {
+                Some(row) => {
+                    match row.map_err(&fail)?.get_checked(2) {
+                        Ok(Value::Text(file)) => file,
+                        _ => String::new(),
+                    }
+                }
+                None => String::new(),
+            }
+        };
+        if file.is_empty() {
+            return Ok(false);
+        }
+        copy(&file, dest).map_err(|e| {
+                IndexError::Fatal(format!("Failed to copy the index to {:?}: {}", dest, e), None)
+            })?;
+        Ok(true)
+    }
+
     fn dump(&self) -> Vec<Record> {
         let mut vec = vec![];
         let conn = self.conn.lock().expect("conn lock");
@@ -501,27 +1158,127 @@ impl Index for SqlLightIndex {
 
         while let Some(row) = rows.next() {
             let row = row.unwrap();
-            // let id = get_string_from_row(&row, "node_id");
-            let path = get_string_from_row(&row, "path");
-            let size = get_u64_from_row(&row, "size");
-            let kind = match get_string_from_row(&row, "kind").as_ref() {
-                "D" => NodeKind::Dir,
-                "F" => NodeKind::File,
-                n => panic!("Unknown kind: {:?}", n),
+            match record_from_row(&row) {
+                Ok(record) => vec.push(record),
+                Err(e) => warn!("Skipping row in dump: {}", e),
+            }
+        }
+
+        vec
+    }
+
+    fn corrupt_rows(&mut self) -> Result<Vec<String>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| IndexError::Fatal(format!("Failed to check rows: {}", e), None);
+
+        let mut stmt = conn.prepare(ALL_NODES_QUERY_SQL).map_err(&fail)?;
+        let mut rows = stmt.query(&[]).map_err(&fail)?;
+        let mut corrupt = vec![];
+        while let Some(row) = rows.next() {
+            let row = row.map_err(&fail)?;
+            let id = get_string_from_row(&row, "node_id")?;
+            match Node::try_from(row) {
+                Ok(_) => {}
+                Err(IndexError::CorruptRow(msg)) => corrupt.push(format!("node {}: {}", id, msg)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(corrupt)
+    }
+
+    fn prune(&mut self, policy: &RetentionPolicy, now: i64) -> Result<Pruned, IndexError> {
+        let mut pruned = Pruned::default();
+        if policy.is_keep_forever() {
+            return Ok(pruned);
+        }
+
+        // blobs of records still waiting in the open backup set are in use too
+        let mut kept = HashSet::new();
+        {
+            let mut controller = self.controller.lock().expect("backup_set lock");
+            if let Some(backup_set) = controller.get() {
+                for node in backup_set.iter() {
+                    kept.extend(blobs_of(node));
+                }
+            }
+        }
+
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| IndexError::Fatal(format!("Failed to prune: {}", e), None);
+
+        // node id, path id and backup set timestamp of every version
+        let mut versions: Vec<(u64, u64, i64, Node)> = vec![];
+        {
+            let mut stmt = conn.prepare(PRUNE_NODES_QUERY_SQL).map_err(&fail)?;
+            let mut rows = stmt.query(&[&now]).map_err(&fail)?;
+            while let Some(row) = rows.next() {
+                let row = row.map_err(&fail)?;
+                let id = get_u64_from_row(&row, "node_id")?;
+                let path_id = get_u64_from_row(&row, "path_id")?;
+                let at = get_u64_from_row(&row, "at")? as i64;
+                // a record that cannot be read may still hold blobs, so stop rather than guess
+                versions.push((id, path_id, at, Node::try_from(row)?));
+            }
+        }
+
+        let mut removed = vec![];
+        let mut released = HashSet::new();
+        for (i, &(id, path_id, at, ref node)) in versions.iter().enumerate() {
+            // when this version stopped being current, if it has
+            let superseded = match versions.get(i + 1) {
+                Some(&(_, next_path, next_at, _)) if next_path == path_id => Some(next_at),
+                _ if node.deleted() => Some(at),
+                _ => None,
             };
-            let mode = get_u32_from_row(&row, "mode");
-            let deleted = get_bool_from_row(&row, "deleted");
+            let expired = match (policy.age_for(node.path()), superseded) {
+                (Some(age), Some(superseded)) => superseded < now - age as i64,
+                _ => false,
+            };
+            if expired {
+                removed.push((id, node.backup_set().unwrap_or(0)));
+                released.extend(blobs_of(node));
+            } else {
+                kept.extend(blobs_of(node));
+            }
+        }
+        if removed.is_empty() {
+            return Ok(pruned);
+        }
 
-            vec.push(Record {
-                kind: kind,
-                path: path,
-                size: size,
-                mode: mode,
-                deleted: deleted,
-            });
+        conn.execute_batch("BEGIN").map_err(&fail)?;
+        match delete_pruned(&conn, &removed) {
+            Ok(backup_sets) => {
+                conn.execute_batch("COMMIT").map_err(&fail)?;
+                pruned.backup_sets = backup_sets;
+            }
+            Err(e) => {
+                let _ = conn.execute_batch("ROLLBACK");
+                return Err(fail(e));
+            }
         }
 
-        vec
+        pruned.versions = removed.len() as u64;
+        pruned.orphans = released.into_iter().filter(|b| !kept.contains(b)).collect();
+        pruned.orphans.sort_by(|a, b| a.1.cmp(&b.1));
+        info!("Pruned {}", pruned);
+        Ok(pruned)
+    }
+
+    fn blobs(&mut self) -> Result<HashSet<BlobRef>, IndexError> {
+        let conn = self.conn.lock().expect("conn lock");
+        let fail = |e: SqlError| IndexError::Fatal(format!("Failed to list blobs: {}", e), None);
+
+        let mut blobs = HashSet::new();
+        {
+            // the timestamp only stands in for sets without a row, which does not matter here
+            let mut stmt = conn.prepare(PRUNE_NODES_QUERY_SQL).map_err(&fail)?;
+            let mut rows = stmt.query(&[&0i64]).map_err(&fail)?;
+            while let Some(row) = rows.next() {
+                let row = row.map_err(&fail)?;
+                blobs.extend(blobs_of(&Node::try_from(row)?));
+            }
+        }
+        Ok(blobs)
     }
 
     fn list(&mut self, path: String, from: Option<Timespec>) -> Result<Vec<Node>, IndexError> {
@@ -542,10 +1299,13 @@ impl Index for SqlLightIndex {
 
         let mut v = vec![];
         while let Some(row_result) = rows.next() {
-            let row = row_result.unwrap();
-            let node: Node = row.try_into()?;
-            node.validate();
-            v.push(node);
+            let row = row_result.map_err(|e| {
+                    IndexError::Fatal(format!("Failed to get next row: {}", e), None)
+                })?;
+            if let Some(node) = node_or_skip(row)? {
+                node.validate();
+                v.push(node);
+            }
         }
 
         Ok(v)
@@ -556,34 +1316,39 @@ impl<'a, 'stmt> TryFrom<Row<'a, 'stmt>> for Node {
     type Err = IndexError;
 
     fn try_from(row: Row<'a, 'stmt>) -> Result<Self, Self::Err> {
-        let path_str: String = row.get("path");
+        let path_str = match get_value_from_row(&row, "path")? {
+            Value::Text(t) => t,
+            n => return Err(IndexError::CorruptRow(format!("Wrong type for path: {:?}", n))),
+        };
 
-        let mtime: i64 = match row.get_checked("mtime") {
-            Ok(Value::Integer(i)) => i,
-            Ok(n) => {
-                return Err(IndexError::Fatal(format!("Wrong type for mtime: {:?}", n), None));
-            }
-            Err(e) => {
-                error!("Unable to get mtime: {}", e);
-                return Err(IndexError::Fatal(format!("Unable to get mtime: {}", e), None));
+        let mtime: i64 = match get_value_from_row(&row, "mtime")? {
+            Value::Integer(i) => i,
+            n => {
+                return Err(IndexError::CorruptRow(format!("Wrong type for mtime of {}: {:?}",
+                                                          path_str,
+                                                          n)));
             }
         };
 
         // let id = get_u64_from_row(&row, "id");
-        let backup_set_id = get_u64_from_row(&row, "backup_set_id");
-        let size = get_u64_from_row(&row, "size");
-        let mode = get_u32_from_row(&row, "mode");
+        let backup_set_id = get_u64_from_row(&row, "backup_set_id")?;
+        let size = get_u64_from_row(&row, "size")?;
+        let mode = get_u32_from_row(&row, "mode")?;
 
-        let kind_char = get_string_from_row(&row, "kind");
+        let kind_char = get_string_from_row(&row, "kind")?;
 
         let mut node = match kind_char.as_ref() {
                 "F" => Node::new_file(path_str, Timespec::new(mtime, 0), size, mode),
                 "D" => Node::new_dir(path_str, Timespec::new(mtime, 0), mode),
-                k => return Err(IndexError::Fatal(format!("Unknown kind: {}", k), None)),
+                k => {
+                    return Err(IndexError::CorruptRow(format!("Unknown kind of {}: {}",
+                                                              path_str,
+                                                              k)))
+                }
             }
             .with_backup_set(backup_set_id);
 
-        let deleted = get_bool_from_row(&row, "deleted");
+        let deleted = get_bool_from_row(&row, "deleted")?;
         if deleted {
             node.set_deleted(true);
         }
@@ -595,8 +1360,116 @@ impl<'a, 'stmt> TryFrom<Row<'a, 'stmt>> for Node {
                 node = node.with_hash(b)
             }
             Value::Null => trace!("Hash is Null"),
+            v => return Err(IndexError::CorruptRow(format!("node.hash is not blob type: {:?}", v))),
+        }
+
+        match row.get_checked("hash_algorithm")
+            .map_err(|e| {
+                IndexError::Fatal(format!("Unable to get hash_algorithm from row: {}", e), None)
+            })? {
+            Value::Text(t) => {
+                match HashAlgorithm::from_tag(&t) {
+                    Some(a) => node.set_hash_algorithm(a),
+                    None => {
+                        return Err(IndexError::CorruptRow(format!("Unknown hash algorithm: {}",
+                                                                  t)))
+                    }
+                }
+            }
+            Value::Null => trace!("Hash algorithm is Null, assuming sha256"),
             v => {
-                return Err(IndexError::Fatal(format!("node.hash is not blob type: {:?}", v), None))
+                return Err(IndexError::CorruptRow(format!("node.hash_algorithm is not text \
+                                                           type: {:?}",
+                                                          v)))
+            }
+        }
+
+        match get_value_from_row(&row, "filter")? {
+            Value::Text(t) => node.set_filter(&t),
+            Value::Null => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.filter is not text type: {:?}", v)))
+            }
+        }
+
+        match get_value_from_row(&row, "inconsistent")? {
+            Value::Integer(i) => node.set_inconsistent(i == 1),
+            Value::Null => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.inconsistent is not integer \
+                                                           type: {:?}",
+                                                          v)))
+            }
+        }
+
+        match get_value_from_row(&row, "capability")? {
+            Value::Blob(b) => node.set_capability(Some(b)),
+            Value::Null => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.capability is not blob type: \
+                                                           {:?}",
+                                                          v)))
+            }
+        }
+
+        match (get_value_from_row(&row, "uid")?, get_value_from_row(&row, "gid")?) {
+            (Value::Integer(uid), Value::Integer(gid)) => {
+                node.set_owner(Some((uid as u32, gid as u32)))
+            }
+            (Value::Null, Value::Null) => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.uid and node.gid are not \
+                                                           integer type: {:?}",
+                                                          v)))
+            }
+        }
+
+        match (get_value_from_row(&row, "dev")?, get_value_from_row(&row, "inode")?) {
+            (Value::Integer(dev), Value::Integer(inode)) => {
+                node.set_inode(Some((dev as u64, inode as u64)))
+            }
+            (Value::Null, Value::Null) => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.dev and node.inode are not \
+                                                           integer type: {:?}",
+                                                          v)))
+            }
+        }
+
+        // chunks recorded before their algorithm was are SHA256
+        let chunk_algorithm = match get_value_from_row(&row, "chunk_algorithm")? {
+            Value::Text(t) => {
+                match HashAlgorithm::from_tag(&t) {
+                    Some(a) => a,
+                    None => {
+                        return Err(IndexError::CorruptRow(format!("Unknown chunk algorithm: {}",
+                                                                  t)))
+                    }
+                }
+            }
+            Value::Null => HashAlgorithm::Sha256,
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.chunk_algorithm is not text \
+                                                           type: {:?}",
+                                                          v)))
+            }
+        };
+
+        match get_value_from_row(&row, "chunks")? {
+            Value::Blob(b) => node.set_chunks(decode_chunks(chunk_algorithm, &b)?),
+            Value::Null => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.chunks is not blob type: {:?}", v)))
+            }
+        }
+
+        match get_value_from_row(&row, "stored_size")? {
+            Value::Integer(s) => node.set_stored_size(Some(s as u64)),
+            Value::Null => {}
+            v => {
+                return Err(IndexError::CorruptRow(format!("node.stored_size is not integer \
+                                                           type: {:?}",
+                                                          v)))
             }
         }
 
@@ -607,39 +1480,165 @@ impl<'a, 'stmt> TryFrom<Row<'a, 'stmt>> for Node {
     }
 }
 
-fn get_string_from_row(row: &Row, name: &str) -> String {
-    match row.get_checked(name) {
-        Ok(Value::Integer(i)) => i.to_string(),
-        Ok(Value::Text(t)) => t,
-        Ok(n) => format!("{:?}", n),
-        Err(e) => {
-            panic!(format!("Unable to get col {} from row: {:?}", name, e));
+/// Bytes each chunk takes in `node.chunks`: its hash, then its size as a big endian u64. The
+/// chunks of a file are all hashed with the algorithm in `node.chunk_algorithm`.
+const CHUNK_ENTRY_LEN: usize = 40;
+
+fn encode_chunks(chunks: &[Chunk]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(chunks.len() * CHUNK_ENTRY_LEN);
+    for chunk in chunks {
+        assert_eq!(chunks[0].algorithm, chunk.algorithm, "chunk algorithm");
+        encoded.extend_from_slice(&chunk.hash);
+        for shift in (0..8).rev() {
+            encoded.push((chunk.size >> (shift * 8)) as u8);
+        }
+    }
+    encoded
+}
+
+fn decode_chunks(algorithm: HashAlgorithm, encoded: &[u8]) -> Result<Vec<Chunk>, IndexError> {
+    if encoded.len() % CHUNK_ENTRY_LEN != 0 {
+        return Err(IndexError::CorruptRow(format!("node.chunks has {} bytes, not a multiple of {}",
+                                                  encoded.len(),
+                                                  CHUNK_ENTRY_LEN)));
+    }
+    Ok(encoded.chunks(CHUNK_ENTRY_LEN)
+        .map(|entry| {
+            Chunk {
+                algorithm: algorithm,
+                hash: entry[..32].to_vec(),
+                size: entry[32..].iter().fold(0, |size, b| size << 8 | *b as u64),
+            }
+        })
+        .collect())
+}
+
+/// Adds a column to an existing table, so indexes created by older versions pick up new
+/// fields without a rebuild.
+fn add_column_if_missing(conn: &Connection,
+                         table: &str,
+                         column: &str,
+                         definition: &str)
+                         -> Result<(), SqlLightIndexError> {
+    let exists = {
+        let sql = format!("PRAGMA table_info({})", table);
+        let mut stmt = conn.prepare(&sql)
+            .map_err(|e| SqlLightIndexError::CreateStatement(sql.clone(), e))?;
+        let mut rows = stmt.query(&[])
+            .map_err(|e| SqlLightIndexError::FailedStatement(sql.clone(), e))?;
+        let mut exists = false;
+        while let Some(row) = rows.next() {
+            let row = row.map_err(|e| SqlLightIndexError::FailedStatement(sql.clone(), e))?;
+            let name: String = row.get(1);
+            if name == column {
+                exists = true;
+            }
+        }
+        exists
+    };
+
+    if !exists {
+        info!("Adding column {}.{} to index", table, column);
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                     &[])
+            .map_err(|e| SqlLightIndexError::CreateTable(format!("{}.{}", table, column), e))?;
+    }
+    Ok(())
+}
+
+/// `s` with LIKE wildcards escaped, for queries using `escape '\\'`.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn record_from_row(row: &Row) -> Result<Record, IndexError> {
+    let kind = match get_string_from_row(row, "kind")?.as_ref() {
+        "D" => NodeKind::Dir,
+        "F" => NodeKind::File,
+        n => return Err(IndexError::CorruptRow(format!("Unknown kind: {:?}", n))),
+    };
+    Ok(Record {
+        kind: kind,
+        path: get_string_from_row(row, "path")?,
+        size: get_u64_from_row(row, "size")?,
+        mode: get_u32_from_row(row, "mode")?,
+        deleted: get_bool_from_row(row, "deleted")?,
+    })
+}
+
+/// Convert `row` to a node, or `None` with a warning if the row is corrupt.
+/// Delete the `removed` node rows, given with their backup sets, then the backup sets and
+/// paths they leave empty. Returns how many backup sets went.
+fn delete_pruned(conn: &Connection, removed: &[(u64, u64)]) -> Result<u64, SqlError> {
+    let mut backup_sets = HashSet::new();
+    {
+        let mut stmt = conn.prepare(DELETE_NODE_SQL)?;
+        for &(id, backup_set) in removed {
+            stmt.execute(&[&(id as i64)])?;
+            backup_sets.insert(backup_set);
+        }
+    }
+    let mut emptied = 0;
+    for backup_set in backup_sets {
+        if conn.execute(DELETE_EMPTY_BACKUP_SET_SQL, &[&(backup_set as i64)])? > 0 {
+            conn.execute(DELETE_BACKUP_SET_SKIPPED_SQL, &[&(backup_set as i64)])?;
+            emptied += 1;
+        }
+    }
+    conn.execute(DELETE_UNUSED_PATHS_SQL, &[])?;
+    Ok(emptied)
+}
+
+fn node_or_skip(row: Row) -> Result<Option<Node>, IndexError> {
+    match row.try_into() {
+        Ok(node) => Ok(Some(node)),
+        Err(IndexError::CorruptRow(msg)) => {
+            warn!("Skipping corrupt index row: {}", msg);
+            Ok(None)
         }
+        Err(e) => Err(e),
     }
 }
 
-fn get_u64_from_row(row: &Row, name: &str) -> u64 {
-    match row.get_checked(name) {
-        Ok(Value::Integer(i)) => i as u64,
-        Ok(Value::Null) => 0,
-        Ok(n) => panic!(format!("Unable to get col {}. Was {:?}", name, n)),
-        Err(e) => panic!(format!("Unable to get col {} from row: {:?}", name, e)),
+fn get_value_from_row(row: &Row, name: &str) -> Result<Value, IndexError> {
+    row.get_checked(name)
+        .map_err(|e| IndexError::Fatal(format!("Unable to get col {} from row: {}", name, e), None))
+}
+
+fn get_string_from_row(row: &Row, name: &str) -> Result<String, IndexError> {
+    match get_value_from_row(row, name)? {
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Text(t) => Ok(t),
+        n => Ok(format!("{:?}", n)),
+    }
+}
+
+fn get_u64_from_row(row: &Row, name: &str) -> Result<u64, IndexError> {
+    match get_value_from_row(row, name)? {
+        Value::Integer(i) => Ok(i as u64),
+        Value::Null => Ok(0),
+        n => Err(IndexError::CorruptRow(format!("col {} was {:?}", name, n))),
     }
 }
 
-fn get_u32_from_row(row: &Row, name: &str) -> u32 {
-    match row.get_checked(name) {
-        Ok(Value::Integer(i)) => i as u32,
-        Ok(n) => panic!(format!("Unable to get col {}. Was {:?}", name, n)),
-        Err(e) => panic!(format!("Unable to get col {} from row: {:?}", name, e)),
+fn get_u32_from_row(row: &Row, name: &str) -> Result<u32, IndexError> {
+    match get_value_from_row(row, name)? {
+        Value::Integer(i) => Ok(i as u32),
+        n => Err(IndexError::CorruptRow(format!("col {} was {:?}", name, n))),
     }
 }
 
-fn get_bool_from_row(row: &Row, name: &str) -> bool {
-    match row.get_checked(name) {
-        Ok(Value::Integer(i)) => i == 1,
-        Ok(n) => panic!(format!("Unable to get col {}. Was {:?}", name, n)),
-        Err(e) => panic!(format!("Unable to get col {} from row: {:?}", name, e)),
+fn get_bool_from_row(row: &Row, name: &str) -> Result<bool, IndexError> {
+    match get_value_from_row(row, name)? {
+        Value::Integer(i) => Ok(i == 1),
+        n => Err(IndexError::CorruptRow(format!("col {} was {:?}", name, n))),
     }
 }
 
@@ -647,7 +1646,9 @@ fn get_bool_from_row(row: &Row, name: &str) -> bool {
 mod test {
     extern crate env_logger;
 
-    use {Index, Node, NodeKind};
+    use {Chunk, HashAlgorithm, Index, Node, NodeKind};
+    use index::Pruned;
+    use retention::RetentionPolicy;
     use rusqlite::Connection;
     use super::*;
     use time::Timespec;
@@ -671,6 +1672,99 @@ mod test {
         index.insert(n).unwrap();
     }
 
+    #[test]
+    fn checkpoint() {
+        let mut index = index();
+        index.create_backup_set(0).expect("create_backup_set");
+
+        let n = Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+            .with_backup_set(1)
+            .with_hash(vec![1; 32]);
+        index.insert(n).unwrap();
+        assert!(index.get("a".to_string(), None).expect("ok").is_none());
+
+        index.checkpoint().expect("checkpoint");
+        assert_eq!(Some(1024),
+                   index.get("a".to_string(), None).expect("ok").map(|n| n.size()));
+
+        index.close_backup_set().expect("close");
+        assert_eq!(1, index.versions("a".to_string()).expect("versions").len());
+    }
+
+    #[test]
+    fn copy_to() {
+        use std::fs::{create_dir_all, remove_dir_all};
+        use std::path::Path;
+
+        let dir = Path::new("target/test/index_copy_to");
+        let _ = remove_dir_all(dir);
+        create_dir_all(dir).unwrap();
+        let mut index = SqlLightIndex::new(Connection::open(dir.join("haumaru.idx")).unwrap())
+            .unwrap();
+        index.create_backup_set(0).expect("create_backup_set");
+        index.insert(Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(1)
+                .with_hash(vec![1; 32]))
+            .unwrap();
+        index.close_backup_set().expect("close");
+
+        let copy = dir.join("copy.idx");
+        assert!(index.copy_to(&copy).expect("copy"));
+        let mut copied = SqlLightIndex::new(Connection::open(&copy).unwrap()).unwrap();
+        assert!(copied.get("a".to_string(), None).expect("ok").is_some());
+
+        // nothing to copy for an index in memory
+        assert!(!self::index().copy_to(&dir.join("memory.idx")).expect("copy"));
+    }
+
+    #[test]
+    fn reopen_backup_set() {
+        let mut index = index();
+        let bs = index.create_backup_set(0).expect("create_backup_set");
+        index.insert(Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(bs)
+                .with_hash(vec![1; 32]))
+            .unwrap();
+        index.close_backup_set().expect("close");
+
+        index.reopen_backup_set(bs).expect("reopen");
+        index.insert(Node::new_file("b", Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(bs)
+                .with_hash(vec![2; 32]))
+            .unwrap();
+        index.close_backup_set().expect("close again");
+
+        let b = index.get("b".to_string(), None).expect("ok").expect("b");
+        assert_eq!(Some(bs), b.backup_set());
+        assert_eq!(1, index.stats().expect("stats").backup_sets.len());
+        assert!(index.abort_open_sets().expect("abort").is_empty());
+    }
+
+    #[test]
+    fn abort_open_sets() {
+        let mut index = index();
+        index.create_backup_set(0).expect("create_backup_set");
+        index.insert(Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(1)
+                .with_hash(vec![1; 32]))
+            .unwrap();
+        index.close_backup_set().expect("close");
+        index.create_backup_set(10).expect("create_backup_set");
+        index.insert(Node::new_file("b", Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(2)
+                .with_hash(vec![2; 32]))
+            .unwrap();
+        index.checkpoint().expect("checkpoint");
+
+        // as if the process died here and a new one opened the index
+        assert_eq!(vec![2], index.abort_open_sets().expect("abort"));
+        assert_eq!(Vec::<u64>::new(), index.abort_open_sets().expect("abort again"));
+        let aborted: Vec<bool> =
+            index.stats().expect("stats").backup_sets.iter().map(|s| s.aborted).collect();
+        assert_eq!(vec![false, true], aborted);
+        assert!(index.get("b".to_string(), None).expect("ok").is_some());
+    }
+
     #[test]
     fn delete_file() {
         let mut index = index();
@@ -733,6 +1827,230 @@ mod test {
         assert_eq!(1024, n.size());
     }
 
+    #[test]
+    fn visit_glob() {
+        let mut index = index();
+        let bs = index.create_backup_set(0).expect("backup set");
+        for path in &["photos/2023/a.jpg", "photos/2023/b.png", "photos/2023_old/c.jpg", "x"] {
+            let n = Node::new_file(*path, Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(bs)
+                .with_hash(vec![0; 32]);
+            expect!(index.insert(n), "insert");
+        }
+        expect!(index.close_backup_set(), "close backup set");
+
+        let mut visit = |scope: KeyScope| {
+            let mut paths = vec![];
+            index.visit_all_hashable(&scope,
+                                    &mut |n| {
+                    paths.push(n.path().to_string());
+                    Ok(())
+                })
+                .unwrap();
+            paths
+        };
+        assert_eq!(vec!["photos/2023/a.jpg", "photos/2023/b.png"],
+                   visit(KeyScope::glob("photos/2023/**")));
+        assert_eq!(vec!["photos/2023/a.jpg"], visit(KeyScope::glob("photos/2023/*.jpg")));
+        assert_eq!(vec!["photos/2023_old/c.jpg"], visit(KeyScope::glob("photos/2023_old")));
+        assert_eq!(4, visit(KeyScope::all()).len());
+        assert_eq!(vec!["photos/2023/b.png"], visit(KeyScope::like("png")));
+    }
+
+    #[test]
+    fn latest_backup_set() {
+        let mut index = index();
+        assert_eq!(None, index.latest_backup_set().unwrap());
+
+        let bs = index.create_backup_set(600).expect("backup set");
+        let n = Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+            .with_backup_set(bs)
+            .with_hash(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
+                            20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31]);
+        expect!(index.insert(n), "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        // empty sets are skipped
+        index.create_backup_set(700).expect("empty backup set");
+        expect!(index.close_backup_set(), "close empty backup set");
+
+        assert_eq!(Some((bs, 600)), index.latest_backup_set().unwrap());
+    }
+
+    #[test]
+    fn stats() {
+        let mut index = index();
+        let hash = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+                        21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+
+        let bs_a = index.create_backup_set(600).expect("bs_a");
+        expect!(index.insert(Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+                    .with_backup_set(bs_a)
+                    .with_hash(hash.clone())),
+                "insert a");
+        expect!(index.close_backup_set(), "close bs_a");
+
+        // same content under another name is deduplicated
+        let bs_b = index.create_backup_set(700).expect("bs_b");
+        expect!(index.insert(Node::new_file("b", Timespec::new(10, 0), 1024, 500)
+                    .with_backup_set(bs_b)
+                    .with_hash(hash.clone())),
+                "insert b");
+        // compressed content counts at the size it was stored at
+        let mut c = Node::new_file("c", Timespec::new(10, 0), 4096, 500)
+            .with_backup_set(bs_b)
+            .with_hash(vec![1; 32]);
+        c.set_stored_size(Some(100));
+        expect!(index.insert(c), "insert c");
+        expect!(index.close_backup_set(), "close bs_b");
+
+        let stats = index.stats().unwrap();
+        assert_eq!(3, stats.paths);
+        assert_eq!(3, stats.live_files);
+        assert_eq!(3, stats.versions);
+        assert_eq!(6144, stats.logical_bytes);
+        assert_eq!(2, stats.blobs);
+        assert_eq!(1124, stats.stored_bytes);
+        assert_eq!(2, stats.backup_sets.len());
+        assert_eq!(1024, stats.backup_sets[0].new_bytes);
+        assert_eq!(100, stats.backup_sets[1].new_bytes);
+        assert_eq!(None, stats.backup_sets[0].summary);
+    }
+
+    #[test]
+    fn prune() {
+        let mut index = index();
+        let file = |path: &str, bs: u64, hash: u8| {
+            Node::new_file(path, Timespec::new(10, 0), 1024, 500)
+                .with_backup_set(bs)
+                .with_hash(vec![hash; 32])
+        };
+
+        let bs_a = index.create_backup_set(0).expect("bs_a");
+        expect!(index.insert(file("a", bs_a, 1)), "insert a");
+        expect!(index.insert(file("b", bs_a, 2)), "insert b");
+        expect!(index.close_backup_set(), "close bs_a");
+
+        // c keeps the content of the first a in use
+        let bs_b = index.create_backup_set(100).expect("bs_b");
+        expect!(index.insert(file("a", bs_b, 3)), "insert a again");
+        expect!(index.insert(file("c", bs_b, 1)), "insert c");
+        expect!(index.close_backup_set(), "close bs_b");
+
+        let bs_c = index.create_backup_set(200).expect("bs_c");
+        expect!(index.insert(file("b", bs_c, 2).as_deleted()), "delete b");
+        expect!(index.close_backup_set(), "close bs_c");
+
+        let forever = RetentionPolicy::new();
+        assert_eq!(Pruned::default(), index.prune(&forever, 300).expect("prune forever"));
+        let long = RetentionPolicy::new().with_age(1000);
+        assert_eq!(Pruned::default(), index.prune(&long, 300).expect("prune long"));
+
+        let short = RetentionPolicy::new().with_age(50);
+        let pruned = index.prune(&short, 300).expect("prune");
+        assert_eq!(3, pruned.versions);
+        assert_eq!(2, pruned.backup_sets);
+        assert_eq!(vec![(HashAlgorithm::Sha256, vec![2; 32])], pruned.orphans);
+
+        let mut blobs: Vec<_> = index.blobs().expect("blobs").into_iter().collect();
+        blobs.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(vec![(HashAlgorithm::Sha256, vec![1; 32]),
+                        (HashAlgorithm::Sha256, vec![3; 32])],
+                   blobs);
+
+        assert_eq!(1, index.versions("a".to_string()).expect("versions a").len());
+        assert!(index.versions("b".to_string()).expect("versions b").is_empty());
+        assert!(index.get("c".to_string(), None).expect("get c").is_some());
+        assert_eq!(1, index.stats().expect("stats").backup_sets.len());
+        assert_eq!(Pruned::default(), index.prune(&short, 300).expect("prune again"));
+    }
+
+    #[test]
+    fn set_summary() {
+        let mut index = index();
+        let bs = index.create_backup_set(600).expect("bs");
+        expect!(index.insert(Node::new_file("a", Timespec::new(10, 0), 1024, 500)
+                    .with_backup_set(bs)
+                    .with_hash(vec![0; 32])),
+                "insert a");
+        expect!(index.close_backup_set(), "close bs");
+
+        let summary = RunSummary {
+            scanned: 3,
+            new: 1,
+            bytes_read: 1024,
+            bytes_uploaded: 1024,
+            ..RunSummary::default()
+        };
+        expect!(index.set_summary(bs, &summary), "set summary");
+
+        let stats = index.stats().unwrap();
+        assert_eq!(Some(summary), stats.backup_sets[0].summary);
+    }
+
+    #[test]
+    fn set_report() {
+        let mut index = index();
+        let bs = index.create_backup_set(600).expect("bs");
+        expect!(index.close_backup_set(), "close bs");
+        assert_eq!(None, index.report(bs).unwrap());
+
+        let mut report = RunReport::default();
+        report.uploaded("a", 1024, 20, 0);
+        report.record_retries("a", 1);
+        expect!(index.set_report(bs, &report), "set report");
+
+        assert_eq!(Some(report), index.report(bs).unwrap());
+    }
+
+    #[test]
+    fn set_skipped() {
+        let mut index = index();
+        let bs = index.create_backup_set(600).expect("bs");
+        expect!(index.close_backup_set(), "close bs");
+        assert_eq!(Vec::<SkippedPath>::new(), index.skipped(bs).unwrap());
+
+        let skipped = vec![SkippedPath {
+                               path: "b/big.iso".to_string(),
+                               reason: SkipReason::OverSize,
+                               size: Some(1 << 32),
+                           },
+                           SkippedPath {
+                               path: "a/link".to_string(),
+                               reason: SkipReason::Symlink,
+                               size: None,
+                           }];
+        expect!(index.set_skipped(bs, &skipped), "set skipped");
+
+        assert_eq!(vec![skipped[1].clone(), skipped[0].clone()],
+                   index.skipped(bs).unwrap());
+        assert!(index.skipped(bs + 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn set_tree_hashes() {
+        let mut index = index();
+        assert!(index.tree_hashes().unwrap().is_empty());
+
+        let mut hashes = HashMap::new();
+        hashes.insert("".to_string(), vec![1; 32]);
+        hashes.insert("a".to_string(), vec![2; 32]);
+        hashes.insert("a/b".to_string(), vec![3; 32]);
+        hashes.insert("ab".to_string(), vec![4; 32]);
+        expect!(index.set_tree_hashes("", &hashes), "set all");
+        assert_eq!(hashes, index.tree_hashes().unwrap());
+
+        // rescanning a subtree replaces only the hashes under it
+        let mut a = HashMap::new();
+        a.insert("a".to_string(), vec![5; 32]);
+        expect!(index.set_tree_hashes("a", &a), "set a");
+        let stored = index.tree_hashes().unwrap();
+        assert_eq!(3, stored.len());
+        assert_eq!(Some(&vec![5; 32]), stored.get("a"));
+        assert_eq!(None, stored.get("a/b"));
+        assert_eq!(Some(&vec![4; 32]), stored.get("ab"));
+    }
+
     #[test]
     fn get_file_from() {
         let mut index = index();
@@ -892,4 +2210,193 @@ mod test {
         assert_eq!(expected, list);
     }
 
+    #[test]
+    fn versions() {
+        let mut index = index();
+        let file = Node::new_file("a", Timespec::new(10, 0), 3, 420).with_hash(vec![7; 32]);
+
+        let set = index.create_backup_set(0).expect("backup set");
+        expect!(index.insert(file.clone().with_backup_set(set)), "insert");
+        expect!(index.close_backup_set(), "close backup set");
+        let set = index.create_backup_set(100).expect("backup set");
+        expect!(index.insert(file.as_deleted().with_backup_set(set)), "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let versions = index.versions("a".to_string()).expect("versions");
+        assert_eq!(2, versions.len());
+        assert!(!versions[0].deleted());
+        assert_eq!(3, versions[0].size());
+        assert!(versions[1].deleted());
+        assert!(index.versions("b".to_string()).expect("versions").is_empty());
+    }
+
+    #[test]
+    fn filter_is_stored() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        let n = Node::new_file("dump.sql", Timespec::new(10, 0), 3, 500)
+            .with_backup_set(5)
+            .with_filter("gzip -c")
+            .with_hash(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
+                            20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31]);
+        expect!(index.insert(n), "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let n = index.get("dump.sql".to_string(), None).unwrap().unwrap();
+        assert_eq!(Some("gzip -c"), n.filter());
+    }
+
+    #[test]
+    fn inconsistent_is_stored() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        let mut n = Node::new_file("growing.log", Timespec::new(10, 0), 3, 500)
+            .with_backup_set(5)
+            .with_hash(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
+                            20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31]);
+        n.set_inconsistent(true);
+        expect!(index.insert(n), "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let n = index.get("growing.log".to_string(), None).unwrap().unwrap();
+        assert!(n.inconsistent());
+        assert!(index.list("".to_string(), None).unwrap()[0].inconsistent());
+    }
+
+    #[test]
+    fn capability_is_stored() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        let mut n = Node::new_file("ping", Timespec::new(10, 0), 3, 0o755)
+            .with_backup_set(5)
+            .with_hash(vec![1; 32]);
+        n.set_capability(Some(vec![1, 0, 0, 2, 0, 32, 0, 0]));
+        expect!(index.insert(n), "insert");
+        expect!(index.insert(Node::new_file("plain", Timespec::new(10, 0), 3, 0o644)
+                    .with_backup_set(5)
+                    .with_hash(vec![2; 32])),
+                "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let n = index.get("ping".to_string(), None).unwrap().unwrap();
+        assert_eq!(Some(&[1, 0, 0, 2, 0, 32, 0, 0][..]), n.capability());
+        let n = index.get("plain".to_string(), None).unwrap().unwrap();
+        assert_eq!(None, n.capability());
+    }
+
+    #[test]
+    fn owner_is_stored() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        expect!(index.insert(Node::new_file("shadow", Timespec::new(10, 0), 3, 0o640)
+                    .with_backup_set(5)
+                    .with_hash(vec![1; 32])
+                    .with_owner(0, 42)),
+                "insert");
+        expect!(index.insert(Node::new_dir("home", Timespec::new(10, 0), 0o755)
+                    .with_backup_set(5)),
+                "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let n = index.get("shadow".to_string(), None).unwrap().unwrap();
+        assert_eq!(Some((0, 42)), n.owner());
+        assert_eq!(Some((0, 42)), index.files(None).unwrap()[0].owner());
+        let n = index.get("home".to_string(), None).unwrap().unwrap();
+        assert_eq!(None, n.owner());
+    }
+
+    #[test]
+    fn links_are_found_by_inode() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        for path in &["a", "b", "c"] {
+            expect!(index.insert(Node::new_file(*path, Timespec::new(10, 0), 3, 0o644)
+                        .with_backup_set(5)
+                        .with_hash(vec![1; 32])
+                        .with_inode(2049, 77)),
+                    "insert");
+        }
+        expect!(index.insert(Node::new_file("c", Timespec::new(10, 0), 3, 0o644)
+                    .as_deleted()
+                    .with_backup_set(5)),
+                "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let linked = index.linked((2049, 77)).unwrap();
+        let paths: Vec<&str> = linked.iter().map(|n| n.path()).collect();
+        assert_eq!(vec!["a", "b"], paths);
+        assert_eq!(Some((2049, 77)), linked[0].inode());
+        assert!(index.linked((2049, 78)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn chunks_are_stored() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        let chunks = vec![Chunk {
+                              algorithm: HashAlgorithm::Blake3,
+                              hash: vec![1; 32],
+                              size: 300000,
+                          },
+                          Chunk {
+                              algorithm: HashAlgorithm::Blake3,
+                              hash: vec![2; 32],
+                              size: 5,
+                          }];
+        let mut node = Node::new_file("disk.img", Timespec::new(10, 0), 300005, 0o644)
+            .with_backup_set(5)
+            .with_hash(vec![3; 32]);
+        node.set_chunks(chunks.clone());
+        expect!(index.insert(node), "insert");
+        expect!(index.insert(Node::new_file("small", Timespec::new(10, 0), 3, 0o644)
+                    .with_backup_set(5)
+                    .with_hash(vec![4; 32])),
+                "insert");
+        expect!(index.close_backup_set(), "close backup set");
+
+        let n = index.get("disk.img".to_string(), None).unwrap().unwrap();
+        assert_eq!(&chunks[..], n.chunks());
+        let n = index.get("small".to_string(), None).unwrap().unwrap();
+        assert!(!n.is_chunked());
+        assert!(decode_chunks(HashAlgorithm::Sha256, &[0; 39]).is_err());
+    }
+
+    #[test]
+    fn corrupt_rows_are_skipped() {
+        let mut index = index();
+        expect!(index.create_backup_set(0), "backup set");
+
+        let mtime = Timespec::new(10, 0);
+        for path in &["a", "b"] {
+            let n = Node::new_file(*path, mtime, 3, 500)
+                .with_backup_set(5)
+                .with_hash(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18,
+                                19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31]);
+            expect!(index.insert(n), "insert");
+        }
+        expect!(index.close_backup_set(), "close backup set");
+        assert!(index.corrupt_rows().unwrap().is_empty());
+
+        index.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE node SET mtime = 'x' WHERE path_id = \
+                      (SELECT id FROM path WHERE path = 'a')",
+                     &[])
+            .unwrap();
+
+        let files = index.files(None).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("b", files[0].path());
+
+        let corrupt = index.corrupt_rows().unwrap();
+        assert_eq!(1, corrupt.len());
+        assert!(corrupt[0].contains("mtime of a"));
+    }
 }
@@ -0,0 +1,83 @@
+use std::fmt;
+use time::{Timespec, at, strftime};
+use index::RunSummary;
+use units::{format_bytes, format_ratio};
+
+/// Repository totals computed from the index.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct IndexStats {
+    /// Distinct file paths ever backed up.
+    pub paths: u64,
+    /// Files present in the latest version of the backup.
+    pub live_files: u64,
+    /// File versions holding content.
+    pub versions: u64,
+    /// Size of every version, as if each was stored separately.
+    pub logical_bytes: u64,
+    /// Distinct content blobs.
+    pub blobs: u64,
+    /// Size of the distinct blobs in storage, after compression.
+    pub stored_bytes: u64,
+    /// Codec new blobs are compressed with, from the engine config rather than the index.
+    pub compression: Option<String>,
+    pub backup_sets: Vec<BackupSetStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackupSetStats {
+    pub id: u64,
+    pub at: i64,
+    pub versions: u64,
+    pub bytes: u64,
+    /// Bytes of blobs first seen in this set.
+    pub new_bytes: u64,
+    /// What the run producing this set did, when it was recorded.
+    pub summary: Option<RunSummary>,
+    /// The run producing this set died before closing it.
+    pub aborted: bool,
+}
+
+/// Backup sets shown in the growth table.
+const RECENT_BACKUP_SETS: usize = 10;
+
+impl fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Files:        {} ({} ever)", self.live_files, self.paths)?;
+        writeln!(f, "Versions:     {}", self.versions)?;
+        writeln!(f, "Logical:      {}", format_bytes(self.logical_bytes))?;
+        writeln!(f,
+                 "Stored:       {} in {} blob(s)",
+                 format_bytes(self.stored_bytes),
+                 self.blobs)?;
+        writeln!(f,
+                 "Dedup:        {}",
+                 format_ratio(self.logical_bytes, self.stored_bytes))?;
+        write!(f,
+               "Compression:  {}",
+               self.compression.as_ref().map(|c| c.as_str()).unwrap_or("none"))?;
+
+        if self.backup_sets.is_empty() {
+            return Ok(());
+        }
+        write!(f,
+               "\n\nRecent backup sets:\n{:>6}  {:<19}  {:>8}  {:>10}  {:>10}",
+               "Set",
+               "Time",
+               "Versions",
+               "Size",
+               "Growth")?;
+        let skip = self.backup_sets.len().saturating_sub(RECENT_BACKUP_SETS);
+        for set in self.backup_sets.iter().skip(skip) {
+            let tm = strftime("%Y-%m-%d %H:%M:%S", &at(Timespec::new(set.at, 0)))
+                .expect("time format");
+            write!(f,
+                   "\n{:>6}  {:<19}  {:>8}  {:>10}  {:>10}",
+                   set.id,
+                   tm,
+                   set.versions,
+                   format_bytes(set.bytes),
+                   format_bytes(set.new_bytes))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+use std::fmt;
+
+/// What a backup run did, stored with the backup set it produced.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct RunSummary {
+    /// Paths looked at, whether or not they had changed.
+    pub scanned: u64,
+    pub new: u64,
+    pub changed: u64,
+    pub deleted: u64,
+    /// Content read from disk for hashing and sending.
+    pub bytes_read: u64,
+    /// Content written to storage. Blobs storage already had are not counted.
+    pub bytes_uploaded: u64,
+    /// Files whose content storage already had.
+    pub dedup_hits: u64,
+    pub wall_time_ms: u64,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "scanned={} new={} changed={} deleted={} bytes_read={} bytes_uploaded={} \
+                dedup_hits={} wall_time_ms={}",
+               self.scanned,
+               self.new,
+               self.changed,
+               self.deleted,
+               self.bytes_read,
+               self.bytes_uploaded,
+               self.dedup_hits,
+               self.wall_time_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let summary = RunSummary {
+            scanned: 10,
+            new: 2,
+            changed: 1,
+            deleted: 3,
+            bytes_read: 4096,
+            bytes_uploaded: 1024,
+            dedup_hits: 1,
+            wall_time_ms: 1500,
+        };
+        assert_eq!("scanned=10 new=2 changed=1 deleted=3 bytes_read=4096 bytes_uploaded=1024 \
+                    dedup_hits=1 wall_time_ms=1500",
+                   format!("{}", summary));
+    }
+}
@@ -1,22 +1,34 @@
 #![deny(warnings)]
 #![feature(box_syntax, try_from, custom_derive, plugin, proc_macro)]
+#![cfg_attr(test, feature(test))]
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate lazy_static;
 extern crate notify;
 extern crate time;
+#[cfg(feature = "s3")]
 extern crate chrono;
 extern crate rusqlite;
-extern crate crypto;
+extern crate digest;
+extern crate sha2;
+extern crate md5;
+#[cfg(feature = "s3")]
+extern crate hmac;
+extern crate num_cpus;
+extern crate libc;
 extern crate rustc_serialize;
 extern crate regex;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_yaml;
+extern crate serde_json;
 extern crate hyper;
 extern crate threadpool;
+extern crate flate2;
+#[cfg(test)]
+extern crate test;
 
 #[cfg(test)]
 extern crate env_logger;
@@ -29,40 +41,92 @@ pub mod engine;
 pub mod index;
 pub mod storage;
 pub mod config;
+pub mod control;
+pub mod builder;
 
 mod node;
 mod hasher;
+mod blake3;
 mod retry;
 mod queue;
-
+mod throttle;
+mod priority;
+mod signals;
+mod power;
+mod budget;
+mod network;
+mod pattern;
+mod metrics;
+mod notification;
+mod systemd;
+mod units;
+mod du;
+mod disk;
+mod capability;
+mod owner;
+mod file_times;
+mod filter;
+mod compression;
+mod chunker;
+mod path_priority;
+mod retention;
+mod output;
+mod progress_bar;
+mod serve;
+mod namespace;
+mod listing;
+mod find;
+mod compare;
+mod cost;
+mod estimate;
+mod time_format;
+mod doctor;
+
+pub use builder::{Haumaru, HaumaruBuilder};
 pub use config::{AsConfig, Config};
+pub use hasher::{ContentHasher, HashAlgorithm};
 
-use engine::DefaultEngine;
 pub use engine::EngineConfig;
+use engine::{DEFAULT_SEND_THREADS, JobGroup};
 use filesystem::Change;
 
-pub use index::Index;
+pub use index::{Index, KeyScope};
 use index::SqlLightIndex;
-pub use node::{Node, NodeKind};
+use progress_bar::ProgressBar;
+pub use node::{Chunk, Node, NodeKind};
+pub use notification::{Notification, Notifier, SmtpSettings};
+pub use find::FindQuery;
+pub use listing::{ListOptions, SortKey};
+pub use retention::{RetentionPolicy, RetentionRule};
+pub use output::OutputFormat;
+pub use time_format::{TimeFormat, TimeStyle};
 use rusqlite::Connection;
 use rusqlite::Error as SqliteError;
 use std::borrow::Borrow;
-use std::collections::HashSet;
 
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
-use std::fs::create_dir_all;
+use std::fs::{File, create_dir_all};
 use std::io::{Read, Write};
-use std::path::PathBuf;
-// use storage::LocalStorage;
-use storage::SendRequest;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use storage::{Blob, SendReceipt, SendRequest};
 use time::Timespec;
 
 pub trait Engine {
     fn run(&mut self) -> Result<(), Box<Error>>;
+    /// Full scan into a single backup set, returning once it is stored and closed.
+    fn backup_once(&mut self) -> Result<(), Box<Error>>;
+    /// Wait until everything queued so far has been stored and indexed.
+    fn flush(&mut self) -> Result<(), Box<Error>>;
+    /// Stop current work at the next file, flush, and stop the workers. The engine cannot
+    /// back up again afterwards.
+    fn shutdown(&mut self) -> Result<(), Box<Error>>;
     fn process_changes(&mut self, for_time: i64, changes: Vec<Change>) -> Result<(), Box<Error>>;
-    fn verify_store(&mut self, like: String) -> Result<(), Box<Error>>;
+    fn verify_store(&mut self, scope: &KeyScope) -> Result<(), Box<Error>>;
     fn restore(&mut self,
                key: &str,
                from: Option<Timespec>,
@@ -73,12 +137,54 @@ pub trait Engine {
             from: Option<Timespec>,
             out: &mut Write)
             -> Result<(), Box<Error>>;
+    /// Write the content of the file at `key` to `out`.
+    fn cat(&mut self,
+           key: &str,
+           from: Option<Timespec>,
+           out: &mut Write)
+           -> Result<(), Box<Error>>;
+    /// Store everything read from `input` under `VIRTUAL_DIR/key`, in the latest backup set
+    /// when `current` is set, otherwise in a new one.
+    fn put(&mut self, key: &str, current: bool, input: &mut Read) -> Result<(), Box<Error>>;
 }
 
 pub trait Storage: Send + Clone {
-    fn send(&self, req: &mut SendRequest) -> Result<(), Box<Error>>;
-    fn retrieve(&self, hash: &[u8]) -> Result<Option<Box<Read>>, Box<Error>>;
+    /// Store the content of `req`, streaming it from the request's reader.
+    fn send(&self, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>>;
+    /// Stream a blob back, or `None` when the backend does not have it.
+    fn retrieve(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<Option<Blob>, Box<Error>>;
     fn verify(&self, Node) -> Result<(Node, bool), Box<Error>>;
+    /// Remove a blob nothing references any more. Returns whether it was removed, false when
+    /// the backend does not have it or cannot remove blobs.
+    fn delete(&self, _algorithm: HashAlgorithm, _hash: &[u8]) -> Result<bool, Box<Error>> {
+        Ok(false)
+    }
+    /// Bytes used by the backend, when it can tell cheaply.
+    fn usage(&self) -> Result<Option<u64>, Box<Error>> {
+        Ok(None)
+    }
+    /// Remove temp files left behind by sends in processes that died, returning how many.
+    fn sweep_temp_files(&self) -> Result<u64, Box<Error>> {
+        Ok(0)
+    }
+    /// Sends the backend copes with at once, used unless `send_threads` is configured.
+    fn max_sends(&self) -> Option<usize> {
+        None
+    }
+    /// Keep a copy of the index file at `index` with the backend, under `namespace`, so the
+    /// backups can be found from other machines sharing it.
+    fn push_index(&self, _namespace: &str, _index: &Path) -> Result<(), Box<Error>> {
+        Ok(())
+    }
+    /// The index copy last pushed under `namespace`, or `None` when the backend has none.
+    fn fetch_index(&self, _namespace: &str) -> Result<Option<Blob>, Box<Error>> {
+        Ok(None)
+    }
+    /// Every namespace with an index copy in the backend, or `None` when the backend cannot
+    /// list them.
+    fn namespaces(&self) -> Result<Option<Vec<String>>, Box<Error>> {
+        Ok(None)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -160,7 +266,7 @@ impl fmt::Display for HaumaruError {
 
 fn split_key(key: &str) -> (String, Option<Timespec>) {
     if !key.contains("@") {
-        return (key.to_string(), None);
+        return (engine::resolve_key(key), None);
     }
 
     use regex::Regex;
@@ -176,7 +282,7 @@ fn split_key(key: &str) -> (String, Option<Timespec>) {
 
     let unix_ts = unix_ts_str.parse::<i64>().expect("unix timestamp");
 
-    (key_str.to_string(),
+    (engine::resolve_key(key_str),
      Some(Timespec {
          sec: unix_ts,
          nsec: 0,
@@ -199,6 +305,10 @@ fn test_split_key() {
                    nsec: 0,
                }));
 
+    let (key, ts) = split_key("virtual:db@123");
+    assert_eq!(".haumaru-virtual/db", key);
+    assert_eq!(ts, Some(Timespec::new(123, 0)));
+
     let (key, ts) = split_key("@123");
     assert_eq!("", key);
     assert_eq!(ts,
@@ -209,9 +319,8 @@ fn test_split_key() {
 
 }
 
-fn build_storage(config: EngineConfig) -> storage::LocalStorage {
-    storage::LocalStorage::new(&config).expect("build storage")
-    // storage::S3Storage::new(config)
+fn build_storage(config: EngineConfig) -> Result<storage::AnyStorage, HaumaruError> {
+    storage::build(&config)
 }
 
 fn build_index(config: EngineConfig) -> Result<SqlLightIndex, HaumaruError> {
@@ -227,52 +336,455 @@ fn build_index(config: EngineConfig) -> Result<SqlLightIndex, HaumaruError> {
     Ok(SqlLightIndex::new(conn).map_err(|e| HaumaruError::Index(box e))?)
 }
 
-fn setup_and_run<F>(config: EngineConfig, mut f: F) -> Result<(), HaumaruError>
+/// `namespace` when it names another client than `config`'s own.
+fn other_namespace<'a>(config: &EngineConfig, namespace: Option<&'a str>) -> Option<&'a str> {
+    namespace.and_then(|n| if n == config.namespace() { None } else { Some(n) })
+}
+
+/// Engine over the index copy `namespace` pushed to the configured storage, with blobs read
+/// from the same storage. The copy is fetched into `working/namespaces` and only read.
+fn namespace_engine(config: EngineConfig,
+                    namespace: &str)
+                    -> Result<engine::DefaultEngine<SqlLightIndex, storage::AnyStorage>,
+                              HaumaruError> {
+    namespace::validate(namespace).map_err(|e| HaumaruError::Config(e.into()))?;
+    let config = config.detached();
+    let storage = build_storage(config.clone())?;
+    let mut blob = storage.fetch_index(namespace)
+        .map_err(|e| HaumaruError::Storage(e))?
+        .ok_or_else(|| {
+            HaumaruError::Other(format!("{} storage has no index for namespace {}",
+                                        config.storage(),
+                                        namespace))
+        })?;
+
+    let dir = config.abs_working().join("namespaces");
+    create_dir_all(&dir)
+        .map_err(|e| HaumaruError::Other(format!("Unable to create {:?}: {}", dir, e)))?;
+    let db_path = dir.join(format!("{}.idx", namespace));
+    File::create(&db_path)
+        .and_then(|mut file| std::io::copy(&mut blob, &mut file))
+        .map_err(|e| HaumaruError::Other(format!("Unable to write {:?}: {}", db_path, e)))?;
+
+    let conn = Connection::open(&db_path)
+        .map_err(|e| HaumaruError::SqlLite(format!("Failed to open database {:?}", db_path), e))?;
+    let index = SqlLightIndex::new(conn).map_err(|e| HaumaruError::Index(box e))?;
+    Haumaru::builder().config(config).index(index).storage(storage).build()
+}
+
+/// Build an engine for `config` and run `f` on it, drawing a progress bar while it runs if
+/// `progress` is set.
+fn setup_and_run<F>(config: EngineConfig, progress: bool, mut f: F) -> Result<(), HaumaruError>
     where F: FnMut(&mut Engine) -> Result<(), HaumaruError>
 {
-    let mut excludes = HashSet::new();
-    excludes.insert(config.abs_working().to_str().unwrap().to_string());
+    if let Some((host, port, prefix)) = config.statsd() {
+        let sink = metrics::StatsdSink::new(host, port, prefix).map_err(|e| {
+                HaumaruError::Config(format!("Unable to set up statsd {}:{}: {}", host, port, e)
+                    .into())
+            })?;
+        metrics::configure(box sink);
+    }
+
+    let mut engine = Haumaru::builder().config(config).default_backends()?.build()?;
+
+    let bar = if progress {
+        Some(ProgressBar::start(engine.progress()))
+    } else {
+        None
+    };
+    let result = f(&mut engine);
+    if let Some(bar) = bar {
+        bar.finish();
+    }
+    result
+}
 
-    let mut engine =
-        DefaultEngine::new(config.clone(),
-                           excludes,
-                           build_index(config.clone())?,
-                           build_storage(config)).map_err(|e| HaumaruError::Engine(e))?;
+/// Run `f` on every job of `user_config` at once, each on a thread of its own, failing if any
+/// of them does. Every job's config is checked before any starts. The jobs share their
+/// pre-send and send slots, as many as the most any one of them asks for. With
+/// `stop_together`, the first to fail stops the rest, otherwise the others keep running.
+fn run_jobs(user_config: Config,
+            stop_together: bool,
+            f: fn(EngineConfig) -> Result<(), HaumaruError>)
+            -> Result<(), HaumaruError> {
+    let mut jobs = vec![];
+    for name in user_config.job_names() {
+        let config: EngineConfig = user_config.job(&name)?.try_into()?;
+        jobs.push((name, config));
+    }
+    let pre_send = jobs.iter().map(|&(_, ref c)| c.pre_send_threads()).max().unwrap_or(1);
+    let send = jobs.iter()
+        .map(|&(_, ref c)| c.send_threads().unwrap_or(DEFAULT_SEND_THREADS))
+        .max()
+        .unwrap_or(1);
+    let mut group = JobGroup::new(pre_send, send);
+    if stop_together {
+        group = group.with_shared_cancel();
+    }
 
-    f(&mut engine)
+    let (done, results) = mpsc::channel();
+    for (name, config) in jobs {
+        info!("Starting job {}", name);
+        let config = config.with_job_group(group.clone());
+        let done = done.clone();
+        thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(config)))
+                .unwrap_or_else(|_| Err(HaumaruError::Other("panicked".to_string())));
+            let _ = done.send((name, result.map_err(|e| format!("{}", e))));
+        });
+    }
+    drop(done);
+
+    let mut failed = vec![];
+    for (name, result) in results {
+        if let Err(e) = result {
+            match group.cancel() {
+                Some(cancel) if failed.is_empty() => {
+                    error!("Job {} failed, stopping the other jobs: {}", name, e);
+                    cancel.cancel();
+                }
+                _ => error!("Job {} failed: {}", name, e),
+            }
+            failed.push(format!("job {}: {}", name, e));
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(HaumaruError::Other(failed.join("; ")))
+    }
 }
 
 pub fn run(user_config: Config) -> Result<(), HaumaruError> {
+    if !user_config.job_names().is_empty() {
+        return run_jobs(user_config, false, run_engine);
+    }
+    run_engine(user_config.try_into()?)
+}
+
+fn run_engine(config: EngineConfig) -> Result<(), HaumaruError> {
+    setup_and_run(config, false, |eng| eng.run().map_err(|e| HaumaruError::Engine(e)))
+}
+
+pub fn backup_once(user_config: Config) -> Result<(), HaumaruError> {
+    if !user_config.job_names().is_empty() {
+        return run_jobs(user_config, true, backup_engine_once);
+    }
+    backup_engine_once(user_config.try_into()?)
+}
+
+fn backup_engine_once(config: EngineConfig) -> Result<(), HaumaruError> {
+    setup_and_run(config,
+                  true,
+                  |eng| eng.backup_once().map_err(|e| HaumaruError::Engine(e)))
+}
+
+/// Store stdin under the virtual `key`.
+pub fn put(user_config: Config, key: &str, current: bool) -> Result<(), HaumaruError> {
     let config: EngineConfig = user_config.try_into()?;
-    setup_and_run(config, |eng| eng.run().map_err(|e| HaumaruError::Engine(e)))
+    setup_and_run(config, false, |eng| {
+        eng.put(key, current, &mut std::io::stdin()).map_err(|e| HaumaruError::Engine(e))
+    })
 }
 
-pub fn verify(user_config: Config, like: String) -> Result<(), HaumaruError> {
+/// Serve blobs and indexes for the configured clients until stopped.
+pub fn serve(user_config: Config, listen: Option<&str>) -> Result<(), HaumaruError> {
     let config: EngineConfig = user_config.try_into()?;
-    setup_and_run(config,
-                  |eng| eng.verify_store(like.clone()).map_err(|e| HaumaruError::Engine(e)))
+    let listen = listen.unwrap_or(config.serve_listen()).to_string();
+    serve::serve(&config, &listen)
 }
 
-pub fn restore(user_config: Config, key: &str, target: &str) -> Result<(), HaumaruError> {
+pub fn verify(user_config: Config, scope: KeyScope) -> Result<(), HaumaruError> {
     let config: EngineConfig = user_config.try_into()?;
+    if config.output() == OutputFormat::Json {
+        let request = control::Request::verify(&scope).with_format(OutputFormat::Json);
+        return control_request(config, &request);
+    }
+    setup_and_run(config,
+                  true,
+                  |eng| eng.verify_store(&scope).map_err(|e| HaumaruError::Engine(e)))
+}
+
+/// Send `request` to the daemon running on `config`, if there is one. Progress is printed as
+/// it arrives.
+fn call_daemon(config: &EngineConfig,
+               request: &control::Request)
+               -> Option<Result<Option<control::Status>, HaumaruError>> {
+    control::Client::connect(&config.abs_working()).map(|client| {
+        debug!("Sending {:?} to the running daemon", request);
+        client.call(request, &mut |line| println!("{}", line))
+            .map_err(|e| HaumaruError::Engine(e))
+    })
+}
+
+/// Run `request` on the daemon, or straight against the index when no daemon is running.
+fn control_request(config: EngineConfig, request: &control::Request) -> Result<(), HaumaruError> {
     let config = config.detached();
+    if let Some(result) = call_daemon(&config, request) {
+        return result.map(|_| ());
+    }
+
+    let mut engine = Haumaru::builder().config(config).default_backends()?.build()?;
+    dispatch_local(&mut engine, request)
+}
+
+/// Run `request` straight against `service`, printing its output.
+fn dispatch_local(service: &mut control::ControlService,
+                  request: &control::Request)
+                  -> Result<(), HaumaruError> {
+    let mut error = None;
+    control::dispatch(service, request, &mut |reply| {
+        if let Some(line) = reply.line() {
+            println!("{}", line);
+        }
+        if let Some(e) = reply.error() {
+            error = Some(e.to_string());
+        }
+    });
+    match error {
+        Some(e) => Err(HaumaruError::Other(e)),
+        None => Ok(()),
+    }
+}
+
+/// Restore `key` into `target`, from another client's backups when `namespace` is given.
+/// `limit_rate` caps the download rate, a size per second such as `512K`.
+pub fn restore(user_config: Config,
+               key: &str,
+               target: &str,
+               dry_run: bool,
+               namespace: Option<&str>,
+               limit_rate: Option<&str>)
+               -> Result<(), HaumaruError> {
+    let mut config: EngineConfig = user_config.try_into()?;
+    let limit_rate = match limit_rate {
+        Some(rate) => Some(units::parse_bytes(rate).map_err(|e| HaumaruError::Other(e))?),
+        None => None,
+    };
+    if let Some(rate) = limit_rate {
+        if rate == 0 {
+            return Err(HaumaruError::Other("--limit-rate must be positive".to_string()));
+        }
+        config = config.with_download_rate(rate);
+    }
+    let format = config.output();
     let (key, from) = split_key(key);
-    setup_and_run(config,
+
+    // the daemon may run from another directory
+    let abs_target = std::env::current_dir()
+        .map_err(|e| HaumaruError::Other(format!("Unable to get current dir: {}", e)))?
+        .join(target);
+    let abs_target = abs_target.to_str()
+        .ok_or_else(|| HaumaruError::Other(format!("Target {:?} is not valid UTF-8", abs_target)))?;
+    let request = control::Request::restore(&key, from, abs_target, dry_run)
+        .with_limit_rate(limit_rate)
+        .with_format(format);
+    if let Some(namespace) = other_namespace(&config, namespace) {
+        let mut engine = namespace_engine(config, namespace)?;
+        if dry_run {
+            return dispatch_local(&mut engine, &request);
+        }
+        return engine.restore(&key, from, target).map_err(|e| HaumaruError::Engine(e));
+    }
+    if dry_run {
+        return control_request(config, &request);
+    }
+    if let Some(result) = call_daemon(&config.clone().detached(), &request) {
+        return result.map(|_| ());
+    }
+
+    setup_and_run(config.detached(),
+                  true,
                   |eng| eng.restore(&key, from, target).map_err(|e| HaumaruError::Engine(e)))
 }
 
-pub fn list(user_config: Config, key: &str) -> Result<(), HaumaruError> {
-    use std::io::Cursor;
+/// Browse backups interactively on the terminal, restoring what is marked.
+pub fn browse(user_config: Config) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let mut engine = Haumaru::builder().config(config.detached()).default_backends()?.build()?;
+    let stdin = std::io::stdin();
+    engine::Browser::new()
+        .run(&mut engine, &mut stdin.lock(), &mut std::io::stdout())
+        .map_err(|e| HaumaruError::Engine(e))
+}
 
+/// Write the content of the file at `key` to stdout.
+pub fn cat(user_config: Config, key: &str) -> Result<(), HaumaruError> {
     let config: EngineConfig = user_config.try_into()?;
-    let config = config.detached();
     let (key, from) = split_key(key);
+    setup_and_run(config.detached(), false, |eng| {
+        eng.cat(&key, from, &mut std::io::stdout()).map_err(|e| HaumaruError::Engine(e))
+    })
+}
 
-    let mut cur = Cursor::new(Vec::new());
-    setup_and_run(config,
-                  |eng| eng.list(&key, from, &mut cur).map_err(|e| HaumaruError::Engine(e)))?;
-    let content = String::from_utf8(cur.into_inner()).expect("from_utf8");
-    println!("{}", content);
+/// List `key`, from another client's backups when `namespace` is given.
+pub fn list(user_config: Config,
+            key: &str,
+            options: ListOptions,
+            namespace: Option<&str>)
+            -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let (key, from) = split_key(key);
+    let request = control::Request::list(&key, from)
+        .with_list_options(options.with_times(config.times()))
+        .with_format(config.output());
+    match other_namespace(&config, namespace) {
+        Some(namespace) => dispatch_local(&mut namespace_engine(config, namespace)?, &request),
+        None => control_request(config, &request),
+    }
+}
+
+/// Print everything below `key` matching `query`.
+pub fn find(user_config: Config, key: &str, query: FindQuery) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let (key, from) = split_key(key);
+    let request = control::Request::find(&key, from, query).with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Bring back the files below `key` deleted since `since`, a unix timestamp or `YYYY-MM-DD`
+/// date, to where they were under `target`, or the backup path.
+pub fn undelete(user_config: Config,
+                key: &str,
+                since: &str,
+                target: Option<&str>)
+                -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let since = find::parse_time(since).map_err(|e| HaumaruError::Other(e))?;
+    let target = target.unwrap_or(config.path()).to_string();
+
+    // the daemon may run from another directory
+    let abs_target = std::env::current_dir()
+        .map_err(|e| HaumaruError::Other(format!("Unable to get current dir: {}", e)))?
+        .join(target);
+    let abs_target = abs_target.to_str()
+        .ok_or_else(|| HaumaruError::Other(format!("Target {:?} is not valid UTF-8", abs_target)))?;
+    let request = control::Request::undelete(key, since, abs_target)
+        .with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Print the paths the run producing `backup_set` left out, and why.
+pub fn skipped(user_config: Config, backup_set: u64) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let request = control::Request::skipped(backup_set).with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Print the files on disk that are new, changed or missing compared to the latest backup.
+pub fn compare(user_config: Config, hash: bool) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let request = control::Request::compare(hash).with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Print the estimated monthly S3 cost of the repository in `storage_class`, or the class the
+/// S3 backend uploads with.
+pub fn cost(user_config: Config, storage_class: Option<&str>) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let class = storage_class.unwrap_or(cost::DEFAULT_STORAGE_CLASS);
+    let request = control::Request::cost(class).with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Remove versions past their retention from the index, and the blobs only they referenced
+/// from storage.
+pub fn prune(user_config: Config) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let request = control::Request::prune().with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Scan the backup path without uploading and print what a first backup would hold and how
+/// long it would take at `bandwidth`, a size per second such as `1M`.
+pub fn estimate(user_config: Config, bandwidth: &str) -> Result<(), HaumaruError> {
+    let bandwidth = units::parse_bytes(bandwidth).map_err(|e| HaumaruError::Other(e))?;
+    let config: EngineConfig = user_config.try_into()?;
+    let format = config.output();
+    let engine = Haumaru::builder().config(config.detached()).default_backends()?.build()?;
+    let estimate = engine.estimate_scan().map_err(|e| HaumaruError::Engine(e))?;
+    let estimate = estimate.at_bandwidth(bandwidth);
+    match format {
+        OutputFormat::Json => println!("{}", output::json_line(&estimate)),
+        OutputFormat::Text => println!("{}", estimate),
+    }
+    Ok(())
+}
+
+/// Check the environment haumaru runs in and print what was found, failing if anything needs
+/// fixing before backups can work.
+pub fn doctor(user_config: Config) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let findings = doctor::diagnose(&config);
+    for finding in &findings {
+        match config.output() {
+            OutputFormat::Json => println!("{}", output::json_line(finding)),
+            OutputFormat::Text => println!("{}", finding),
+        }
+    }
+    let problems = findings.iter().filter(|f| f.severity == doctor::Severity::Problem).count();
+    if problems > 0 {
+        return Err(HaumaruError::Other(format!("doctor found {} problem(s)", problems)));
+    }
+    Ok(())
+}
+
+/// Print repository totals.
+pub fn stats(user_config: Config) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let request = control::Request::stats().with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Print every backup set with its version count and size.
+pub fn snapshots(user_config: Config) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let request = control::Request::snapshots()
+        .with_times(config.times())
+        .with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Print where the time of the run producing `backup_set` went.
+pub fn report(user_config: Config, backup_set: u64) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let request = control::Request::report(backup_set)
+        .with_times(config.times())
+        .with_format(config.output());
+    control_request(config, &request)
+}
+
+/// Print stored bytes per directory under `key`, `depth` levels deep.
+pub fn du(user_config: Config, key: &str, depth: usize) -> Result<(), HaumaruError> {
+    let config: EngineConfig = user_config.try_into()?;
+    let (key, from) = split_key(key);
+    control_request(config, &control::Request::du(&key, from, depth))
+}
+
+/// Print the running daemon's status, or what the index knows when none is running.
+pub fn status(user_config: Config) -> Result<(), HaumaruError> {
+    use control::ControlService;
+
+    let config: EngineConfig = user_config.try_into()?;
+    let config = config.detached();
+    let format = config.output();
+
+    let status = match control::Client::connect(&config.abs_working()) {
+        Some(client) => {
+            client.call(&control::Request::status(), &mut |line| println!("{}", line))
+                .map_err(|e| HaumaruError::Engine(e))?
+        }
+        None => {
+            let mut engine = Haumaru::builder().config(config).default_backends()?.build()?;
+            Some(engine.status().map_err(|e| HaumaruError::Engine(e))?)
+        }
+    };
+    if let Some(status) = status {
+        match format {
+            OutputFormat::Json => println!("{}", output::json_line(&status)),
+            OutputFormat::Text => println!("{}", status),
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,240 @@
+//! How `ls` walks and renders a directory.
+
+use Node;
+use engine::mode_string;
+use std::cmp::Ordering;
+use std::io::Write;
+use std::str::FromStr;
+use time::Timespec;
+use time_format::{TimeFormat, TimeStyle};
+
+/// What `ls --sort` orders entries by.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SortKey {
+    Name,
+    Size,
+    Mtime,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortKey::Name),
+            "size" => Ok(SortKey::Size),
+            "mtime" => Ok(SortKey::Mtime),
+            _ => Err(format!("Unknown sort {:?}, expected size, mtime or name", s)),
+        }
+    }
+}
+
+/// Options for `ls`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ListOptions {
+    recursive: bool,
+    tree: bool,
+    long: bool,
+    sort: Option<SortKey>,
+    reverse: bool,
+    deleted: bool,
+    times: TimeStyle,
+}
+
+impl ListOptions {
+    pub fn new() -> Self {
+        ListOptions::default()
+    }
+    /// List everything below directories, not only their entries.
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+    /// Render the listing as an indented tree. Implies `recursive`.
+    pub fn with_tree(mut self, tree: bool) -> Self {
+        self.tree = tree;
+        self
+    }
+    /// Show the hash, backup set and full timestamp of each entry.
+    pub fn with_long(mut self, long: bool) -> Self {
+        self.long = long;
+        self
+    }
+    /// Order the entries of each directory by `sort` instead of by name.
+    pub fn with_sort(mut self, sort: SortKey) -> Self {
+        self.sort = Some(sort);
+        self
+    }
+    pub fn with_reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+    /// Include deleted entries, marked with when they were deleted.
+    pub fn with_deleted(mut self, deleted: bool) -> Self {
+        self.deleted = deleted;
+        self
+    }
+    pub fn with_times(mut self, times: TimeStyle) -> Self {
+        self.times = times;
+        self
+    }
+    pub fn recursive(&self) -> bool {
+        self.recursive || self.tree
+    }
+    pub fn tree(&self) -> bool {
+        self.tree
+    }
+    pub fn long(&self) -> bool {
+        self.long
+    }
+    pub fn deleted(&self) -> bool {
+        self.deleted
+    }
+    pub fn times(&self) -> &TimeStyle {
+        &self.times
+    }
+
+    /// Order the entries of one directory. Sizes and times sort largest and newest first, as
+    /// those are usually what is looked for; ties fall back to the name.
+    pub fn sort(&self, nodes: &mut Vec<Node>) {
+        let sort = self.sort.unwrap_or(SortKey::Name);
+        nodes.sort_by(|a, b| {
+            let by_key = match sort {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => b.size().cmp(&a.size()),
+                SortKey::Mtime => b.mtime().cmp(a.mtime()),
+            };
+            match by_key {
+                Ordering::Equal => a.path().cmp(b.path()),
+                order => order,
+            }
+        });
+        if self.reverse {
+            nodes.reverse();
+        }
+    }
+}
+
+/// An entry `ls` shows, `depth` levels below the listed directory. A deleted entry is shown as
+/// its last version before the deletion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListEntry {
+    pub depth: usize,
+    pub node: Node,
+    pub deleted_at: Option<Timespec>,
+}
+
+/// Write the column `ls --deleted` puts before each entry: when it was deleted, or blank.
+pub fn write_deleted_marker(out: &mut Write, deleted_at: Option<Timespec>, times: &TimeStyle) {
+    match deleted_at {
+        Some(t) => {
+            write!(out, "deleted {} ", times.show(t, TimeFormat::Short)).expect("write");
+        }
+        None => {
+            let width = format!("deleted {} ", times.show(Timespec::new(0, 0), TimeFormat::Short))
+                .len();
+            write!(out, "{:1$}", "", width).expect("write")
+        }
+    }
+}
+
+/// Write `node` with its abbreviated hash, backup set and timestamp, UTC unless another format
+/// was picked, as `ls -l` does.
+pub fn write_long_node(out: &mut Write, node: &Node, times: &TimeStyle) {
+    let hash = if node.has_hash() {
+        node.hash_string()[..12].to_string()
+    } else {
+        "-".to_string()
+    };
+    let set = node.backup_set().map(|s| s.to_string()).unwrap_or("-".to_string());
+    let tm = times.show(node.mtime().clone(), TimeFormat::Iso);
+    write!(out,
+           "{} {:>10} {:>5} {:12} {} {}\n",
+           mode_string(node),
+           node.size(),
+           set,
+           hash,
+           tm,
+           node.path())
+        .expect("write");
+}
+
+/// Write `node`, `depth` levels below the listed directory, as a line of a tree.
+pub fn write_tree_node(out: &mut Write, depth: usize, node: &Node) {
+    let name = node.path().rsplit('/').next().unwrap_or("");
+    let indent = vec!["  "; depth].concat();
+    if node.is_dir() {
+        write!(out, "{}{}/\n", indent, name).expect("write");
+    } else {
+        write!(out, "{}{} ({}B)\n", indent, name, node.size()).expect("write");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::Timespec;
+    use time_format::TimeStyle;
+    use Node;
+
+    fn names(nodes: &[Node]) -> Vec<&str> {
+        nodes.iter().map(|n| n.path()).collect()
+    }
+
+    #[test]
+    fn sorting() {
+        let mut nodes = vec![Node::new_file("b", Timespec::new(30, 0), 10, 0o644),
+                             Node::new_file("a", Timespec::new(20, 0), 10, 0o644),
+                             Node::new_file("c", Timespec::new(10, 0), 99, 0o644)];
+        ListOptions::new().sort(&mut nodes);
+        assert_eq!(vec!["a", "b", "c"], names(&nodes));
+        ListOptions::new().with_sort(SortKey::Size).sort(&mut nodes);
+        assert_eq!(vec!["c", "a", "b"], names(&nodes));
+        ListOptions::new().with_sort(SortKey::Mtime).with_reverse(true).sort(&mut nodes);
+        assert_eq!(vec!["c", "a", "b"], names(&nodes));
+        assert_eq!(Ok(SortKey::Mtime), "mtime".parse());
+        assert!("age".parse::<SortKey>().is_err());
+    }
+
+    #[test]
+    fn long_lines() {
+        let mut out = vec![];
+        let times = TimeStyle::new();
+        write_long_node(&mut out,
+                        &Node::new_file("etc/hosts", Timespec::new(1480000000, 0), 12, 0o644)
+                            .with_hash((0..32).collect())
+                            .with_backup_set(7),
+                        &times);
+        write_long_node(&mut out, &Node::new_dir("etc", Timespec::new(0, 0), 0o755), &times);
+        assert_eq!("-rw-r--r--         12     7 000102030405 2016-11-24T15:06:40Z etc/hosts\n\
+                    drwxr-xr-x          0     - -            1970-01-01T00:00:00Z etc\n",
+                   String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn deleted_marker_lines_up() {
+        let times = TimeStyle::new();
+        let mut deleted = vec![];
+        write_deleted_marker(&mut deleted, Some(Timespec::new(10, 0)), &times);
+        let mut live = vec![];
+        write_deleted_marker(&mut live, None, &times);
+        assert!(deleted.starts_with(b"deleted "));
+        assert_eq!(deleted.len(), live.len());
+    }
+
+    #[test]
+    fn tree_implies_recursive() {
+        assert!(!ListOptions::new().recursive());
+        assert!(ListOptions::new().with_tree(true).recursive());
+    }
+
+    #[test]
+    fn tree_lines() {
+        let mut out = vec![];
+        write_tree_node(&mut out, 0, &Node::new_dir("etc", Timespec::new(0, 0), 0o755));
+        write_tree_node(&mut out,
+                        1,
+                        &Node::new_file("etc/hosts", Timespec::new(0, 0), 12, 0o644));
+        assert_eq!("etc/\n  hosts (12B)\n", String::from_utf8(out).unwrap());
+    }
+}
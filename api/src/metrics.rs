@@ -0,0 +1,120 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Destination for counters, gauges and timings.
+pub trait MetricsSink: Send + Sync {
+    fn count(&self, name: &str, value: i64);
+    fn gauge(&self, name: &str, value: u64);
+    fn timing(&self, name: &str, ms: u64);
+}
+
+lazy_static! {
+    static ref SINK: RwLock<Option<Box<MetricsSink>>> = RwLock::new(None);
+}
+
+/// Send metrics from every layer to `sink`. Until configured, metrics are discarded.
+pub fn configure(sink: Box<MetricsSink>) {
+    *SINK.write().expect("metrics lock") = Some(sink);
+}
+
+pub fn count(name: &str, value: i64) {
+    if let Some(ref sink) = *SINK.read().expect("metrics lock") {
+        sink.count(name, value);
+    }
+}
+
+pub fn incr(name: &str) {
+    count(name, 1);
+}
+
+pub fn gauge(name: &str, value: u64) {
+    if let Some(ref sink) = *SINK.read().expect("metrics lock") {
+        sink.gauge(name, value);
+    }
+}
+
+pub fn timing(name: &str, duration: Duration) {
+    if let Some(ref sink) = *SINK.read().expect("metrics lock") {
+        let ms = duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000;
+        sink.timing(name, ms);
+    }
+}
+
+fn format_metric(prefix: &str, name: &str, value: &str, kind: &str) -> String {
+    if prefix.is_empty() {
+        format!("{}:{}|{}", name, value, kind)
+    } else {
+        format!("{}.{}:{}|{}", prefix, name, value, kind)
+    }
+}
+
+/// Fire and forget StatsD over UDP. Also accepted by Graphite via its StatsD frontends.
+pub struct StatsdSink {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+}
+
+impl StatsdSink {
+    pub fn new(host: &str, port: u16, prefix: &str) -> io::Result<Self> {
+        let addr = (host, port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, format!("Unable to resolve {}", host))
+            })?;
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        Ok(StatsdSink {
+            socket: socket,
+            addr: addr,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str) {
+        let metric = format_metric(&self.prefix, name, value, kind);
+        if let Err(e) = self.socket.send_to(metric.as_bytes(), &self.addr) {
+            debug!("Failed sending metric {}: {}", metric, e);
+        }
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn count(&self, name: &str, value: i64) {
+        self.send(name, &value.to_string(), "c");
+    }
+    fn gauge(&self, name: &str, value: u64) {
+        self.send(name, &value.to_string(), "g");
+    }
+    fn timing(&self, name: &str, ms: u64) {
+        self.send(name, &ms.to_string(), "ms");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use super::*;
+    use super::format_metric;
+
+    #[test]
+    fn format() {
+        assert_eq!("haumaru.files.new:1|c",
+                   format_metric("haumaru", "files.new", "1", "c"));
+        assert_eq!("queue.send:3|g", format_metric("", "queue.send", "3", "g"));
+    }
+
+    #[test]
+    fn statsd_sends_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let port = server.local_addr().expect("local_addr").port();
+
+        let sink = StatsdSink::new("127.0.0.1", port, "test").expect("sink");
+        sink.timing("send.time", 12);
+
+        let mut buf = [0u8; 64];
+        let (n, _) = server.recv_from(&mut buf).expect("recv");
+        assert_eq!(b"test.send.time:12|ms", &buf[..n]);
+    }
+}
@@ -0,0 +1,50 @@
+use libc;
+
+/// Namespace used when none is configured and the hostname can not be read.
+pub const DEFAULT_NAMESPACE: &'static str = "default";
+
+/// This machine's hostname, the default namespace for its backups.
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).trim().to_string();
+    if name.is_empty() { None } else { Some(name) }
+}
+
+/// Check `namespace` can name an index copy in a storage backend.
+pub fn validate(namespace: &str) -> Result<(), String> {
+    if namespace.is_empty() || namespace.starts_with('.') {
+        return Err(format!("Invalid namespace: {:?}", namespace));
+    }
+    let valid = namespace.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err(format!("Namespace may only use letters, digits, '-', '_' and '.': {}",
+                           namespace));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(validate("web1.example.com").is_ok());
+        assert!(validate("laptop_2").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("..").is_err());
+        assert!(validate("a/b").is_err());
+    }
+
+    #[test]
+    fn hostname_is_valid() {
+        if let Some(name) = hostname() {
+            assert!(validate(&name).is_ok(), name);
+        }
+    }
+}
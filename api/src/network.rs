@@ -0,0 +1,153 @@
+use engine::{Pause, PauseReason};
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// Networks on which uploads are suspended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeteredNetworks {
+    /// Interface names. A trailing `*` matches any suffix, e.g. `wwan*`.
+    interfaces: Vec<String>,
+    /// Wireless network names.
+    ssids: Vec<String>,
+    /// Shell command that exits zero when the current connection is metered.
+    command: Option<String>,
+}
+
+/// Split a comma separated config value.
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
+}
+
+impl MeteredNetworks {
+    pub fn new() -> Self {
+        MeteredNetworks {
+            interfaces: vec![],
+            ssids: vec![],
+            command: None,
+        }
+    }
+    pub fn with_interfaces(mut self, interfaces: &str) -> Self {
+        self.interfaces = split_list(interfaces);
+        self
+    }
+    pub fn with_ssids(mut self, ssids: &str) -> Self {
+        self.ssids = split_list(ssids);
+        self
+    }
+    pub fn with_command(mut self, command: &str) -> Self {
+        self.command = Some(command.to_string());
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.is_empty() && self.ssids.is_empty() && self.command.is_none()
+    }
+
+    fn matches_interface(&self, iface: &str) -> bool {
+        self.interfaces.iter().any(|i| if i.ends_with('*') {
+            iface.starts_with(&i[..i.len() - 1])
+        } else {
+            i == iface
+        })
+    }
+
+    /// Is the connection described by the default route interface and SSID metered.
+    pub fn matches(&self, iface: Option<&str>, ssid: Option<&str>) -> bool {
+        iface.map(|i| self.matches_interface(i)).unwrap_or(false) ||
+        ssid.map(|s| self.ssids.iter().any(|m| m == s)).unwrap_or(false)
+    }
+
+    /// Check the current connection.
+    pub fn is_metered(&self) -> bool {
+        if !self.interfaces.is_empty() || !self.ssids.is_empty() {
+            let iface = default_route_interface();
+            let ssid = if self.ssids.is_empty() { None } else { current_ssid() };
+            if self.matches(iface.as_ref().map(|s| s.as_str()),
+                            ssid.as_ref().map(|s| s.as_str())) {
+                return true;
+            }
+        }
+
+        if let Some(ref command) = self.command {
+            match Command::new("sh").arg("-c").arg(command).status() {
+                Ok(status) => return status.success(),
+                Err(e) => warn!("Failed to run metered command {}: {}", command, e),
+            }
+        }
+
+        false
+    }
+}
+
+/// Interface of the default route from the contents of `/proc/net/route`.
+pub fn parse_default_route(route: &str) -> Option<String> {
+    for line in route.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 1 && fields[1] == "00000000" {
+            return Some(fields[0].to_string());
+        }
+    }
+    None
+}
+
+fn default_route_interface() -> Option<String> {
+    let mut route = String::new();
+    match File::open("/proc/net/route").and_then(|mut f| f.read_to_string(&mut route)) {
+        Ok(_) => parse_default_route(&route),
+        Err(e) => {
+            debug!("Unable to read routes: {}", e);
+            None
+        }
+    }
+}
+
+fn current_ssid() -> Option<String> {
+    Command::new("iwgetid")
+        .arg("-r")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .and_then(|s| if s.is_empty() { None } else { Some(s) })
+}
+
+/// Suspend uploads while on a metered connection, resuming when the connection changes.
+pub fn watch_metered(pause: Pause, metered: MeteredNetworks) {
+    thread::spawn(move || {
+        loop {
+            if metered.is_metered() {
+                pause.pause(PauseReason::Metered);
+            } else {
+                pause.resume(PauseReason::Metered);
+            }
+            thread::sleep(Duration::new(30, 0));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_route() {
+        let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     wlan0\t0000A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\n\
+                     wwan0\t00000000\t0100A8C0\t0003\t0\t0\t700\t00000000\n";
+        assert_eq!(Some("wwan0".to_string()), parse_default_route(route));
+        assert_eq!(None, parse_default_route("Iface\tDestination\n"));
+    }
+
+    #[test]
+    fn matches() {
+        let metered = MeteredNetworks::new().with_interfaces("wwan*, usb0").with_ssids("Phone");
+        assert!(metered.matches(Some("wwan0"), None));
+        assert!(metered.matches(Some("usb0"), None));
+        assert!(!metered.matches(Some("usb1"), None));
+        assert!(metered.matches(Some("wlan0"), Some("Phone")));
+        assert!(!metered.matches(Some("wlan0"), Some("Home")));
+        assert!(!metered.matches(None, None));
+    }
+}
@@ -1,5 +1,6 @@
 use time::{now, Timespec};
 use rustc_serialize::hex::ToHex;
+use hasher::HashAlgorithm;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
@@ -11,15 +12,52 @@ pub struct Node {
     mode: u32,
     deleted: bool,
     hash: Option<Vec<u8>>,
+    hash_algorithm: HashAlgorithm,
+    /// Filter the content was stored through, see `filter::Filter::id`.
+    filter: Option<String>,
+    /// The file kept changing while it was read, so the content may not match any one version
+    /// of it.
+    inconsistent: bool,
+    /// The `security.capability` extended attribute, as read from the file.
+    capability: Option<Vec<u8>>,
+    /// Owning uid and gid. Records from before ownership was kept have none.
+    owner: Option<(u32, u32)>,
+    /// Device and inode of a file with more than one hard link, so the links can be told
+    /// apart from copies. Files with a single link have none.
+    inode: Option<(u64, u64)>,
+    /// The chunks a large file's content was stored as, in order, see `chunker`. Empty for
+    /// content stored as one blob.
+    chunks: Vec<Chunk>,
+    /// Bytes the content takes in storage, after compression. Records from before it was kept
+    /// have none.
+    stored_size: Option<u64>,
     backup_set: Option<u64>,
 }
 
+/// A piece of a file's content stored as a blob of its own, under its hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub algorithm: HashAlgorithm,
+    pub hash: Vec<u8>,
+    pub size: u64,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeKind {
     File,
     Dir,
 }
 
+impl NodeKind {
+    /// The character `ls` shows for this kind in front of the permissions.
+    pub fn type_char(&self) -> char {
+        match *self {
+            NodeKind::File => '-',
+            NodeKind::Dir => 'd',
+        }
+    }
+}
+
 impl Node {
     pub fn new<S>(path: S, kind: NodeKind, mtime: Timespec, size: u64, mode: u32) -> Self
         where S: Into<String>
@@ -32,6 +70,14 @@ impl Node {
             mode: mode,
             deleted: false,
             hash: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            filter: None,
+            inconsistent: false,
+            capability: None,
+            owner: None,
+            inode: None,
+            chunks: vec![],
+            stored_size: None,
             backup_set: None,
         }
     }
@@ -52,21 +98,87 @@ impl Node {
         &self.hash
     }
     pub fn set_hash(&mut self, hash: Vec<u8>) {
-        assert_eq!(32, hash.len(), "hash size");
+        assert!(!hash.is_empty(), "hash size");
         self.hash = Some(hash);
     }
     pub fn with_hash(mut self, hash: Vec<u8>) -> Self {
-        assert_eq!(32, hash.len(), "hash size");
+        assert!(!hash.is_empty(), "hash size");
         self.hash = Some(hash);
         self
     }
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+    pub fn set_hash_algorithm(&mut self, hash_algorithm: HashAlgorithm) {
+        self.hash_algorithm = hash_algorithm;
+    }
+    pub fn with_hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_ref().map(|f| f.as_ref())
+    }
+    pub fn set_filter(&mut self, filter: &str) {
+        self.filter = Some(filter.to_string());
+    }
+    pub fn with_filter(mut self, filter: &str) -> Self {
+        self.filter = Some(filter.to_string());
+        self
+    }
+    pub fn inconsistent(&self) -> bool {
+        self.inconsistent
+    }
+    pub fn set_inconsistent(&mut self, inconsistent: bool) {
+        self.inconsistent = inconsistent;
+    }
+    pub fn capability(&self) -> Option<&[u8]> {
+        self.capability.as_ref().map(|c| c.as_slice())
+    }
+    pub fn set_capability(&mut self, capability: Option<Vec<u8>>) {
+        self.capability = capability;
+    }
+    pub fn owner(&self) -> Option<(u32, u32)> {
+        self.owner
+    }
+    pub fn set_owner(&mut self, owner: Option<(u32, u32)>) {
+        self.owner = owner;
+    }
+    pub fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+    pub fn inode(&self) -> Option<(u64, u64)> {
+        self.inode
+    }
+    pub fn set_inode(&mut self, inode: Option<(u64, u64)>) {
+        self.inode = inode;
+    }
+    pub fn with_inode(mut self, dev: u64, ino: u64) -> Self {
+        self.inode = Some((dev, ino));
+        self
+    }
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+    pub fn set_chunks(&mut self, chunks: Vec<Chunk>) {
+        self.chunks = chunks;
+    }
+    pub fn stored_size(&self) -> Option<u64> {
+        self.stored_size
+    }
+    pub fn set_stored_size(&mut self, stored_size: Option<u64>) {
+        self.stored_size = stored_size;
+    }
+    pub fn is_chunked(&self) -> bool {
+        !self.chunks.is_empty()
+    }
     pub fn kind(&self) -> NodeKind {
         self.kind.clone()
     }
     pub fn mtime(&self) -> &Timespec {
         &self.mtime
     }
-    #[cfg(test)]
     pub fn set_mtime(&mut self, mtime: Timespec) {
         self.mtime = mtime;
     }
@@ -76,6 +188,9 @@ impl Node {
     pub fn size(&self) -> u64 {
         self.size
     }
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
+    }
     pub fn deleted(&self) -> bool {
         self.deleted
     }
@@ -85,6 +200,14 @@ impl Node {
         self.mode = 0;
         self.mtime = now().to_timespec();
         self.hash = None;
+        self.hash_algorithm = HashAlgorithm::Sha256;
+        self.filter = None;
+        self.inconsistent = false;
+        self.capability = None;
+        self.owner = None;
+        self.inode = None;
+        self.chunks = vec![];
+        self.stored_size = None;
         self
     }
     pub fn set_deleted(&mut self, deleted: bool) {
@@ -116,7 +239,10 @@ impl Node {
     }
     pub fn validate(&self) {
         if let Some(ref hash) = self.hash.as_ref() {
-            assert_eq!(32, hash.len(), "hash size: {:?}", self);
+            assert_eq!(self.hash_algorithm.digest_len(),
+                       hash.len(),
+                       "hash size: {:?}",
+                       self);
         }
         if self.kind == NodeKind::File {
             if !self.deleted && self.hash.is_none() {
@@ -0,0 +1,581 @@
+use engine::EngineConfig;
+use hyper::Client;
+use hyper::header::ContentType;
+use retry::retry_with_backoff;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+use std::time::Duration;
+use time;
+use units::format_bytes;
+
+/// Something users may want to hear about without reading logs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    BackupStarted,
+    BackupComplete { backup_set: u64 },
+    /// `backup_set` closed without `failures` files that could not be read or sent.
+    BackupPartial { backup_set: u64, failures: u64 },
+    /// A run finished with nothing to store.
+    BackupUpToDate,
+    BackupFailed { error: String },
+    UploadFailed { path: String, error: String },
+    VerifyFailed { failed: Vec<String> },
+    PruneComplete { removed: u64 },
+    StorageSoftCap { used: u64, cap: u64 },
+    StorageHardCap { used: u64, cap: u64 },
+}
+
+impl Notification {
+    /// Machine readable event name.
+    pub fn event(&self) -> &'static str {
+        match *self {
+            Notification::BackupStarted => "backup_started",
+            Notification::BackupComplete { .. } => "backup_complete",
+            Notification::BackupPartial { .. } => "backup_partial",
+            Notification::BackupUpToDate => "backup_up_to_date",
+            Notification::BackupFailed { .. } => "backup_failed",
+            Notification::UploadFailed { .. } => "upload_failed",
+            Notification::VerifyFailed { .. } => "verify_failed",
+            Notification::PruneComplete { .. } => "prune_complete",
+            Notification::StorageSoftCap { .. } => "storage_soft_cap",
+            Notification::StorageHardCap { .. } => "storage_hard_cap",
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        match *self {
+            Notification::BackupPartial { .. } |
+            Notification::BackupFailed { .. } |
+            Notification::UploadFailed { .. } |
+            Notification::VerifyFailed { .. } |
+            Notification::StorageHardCap { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notification::BackupStarted => write!(f, "Backup started"),
+            Notification::BackupComplete { backup_set } => {
+                write!(f, "Backup set {} complete", backup_set)
+            }
+            Notification::BackupPartial { backup_set, failures } => {
+                write!(f, "Backup set {} complete, {} file(s) failed", backup_set, failures)
+            }
+            Notification::BackupUpToDate => write!(f, "Backup up to date, nothing changed"),
+            Notification::BackupFailed { ref error } => write!(f, "Backup failed: {}", error),
+            Notification::UploadFailed { ref path, ref error } => {
+                write!(f, "Failed to upload {}: {}", path, error)
+            }
+            Notification::VerifyFailed { ref failed } => {
+                write!(f, "Verification failed for {} file(s)", failed.len())
+            }
+            Notification::PruneComplete { removed } => {
+                write!(f, "Prune complete, removed {} version(s)", removed)
+            }
+            Notification::StorageSoftCap { used, cap } => {
+                write!(f,
+                       "Repository holds {}, over its soft cap of {}",
+                       format_bytes(used),
+                       format_bytes(cap))
+            }
+            Notification::StorageHardCap { used, cap } => {
+                write!(f,
+                       "Repository holds {}, reaching its hard cap of {}; uploads are paused",
+                       format_bytes(used),
+                       format_bytes(cap))
+            }
+        }
+    }
+}
+
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<Error>>;
+}
+
+/// Work for the thread delivering notifications.
+enum Delivery {
+    Send(Notification),
+    /// Reply once everything sent before has been delivered.
+    Flush(Sender<()>),
+}
+
+/// Every configured notifier. Notifications are delivered in the order they were sent by a
+/// thread of their own, so slow endpoints never hold up the engine. `flush` waits for them
+/// before the process exits.
+#[derive(Clone)]
+pub struct Notifiers {
+    worker: Option<Arc<Mutex<Sender<Delivery>>>>,
+}
+
+impl Notifiers {
+    pub fn new(notifiers: Vec<Box<Notifier>>) -> Self {
+        if notifiers.is_empty() {
+            return Notifiers { worker: None };
+        }
+        let (tx, rx) = channel();
+        thread::spawn(move || for delivery in rx {
+            match delivery {
+                Delivery::Send(notification) => deliver(&notifiers, &notification),
+                Delivery::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        });
+        Notifiers { worker: Some(Arc::new(Mutex::new(tx))) }
+    }
+
+    pub fn from_config(config: &EngineConfig) -> Self {
+        let mut notifiers: Vec<Box<Notifier>> = vec![];
+        if let Some(url) = config.webhook_url() {
+            notifiers.push(box WebhookNotifier::new(url));
+        }
+        if let Some(smtp) = config.smtp() {
+            notifiers.push(box EmailNotifier::new(smtp.clone()));
+        }
+        if config.desktop_notifications() {
+            notifiers.push(box DesktopNotifier);
+        }
+        if let Some(url) = config.healthcheck_url() {
+            notifiers.push(box HealthcheckNotifier::new(url));
+        }
+        Self::new(notifiers)
+    }
+
+    pub fn send(&self, notification: Notification) {
+        if let Some(ref worker) = self.worker {
+            let _ = worker.lock().expect("notifiers lock").send(Delivery::Send(notification));
+        }
+    }
+
+    /// Wait up to `timeout` for every notification sent so far to be delivered, returning
+    /// whether they were.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let worker = match self.worker {
+            Some(ref worker) => worker,
+            None => return true,
+        };
+        let (tx, rx) = channel();
+        if worker.lock().expect("notifiers lock").send(Delivery::Flush(tx)).is_err() {
+            return true;
+        }
+        rx.recv_timeout(timeout).is_ok()
+    }
+}
+
+fn deliver(notifiers: &[Box<Notifier>], notification: &Notification) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.notify(notification) {
+            error!("Failed to send {} notification: {}", notification.event(), e);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload {
+    event: String,
+    message: String,
+    time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_set: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    used_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cap_bytes: Option<u64>,
+}
+
+impl<'a> From<&'a Notification> for WebhookPayload {
+    fn from(n: &Notification) -> Self {
+        let mut payload = WebhookPayload {
+            event: n.event().to_string(),
+            message: format!("{}", n),
+            time: time::now_utc().to_timespec().sec,
+            backup_set: None,
+            path: None,
+            failed: vec![],
+            used_bytes: None,
+            cap_bytes: None,
+        };
+        match *n {
+            Notification::BackupComplete { backup_set } |
+            Notification::BackupPartial { backup_set, .. } => payload.backup_set = Some(backup_set),
+            Notification::UploadFailed { ref path, .. } => payload.path = Some(path.clone()),
+            Notification::VerifyFailed { ref failed } => payload.failed = failed.clone(),
+            Notification::StorageSoftCap { used, cap } |
+            Notification::StorageHardCap { used, cap } => {
+                payload.used_bytes = Some(used);
+                payload.cap_bytes = Some(cap);
+            }
+            _ => (),
+        }
+        payload
+    }
+}
+
+/// POSTs a JSON payload to a URL, retrying with backoff.
+pub struct WebhookNotifier {
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        WebhookNotifier { url: url.to_string() }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<Error>> {
+        let body = serde_json::to_string(&WebhookPayload::from(notification))?;
+        let client = Client::new();
+
+        retry_with_backoff(4, Duration::new(1, 0), || {
+            let mut res = client.post(&self.url)
+                .header(ContentType::json())
+                .body(body.as_str())
+                .send()
+                .map_err(|e| format!("POST {} failed: {}", self.url, e))?;
+            if res.status.is_success() {
+                Ok(())
+            } else {
+                let mut response = String::new();
+                let _ = res.read_to_string(&mut response);
+                Err(format!("POST {} returned {}: {}", self.url, res.status, response))
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Where and how to deliver failure emails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmtpSettings {
+    host: String,
+    port: u16,
+    from: String,
+    to: Vec<String>,
+    credentials: Option<(String, String)>,
+}
+
+impl SmtpSettings {
+    pub fn new(host: &str, port: u16, from: &str, to: Vec<String>) -> Self {
+        SmtpSettings {
+            host: host.to_string(),
+            port: port,
+            from: from.to_string(),
+            to: to,
+            credentials: None,
+        }
+    }
+
+    /// Authenticate with AUTH LOGIN before sending.
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+    pub fn to(&self) -> &[String] {
+        &self.to
+    }
+}
+
+/// Emails failures over plain SMTP. Successful runs are not worth an email.
+pub struct EmailNotifier {
+    settings: SmtpSettings,
+}
+
+impl EmailNotifier {
+    pub fn new(settings: SmtpSettings) -> Self {
+        EmailNotifier { settings: settings }
+    }
+
+    fn send(&self, subject: &str, body: &str) -> Result<(), Box<Error>> {
+        let s = &self.settings;
+        let stream = TcpStream::connect((s.host.as_str(), s.port))?;
+        let mut session = SmtpSession {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        session.expect(220)?;
+        session.command("EHLO haumaru", 250)?;
+        if let Some((ref username, ref password)) = s.credentials {
+            session.command("AUTH LOGIN", 334)?;
+            session.command(&username.as_bytes().to_base64(STANDARD), 334)?;
+            session.command(&password.as_bytes().to_base64(STANDARD), 235)?;
+        }
+        session.command(&format!("MAIL FROM:<{}>", s.from), 250)?;
+        for to in &s.to {
+            session.command(&format!("RCPT TO:<{}>", to), 250)?;
+        }
+        session.command("DATA", 354)?;
+        session.command(&message(&s.from, &s.to, subject, body), 250)?;
+        session.command("QUIT", 221)?;
+        Ok(())
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<Error>> {
+        if !notification.is_failure() {
+            return Ok(());
+        }
+        let subject = format!("haumaru: {}", notification);
+        let mut body = format!("{}\r\n", notification);
+        match *notification {
+            Notification::BackupFailed { .. } => {
+                body.push_str("\r\nThe backup run was aborted. Check the haumaru logs.\r\n")
+            }
+            Notification::BackupPartial { .. } => {
+                body.push_str("\r\nThe files left out are listed in the haumaru logs.\r\n")
+            }
+            Notification::VerifyFailed { ref failed } => {
+                body.push_str("\r\nFiles that failed verification:\r\n");
+                for path in failed {
+                    body.push_str(&format!("  {}\r\n", path));
+                }
+            }
+            _ => (),
+        }
+        retry_with_backoff(3, Duration::new(5, 0), || self.send(&subject, &body))?;
+        Ok(())
+    }
+}
+
+/// Pops up a notification on the user's desktop for finished and failed runs.
+pub struct DesktopNotifier;
+
+impl DesktopNotifier {
+    #[cfg(target_os = "macos")]
+    fn command(summary: &str, body: &str) -> Command {
+        let mut cmd = Command::new("osascript");
+        cmd.arg("-e").arg(format!("display notification {:?} with title {:?}", body, summary));
+        cmd
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn command(summary: &str, body: &str) -> Command {
+        let mut cmd = Command::new("notify-send");
+        cmd.arg("--app-name=haumaru").arg(summary).arg(body);
+        cmd
+    }
+}
+
+impl Notifier for DesktopNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<Error>> {
+        let summary = match *notification {
+            Notification::BackupComplete { .. } => "Backup complete",
+            Notification::BackupPartial { .. } => "Backup incomplete",
+            Notification::BackupFailed { .. } => "Backup failed",
+            _ => return Ok(()),
+        };
+        let status = Self::command(summary, &format!("{}", notification)).status()?;
+        if !status.success() {
+            return Err(format!("Desktop notification exited with {}", status).into());
+        }
+        Ok(())
+    }
+}
+
+/// Pings a healthchecks.io style URL as runs start, succeed and fail, so a monitor notices when
+/// backups stop running at all.
+pub struct HealthcheckNotifier {
+    url: String,
+}
+
+impl HealthcheckNotifier {
+    pub fn new(url: &str) -> Self {
+        HealthcheckNotifier { url: url.trim_right_matches('/').to_string() }
+    }
+
+    /// URL to ping for `notification`, if it marks the start or end of a run.
+    fn ping_url(&self, notification: &Notification) -> Option<String> {
+        match *notification {
+            Notification::BackupStarted => Some(format!("{}/start", self.url)),
+            Notification::BackupComplete { .. } |
+            Notification::BackupUpToDate => Some(self.url.clone()),
+            Notification::BackupPartial { .. } |
+            Notification::BackupFailed { .. } => Some(format!("{}/fail", self.url)),
+            _ => None,
+        }
+    }
+}
+
+impl Notifier for HealthcheckNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), Box<Error>> {
+        let url = match self.ping_url(notification) {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+        let client = Client::new();
+        retry_with_backoff(3, Duration::new(1, 0), || {
+            let res = client.get(&url).send().map_err(|e| format!("GET {} failed: {}", url, e))?;
+            if res.status.is_success() {
+                Ok(())
+            } else {
+                Err(format!("GET {} returned {}", url, res.status))
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Build the DATA section, dot-stuffed and terminated.
+fn message(from: &str, to: &[String], subject: &str, body: &str) -> String {
+    let mut data = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n",
+                           from,
+                           to.join(", "),
+                           subject,
+                           time::now_utc().rfc822());
+    for line in body.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+    data
+}
+
+struct SmtpSession {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl SmtpSession {
+    fn command(&mut self, line: &str, code: u32) -> Result<(), Box<Error>> {
+        write!(self.writer, "{}\r\n", line)?;
+        self.expect(code)
+    }
+
+    /// Read a possibly multi-line reply and check its status code.
+    fn expect(&mut self, code: u32) -> Result<(), Box<Error>> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err("SMTP server closed the connection".into());
+            }
+            if line.len() < 4 {
+                return Err(format!("Bad SMTP reply: {}", line.trim()).into());
+            }
+            let status = line[0..3].parse::<u32>()?;
+            if status != code {
+                return Err(format!("SMTP server replied: {}", line.trim()).into());
+            }
+            if &line[3..4] != "-" {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use super::*;
+    use super::WebhookPayload;
+
+    #[test]
+    fn payload() {
+        let n = Notification::UploadFailed {
+            path: "a/b".into(),
+            error: "disk full".into(),
+        };
+        let json = serde_json::to_value(&WebhookPayload::from(&n));
+        assert_eq!(Some("upload_failed"),
+                   json.find("event").and_then(|v| v.as_str()));
+        assert_eq!(Some("Failed to upload a/b: disk full"),
+                   json.find("message").and_then(|v| v.as_str()));
+        assert_eq!(Some("a/b"), json.find("path").and_then(|v| v.as_str()));
+        assert_eq!(None, json.find("backup_set"));
+    }
+
+    #[test]
+    fn healthcheck_urls() {
+        let hc = HealthcheckNotifier::new("https://hc-ping.com/abc/");
+        assert_eq!(Some("https://hc-ping.com/abc/start".to_string()),
+                   hc.ping_url(&Notification::BackupStarted));
+        assert_eq!(Some("https://hc-ping.com/abc".to_string()),
+                   hc.ping_url(&Notification::BackupUpToDate));
+        assert_eq!(Some("https://hc-ping.com/abc/fail".to_string()),
+                   hc.ping_url(&Notification::BackupFailed { error: "x".into() }));
+        assert_eq!(Some("https://hc-ping.com/abc/fail".to_string()),
+                   hc.ping_url(&Notification::BackupPartial {
+                       backup_set: 1,
+                       failures: 2,
+                   }));
+        assert_eq!(None, hc.ping_url(&Notification::PruneComplete { removed: 1 }));
+    }
+
+    #[test]
+    fn storage_cap_payload() {
+        let n = Notification::StorageHardCap {
+            used: 2048,
+            cap: 1024,
+        };
+        assert!(n.is_failure());
+        let json = serde_json::to_value(&WebhookPayload::from(&n));
+        assert_eq!(Some("storage_hard_cap"),
+                   json.find("event").and_then(|v| v.as_str()));
+        assert_eq!(Some(2048), json.find("used_bytes").and_then(|v| v.as_u64()));
+        assert_eq!(Some(1024), json.find("cap_bytes").and_then(|v| v.as_u64()));
+    }
+
+    #[test]
+    fn email_message_is_dot_stuffed() {
+        let data = message("a@example.com",
+                           &["b@example.com".to_string(), "c@example.com".to_string()],
+                           "haumaru: oops",
+                           "line one\r\n.hidden\r\n");
+        assert!(data.starts_with("From: a@example.com\r\nTo: b@example.com, c@example.com\r\n"));
+        assert!(data.contains("\r\nline one\r\n..hidden\r\n.") );
+        assert!(data.ends_with("\r\n."));
+    }
+
+    struct Recorder {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Notifier for Recorder {
+        fn notify(&self, notification: &Notification) -> Result<(), Box<Error>> {
+            thread::sleep(Duration::from_millis(10));
+            self.sent.lock().unwrap().push(notification.event().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn delivered_in_order_by_flush() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let notifiers = Notifiers::new(vec![box Recorder { sent: sent.clone() }]);
+        notifiers.send(Notification::BackupStarted);
+        notifiers.send(Notification::BackupFailed { error: "x".into() });
+        assert!(notifiers.flush(Duration::from_secs(5)));
+        assert_eq!(vec!["backup_started", "backup_failed"], *sent.lock().unwrap());
+
+        assert!(Notifiers::new(vec![]).flush(Duration::from_secs(0)));
+    }
+}
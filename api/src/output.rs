@@ -0,0 +1,157 @@
+//! Machine readable records for `--json` output. Commands print one JSON object per line.
+
+use Node;
+use serde::Serialize;
+use serde_json;
+use std::fmt;
+use std::str::FromStr;
+
+/// How command results are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+pub fn json_line<T>(value: &T) -> String
+    where T: Serialize
+{
+    serde_json::to_string(value).expect("serialize output")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeRecord {
+    pub path: String,
+    pub kind: String,
+    pub size: u64,
+    pub mode: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    pub mtime: i64,
+    pub deleted: bool,
+    pub backup_set: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_algorithm: Option<String>,
+    /// When a deleted entry, shown as its last version, was deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    /// The file changed while it was read, so the content may not match any one version of it.
+    #[serde(skip_serializing_if = "is_false")]
+    pub inconsistent: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl<'a> From<&'a Node> for NodeRecord {
+    fn from(node: &Node) -> Self {
+        let hash = if node.has_hash() {
+            Some(node.hash_string())
+        } else {
+            None
+        };
+        NodeRecord {
+            path: node.path().to_string(),
+            kind: if node.is_dir() { "dir" } else { "file" }.to_string(),
+            size: node.size(),
+            mode: node.mode(),
+            uid: node.owner().map(|(uid, _)| uid),
+            gid: node.owner().map(|(_, gid)| gid),
+            mtime: node.mtime().sec,
+            deleted: node.deleted(),
+            backup_set: node.backup_set(),
+            hash_algorithm: hash.as_ref().map(|_| node.hash_algorithm().tag().to_string()),
+            hash: hash,
+            deleted_at: None,
+            inconsistent: node.inconsistent(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VerifyRecord {
+    pub path: String,
+    pub backup_set: Option<u64>,
+    pub ok: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriftRecord {
+    pub path: String,
+    /// "new", "changed" or "missing".
+    pub drift: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedRecord {
+    pub path: String,
+    /// "symlink", "over-size", "excluded" or "reserved".
+    pub reason: String,
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RestoreRecord {
+    pub path: String,
+    pub target: String,
+    pub kind: String,
+    pub size: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use Node;
+    use serde_json;
+    use super::*;
+    use time::Timespec;
+
+    #[test]
+    fn format_from_str() {
+        assert_eq!(Ok(OutputFormat::Json), "json".parse());
+        assert_eq!(Ok(OutputFormat::Text), "text".parse());
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn dir_record() {
+        let node = Node::new_dir("a/b", Timespec::new(10, 0), 493).with_backup_set(2);
+        let json = serde_json::to_value(&NodeRecord::from(&node));
+        assert_eq!(Some("dir"), json.find("kind").and_then(|v| v.as_str()));
+        assert_eq!(Some(10), json.find("mtime").and_then(|v| v.as_i64()));
+        assert_eq!(None, json.find("hash"));
+        assert_eq!(None, json.find("inconsistent"));
+    }
+}
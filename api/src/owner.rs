@@ -0,0 +1,41 @@
+//! File ownership, put back by restores run as root.
+
+use libc;
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Give the file at `path` to `uid` and `gid`. Needs CAP_CHOWN, so in practice root.
+pub fn chown(path: &Path, uid: u32, gid: u32) -> IoResult<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+    let rc = unsafe { libc::chown(c_path.as_ptr(), uid as libc::uid_t, gid as libc::gid_t) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+/// Whether this process may give files away.
+pub fn can_chown() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{File, metadata};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    #[test]
+    fn chown_to_self() {
+        let path = Path::new("target/owner_self");
+        File::create(path).unwrap();
+        let before = metadata(path).unwrap();
+        chown(path, before.uid(), before.gid()).unwrap();
+        assert!(chown(Path::new("target/does/not/exist"), 0, 0).is_err());
+    }
+}
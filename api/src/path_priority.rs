@@ -0,0 +1,107 @@
+use std::str::FromStr;
+
+/// How soon a subtree is backed up after a burst of changes, ordered most important first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            s => Err(format!("Unknown priority {:?}, expected high, normal or low", s)),
+        }
+    }
+}
+
+/// Files under the backup key `prefix` are backed up with `priority`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathPriority {
+    prefix: String,
+    priority: Priority,
+}
+
+impl PathPriority {
+    pub fn new(prefix: &str, priority: Priority) -> Self {
+        PathPriority {
+            prefix: prefix.trim_matches('/').to_string(),
+            priority: priority,
+        }
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        key == self.prefix || key.starts_with(&format!("{}/", self.prefix))
+    }
+}
+
+/// Priority of `key`, from the longest prefix covering it.
+pub fn priority_of(priorities: &[PathPriority], key: &str) -> Priority {
+    priorities.iter()
+        .filter(|p| p.covers(key))
+        .max_by_key(|p| p.prefix.len())
+        .map(|p| p.priority)
+        .unwrap_or(Priority::Normal)
+}
+
+/// Priority to scan the directory `key` with: its own, or that of the most important subtree
+/// configured below it, so scans reach that subtree early.
+pub fn subtree_priority(priorities: &[PathPriority], key: &str) -> Priority {
+    let below = format!("{}/", key);
+    priorities.iter()
+        .filter(|p| key.is_empty() || p.prefix.starts_with(&below))
+        .map(|p| p.priority)
+        .fold(priority_of(priorities, key), ::std::cmp::min)
+}
+
+/// Parse `prefix=priority` pairs separated by `;`.
+pub fn parse_priorities(s: &str) -> Result<Vec<PathPriority>, String> {
+    let mut priorities = vec![];
+    for entry in s.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let prefix = parts.next().unwrap_or("").trim();
+        let priority = parts.next().unwrap_or("").trim();
+        if prefix.is_empty() || priority.is_empty() {
+            return Err(format!("Priority must be path=priority: {}", entry));
+        }
+        priorities.push(PathPriority::new(prefix, priority.parse()?));
+    }
+    Ok(priorities)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let priorities = parse_priorities("Documents=high; Documents/cache=low; Downloads=low")
+            .unwrap();
+        assert_eq!(Priority::High, priority_of(&priorities, "Documents/cv.odt"));
+        assert_eq!(Priority::Low, priority_of(&priorities, "Documents/cache/a"));
+        assert_eq!(Priority::Low, priority_of(&priorities, "Downloads"));
+        assert_eq!(Priority::Normal, priority_of(&priorities, "DocumentsOld/a"));
+    }
+
+    #[test]
+    fn subtrees() {
+        let priorities = parse_priorities("home/me/Documents=high; home/me/Downloads=low")
+            .unwrap();
+        assert_eq!(Priority::High, subtree_priority(&priorities, "home/me"));
+        assert_eq!(Priority::Normal, subtree_priority(&priorities, "home/other"));
+        assert_eq!(Priority::Low, subtree_priority(&priorities, "home/me/Downloads"));
+    }
+
+    #[test]
+    fn bad_entries() {
+        assert!(parse_priorities("Documents").is_err());
+        assert!(parse_priorities("Documents=urgent").is_err());
+        assert_eq!(Ok(vec![]), parse_priorities(" ; "));
+    }
+}
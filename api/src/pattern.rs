@@ -0,0 +1,267 @@
+/// Editor and build tool scratch files that are never worth backing up.
+pub const DEFAULT_TRANSIENT_PATTERNS: &'static [&'static str] = &["*.swp",
+                                                                  "*.swo",
+                                                                  "*.swx",
+                                                                  "*~",
+                                                                  ".#*",
+                                                                  "#*#",
+                                                                  "*.tmp",
+                                                                  "4913",
+                                                                  ".goutputstream-*"];
+
+/// Shell style wildcard pattern. `*` matches any run of characters and `?` any single
+/// character.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pattern: String,
+    chars: Vec<char>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Pattern {
+            pattern: pattern.to_string(),
+            chars: pattern.chars().collect(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, s: &str) -> bool {
+        let s: Vec<char> = s.chars().collect();
+        let p = &self.chars;
+
+        let (mut si, mut pi) = (0, 0);
+        // position of the last `*` and where in `s` it started matching
+        let mut star: Option<(usize, usize)> = None;
+
+        while si < s.len() {
+            if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+                si += 1;
+                pi += 1;
+            } else if pi < p.len() && p[pi] == '*' {
+                star = Some((pi, si));
+                pi += 1;
+            } else if let Some((star_pi, star_si)) = star {
+                // let the last `*` swallow one more character
+                pi = star_pi + 1;
+                si = star_si + 1;
+                star = Some((star_pi, star_si + 1));
+            } else {
+                return false;
+            }
+        }
+
+        while pi < p.len() && p[pi] == '*' {
+            pi += 1;
+        }
+        pi == p.len()
+    }
+}
+
+/// Glob over backup keys. `*` and `?` stay within one path component while `**` matches
+/// across them, so `photos/**/*.jpg` matches `photos/2023/a.jpg`. A glob without wildcards
+/// matches that key and everything below it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    pattern: String,
+    chars: Vec<char>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim_matches('/');
+        Glob {
+            pattern: pattern.to_string(),
+            chars: pattern.chars().collect(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The part before the first wildcard. Every key the glob matches starts with it.
+    pub fn literal_prefix(&self) -> &str {
+        match self.pattern.find(|c: char| c == '*' || c == '?') {
+            Some(i) => &self.pattern[..i],
+            None => &self.pattern,
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        if self.literal_prefix().len() == self.pattern.len() {
+            return self.pattern.is_empty() || key == self.pattern ||
+                   key.starts_with(&format!("{}/", self.pattern));
+        }
+        let key: Vec<char> = key.chars().collect();
+        glob_matches(&self.chars, &key)
+    }
+}
+
+fn glob_matches(p: &[char], s: &[char]) -> bool {
+    if p.is_empty() {
+        return s.is_empty();
+    }
+    match p[0] {
+        '*' if p.len() > 1 && p[1] == '*' => {
+            let rest = &p[2..];
+            // `**/` may also stand for no directories at all
+            if !rest.is_empty() && rest[0] == '/' && glob_matches(&rest[1..], s) {
+                return true;
+            }
+            (0..s.len() + 1).any(|i| glob_matches(rest, &s[i..]))
+        }
+        '*' => {
+            for i in 0..s.len() + 1 {
+                if glob_matches(&p[1..], &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        '?' => !s.is_empty() && s[0] != '/' && glob_matches(&p[1..], &s[1..]),
+        c => !s.is_empty() && s[0] == c && glob_matches(&p[1..], &s[1..]),
+    }
+}
+
+/// Parse a comma separated list of patterns.
+pub fn parse_patterns(s: &str) -> Vec<Pattern> {
+    s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()).map(Pattern::new).collect()
+}
+
+pub fn default_transient_patterns() -> Vec<Pattern> {
+    DEFAULT_TRANSIENT_PATTERNS.iter().map(|p| Pattern::new(p)).collect()
+}
+
+pub fn matches_any(patterns: &[Pattern], s: &str) -> bool {
+    patterns.iter().any(|p| p.matches(s))
+}
+
+/// Does any of `globs` match `key` or a directory above it. A glob ending in `/**` also
+/// matches the directory itself, so `**/node_modules/**` covers `node_modules` as well.
+pub fn glob_covers(globs: &[Glob], key: &str) -> bool {
+    if globs.is_empty() || key.is_empty() {
+        return false;
+    }
+    let mut dir = key;
+    loop {
+        let as_dir = format!("{}/", dir);
+        if globs.iter().any(|g| g.matches(dir) || g.matches(&as_dir)) {
+            return true;
+        }
+        match dir.rfind('/') {
+            Some(i) => dir = &dir[..i],
+            None => return false,
+        }
+    }
+}
+
+/// Could any of `globs` match a key below the directory `key`. Only the part of each glob
+/// before its first wildcard is considered, so this may say yes when none will.
+pub fn glob_may_contain(globs: &[Glob], key: &str) -> bool {
+    let dir = format!("{}/", key);
+    globs.iter().any(|g| {
+        let prefix = g.literal_prefix();
+        prefix.starts_with(&dir) || (prefix.len() < g.as_str().len() && dir.starts_with(prefix))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(Pattern::new("*.swp").matches(".a.txt.swp"));
+        assert!(!Pattern::new("*.swp").matches("a.swpx"));
+        assert!(Pattern::new("*~").matches("a.txt~"));
+        assert!(Pattern::new(".#*").matches(".#a.txt"));
+        assert!(!Pattern::new(".#*").matches("a.#txt"));
+        assert!(Pattern::new("a?c").matches("abc"));
+        assert!(!Pattern::new("a?c").matches("ac"));
+        assert!(Pattern::new("*").matches(""));
+        assert!(Pattern::new("a*b*c").matches("aXbYbZc"));
+        assert!(!Pattern::new("a*b*c").matches("aXbYbZ"));
+        assert!(Pattern::new("4913").matches("4913"));
+    }
+
+    #[test]
+    fn globs() {
+        let glob = Glob::new("photos/2023/**");
+        assert_eq!("photos/2023/", glob.literal_prefix());
+        assert!(glob.matches("photos/2023/a.jpg"));
+        assert!(glob.matches("photos/2023/trip/b.jpg"));
+        assert!(!glob.matches("photos/20234/a.jpg"));
+
+        let glob = Glob::new("photos/*.jpg");
+        assert!(glob.matches("photos/a.jpg"));
+        assert!(!glob.matches("photos/2023/a.jpg"));
+
+        let glob = Glob::new("**/*.tmp");
+        assert_eq!("", glob.literal_prefix());
+        assert!(glob.matches("a.tmp"));
+        assert!(glob.matches("build/out/a.tmp"));
+        assert!(!glob.matches("a.tmp/b"));
+
+        assert!(Glob::new("**/node_modules/**").matches("web/node_modules/x/index.js"));
+        assert!(Glob::new("a?c").matches("abc"));
+        assert!(!Glob::new("a?c").matches("a/c"));
+    }
+
+    #[test]
+    fn glob_coverage() {
+        let globs = vec![Glob::new("**/*.tmp"), Glob::new("**/node_modules/**")];
+        assert!(glob_covers(&globs, "a.tmp"));
+        assert!(glob_covers(&globs, "build/a.tmp"));
+        assert!(glob_covers(&globs, "build/a.tmp/inside"));
+        assert!(glob_covers(&globs, "web/node_modules"));
+        assert!(glob_covers(&globs, "web/node_modules/x/index.js"));
+        assert!(!glob_covers(&globs, "web/index.js"));
+        assert!(!glob_covers(&globs, ""));
+        assert!(!glob_covers(&[], "a.tmp"));
+    }
+
+    #[test]
+    fn glob_containment() {
+        let globs = vec![Glob::new("Documents/**"), Glob::new("Projects/work/*.md")];
+        assert!(glob_may_contain(&globs, "Documents"));
+        assert!(glob_may_contain(&globs, "Documents/taxes"));
+        assert!(glob_may_contain(&globs, "Projects"));
+        assert!(glob_may_contain(&globs, "Projects/work"));
+        assert!(!glob_may_contain(&globs, "Projects/home"));
+        assert!(!glob_may_contain(&globs, "Music"));
+        assert!(glob_may_contain(&[Glob::new("**/*.pdf")], "Music"));
+    }
+
+    #[test]
+    fn glob_prefixes() {
+        let glob = Glob::new("/photos/2023/");
+        assert_eq!("photos/2023", glob.as_str());
+        assert!(glob.matches("photos/2023"));
+        assert!(glob.matches("photos/2023/a.jpg"));
+        assert!(!glob.matches("photos/20234"));
+        assert!(Glob::new("").matches("anything/at/all"));
+    }
+
+    #[test]
+    fn defaults() {
+        let patterns = default_transient_patterns();
+        assert!(matches_any(&patterns, ".notes.md.swp"));
+        assert!(matches_any(&patterns, "#notes.md#"));
+        assert!(matches_any(&patterns, "build.tmp"));
+        assert!(!matches_any(&patterns, "notes.md"));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(vec![Pattern::new("*.a"), Pattern::new("b?")],
+                   parse_patterns(" *.a, ,b? "));
+        assert!(parse_patterns("").is_empty());
+    }
+}
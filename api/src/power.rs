@@ -0,0 +1,149 @@
+use engine::{Pause, PauseReason};
+use std::cmp::min;
+use std::fs::{File, read_dir};
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const POWER_SUPPLY_PATH: &'static str = "/sys/class/power_supply";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerState {
+    /// Running from mains power. Machines without a battery always report true.
+    pub on_ac: bool,
+    /// Lowest charge across all batteries, in percent.
+    pub battery: Option<u32>,
+}
+
+impl PowerState {
+    /// Should uploads pause, given the battery level below which to pause.
+    pub fn should_pause(&self, threshold: u32) -> bool {
+        !self.on_ac && self.battery.map(|b| b < threshold).unwrap_or(false)
+    }
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    let mut s = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut s)).ok().map(|_| s.trim().to_string())
+}
+
+/// Read the power state from a sysfs style `power_supply` directory.
+pub fn read_power_state(root: &Path) -> PowerState {
+    let mut saw_mains = false;
+    let mut mains_online = false;
+    let mut discharging = false;
+    let mut battery: Option<u32> = None;
+
+    let entries = match read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Unable to read {:?}: {}", root, e);
+            return PowerState {
+                on_ac: true,
+                battery: None,
+            };
+        }
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let supply = entry.path();
+        match read_trimmed(&supply.join("type")).as_ref().map(|s| s.as_str()) {
+            Some("Mains") => {
+                saw_mains = true;
+                if read_trimmed(&supply.join("online")).as_ref().map(|s| s.as_str()) == Some("1") {
+                    mains_online = true;
+                }
+            }
+            Some("Battery") => {
+                if read_trimmed(&supply.join("status")).as_ref().map(|s| s.as_str()) ==
+                   Some("Discharging") {
+                    discharging = true;
+                }
+                if let Some(capacity) = read_trimmed(&supply.join("capacity"))
+                    .and_then(|c| c.parse::<u32>().ok()) {
+                    battery = Some(battery.map(|b| min(b, capacity)).unwrap_or(capacity));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let on_ac = if saw_mains { mains_online } else { !discharging };
+
+    PowerState {
+        on_ac: on_ac,
+        battery: battery,
+    }
+}
+
+/// Pause while on battery below `threshold` percent, resuming once back on AC or charged.
+pub fn watch_power(pause: Pause, threshold: u32) {
+    thread::spawn(move || {
+        loop {
+            let state = read_power_state(Path::new(POWER_SUPPLY_PATH));
+            trace!("Power state: {:?}", state);
+            if state.should_pause(threshold) {
+                pause.pause(PauseReason::Battery);
+            } else {
+                pause.resume(PauseReason::Battery);
+            }
+            thread::sleep(Duration::new(60, 0));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use std::path::Path;
+    use super::*;
+
+    fn supply(root: &str, name: &str, files: &[(&str, &str)]) {
+        let dir = Path::new(root).join(name);
+        create_dir_all(&dir).expect("mkdir supply");
+        for &(file, content) in files {
+            File::create(dir.join(file))
+                .and_then(|mut f| f.write_all(content.as_bytes()))
+                .expect("write supply file");
+        }
+    }
+
+    #[test]
+    fn laptop_on_battery() {
+        let root = "target/test/power_laptop_on_battery";
+        let _ = remove_dir_all(root);
+        supply(root, "AC", &[("type", "Mains\n"), ("online", "0\n")]);
+        supply(root,
+               "BAT0",
+               &[("type", "Battery\n"), ("status", "Discharging\n"), ("capacity", "25\n")]);
+
+        let state = read_power_state(Path::new(root));
+        assert_eq!(PowerState {
+                       on_ac: false,
+                       battery: Some(25),
+                   },
+                   state);
+        assert!(state.should_pause(30));
+        assert!(!state.should_pause(20));
+    }
+
+    #[test]
+    fn laptop_on_ac() {
+        let root = "target/test/power_laptop_on_ac";
+        let _ = remove_dir_all(root);
+        supply(root, "AC", &[("type", "Mains\n"), ("online", "1\n")]);
+        supply(root,
+               "BAT0",
+               &[("type", "Battery\n"), ("status", "Charging\n"), ("capacity", "5\n")]);
+
+        assert!(!read_power_state(Path::new(root)).should_pause(30));
+    }
+
+    #[test]
+    fn no_power_supply() {
+        let state = read_power_state(Path::new("target/test/power_does_not_exist"));
+        assert!(state.on_ac);
+    }
+}
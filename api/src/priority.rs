@@ -0,0 +1,53 @@
+use libc;
+use std::io::{Error as IoError, Result as IoResult};
+
+/// Set the niceness of the calling thread. Linux applies this per thread, so only the scan and
+/// worker threads are affected.
+pub fn set_nice(nice: i32) -> IoResult<()> {
+    let rc = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+/// Move the calling thread into the idle IO scheduling class (`ionice -c3`).
+#[cfg(target_os = "linux")]
+pub fn set_idle_io() -> IoResult<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    let rc = unsafe {
+        libc::syscall(libc::SYS_ioprio_set,
+                      IOPRIO_WHO_PROCESS,
+                      0,
+                      IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT)
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_idle_io() -> IoResult<()> {
+    debug!("Idle IO priority not supported on this platform");
+    Ok(())
+}
+
+/// Apply the configured priorities to the calling thread. Failures are logged, not fatal.
+pub fn lower_current_thread(nice: Option<i32>, idle_io: bool) {
+    if let Some(nice) = nice {
+        if let Err(e) = set_nice(nice) {
+            warn!("Unable to set nice {}: {}", nice, e);
+        }
+    }
+    if idle_io {
+        if let Err(e) = set_idle_io() {
+            warn!("Unable to set idle IO priority: {}", e);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Progress bars for interactive commands, drawn on stdout only when it is a terminal.
+
+use engine::{Progress, ProgressSnapshot};
+use libc;
+use std::io::{Write, stdout};
+use std::iter::repeat;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle, sleep};
+use std::time::Duration;
+use units::format_bytes;
+
+const WIDTH: usize = 100;
+const BAR_WIDTH: usize = 20;
+
+/// Redraws a progress line for `Progress` until finished.
+pub struct ProgressBar {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressBar {
+    /// Start drawing `progress`, or do nothing when stdout is not a terminal.
+    pub fn start(progress: Progress) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = if is_tty() {
+            let stop = stop.clone();
+            Some(thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    draw(&render(&progress.snapshot(), WIDTH));
+                    sleep(Duration::from_millis(200));
+                }
+                draw(&render(&progress.snapshot(), WIDTH));
+                println!("");
+            }))
+        } else {
+            None
+        };
+        ProgressBar {
+            stop: stop,
+            thread: thread,
+        }
+    }
+
+    /// Draw the final state and leave the line in place.
+    pub fn finish(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn is_tty() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+fn draw(line: &str) {
+    let mut out = stdout();
+    let _ = write!(out, "\r{}\x1b[K", line);
+    let _ = out.flush();
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// One line of at most `width` characters describing `s`.
+pub fn render(s: &ProgressSnapshot, width: usize) -> String {
+    let filled = if s.bytes_total > 0 {
+        (BAR_WIDTH as u64 * s.bytes_done / s.bytes_total) as usize
+    } else if s.files_total > 0 {
+        (BAR_WIDTH as u64 * s.files_done / s.files_total) as usize
+    } else {
+        0
+    };
+    let filled = if filled > BAR_WIDTH { BAR_WIDTH } else { filled };
+
+    let mut line = format!("[{}{}] {}/{} files  {}/{}  {}/s",
+                           repeat('#').take(filled).collect::<String>(),
+                           repeat('-').take(BAR_WIDTH - filled).collect::<String>(),
+                           s.files_done,
+                           s.files_total,
+                           format_bytes(s.bytes_done),
+                           format_bytes(s.bytes_total),
+                           format_bytes(s.throughput() as u64));
+    if let Some(eta) = s.eta() {
+        line.push_str(&format!("  ETA {}", format_duration(eta)));
+    }
+
+    // the current file gets whatever room is left, keeping the end of its path
+    if let Some(ref current) = s.current {
+        let room = width.saturating_sub(line.chars().count() + 2);
+        let chars: Vec<char> = current.chars().collect();
+        if room > 3 {
+            line.push_str("  ");
+            if chars.len() <= room {
+                line.push_str(current);
+            } else {
+                line.push_str("...");
+                let tail: String = chars[chars.len() - (room - 3)..].iter().cloned().collect();
+                line.push_str(&tail);
+            }
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use engine::ProgressSnapshot;
+    use std::time::Duration;
+
+    fn snapshot(current: &str) -> ProgressSnapshot {
+        ProgressSnapshot {
+            files_total: 4,
+            files_done: 1,
+            bytes_total: 4096,
+            bytes_done: 1024,
+            current: Some(current.to_string()),
+            elapsed: Duration::new(2, 0),
+        }
+    }
+
+    #[test]
+    fn render_line() {
+        assert_eq!("[#####---------------] 1/4 files  1.0 KiB/4.0 KiB  512 B/s  ETA 0:06  a/b",
+                   render(&snapshot("a/b"), 100));
+    }
+
+    #[test]
+    fn render_truncates_current() {
+        let line = render(&snapshot("some/very/long/path/to/a/file"), 80);
+        assert_eq!(80, line.chars().count());
+        assert!(line.ends_with("  .../a/file"), line);
+    }
+}
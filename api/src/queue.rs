@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
 
 pub struct Queue<T> {
     name: String,
@@ -23,6 +24,7 @@ impl<T> Clone for Queue<T> {
 struct QueueState<T> {
     q: VecDeque<T>,
     in_progress: u64,
+    closed: bool,
 }
 
 impl<T> Queue<T> {
@@ -33,6 +35,7 @@ impl<T> Queue<T> {
             q: Arc::new(Mutex::new(QueueState {
                 q: VecDeque::new(),
                 in_progress: 0,
+                closed: false,
             })),
             cvar: Arc::new(Condvar::new()),
         }
@@ -85,6 +88,27 @@ impl<T> Queue<T> {
         }
         unreachable!();
     }
+    /// Like `pop`, but `None` once the queue is closed and empty.
+    pub fn pop_or_closed(&mut self) -> Option<QueueItem<T>> {
+        let mut state = self.q.lock().expect("lock");
+        while state.q.is_empty() && !state.closed {
+            debug!("({}) Waiting to pop", self.name);
+            state = self.cvar.wait(state).expect("cvar");
+        }
+        if let Some(item) = state.q.pop_front() {
+            state.in_progress += 1;
+            self.cvar.notify_all();
+            return Some(QueueItem::new(self, item));
+        }
+        debug!("({}) Queue is closed", self.name);
+        None
+    }
+    /// Wake every `pop_or_closed` waiter once the remaining items are taken.
+    pub fn close(&mut self) {
+        let mut state = self.q.lock().expect("lock");
+        state.closed = true;
+        self.cvar.notify_all();
+    }
     pub fn pop_until_complete(&mut self) -> Option<QueueItem<T>> {
         let mut state = self.q.lock().expect("lock");
         while !state.q.is_empty() || self.in_progress() > 0 {
@@ -120,6 +144,30 @@ impl<T> Queue<T> {
         }
         debug!("({}) Queue is empty", self.name);
     }
+    /// Like `wait`, but gives up after `timeout`. Returns whether the queue emptied.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.q.lock().expect("lock");
+        while !state.q.is_empty() || state.in_progress != 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                debug!("({}) Timed out waiting for queue empty. len={}, in_progress={}",
+                       self.name,
+                       state.q.len(),
+                       state.in_progress);
+                return false;
+            }
+            state = self.cvar.wait_timeout(state, deadline - now).expect("cvar").0;
+        }
+        true
+    }
+    /// Remove every item not yet popped.
+    pub fn take_pending(&mut self) -> Vec<T> {
+        let mut state = self.q.lock().expect("lock");
+        let pending = state.q.drain(..).collect();
+        self.cvar.notify_all();
+        pending
+    }
 }
 
 // QueueItem
@@ -129,6 +177,7 @@ pub struct QueueItem<T> {
     state: Arc<Mutex<QueueState<T>>>,
     cvar: Arc<Condvar>,
     success: bool,
+    requeue: bool,
 }
 
 impl<T> QueueItem<T> {
@@ -138,12 +187,17 @@ impl<T> QueueItem<T> {
             state: queue.q.clone(),
             cvar: queue.cvar.clone(),
             success: false,
+            requeue: false,
         }
     }
     pub fn success(mut self) -> T {
         self.success = true;
         self.t.take().expect("Already taken")
     }
+    /// Put the item back at the end of the queue, as it is not ready to be handled yet.
+    pub fn requeue(mut self) {
+        self.requeue = true;
+    }
 }
 
 impl<T> AsRef<T> for QueueItem<T> {
@@ -165,6 +219,9 @@ impl<T> Drop for QueueItem<T> {
 
         if self.success {
             trace!("Drop with success");
+        } else if self.requeue {
+            trace!("Requeued");
+            state.q.push_back(self.t.take().expect("Already taken"));
         } else {
             if self.t.is_some() {
                 warn!("Drop NO success. Adding to back of queue.");
@@ -201,6 +258,18 @@ mod test {
         assert_eq!(0, queue.len());
     }
 
+    #[test]
+    fn requeue_goes_to_the_back() {
+        let mut queue = Queue::new("test");
+        queue.push(0);
+        queue.push(1);
+        queue.pop().requeue();
+        assert_eq!(0, queue.in_progress());
+        assert_eq!(1, queue.pop().success());
+        assert_eq!(0, queue.pop().success());
+        assert_eq!(0, queue.len());
+    }
+
     #[test]
     fn push_pop_single_thread_multi_item() {
         let _ = env_logger::init();
@@ -281,6 +350,44 @@ mod test {
         assert_eq!(0, queue.len());
     }
 
+    #[test]
+    fn close_ends_pop_or_closed() {
+        let _ = env_logger::init();
+
+        let mut queue = Queue::new("test");
+        queue.push(1);
+        let popper = {
+            let mut queue = queue.clone();
+            thread::spawn(move || {
+                let mut popped = vec![];
+                while let Some(x) = queue.pop_or_closed() {
+                    popped.push(x.success());
+                }
+                popped
+            })
+        };
+        queue.push(2);
+        queue.close();
+
+        assert_eq!(vec![1, 2], popper.join().expect("pop_join"));
+        assert_eq!(0, queue.len());
+    }
+
+    #[test]
+    fn wait_timeout_and_take_pending() {
+        let _ = env_logger::init();
+
+        let mut queue = Queue::new("test");
+        queue.push(1);
+        queue.push(2);
+        let popped = queue.pop();
+        assert!(!queue.wait_timeout(Duration::from_millis(10)));
+
+        assert_eq!(vec![2], queue.take_pending());
+        popped.success();
+        assert!(queue.wait_timeout(Duration::from_millis(10)));
+    }
+
     #[test]
     fn pop_drain_multi_thread_with_max_len() {
         let _ = env_logger::init();
@@ -0,0 +1,127 @@
+//! How long old versions are kept. Rules scoped to globs come first, in the order given, and
+//! the first matching a key decides; keys no rule matches fall under the global age.
+
+use pattern::Glob;
+
+/// Parse an age such as `90d`, in seconds. Units are `h`ours, `d`ays, `w`eeks and `y`ears.
+pub fn parse_age(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    let value = s[..digits].parse::<u64>().map_err(|_| format!("Invalid age: {:?}", s))?;
+    let unit = match s[digits..].trim() {
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        "y" => 365 * 86400,
+        _ => return Err(format!("Invalid age unit, expected h, d, w or y: {:?}", s)),
+    };
+    value.checked_mul(unit).ok_or_else(|| format!("Age too large: {:?}", s))
+}
+
+/// Versions of keys matching `glob` are kept for `age` seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionRule {
+    glob: Glob,
+    age: u64,
+}
+
+impl RetentionRule {
+    pub fn new(glob: &str, age: u64) -> Self {
+        RetentionRule {
+            glob: Glob::new(glob),
+            age: age,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+    age: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn new() -> Self {
+        RetentionPolicy::default()
+    }
+    pub fn with_rules(mut self, rules: Vec<RetentionRule>) -> Self {
+        self.rules = rules;
+        self
+    }
+    /// Keep versions for `age` seconds where no rule applies.
+    pub fn with_age(mut self, age: u64) -> Self {
+        self.age = Some(age);
+        self
+    }
+    /// Whether every version is kept forever.
+    pub fn is_keep_forever(&self) -> bool {
+        self.rules.is_empty() && self.age.is_none()
+    }
+
+    /// Seconds old versions of `key` are kept for, or `None` to keep them forever.
+    pub fn age_for(&self, key: &str) -> Option<u64> {
+        self.rules
+            .iter()
+            .find(|r| r.glob.matches(key))
+            .map(|r| r.age)
+            .or(self.age)
+    }
+}
+
+/// Parse `glob=age` pairs separated by `;`.
+pub fn parse_rules(s: &str) -> Result<Vec<RetentionRule>, String> {
+    let mut rules = vec![];
+    for entry in s.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let glob = parts.next().unwrap_or("").trim();
+        let age = parts.next().unwrap_or("").trim();
+        if glob.is_empty() || age.is_empty() {
+            return Err(format!("Retention rule must be glob=age: {}", entry));
+        }
+        rules.push(RetentionRule::new(glob, parse_age(age)?));
+    }
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DAY: u64 = 86400;
+
+    #[test]
+    fn ages() {
+        assert_eq!(Ok(7 * DAY), parse_age("7d"));
+        assert_eq!(Ok(2 * 7 * DAY), parse_age("2w"));
+        assert_eq!(Ok(7 * 365 * DAY), parse_age("7y"));
+        assert_eq!(Ok(12 * 3600), parse_age(" 12h "));
+        assert!(parse_age("7").is_err());
+        assert!(parse_age("d").is_err());
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let policy = RetentionPolicy::new()
+            .with_rules(parse_rules("finance/**=7y; tmp/**=7d; **/*.log=1d").unwrap())
+            .with_age(90 * DAY);
+        assert_eq!(Some(7 * 365 * DAY), policy.age_for("finance/2016/tax.pdf"));
+        assert_eq!(Some(7 * DAY), policy.age_for("tmp/build.log"));
+        assert_eq!(Some(DAY), policy.age_for("var/app.log"));
+        assert_eq!(Some(90 * DAY), policy.age_for("photos/a.jpg"));
+    }
+
+    #[test]
+    fn forever() {
+        let policy = RetentionPolicy::new().with_rules(parse_rules("tmp/**=7d").unwrap());
+        assert_eq!(None, policy.age_for("photos/a.jpg"));
+        assert!(!policy.is_keep_forever());
+        assert!(RetentionPolicy::new().is_keep_forever());
+    }
+
+    #[test]
+    fn bad_rules() {
+        assert!(parse_rules("tmp/**").is_err());
+        assert!(parse_rules("tmp/**=soon").is_err());
+        assert_eq!(Ok(vec![]), parse_rules(" ; "));
+    }
+}
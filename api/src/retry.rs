@@ -1,6 +1,8 @@
 #![allow(warnings)]
 
 use std::fmt::Display;
+use std::thread::sleep;
+use std::time::Duration;
 
 pub fn retry_forever<F, T, E>(mut f: F) -> T
     where F: FnMut() -> Result<T, E>,
@@ -17,3 +19,53 @@ pub fn retry_forever<F, T, E>(mut f: F) -> T
         i += 1;
     }
 }
+
+/// Call `f` up to `attempts` times, doubling the delay between attempts from `delay`.
+pub fn retry_with_backoff<F, T, E>(attempts: u32, delay: Duration, mut f: F) -> Result<T, E>
+    where F: FnMut() -> Result<T, E>,
+          E: Display
+{
+    let mut delay = delay;
+    let mut i = 1;
+    loop {
+        match f() {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                if i >= attempts {
+                    return Err(e);
+                }
+                warn!("Attempt {} of {}. {}", i, attempts, e);
+            }
+        }
+        sleep(delay);
+        delay = delay * 2;
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn backoff_gives_up() {
+        let mut calls = 0;
+        let result: Result<(), String> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err("nope".to_string())
+        });
+        assert_eq!(Err("nope".to_string()), result);
+        assert_eq!(3, calls);
+    }
+
+    #[test]
+    fn backoff_succeeds() {
+        let mut calls = 0;
+        let result: Result<u32, String> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 2 { Err("nope".to_string()) } else { Ok(calls) }
+        });
+        assert_eq!(Ok(2), result);
+    }
+}
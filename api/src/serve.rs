@@ -0,0 +1,531 @@
+//! `haumaru serve`: one repository that several machines back up into over HTTP.
+//!
+//! Each client authenticates with a bearer token and gets a namespace of its own, holding its
+//! blobs and the latest copy of its index. Requests are:
+//!
+//! - `HEAD /blobs/<algorithm>/<hex>`: whether the blob is stored
+//! - `GET /blobs/<algorithm>/<hex>`: the blob
+//! - `PUT /blobs/<algorithm>/<hex>`: store the body, which must hash to `<hex>`
+//! - `GET /index`, `PUT /index`: the client's index
+//!
+//! There is no TLS, so it only listens on loopback. Put a TLS terminating proxy in front of it
+//! for clients on other machines.
+
+use {EngineConfig, HashAlgorithm, HaumaruError, Node, Storage};
+use compression::decode;
+use digest::Digest;
+use disk::ensure_space;
+use hasher::Hasher;
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::hex::FromHex;
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::io::{Read, Write, copy};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use md5::Md5;
+use storage::{Blob, LocalStorage, SendRequest, SendRequestReader};
+use time::Timespec;
+
+/// Address `haumaru serve` listens on unless told otherwise.
+pub const DEFAULT_LISTEN: &'static str = "127.0.0.1:7373";
+
+/// Largest blob accepted unless told otherwise, well past the largest chunk. Files stored
+/// whole are bounded by it too.
+pub const DEFAULT_MAX_BODY: u64 = 1024 * 1024 * 1024;
+
+/// A machine allowed to back up into the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServeClient {
+    name: String,
+    token: String,
+}
+
+impl ServeClient {
+    pub fn new(name: &str, token: &str) -> Self {
+        ServeClient {
+            name: name.to_string(),
+            token: token.to_string(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Parse `name=token` pairs separated by `;`.
+pub fn parse_clients(s: &str) -> Result<Vec<ServeClient>, String> {
+    let mut clients: Vec<ServeClient> = vec![];
+    for entry in s.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let token = parts.next().unwrap_or("").trim();
+        if name.is_empty() || token.is_empty() {
+            return Err(format!("Client must be name=token: {}", name));
+        }
+        if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') ||
+           name.starts_with('.') {
+            return Err(format!("Invalid client name: {}", name));
+        }
+        if clients.iter().any(|c| c.name == name || c.token == token) {
+            return Err(format!("Client {} or its token given twice", name));
+        }
+        clients.push(ServeClient::new(name, token));
+    }
+    Ok(clients)
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    HasBlob(HashAlgorithm, Vec<u8>),
+    GetBlob(HashAlgorithm, Vec<u8>),
+    PutBlob(HashAlgorithm, Vec<u8>),
+    GetIndex,
+    PutIndex,
+}
+
+fn route(method: &Method, path: &str) -> Option<Route> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if parts == ["index"] {
+        return match *method {
+            Method::Get => Some(Route::GetIndex),
+            Method::Put => Some(Route::PutIndex),
+            _ => None,
+        };
+    }
+    if parts.len() != 3 || parts[0] != "blobs" {
+        return None;
+    }
+
+    let algorithm = match HashAlgorithm::from_tag(parts[1]) {
+        Some(algorithm) => algorithm,
+        None => return None,
+    };
+    let hash = match parts[2].from_hex() {
+        Ok(hash) => hash,
+        Err(_) => return None,
+    };
+    if hash.len() != algorithm.digest_len() {
+        return None;
+    }
+    match *method {
+        Method::Head => Some(Route::HasBlob(algorithm, hash)),
+        Method::Get => Some(Route::GetBlob(algorithm, hash)),
+        Method::Put => Some(Route::PutBlob(algorithm, hash)),
+        _ => None,
+    }
+}
+
+/// A client's namespace on the server.
+struct Namespace {
+    client: ServeClient,
+    storage: LocalStorage,
+    index: PathBuf,
+}
+
+struct ServeHandler {
+    namespaces: Vec<Namespace>,
+    max_body: u64,
+    /// Free space index uploads must leave behind.
+    reserve: u64,
+    /// Numbers the temporary files uploads are streamed into.
+    uploads: AtomicUsize,
+}
+
+/// Compare `a` and `b` in time that depends only on their lengths, so a token cannot be
+/// guessed a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether every address `listen` resolves to is a loopback address.
+pub fn loopback_only(listen: &str) -> Result<bool, String> {
+    let addrs: Vec<SocketAddr> = listen.to_socket_addrs()
+        .map_err(|e| format!("{}: {}", listen, e))?
+        .collect();
+    Ok(!addrs.is_empty() &&
+       addrs.iter().all(|addr| match *addr {
+        SocketAddr::V4(ref addr) => addr.ip().is_loopback(),
+        SocketAddr::V6(ref addr) => addr.ip().is_loopback(),
+    }))
+}
+
+impl ServeHandler {
+    fn namespace(&self, req: &Request) -> Option<&Namespace> {
+        let raw = match req.headers.get_raw("Authorization") {
+            Some(raw) if raw.len() == 1 => String::from_utf8_lossy(&raw[0]).into_owned(),
+            _ => return None,
+        };
+        if !raw.starts_with("Bearer ") {
+            return None;
+        }
+        let token = raw["Bearer ".len()..].trim();
+        // check every client so the time taken does not tell which one matched
+        let mut found = None;
+        for ns in &self.namespaces {
+            if constant_time_eq(ns.client.token.as_bytes(), token.as_bytes()) {
+                found = Some(ns);
+            }
+        }
+        found
+    }
+
+    fn put_blob(&self,
+                ns: &Namespace,
+                algorithm: HashAlgorithm,
+                hash: Vec<u8>,
+                req: &mut Request)
+                -> Result<StatusCode, String> {
+        if let Some(&ContentLength(len)) = req.headers.get::<ContentLength>() {
+            if len > self.max_body {
+                return Ok(StatusCode::PayloadTooLarge);
+            }
+        }
+        let tmp = ns.index
+            .with_file_name(format!("upload-{}.tmp", self.uploads.fetch_add(1, Ordering::SeqCst)));
+        let result = self.store_upload(ns, algorithm, hash, req, &tmp);
+        let _ = remove_file(&tmp);
+        result
+    }
+
+    /// Stream the body of `req` into `tmp`, then store it if it hashes to `hash`.
+    fn store_upload(&self,
+                    ns: &Namespace,
+                    algorithm: HashAlgorithm,
+                    hash: Vec<u8>,
+                    req: &mut Request,
+                    tmp: &Path)
+                    -> Result<StatusCode, String> {
+        let mut md5 = Md5::default();
+        let mut size = 0;
+        {
+            let mut file = File::create(tmp).map_err(|e| format!("{:?}: {}", tmp, e))?;
+            let mut body = req.take(self.max_body + 1);
+            let mut buf = [0; 64 * 1024];
+            loop {
+                let n = body.read(&mut buf).map_err(|e| format!("Failed reading body: {}", e))?;
+                if n == 0 {
+                    break;
+                }
+                md5.input(&buf[..n]);
+                file.write_all(&buf[..n]).map_err(|e| format!("{:?}: {}", tmp, e))?;
+                size += n as u64;
+            }
+        }
+        if size > self.max_body {
+            return Ok(StatusCode::PayloadTooLarge);
+        }
+
+        // clients send compressed content as stored, the hash is of what it decompresses to
+        let mut hasher = Hasher::with_algorithm(algorithm);
+        let hashed = File::open(tmp)
+            .and_then(|file| decode(Blob::new(size, box file)))
+            .and_then(|mut content| copy(&mut content, &mut hasher));
+        if hashed.is_err() {
+            return Ok(StatusCode::BadRequest);
+        }
+        let (_, actual) = hasher.result();
+        if actual != hash {
+            return Ok(StatusCode::BadRequest);
+        }
+
+        let md5 = md5.result().to_vec();
+        let file = File::open(tmp).map_err(|e| format!("{:?}: {}", tmp, e))?;
+        let node = Node::new_file("", Timespec::new(0, 0), size, 0).with_hash_algorithm(algorithm);
+        let mut req = SendRequest::new(md5, hash, node, SendRequestReader::Disk(file), size);
+        let receipt = ns.storage.send(&mut req).map_err(|e| format!("{}", e))?;
+        if receipt.deduplicated() {
+            Ok(StatusCode::Ok)
+        } else {
+            Ok(StatusCode::Created)
+        }
+    }
+
+    fn put_index(&self, ns: &Namespace, req: &mut Request) -> Result<StatusCode, String> {
+        if let Some(&ContentLength(len)) = req.headers.get::<ContentLength>() {
+            if len > self.max_body {
+                return Ok(StatusCode::PayloadTooLarge);
+            }
+            let dir = ns.index.parent().expect("namespace dir");
+            if let Err(e) = ensure_space(dir, len, self.reserve) {
+                warn!("Refusing index from {}: {}", ns.client.name, e);
+                return Ok(StatusCode::InsufficientStorage);
+            }
+        }
+        // a unique name, so two pushes from the same client cannot write into each other
+        let tmp = ns.index
+            .with_file_name(format!("index-{}.tmp", self.uploads.fetch_add(1, Ordering::SeqCst)));
+        let result = self.store_index(ns, req, &tmp);
+        let _ = remove_file(&tmp);
+        result
+    }
+
+    /// Stream the body of `req` into `tmp`, then move it over the namespace's index.
+    fn store_index(&self,
+                   ns: &Namespace,
+                   req: &mut Request,
+                   tmp: &Path)
+                   -> Result<StatusCode, String> {
+        let mut file = File::create(tmp).map_err(|e| format!("{:?}: {}", tmp, e))?;
+        let size = copy(&mut req.take(self.max_body + 1), &mut file)
+            .map_err(|e| format!("{:?}: {}", tmp, e))?;
+        if size > self.max_body {
+            return Ok(StatusCode::PayloadTooLarge);
+        }
+        file.sync_all().map_err(|e| format!("{:?}: {}", tmp, e))?;
+        rename(tmp, &ns.index).map_err(|e| format!("{:?}: {}", ns.index, e))?;
+        Ok(StatusCode::Ok)
+    }
+}
+
+impl Handler for ServeHandler {
+    fn handle(&self, mut req: Request, mut res: Response) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new(),
+        };
+        let ns = match self.namespace(&req) {
+            Some(ns) => ns,
+            None => {
+                warn!("Refused unauthenticated {} {} from {}", req.method, path, req.remote_addr);
+                *res.status_mut() = StatusCode::Unauthorized;
+                return;
+            }
+        };
+        debug!("{}: {} {}", ns.client.name, req.method, path);
+
+        let result = match route(&req.method, &path) {
+            None => Ok(StatusCode::NotFound),
+            Some(Route::HasBlob(algorithm, hash)) => {
+                match ns.storage.retrieve(algorithm, &hash) {
+                    Ok(Some(blob)) => {
+                        res.headers_mut().set(ContentLength(blob.size()));
+                        Ok(StatusCode::Ok)
+                    }
+                    Ok(None) => Ok(StatusCode::NotFound),
+                    Err(e) => Err(format!("{}", e)),
+                }
+            }
+            Some(Route::GetBlob(algorithm, hash)) => {
+                match ns.storage.retrieve(algorithm, &hash) {
+                    Ok(Some(mut blob)) => {
+                        res.headers_mut().set(ContentLength(blob.size()));
+                        let mut out = match res.start() {
+                            Ok(out) => out,
+                            Err(e) => {
+                                error!("Failed to respond: {}", e);
+                                return;
+                            }
+                        };
+                        if let Err(e) = copy(&mut blob, &mut out).and_then(|_| out.end()) {
+                            error!("Failed sending blob to {}: {}", ns.client.name, e);
+                        }
+                        return;
+                    }
+                    Ok(None) => Ok(StatusCode::NotFound),
+                    Err(e) => Err(format!("{}", e)),
+                }
+            }
+            Some(Route::PutBlob(algorithm, hash)) => self.put_blob(ns, algorithm, hash, &mut req),
+            Some(Route::GetIndex) => {
+                match File::open(&ns.index) {
+                    Ok(mut file) => {
+                        let mut out = match res.start() {
+                            Ok(out) => out,
+                            Err(e) => {
+                                error!("Failed to respond: {}", e);
+                                return;
+                            }
+                        };
+                        if let Err(e) = copy(&mut file, &mut out).and_then(|_| out.end()) {
+                            error!("Failed sending index to {}: {}", ns.client.name, e);
+                        }
+                        return;
+                    }
+                    Err(_) => Ok(StatusCode::NotFound),
+                }
+            }
+            Some(Route::PutIndex) => self.put_index(ns, &mut req),
+        };
+
+        *res.status_mut() = match result {
+            Ok(status) => status,
+            Err(e) => {
+                error!("{} {} for {} failed: {}", req.method, path, ns.client.name, e);
+                StatusCode::InternalServerError
+            }
+        };
+    }
+}
+
+/// Serve the clients of `config` on `listen` until the process is stopped. Client namespaces
+/// live under `clients/<name>` in the working directory. `listen` must be a loopback address.
+pub fn serve(config: &EngineConfig, listen: &str) -> Result<(), HaumaruError> {
+    if config.serve_clients().is_empty() {
+        return Err(HaumaruError::Config("serve needs at least one client in serve_clients"
+            .into()));
+    }
+    if !loopback_only(listen).map_err(|e| HaumaruError::Config(e.into()))? {
+        return Err(HaumaruError::Config(format!("serve has no TLS and only listens on \
+                                                 loopback, put a TLS proxy in front of it: {}",
+                                                listen)
+            .into()));
+    }
+
+    let mut namespaces = vec![];
+    for client in config.serve_clients() {
+        let mut dir = PathBuf::from(config.working());
+        dir.push("clients");
+        dir.push(client.name());
+        create_dir_all(&dir).map_err(|e| HaumaruError::Other(format!("{:?}: {}", dir, e)))?;
+
+        let client_config = EngineConfig::new(dir.to_str().unwrap())
+            .with_free_space_reserve(config.free_space_reserve());
+        let storage = LocalStorage::new(&client_config).map_err(|e| HaumaruError::Storage(box e))?;
+        namespaces.push(Namespace {
+            client: client.clone(),
+            storage: storage,
+            index: dir.join("haumaru.idx"),
+        });
+    }
+
+    let server = Server::http(listen)
+        .map_err(|e| HaumaruError::Other(format!("Unable to listen on {}: {}", listen, e)))?;
+    info!("Serving {} client(s) on {}", namespaces.len(), listen);
+    // dropping the listener waits on the server threads, which run until the process stops
+    let handler = ServeHandler {
+        namespaces: namespaces,
+        max_body: config.serve_max_body(),
+        reserve: config.free_space_reserve(),
+        uploads: AtomicUsize::new(0),
+    };
+    server.handle(handler)
+        .map_err(|e| HaumaruError::Other(format!("Server failed: {}", e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{Namespace, Route, ServeHandler, constant_time_eq, loopback_only, route};
+    use {EngineConfig, HashAlgorithm, Node, Storage};
+    use hasher::Hasher;
+    use hyper::method::Method;
+    use hyper::server::Server;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::{Cursor, Read, Write};
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+    use storage::{LocalStorage, SendRequest, SendRequestReader, ServerStorage};
+    use time::Timespec;
+
+    #[test]
+    fn clients() {
+        let clients = parse_clients("laptop=abc; web1=def").unwrap();
+        assert_eq!(vec![ServeClient::new("laptop", "abc"), ServeClient::new("web1", "def")],
+                   clients);
+        assert!(parse_clients("laptop").is_err());
+        assert!(parse_clients("../x=abc").is_err());
+        assert!(parse_clients("a=abc;b=abc").is_err());
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn loopback() {
+        assert!(loopback_only("127.0.0.1:7373").unwrap());
+        assert!(loopback_only("[::1]:7373").unwrap());
+        assert!(!loopback_only("0.0.0.0:7373").unwrap());
+        assert!(!loopback_only("192.0.2.1:7373").unwrap());
+    }
+
+    #[test]
+    fn routes() {
+        let hex = vec!["00"; 32].concat();
+        assert_eq!(Some(Route::GetBlob(HashAlgorithm::Sha256, vec![0; 32])),
+                   route(&Method::Get, &format!("/blobs/sha256/{}", hex)));
+        assert_eq!(Some(Route::HasBlob(HashAlgorithm::Blake3, vec![0; 32])),
+                   route(&Method::Head, &format!("/blobs/blake3/{}", hex)));
+        assert_eq!(Some(Route::PutIndex), route(&Method::Put, "/index"));
+        assert_eq!(None, route(&Method::Get, "/blobs/sha256/00"));
+        assert_eq!(None, route(&Method::Get, &format!("/blobs/md5/{}", hex)));
+        assert_eq!(None, route(&Method::Delete, "/index"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = "target/test/serve_round_trip";
+        let _ = remove_dir_all(dir);
+        create_dir_all(dir).unwrap();
+        let handler = ServeHandler {
+            namespaces: vec![Namespace {
+                                 client: ServeClient::new("laptop", "secret"),
+                                 storage: LocalStorage::new(&EngineConfig::new(dir)).unwrap(),
+                                 index: PathBuf::from(dir).join("haumaru.idx"),
+                             }],
+            max_body: 16,
+            reserve: 0,
+            uploads: AtomicUsize::new(0),
+        };
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle(handler).unwrap();
+        let url = format!("http://{}", listening.socket);
+        let storage = ServerStorage::new(&url, "secret");
+
+        let content = b"hello".to_vec();
+        let mut hasher = Hasher::with_algorithm(HashAlgorithm::Sha256);
+        hasher.write_all(&content).unwrap();
+        let (md5, hash) = hasher.result();
+        let node = Node::new_file("a", Timespec::new(0, 0), 5, 0o644).with_hash(hash.clone());
+        let send = |storage: &ServerStorage| {
+            let mut req = SendRequest::new(md5.clone(),
+                                           hash.clone(),
+                                           node.clone(),
+                                           SendRequestReader::InMemory(Cursor::new(content
+                                               .clone())),
+                                           5);
+            storage.send(&mut req).unwrap()
+        };
+        assert!(!send(&storage).deduplicated());
+        assert!(send(&storage).deduplicated());
+        let mut too_large = SendRequest::new(md5.clone(),
+                                             vec![1; 32],
+                                             node.clone(),
+                                             SendRequestReader::InMemory(Cursor::new(vec![0; 17])),
+                                             17);
+        assert!(storage.send(&mut too_large).is_err());
+        assert!(storage.verify(node.clone()).unwrap().1);
+
+        let mut restored = vec![];
+        storage.retrieve(HashAlgorithm::Sha256, &hash)
+            .unwrap()
+            .expect("blob")
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(content, restored);
+        assert!(storage.retrieve(HashAlgorithm::Sha256, &[0; 32]).unwrap().is_none());
+
+        let index = PathBuf::from(dir).join("client.idx");
+        File::create(&index).unwrap().write_all(b"index").unwrap();
+        storage.push_index("laptop", &index).unwrap();
+        let mut pushed = String::new();
+        File::open(PathBuf::from(dir).join("haumaru.idx"))
+            .unwrap()
+            .read_to_string(&mut pushed)
+            .unwrap();
+        assert_eq!("index", pushed);
+        File::create(&index).unwrap().write_all(&[0; 17]).unwrap();
+        assert!(storage.push_index("laptop", &index).is_err());
+
+        assert!(ServerStorage::new(&url, "wrong").verify(node).is_err());
+        listening.close().unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use engine::{CancelToken, Pause, PauseReason};
+use libc;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const PAUSE: usize = 1;
+const RESUME: usize = 2;
+
+static REQUEST: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Bumped with every request, so each engine in the process acts on it once.
+static REQUESTS: AtomicUsize = ATOMIC_USIZE_INIT;
+static STOP: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_signal(signum: libc::c_int) {
+    // only async-signal-safe work here, the monitor thread does the rest
+    if signum == libc::SIGUSR1 {
+        REQUEST.store(PAUSE, Ordering::SeqCst);
+        REQUESTS.fetch_add(1, Ordering::SeqCst);
+    } else if signum == libc::SIGUSR2 {
+        REQUEST.store(RESUME, Ordering::SeqCst);
+        REQUESTS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Pause on SIGUSR1 and resume on SIGUSR2.
+pub fn watch_pause_signals(pause: Pause) {
+    unsafe {
+        libc::signal(libc::SIGUSR1, handle_signal as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, handle_signal as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        let mut seen = REQUESTS.load(Ordering::SeqCst);
+        loop {
+            let requests = REQUESTS.load(Ordering::SeqCst);
+            if requests != seen {
+                seen = requests;
+                match REQUEST.load(Ordering::SeqCst) {
+                    PAUSE => pause.pause(PauseReason::User),
+                    RESUME => pause.resume(PauseReason::User),
+                    _ => (),
+                }
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    });
+}
+
+extern "C" fn handle_stop_signal(_signum: libc::c_int) {
+    // a second request does not wait for the current file
+    if STOP.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Cancel `cancel` on SIGINT or SIGTERM, so work stops at the next file boundary. A second
+/// signal exits straight away.
+pub fn watch_stop_signals(cancel: CancelToken) {
+    unsafe {
+        libc::signal(libc::SIGINT, handle_stop_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handle_stop_signal as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        loop {
+            if STOP.load(Ordering::SeqCst) {
+                info!("Stop requested, finishing the current file");
+                cancel.cancel();
+                return;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    });
+}
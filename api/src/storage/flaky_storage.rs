@@ -0,0 +1,262 @@
+//! Fault injection for testing: wraps a backend so calls fail, slow down or come back cut short
+//! at configured rates, exercising retries, dead letters and crash recovery without a real
+//! outage. Picked with the `faults:` config value in builds with the `flaky` feature.
+
+use {HashAlgorithm, Node, Storage};
+use std::error::Error;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use storage::{Blob, SendReceipt, SendRequest};
+
+/// What goes wrong and how often. Rates run from 0, never, to 1, every call.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    send: f64,
+    retrieve: f64,
+    verify: f64,
+    /// Rate of retrieved blobs ending after half their content.
+    truncate: f64,
+    /// Delay added to every call.
+    latency: Duration,
+    /// Seeds the fault sequence, so runs can be repeated.
+    seed: u64,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Faults::default()
+    }
+    pub fn with_send(mut self, rate: f64) -> Self {
+        self.send = rate;
+        self
+    }
+    pub fn with_retrieve(mut self, rate: f64) -> Self {
+        self.retrieve = rate;
+        self
+    }
+    pub fn with_verify(mut self, rate: f64) -> Self {
+        self.verify = rate;
+        self
+    }
+    pub fn with_truncate(mut self, rate: f64) -> Self {
+        self.truncate = rate;
+        self
+    }
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Parses `name=value` pairs separated by `;`, such as `send=0.2; truncate=0.05; latency=50`.
+/// Names are `send`, `retrieve`, `verify` and `truncate` for rates, `latency` in milliseconds
+/// and `seed`.
+impl FromStr for Faults {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut faults = Faults::new();
+        for entry in s.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            let rate = || -> Result<f64, String> {
+                match value.parse::<f64>() {
+                    Ok(rate) if rate >= 0.0 && rate <= 1.0 => Ok(rate),
+                    _ => Err(format!("Fault rate must be from 0 to 1: {}", entry)),
+                }
+            };
+            let number = || value.parse::<u64>().map_err(|_| format!("Invalid fault: {}", entry));
+            faults = match name {
+                "send" => faults.with_send(rate()?),
+                "retrieve" => faults.with_retrieve(rate()?),
+                "verify" => faults.with_verify(rate()?),
+                "truncate" => faults.with_truncate(rate()?),
+                "latency" => faults.with_latency(Duration::from_millis(number()?)),
+                "seed" => faults.with_seed(number()?),
+                _ => {
+                    return Err(format!("Unknown fault {:?}, expected send, retrieve, verify, \
+                                        truncate, latency or seed",
+                                       name))
+                }
+            };
+        }
+        Ok(faults)
+    }
+}
+
+/// `inner`, with `faults` injected. Clones share the fault sequence.
+#[derive(Clone)]
+pub struct FlakyStorage<S: Storage> {
+    inner: S,
+    faults: Faults,
+    state: Arc<Mutex<u64>>,
+}
+
+impl<S: Storage> FlakyStorage<S> {
+    pub fn new(inner: S, faults: Faults) -> Self {
+        // xorshift gets stuck on zero
+        let state = if faults.seed == 0 {
+            0x2545f4914f6cdd1d
+        } else {
+            faults.seed
+        };
+        FlakyStorage {
+            inner: inner,
+            faults: faults,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Whether a fault with `rate` strikes this time.
+    fn strikes(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        let mut state = self.state.lock().expect("fault state lock");
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        let sample = (*state >> 11) as f64 / (1u64 << 53) as f64;
+        sample < rate
+    }
+
+    /// Sleep for the latency, then fail at `rate`.
+    fn call(&self, name: &str, rate: f64) -> Result<(), Box<Error>> {
+        if self.faults.latency > Duration::new(0, 0) {
+            thread::sleep(self.faults.latency);
+        }
+        if self.strikes(rate) {
+            return Err(format!("Injected {} failure", name).into());
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for FlakyStorage<S> {
+    /// Injected failures strike before any content is read, as a refused request would.
+    fn send(&self, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>> {
+        self.call("send", self.faults.send)?;
+        self.inner.send(req)
+    }
+    fn retrieve(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<Option<Blob>, Box<Error>> {
+        self.call("retrieve", self.faults.retrieve)?;
+        let blob = match self.inner.retrieve(algorithm, hash)? {
+            Some(blob) => blob,
+            None => return Ok(None),
+        };
+        if !self.strikes(self.faults.truncate) {
+            return Ok(Some(blob));
+        }
+        // the size still claims all of it, as a dropped connection would leave it
+        let size = blob.size();
+        Ok(Some(Blob::new(size, box blob.take(size / 2))))
+    }
+    fn verify(&self, node: Node) -> Result<(Node, bool), Box<Error>> {
+        self.call("verify", self.faults.verify)?;
+        self.inner.verify(node)
+    }
+    fn delete(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<bool, Box<Error>> {
+        self.inner.delete(algorithm, hash)
+    }
+    fn usage(&self) -> Result<Option<u64>, Box<Error>> {
+        self.inner.usage()
+    }
+    fn sweep_temp_files(&self) -> Result<u64, Box<Error>> {
+        self.inner.sweep_temp_files()
+    }
+    fn max_sends(&self) -> Option<usize> {
+        self.inner.max_sends()
+    }
+    fn push_index(&self, namespace: &str, index: &Path) -> Result<(), Box<Error>> {
+        self.inner.push_index(namespace, index)
+    }
+    fn fetch_index(&self, namespace: &str) -> Result<Option<Blob>, Box<Error>> {
+        self.inner.fetch_index(namespace)
+    }
+    fn namespaces(&self) -> Result<Option<Vec<String>>, Box<Error>> {
+        self.inner.namespaces()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {EngineConfig, HashAlgorithm, Storage};
+    use hasher::Hasher;
+    use node::Node;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::io::{Cursor, Read, Write};
+    use std::time::Duration;
+    use storage::{LocalStorage, SendRequest};
+    use storage::SendRequestReader::InMemory;
+    use super::*;
+    use time::Timespec;
+
+    fn storage(name: &str, faults: Faults) -> FlakyStorage<LocalStorage> {
+        let test_dir = format!("target/test/{}", name);
+        let _ = remove_dir_all(&test_dir);
+        create_dir_all(&test_dir).expect("mkdir test_dir");
+        let config = EngineConfig::new(&test_dir);
+        FlakyStorage::new(LocalStorage::new(&config).expect("local storage"), faults)
+    }
+
+    fn request(content: &[u8]) -> SendRequest {
+        let mut hasher = Hasher::new();
+        hasher.write_all(content).unwrap();
+        let (md5, hash) = hasher.result();
+        let node = Node::new_file("a", Timespec::new(10, 0), content.len() as u64, 0o644)
+            .with_hash(hash.clone());
+        SendRequest::new(md5,
+                         hash,
+                         node,
+                         InMemory(Cursor::new(content.to_vec())),
+                         content.len() as u64)
+    }
+
+    #[test]
+    fn parse() {
+        let faults = "send=0.2; truncate=1; latency=50; seed=7".parse::<Faults>().unwrap();
+        assert_eq!(Faults::new()
+                       .with_send(0.2)
+                       .with_truncate(1.0)
+                       .with_latency(Duration::from_millis(50))
+                       .with_seed(7),
+                   faults);
+        assert!("send=2".parse::<Faults>().is_err());
+        assert!("flood=0.5".parse::<Faults>().is_err());
+    }
+
+    #[test]
+    fn rates() {
+        let storage = storage("flaky_storage_rates", Faults::new().with_send(0.5).with_seed(3));
+        let failed = (0..1000).filter(|_| storage.strikes(0.5)).count();
+        assert!(failed > 400 && failed < 600, "failed={}", failed);
+        assert!(!(0..1000).any(|_| storage.strikes(0.0)));
+        assert!((0..1000).all(|_| storage.strikes(1.0)));
+    }
+
+    #[test]
+    fn failures_and_truncation() {
+        let content = b"hello flaky world";
+        let faults = Faults::new().with_send(1.0);
+        assert!(storage("flaky_storage_send", faults).send(&mut request(content)).is_err());
+
+        let storage = storage("flaky_storage_truncate", Faults::new().with_truncate(1.0));
+        let mut req = request(content);
+        storage.send(&mut req).expect("send");
+        let hash = req.node().hash().clone().unwrap();
+        let mut blob = storage.retrieve(HashAlgorithm::Sha256, &hash).unwrap().unwrap();
+        assert_eq!(content.len() as u64, blob.size());
+        let mut read = vec![];
+        blob.read_to_end(&mut read).unwrap();
+        assert_eq!(&content[..content.len() / 2], &read[..]);
+    }
+}
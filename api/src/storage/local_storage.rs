@@ -1,18 +1,21 @@
 
 
-use {EngineConfig, Node, Storage};
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
+use {EngineConfig, HashAlgorithm, Node, Storage};
+use compression::decode;
+use disk::ensure_space;
+use hasher::Hasher;
+use libc;
+use metrics;
 use rustc_serialize::hex::ToHex;
 use std::error::Error;
 use std::fmt;
-use std::fs::{create_dir_all, rename};
+use std::fs::{create_dir_all, read_dir, remove_file, rename};
 use std::fs::File;
 use std::io;
-use std::io::{Read, copy};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use storage::{SendRequest, hash_dir, hash_path};
+use std::io::{Read, Write, copy};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use storage::{Blob, SendReceipt, SendRequest, blob_dir, blob_path};
 
 #[derive(Debug)]
 pub enum LocalStorageError {
@@ -33,18 +36,43 @@ impl fmt::Display for LocalStorageError {
     }
 }
 
-pub struct LocalStorage {
-    target: String,
-    m: Arc<Mutex<bool>>,
+/// Flush a directory's entries to disk.
+fn sync_dir(dir: &Path) -> Result<(), LocalStorageError> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(|e| LocalStorageError::Io(format!("Failed to sync dir {:?}", dir), e))
 }
 
-impl Clone for LocalStorage {
-    fn clone(&self) -> Self {
-        LocalStorage {
-            target: self.target.clone(),
-            m: self.m.clone(),
-        }
+/// Numbers temp files so concurrent sends never write to the same one.
+static TEMP_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Whether `name` is a temp file, written before it is renamed into place.
+fn is_temp(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/// The process writing the temp file `name`, which `send` names `.{hex}.{pid}.{n}.tmp`.
+fn temp_pid(name: &str) -> Option<libc::pid_t> {
+    if !is_temp(name) {
+        return None;
+    }
+    let parts: Vec<&str> = name.split('.').collect();
+    if parts.len() != 5 {
+        return None;
     }
+    parts[2].parse().ok()
+}
+
+/// Whether process `pid` is still running. One owned by another user still is.
+fn is_running(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 } ||
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[derive(Clone)]
+pub struct LocalStorage {
+    target: String,
+    reserve: u64,
 }
 
 impl LocalStorage {
@@ -67,9 +95,13 @@ impl LocalStorage {
         }
         Ok(LocalStorage {
             target: storage_path.to_str().unwrap().to_string(),
-            m: Arc::new(Mutex::new(true)),
+            reserve: config.free_space_reserve(),
         })
     }
+
+    fn index_path(&self, namespace: &str) -> PathBuf {
+        Path::new(&self.target).join("indexes").join(format!("{}.idx", namespace))
+    }
 }
 
 // _md5: &[u8],
@@ -77,45 +109,66 @@ impl LocalStorage {
 // _size: u64,
 // mut ins: Box<Read>
 impl Storage for LocalStorage {
-    fn send(&self, req: &mut SendRequest) -> Result<(), Box<Error>> {
-        let _lock = self.m.lock().unwrap();
-
-        let &mut SendRequest { md5: ref _md5,
-                               sha256: ref hash,
-                               node: ref _node,
-                               ref mut reader,
-                               size: _size } = req;
+    fn send(&self, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>> {
+        let &mut SendRequest { ref hash, ref node, ref mut reader, size, .. } = req;
+        let algorithm = node.hash_algorithm();
         let hex = hash.to_hex();
         debug!("Sending {:?}", hash);
 
         let mut hash_filename = PathBuf::new();
         hash_filename.push(&self.target);
-        hash_filename.push(hash_path(&hex));
+        hash_filename.push(blob_path(algorithm, &hex));
 
         if hash_filename.exists() {
             debug!("Already have {}", hex);
-            return Ok(());
+            metrics::incr("storage.local.blobs_existing");
+            return Ok(SendReceipt::existing());
         }
 
-        let mut dst_path = PathBuf::new();
-        dst_path.push(&self.target);
-        dst_path.push(format!("_"));
-
-        debug!("Writing to {:?}", dst_path);
+        ensure_space(Path::new(&self.target), size, self.reserve)
+            .map_err(|e| LocalStorageError::Generic(e))?;
 
-        // move into final name
         let mut dir = PathBuf::new();
         dir.push(&self.target);
-        dir.push(hash_dir(&hex));
+        dir.push(blob_dir(algorithm, &hex));
+        let new_dir = !dir.exists();
         debug!("Creating dir {:?}", dir);
         create_dir_all(&dir).map_err(|e| {
                 LocalStorageError::Generic(format!("Failed to create dir {:?}: {}", dir, e))
             })?;
 
+        // written beside the blob, so the rename into place stays on one filesystem
+        let mut dst_path = dir.clone();
+        dst_path.push(format!(".{}.{}.{}.tmp",
+                              hex,
+                              unsafe { libc::getpid() },
+                              TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+
         debug!("Writing to {:?}", dst_path);
         let mut dst_file = File::create(&dst_path)?;
-        copy(reader, &mut dst_file)
-            .map_err(|e| LocalStorageError::Io(format!("Failed writing to: {:?}", dst_path), e))?;
+        let written = match copy(reader, &mut dst_file) {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = remove_file(&dst_path);
+                return Err(box LocalStorageError::Io(format!("Failed writing to: {:?}", dst_path),
+                                                     e));
+            }
+        };
+
+        // the content and every new directory must be on disk before the blob appears
+        dst_file.sync_all()
+            .map_err(|e| LocalStorageError::Io(format!("Failed to sync {:?}", dst_path), e))?;
+        if new_dir {
+            let mut parent = dir.parent();
+            while let Some(p) = parent {
+                sync_dir(p)?;
+                if p == Path::new(&self.target) {
+                    break;
+                }
+                parent = p.parent();
+            }
+        }
+        sync_dir(&dir)?;
 
         debug!("Moving new hash to {:?}", hash_filename);
         rename(dst_path, &hash_filename).map_err(|e| {
@@ -123,18 +176,86 @@ impl Storage for LocalStorage {
                                                    hash_filename,
                                                    e))
             })?;
+        sync_dir(&dir)?;
 
-        Ok(())
+        metrics::incr("storage.local.blobs_written");
+        metrics::count("storage.local.bytes_written", written as i64);
+        Ok(SendReceipt::stored(written))
     }
 
-    fn retrieve(&self, hash: &[u8]) -> Result<Option<Box<Read>>, Box<Error>> {
+    fn retrieve(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<Option<Blob>, Box<Error>> {
         let hex = hash.to_hex();
 
         let mut hash_filename = PathBuf::new();
         hash_filename.push(&self.target);
-        hash_filename.push(hash_path(&hex));
+        hash_filename.push(blob_path(algorithm, &hex));
+
+        if !hash_filename.exists() {
+            return Ok(None);
+        }
+        let file = File::open(hash_filename)?;
+        let size = file.metadata()?.len();
+        Ok(Some(decode(Blob::new(size, box file))?))
+    }
 
-        Ok(Some(box File::open(hash_filename)?))
+    fn delete(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<bool, Box<Error>> {
+        let hash_filename = Path::new(&self.target).join(blob_path(algorithm, &hash.to_hex()));
+        match remove_file(&hash_filename) {
+            Ok(()) => {
+                metrics::incr("storage.local.blobs_deleted");
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => {
+                Err(box LocalStorageError::Io(format!("Failed to delete {:?}", hash_filename), e))
+            }
+        }
+    }
+
+    /// More writers than this only make the disk seek between them.
+    fn max_sends(&self) -> Option<usize> {
+        Some(2)
+    }
+
+    fn usage(&self) -> Result<Option<u64>, Box<Error>> {
+        fn dir_size(dir: &Path) -> io::Result<u64> {
+            let mut total = 0;
+            for entry in read_dir(dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    total += dir_size(&entry.path())?;
+                } else if !is_temp(&entry.file_name().to_string_lossy()) {
+                    total += metadata.len();
+                }
+            }
+            Ok(total)
+        }
+        Ok(Some(dir_size(Path::new(&self.target))?))
+    }
+
+    fn sweep_temp_files(&self) -> Result<u64, Box<Error>> {
+        fn sweep(dir: &Path) -> io::Result<u64> {
+            let mut removed = 0;
+            for entry in read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.metadata()?.is_dir() {
+                    removed += sweep(&path)?;
+                    continue;
+                }
+                match temp_pid(&entry.file_name().to_string_lossy()) {
+                    Some(pid) if !is_running(pid) => {
+                        debug!("Removing {:?}, left by process {}", path, pid);
+                        remove_file(&path)?;
+                        removed += 1;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(removed)
+        }
+        Ok(sweep(Path::new(&self.target))?)
     }
 
     fn verify(&self, node: Node) -> Result<(Node, bool), Box<Error>> {
@@ -143,15 +264,17 @@ impl Storage for LocalStorage {
         let hex = node.hash_string();
         let mut hash_filename = PathBuf::new();
         hash_filename.push(&self.target);
-        hash_filename.push(hash_path(&hex));
+        hash_filename.push(blob_path(node.hash_algorithm(), &hex));
 
         if !hash_filename.exists() {
             error!("Hash missing: {}", hex);
             return Ok((node, false));
         }
 
-        let mut src_file = File::open(hash_filename)?;
-        let mut hasher = Sha256::new();
+        let file = File::open(hash_filename)?;
+        let size = file.metadata()?.len();
+        let mut src_file = decode(Blob::new(size, box file))?;
+        let mut hasher = Hasher::with_algorithm(node.hash_algorithm());
 
         let mut buffer = [0; 65536];
 
@@ -162,13 +285,10 @@ impl Storage for LocalStorage {
             }
 
             trace!("Read {} bytes", read);
-            hasher.input(&buffer[0..read]);
+            hasher.write_all(&buffer[0..read])?;
         }
 
-        let mut bytes = [0u8; 32];
-        hasher.result(&mut bytes);
-        let mut vec = Vec::with_capacity(32);
-        vec.append(&mut bytes.to_vec());
+        let (_md5, vec) = hasher.result();
 
         if vec != node.hash().clone().expect("can not validate without hash") {
             error!("Hash checksum failed: {}", hex);
@@ -177,20 +297,75 @@ impl Storage for LocalStorage {
 
         Ok((node, true))
     }
+
+    fn push_index(&self, namespace: &str, index: &Path) -> Result<(), Box<Error>> {
+        let dst = self.index_path(namespace);
+        let dir = dst.parent().expect("indexes dir");
+        create_dir_all(dir).map_err(|e| {
+                LocalStorageError::Generic(format!("Failed to create dir {:?}: {}", dir, e))
+            })?;
+        let tmp = dir.join(format!(".{}.{}.tmp", namespace, unsafe { libc::getpid() }));
+        {
+            let mut src = File::open(index)?;
+            let mut file = File::create(&tmp)?;
+            copy(&mut src, &mut file)
+                .map_err(|e| LocalStorageError::Io(format!("Failed writing to: {:?}", tmp), e))?;
+            file.sync_all()
+                .map_err(|e| LocalStorageError::Io(format!("Failed to sync {:?}", tmp), e))?;
+        }
+        rename(&tmp, &dst).map_err(|e| {
+                LocalStorageError::Generic(format!("Failed to rename to {:?}: {}", dst, e))
+            })?;
+        sync_dir(dir)?;
+        Ok(())
+    }
+
+    fn fetch_index(&self, namespace: &str) -> Result<Option<Blob>, Box<Error>> {
+        let path = self.index_path(namespace);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Some(Blob::new(size, box file)))
+    }
+
+    fn namespaces(&self) -> Result<Option<Vec<String>>, Box<Error>> {
+        let dir = Path::new(&self.target).join("indexes");
+        if !dir.exists() {
+            return Ok(Some(vec![]));
+        }
+        let mut namespaces = vec![];
+        for entry in read_dir(&dir)? {
+            let name = entry?.file_name();
+            // skip copies still being written, `.<namespace>.<pid>.tmp`
+            match name.to_str() {
+                Some(name) if name.ends_with(".idx") && !name.starts_with('.') => {
+                    namespaces.push(name[..name.len() - ".idx".len()].to_string())
+                }
+                _ => {}
+            }
+        }
+        namespaces.sort();
+        Ok(Some(namespaces))
+    }
 }
 
 #[cfg(test)]
 mod test {
     extern crate env_logger;
 
-    use {EngineConfig, Storage};
+    use {EngineConfig, HashAlgorithm, Storage};
+    use hasher::Hasher;
+    use libc;
     use node::{Node, NodeKind};
     use std::fs::{File, create_dir_all, remove_dir_all};
-    use std::io::{Cursor, Read};
+    use std::io::{Cursor, Read, Write};
     use std::path::PathBuf;
-    use storage::SendRequest;
+    use storage::{SendReceipt, SendRequest};
     use storage::SendRequestReader::*;
     use super::*;
+    use test::Bencher;
     use time::Timespec;
 
     #[test]
@@ -253,8 +428,29 @@ mod test {
                              Timespec::new(0, 0),
                              content.len() as u64,
                              100);
-        let mut req = SendRequest::new(vec![], hash, node, InMemory(cursor), content.len() as u64);
-        storage.send(&mut req).expect("Send stream");
+        let mut req = SendRequest::new(vec![],
+                                       hash.clone(),
+                                       node.clone(),
+                                       InMemory(cursor),
+                                       content.len() as u64);
+        let receipt = storage.send(&mut req).expect("Send stream");
+        assert_eq!(SendReceipt::stored(content.len() as u64), receipt);
+
+        let cursor = Cursor::new(content.to_string().into_bytes());
+        let mut req = SendRequest::new(vec![],
+                                       hash.clone(),
+                                       node,
+                                       InMemory(cursor),
+                                       content.len() as u64);
+        assert!(storage.send(&mut req).expect("Send again").deduplicated());
+
+        let mut blob = storage.retrieve(HashAlgorithm::Sha256, &hash)
+            .expect("retrieve")
+            .expect("blob");
+        assert_eq!(content.len() as u64, blob.size());
+        let mut s = String::new();
+        blob.read_to_string(&mut s).expect("read blob");
+        assert_eq!(s, content);
 
         let mut hash_filename = path.clone();
         hash_filename.push("store");
@@ -267,4 +463,162 @@ mod test {
         f.read_to_string(&mut s).expect("read hash_filename");
         assert_eq!(s, content);
     }
+
+    #[test]
+    fn verify_mixed_algorithms() {
+        let name = "local_storage_verify_mixed_algorithms";
+
+        // begin setup
+        let test_dir = format!("target/test/{}", name);
+        let _ = remove_dir_all(&test_dir);
+        create_dir_all(&test_dir).expect("mkdir test_dir");
+        // end setup
+
+        let config = EngineConfig::new(&test_dir);
+        let storage = LocalStorage::new(&config).expect("new local storage");
+        let content = "0123456789abcdefghijklmnopqrstuvwxyz";
+
+        for algorithm in &[HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let mut hasher = Hasher::with_algorithm(*algorithm);
+            hasher.write_all(content.as_bytes()).expect("hash content");
+            let (md5, hash) = hasher.result();
+
+            let node = Node::new("a",
+                                 NodeKind::File,
+                                 Timespec::new(0, 0),
+                                 content.len() as u64,
+                                 100)
+                .with_hash(hash.clone())
+                .with_hash_algorithm(*algorithm);
+            let cursor = Cursor::new(content.to_string().into_bytes());
+            let mut req = SendRequest::new(md5,
+                                           hash,
+                                           node.clone(),
+                                           InMemory(cursor),
+                                           content.len() as u64);
+            storage.send(&mut req).expect("Send stream");
+
+            let (_node, valid) = storage.verify(node).expect("verify");
+            assert!(valid, "{} blob failed verification", algorithm);
+        }
+    }
+
+    #[test]
+    fn concurrent_sends() {
+        use std::thread;
+
+        let test_dir = "target/test/local_storage_concurrent_sends";
+        let _ = remove_dir_all(test_dir);
+        create_dir_all(test_dir).expect("mkdir test_dir");
+
+        let config = EngineConfig::new(test_dir);
+        let storage = LocalStorage::new(&config).expect("new local storage");
+
+        let mut nodes = vec![];
+        let mut workers = vec![];
+        for i in 0..12 {
+            let content = vec![i as u8; 256 * 1024];
+            let mut hasher = Hasher::new();
+            hasher.write_all(&content).expect("hash content");
+            let (md5, hash) = hasher.result();
+            let size = content.len() as u64;
+            let node = Node::new("a", NodeKind::File, Timespec::new(0, 0), size, 100)
+                .with_hash(hash.clone());
+            nodes.push(node.clone());
+
+            let storage = storage.clone();
+            workers.push(thread::spawn(move || {
+                let reader = InMemory(Cursor::new(content));
+                let mut req = SendRequest::new(md5, hash, node, reader, size);
+                storage.send(&mut req).expect("Send stream");
+            }));
+        }
+        for worker in workers {
+            worker.join().expect("join sender");
+        }
+
+        for node in nodes {
+            let (_node, valid) = storage.verify(node).expect("verify");
+            assert!(valid);
+        }
+    }
+
+    #[test]
+    fn index_per_namespace() {
+        let test_dir = "target/test/local_storage_index_per_namespace";
+        let _ = remove_dir_all(test_dir);
+        create_dir_all(test_dir).expect("mkdir test_dir");
+
+        let config = EngineConfig::new(test_dir);
+        let storage = LocalStorage::new(&config).expect("new local storage");
+        assert!(storage.fetch_index("web1").expect("fetch").is_none());
+        assert_eq!(Some(vec![]), storage.namespaces().expect("namespaces"));
+
+        let index = PathBuf::from(test_dir).join("haumaru.idx");
+        File::create(&index).unwrap().write_all(b"web1 index").unwrap();
+        storage.push_index("web1", &index).expect("push");
+        File::create(&index).unwrap().write_all(b"web2 index").unwrap();
+        storage.push_index("web2", &index).expect("push");
+
+        let mut fetched = String::new();
+        storage.fetch_index("web1")
+            .expect("fetch")
+            .expect("index")
+            .read_to_string(&mut fetched)
+            .unwrap();
+        assert_eq!("web1 index", fetched);
+        assert_eq!(Some(vec!["web1".to_string(), "web2".to_string()]),
+                   storage.namespaces().expect("namespaces"));
+    }
+
+    #[test]
+    fn sweep_temp_files() {
+        let test_dir = "target/test/local_storage_sweep_temp_files";
+        let _ = remove_dir_all(test_dir);
+        create_dir_all(test_dir).expect("mkdir test_dir");
+
+        let config = EngineConfig::new(test_dir);
+        let storage = LocalStorage::new(&config).expect("new local storage");
+        let dir = PathBuf::from(test_dir).join("store").join("ab");
+        create_dir_all(&dir).unwrap();
+        // no pid is ever this large, so its writer is gone
+        let stale = dir.join(".abcd.999999999.0.tmp");
+        let live = dir.join(format!(".abcd.{}.1.tmp", unsafe { libc::getpid() }));
+        File::create(&stale).unwrap().write_all(b"stale").unwrap();
+        File::create(&live).unwrap().write_all(b"live").unwrap();
+        File::create(dir.join("abcd")).unwrap().write_all(b"blob").unwrap();
+
+        assert_eq!(Some(4), storage.usage().expect("usage"));
+        assert_eq!(1, storage.sweep_temp_files().expect("sweep"));
+        assert!(!stale.exists());
+        assert!(live.exists());
+    }
+
+    /// Hash and store 1MB, as the pre-send and send workers would.
+    #[bench]
+    fn bench_send_pipeline(b: &mut Bencher) {
+        let test_dir = "target/test/local_storage_bench_send_pipeline";
+        let _ = remove_dir_all(test_dir);
+        create_dir_all(test_dir).expect("mkdir test_dir");
+
+        let config = EngineConfig::new(test_dir);
+        let storage = LocalStorage::new(&config).expect("new local storage");
+        let content = vec![7u8; 1024 * 1024];
+        b.bytes = content.len() as u64;
+
+        b.iter(|| {
+            let mut hasher = Hasher::new();
+            hasher.write_all(&content).expect("hash content");
+            let (md5, hash) = hasher.result();
+
+            let node = Node::new("a", NodeKind::File, Timespec::new(0, 0), content.len() as u64, 100)
+                .with_hash(hash.clone());
+            let mut req = SendRequest::new(md5,
+                                           hash,
+                                           node,
+                                           InMemory(Cursor::new(content.clone())),
+                                           content.len() as u64);
+            storage.send(&mut req).expect("Send stream");
+        });
+    }
 }
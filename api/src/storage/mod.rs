@@ -1,16 +1,31 @@
 mod local_storage;
+#[cfg(feature = "s3")]
 mod s3_storage;
+mod server_storage;
+mod flaky_storage;
+mod registry;
 
 pub use storage::local_storage::*;
+pub use storage::server_storage::ServerStorage;
+pub use storage::flaky_storage::{Faults, FlakyStorage};
+#[cfg(feature = "s3")]
 pub use storage::s3_storage::*;
+pub use storage::registry::{AnyStorage, backends, build};
 
 use std::path::PathBuf;
 
 use std::io;
-use std::io::{Read, Cursor};
+use std::io::{Read, Cursor, Seek, SeekFrom};
 use std::fs::File;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::vec::Vec;
-use Node;
+use {Chunk, HashAlgorithm, Node, Storage};
+use compression::{Compression, encode};
+use digest::Digest;
+use md5::Md5;
+use rustc_serialize::hex::ToHex;
 
 pub enum SendRequestReader {
     InMemory(Cursor<Vec<u8>>),
@@ -29,35 +44,318 @@ impl Read for SendRequestReader {
 
 pub struct SendRequest {
     md5: Vec<u8>,
-    sha256: Vec<u8>,
+    hash: Vec<u8>,
     node: Node,
     reader: SendRequestReader,
     size: u64,
+    attempts: u32,
+    /// The body starts with a compression header, so is not the content `hash` is of.
+    encoded: bool,
+    /// The chunks stored instead of the whole content, see `chunker`.
+    parts: Option<Parts>,
+    /// The file this request stores a chunk of.
+    part_of: Option<Parts>,
+}
+
+/// The chunks of a file, each stored by a request of its own so only a few are held at
+/// once. The request for the file itself waits until all of them are stored, and gives up
+/// if any of them is given up on.
+#[derive(Clone)]
+pub struct Parts {
+    state: Arc<PartsState>,
+}
+
+struct PartsState {
+    pending: AtomicUsize,
+    stored_bytes: AtomicUsize,
+    failed: AtomicBool,
+}
+
+impl Parts {
+    pub fn new() -> Self {
+        Parts {
+            state: Arc::new(PartsState {
+                pending: AtomicUsize::new(0),
+                stored_bytes: AtomicUsize::new(0),
+                failed: AtomicBool::new(false),
+            }),
+        }
+    }
+    /// Count a chunk about to be queued.
+    pub fn add(&self) {
+        self.state.pending.fetch_add(1, Ordering::SeqCst);
+    }
+    /// A chunk has been stored, writing `bytes`.
+    pub fn stored(&self, bytes: u64) {
+        self.state.stored_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+        self.state.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+    /// A chunk has been given up on.
+    pub fn gave_up(&self) {
+        self.state.failed.store(true, Ordering::SeqCst);
+        self.state.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+    /// Whether every chunk has been stored or given up on.
+    pub fn is_done(&self) -> bool {
+        self.state.pending.load(Ordering::SeqCst) == 0
+    }
+    pub fn is_failed(&self) -> bool {
+        self.state.failed.load(Ordering::SeqCst)
+    }
+    fn stored_bytes(&self) -> u64 {
+        self.state.stored_bytes.load(Ordering::SeqCst) as u64
+    }
 }
 
 impl SendRequest {
     pub fn new(md5: Vec<u8>,
-               sha256: Vec<u8>,
+               hash: Vec<u8>,
                node: Node,
                reader: SendRequestReader,
                size: u64)
                -> Self {
         SendRequest {
             md5: md5,
-            sha256: sha256,
+            hash: hash,
             node: node,
             reader: reader,
             size: size,
+            attempts: 0,
+            encoded: false,
+            parts: None,
+            part_of: None,
         }
     }
+    /// Record the file once `parts` are stored, instead of storing the content of this request.
+    pub fn with_parts(mut self, parts: Parts) -> Self {
+        self.parts = Some(parts);
+        self
+    }
+    /// Store a chunk of the file recorded once `parts` are stored.
+    pub fn with_part_of(mut self, parts: Parts) -> Self {
+        self.part_of = Some(parts);
+        self
+    }
+    /// A request storing `content`, compressed with `compression` where that makes it smaller.
+    /// `md5` is of `content`, and is worked out again for the body when that differs.
+    pub fn for_content(compression: Option<Compression>,
+                       md5: Vec<u8>,
+                       hash: Vec<u8>,
+                       node: Node,
+                       content: Vec<u8>)
+                       -> io::Result<Self> {
+        let (body, encoded) = encode(compression, content)?;
+        let md5 = if encoded {
+            let mut digest = Md5::default();
+            digest.input(&body);
+            digest.result().to_vec()
+        } else {
+            md5
+        };
+        let size = body.len() as u64;
+        let reader = SendRequestReader::InMemory(Cursor::new(body));
+        let mut req = SendRequest::new(md5, hash, node, reader, size);
+        req.encoded = encoded;
+        Ok(req)
+    }
+    /// A request storing the body in `file`, of `size` bytes, which starts with a compression
+    /// header when `encoded`. `md5` is of the body.
+    pub fn for_file(md5: Vec<u8>,
+                    hash: Vec<u8>,
+                    node: Node,
+                    file: File,
+                    size: u64,
+                    encoded: bool)
+                    -> Self {
+        let mut req = SendRequest::new(md5, hash, node, SendRequestReader::Disk(file), size);
+        req.encoded = encoded;
+        req
+    }
     pub fn node(&self) -> &Node {
         &self.node
     }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn encoded(&self) -> bool {
+        self.encoded
+    }
+    pub fn parts(&self) -> Option<&Parts> {
+        self.parts.as_ref()
+    }
+    pub fn part_of(&self) -> Option<&Parts> {
+        self.part_of.as_ref()
+    }
+    /// Failed sends so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+    /// Record a failed send, returning how many attempts have failed so far.
+    pub fn failed(&mut self) -> u32 {
+        self.attempts += 1;
+        self.attempts
+    }
     pub fn complete(self) -> Node {
         self.node
     }
 }
 
+/// Store `req` in `storage`. A request split into chunks has nothing of its own to store once
+/// its chunks are, and reports what they wrote.
+pub fn send_request<S>(storage: &S, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>>
+    where S: Storage
+{
+    if let Some(ref parts) = req.parts {
+        return match parts.stored_bytes() {
+            0 => Ok(SendReceipt::existing()),
+            stored => Ok(SendReceipt::stored(stored)),
+        };
+    }
+    // a failed attempt may have read some of it already
+    match req.reader {
+        SendRequestReader::InMemory(ref mut cursor) => cursor.set_position(0),
+        SendRequestReader::Disk(ref mut file) => {
+            file.seek(SeekFrom::Start(0))?;
+        }
+    }
+    storage.send(req)
+}
+
+/// The content of the file `node`, reassembled from its chunks when it has them. `None` when
+/// storage does not have it; a missing chunk only shows up as an error reading it.
+pub fn retrieve_node<S>(storage: &S, node: &Node) -> Result<Option<Blob>, Box<Error>>
+    where S: Storage + 'static
+{
+    if !node.is_chunked() {
+        let hash = node.hash().as_ref().expect("File must have hash");
+        return storage.retrieve(node.hash_algorithm(), hash);
+    }
+    let size: u64 = node.chunks().iter().map(|c| c.size).sum();
+    let reader = ChunkReader {
+        storage: storage.clone(),
+        chunks: node.chunks().to_vec(),
+        next: 0,
+        current: None,
+    };
+    Ok(Some(Blob::new(size, box reader)))
+}
+
+/// Check the stored content of `node`, every chunk of it when it has them.
+pub fn verify_node<S: Storage>(storage: &S, node: Node) -> Result<(Node, bool), Box<Error>> {
+    if !node.is_chunked() {
+        return storage.verify(node);
+    }
+    let parts: Vec<Node> = node.chunks()
+        .iter()
+        .map(|c| {
+            Node::new_file(node.path(), *node.mtime(), c.size, node.mode())
+                .with_hash(c.hash.clone())
+                .with_hash_algorithm(c.algorithm)
+        })
+        .collect();
+    for part in parts {
+        if !storage.verify(part)?.1 {
+            return Ok((node, false));
+        }
+    }
+    Ok((node, true))
+}
+
+/// Reads chunks back one after another, retrieving each as the one before runs out.
+struct ChunkReader<S: Storage> {
+    storage: S,
+    chunks: Vec<Chunk>,
+    next: usize,
+    current: Option<Blob>,
+}
+
+impl<S: Storage> Read for ChunkReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            if let Some(ref mut blob) = self.current {
+                let read = blob.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+            }
+            if self.next == self.chunks.len() {
+                return Ok(0);
+            }
+            let (algorithm, hash) = {
+                let chunk = &self.chunks[self.next];
+                (chunk.algorithm, chunk.hash.clone())
+            };
+            self.next += 1;
+            let blob = self.storage
+                .retrieve(algorithm, &hash)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+            match blob {
+                Some(blob) => self.current = Some(blob),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("Chunk {} is missing from storage",
+                                                      hash.to_hex())))
+                }
+            }
+        }
+    }
+}
+
+/// What `Storage::send` did with a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendReceipt {
+    stored_bytes: u64,
+    deduplicated: bool,
+}
+
+impl SendReceipt {
+    /// `bytes` were written to the backend.
+    pub fn stored(bytes: u64) -> Self {
+        SendReceipt {
+            stored_bytes: bytes,
+            deduplicated: false,
+        }
+    }
+    /// The backend already had the content, so nothing was written.
+    pub fn existing() -> Self {
+        SendReceipt {
+            stored_bytes: 0,
+            deduplicated: true,
+        }
+    }
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes
+    }
+    pub fn deduplicated(&self) -> bool {
+        self.deduplicated
+    }
+}
+
+/// A stored blob being read back, streamed from the backend.
+pub struct Blob {
+    size: u64,
+    reader: Box<Read>,
+}
+
+impl Blob {
+    pub fn new(size: u64, reader: Box<Read>) -> Self {
+        Blob {
+            size: size,
+            reader: reader,
+        }
+    }
+    /// Bytes the backend holds for the blob.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for Blob {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.reader.read(buf)
+    }
+}
+
 pub fn hash_dir(hash: &String) -> PathBuf {
     let mut path = PathBuf::new();
     path.push(hash[0..2].to_string());
@@ -71,3 +369,24 @@ pub fn hash_path(hash: &String) -> PathBuf {
     path.push(hash[4..].to_string());
     path
 }
+
+/// Directory of a blob relative to the store root. SHA256 blobs keep the original layout,
+/// other algorithms are kept under a directory named by their tag.
+pub fn blob_dir(algorithm: HashAlgorithm, hash: &String) -> PathBuf {
+    let mut path = PathBuf::new();
+    if algorithm != HashAlgorithm::Sha256 {
+        path.push(algorithm.tag());
+    }
+    path.push(hash_dir(hash));
+    path
+}
+
+/// Path of a blob relative to the store root.
+pub fn blob_path(algorithm: HashAlgorithm, hash: &String) -> PathBuf {
+    let mut path = PathBuf::new();
+    if algorithm != HashAlgorithm::Sha256 {
+        path.push(algorithm.tag());
+    }
+    path.push(hash_path(hash));
+    path
+}
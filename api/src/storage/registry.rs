@@ -0,0 +1,219 @@
+//! Storage backends compiled into this build, picked by the `storage:` config value.
+//!
+//! Backends needing dependencies of their own, such as S3, sit behind cargo features of the
+//! same name, so builds without them skip those dependencies. Builds with the `flaky` feature
+//! wrap the backend in a `FlakyStorage` when `faults:` is configured.
+
+use {EngineConfig, HashAlgorithm, HaumaruError, Node, Storage};
+use std::error::Error;
+use std::path::Path;
+use storage::{Blob, LocalStorage, SendReceipt, SendRequest, ServerStorage};
+#[cfg(feature = "s3")]
+use storage::S3Storage;
+#[cfg(feature = "flaky")]
+use storage::FlakyStorage;
+
+/// Names accepted for `storage:` by this build.
+pub fn backends() -> Vec<&'static str> {
+    let mut names = vec!["local", "server"];
+    if cfg!(feature = "s3") {
+        names.push("s3");
+    }
+    names
+}
+
+/// Build the backend `config` names, with any faults it configures.
+pub fn build(config: &EngineConfig) -> Result<AnyStorage, HaumaruError> {
+    let storage = build_backend(config)?;
+    match config.faults() {
+        #[cfg(feature = "flaky")]
+        Some(faults) => Ok(AnyStorage::Flaky(box FlakyStorage::new(storage, faults.clone()))),
+        _ => Ok(storage),
+    }
+}
+
+fn build_backend(config: &EngineConfig) -> Result<AnyStorage, HaumaruError> {
+    match config.storage() {
+        "local" => {
+            LocalStorage::new(config)
+                .map(AnyStorage::Local)
+                .map_err(|e| HaumaruError::Storage(box e))
+        }
+        "server" => {
+            match config.server() {
+                Some((url, token)) => Ok(AnyStorage::Server(ServerStorage::new(url, token))),
+                None => {
+                    Err(HaumaruError::Config("server storage needs server_url and server_token"
+                        .into()))
+                }
+            }
+        }
+        #[cfg(feature = "s3")]
+        "s3" => {
+            S3Storage::new(config.clone())
+                .map(AnyStorage::S3)
+                .map_err(|e| HaumaruError::Config(e.into()))
+        }
+        name => {
+            Err(HaumaruError::Config(format!("Unknown storage backend {:?}, this build has: {}",
+                                             name,
+                                             backends().join(", "))
+                .into()))
+        }
+    }
+}
+
+/// Whichever backend the config picked.
+#[derive(Clone)]
+pub enum AnyStorage {
+    Local(LocalStorage),
+    Server(ServerStorage),
+    #[cfg(feature = "s3")]
+    S3(S3Storage),
+    #[cfg(feature = "flaky")]
+    Flaky(Box<FlakyStorage<AnyStorage>>),
+}
+
+impl Storage for AnyStorage {
+    fn send(&self, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.send(req),
+            AnyStorage::Server(ref s) => s.send(req),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.send(req),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.send(req),
+        }
+    }
+    fn retrieve(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<Option<Blob>, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.retrieve(algorithm, hash),
+            AnyStorage::Server(ref s) => s.retrieve(algorithm, hash),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.retrieve(algorithm, hash),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.retrieve(algorithm, hash),
+        }
+    }
+    fn verify(&self, node: Node) -> Result<(Node, bool), Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.verify(node),
+            AnyStorage::Server(ref s) => s.verify(node),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.verify(node),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.verify(node),
+        }
+    }
+    fn delete(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<bool, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.delete(algorithm, hash),
+            AnyStorage::Server(ref s) => s.delete(algorithm, hash),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.delete(algorithm, hash),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.delete(algorithm, hash),
+        }
+    }
+    fn usage(&self) -> Result<Option<u64>, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.usage(),
+            AnyStorage::Server(ref s) => s.usage(),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.usage(),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.usage(),
+        }
+    }
+    fn sweep_temp_files(&self) -> Result<u64, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.sweep_temp_files(),
+            AnyStorage::Server(ref s) => s.sweep_temp_files(),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.sweep_temp_files(),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.sweep_temp_files(),
+        }
+    }
+    fn max_sends(&self) -> Option<usize> {
+        match *self {
+            AnyStorage::Local(ref s) => s.max_sends(),
+            AnyStorage::Server(ref s) => s.max_sends(),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.max_sends(),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.max_sends(),
+        }
+    }
+    fn push_index(&self, namespace: &str, index: &Path) -> Result<(), Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.push_index(namespace, index),
+            AnyStorage::Server(ref s) => s.push_index(namespace, index),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.push_index(namespace, index),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.push_index(namespace, index),
+        }
+    }
+    fn fetch_index(&self, namespace: &str) -> Result<Option<Blob>, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.fetch_index(namespace),
+            AnyStorage::Server(ref s) => s.fetch_index(namespace),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.fetch_index(namespace),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.fetch_index(namespace),
+        }
+    }
+    fn namespaces(&self) -> Result<Option<Vec<String>>, Box<Error>> {
+        match *self {
+            AnyStorage::Local(ref s) => s.namespaces(),
+            AnyStorage::Server(ref s) => s.namespaces(),
+            #[cfg(feature = "s3")]
+            AnyStorage::S3(ref s) => s.namespaces(),
+            #[cfg(feature = "flaky")]
+            AnyStorage::Flaky(ref s) => s.namespaces(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use EngineConfig;
+    use super::*;
+
+    #[test]
+    fn unknown_backend() {
+        let config = EngineConfig::new("target/test/registry_unknown_backend")
+            .with_storage("tape");
+        assert!(build(&config).is_err());
+    }
+
+    #[test]
+    fn local_sends() {
+        let config = EngineConfig::new("target/test/registry_local_sends");
+        assert_eq!(Some(2), build(&config).expect("build").max_sends());
+    }
+
+    #[test]
+    fn local_backend() {
+        let config = EngineConfig::new("target/test/registry_local_backend");
+        assert!(backends().contains(&config.storage()));
+        match build(&config).unwrap() {
+            AnyStorage::Local(_) => {}
+            _ => panic!("expected local storage"),
+        }
+    }
+
+    #[test]
+    fn server_backend_needs_url() {
+        let config = EngineConfig::new("target/test/registry_server_backend")
+            .with_storage("server");
+        assert!(build(&config).is_err());
+        let config = config.with_server("http://backup:7373", "secret");
+        match build(&config).unwrap() {
+            AnyStorage::Server(_) => {}
+            _ => panic!("expected server storage"),
+        }
+    }
+}
@@ -1,36 +1,79 @@
 
 
-use {Node, Storage};
+use {HashAlgorithm, Node, Storage};
 
 use chrono::*;
-use crypto::hmac::Hmac;
-use crypto::mac::Mac;
-use crypto::sha2::Sha256;
+use compression::decode;
+use cost::DEFAULT_STORAGE_CLASS;
 use engine::EngineConfig;
 use hasher::Hasher;
+use hmac::{Hmac, Mac};
+use metrics;
 use hyper;
 use hyper::Url;
 use hyper::client::*;
-use hyper::header::Headers;
+use hyper::header::{ContentLength, Headers};
 use hyper::method::Method;
 use regex::Regex;
 use rustc_serialize::base64;
 use rustc_serialize::base64::{CharacterSet, Newline, ToBase64};
 use rustc_serialize::hex::ToHex;
+use sha2::Sha256;
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
+use std::fs::File;
 use std::io::{Read, Write};
-use storage::SendRequest;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use storage::{Blob, SendReceipt, SendRequest};
 // use retry::retry_forever;
 
+/// How long assumed role credentials last, in seconds.
+const ASSUMED_ROLE_DURATION: u32 = 3600;
+
+/// Assumed role credentials are renewed this many minutes before they expire.
+const RENEW_MINUTES: i64 = 5;
+
 pub struct S3Storage {
     // region: String,
     bucket: String,
     prefix: String,
+    /// Credentials from the environment. With a role configured they only assume it.
+    credentials: Credentials,
+    role_arn: Option<String>,
+    /// Shared between clones, so the role is assumed once for every sender.
+    assumed: Arc<Mutex<Option<Credentials>>>,
+    client: Client,
+}
+
+/// Keys requests are signed with. Short-lived ones, from SSO or STS, come with a session token.
+#[derive(Debug, Clone, PartialEq)]
+struct Credentials {
     access_key: String,
     secret_key: String,
-    client: Client,
+    session_token: Option<String>,
+    expires: Option<DateTime<UTC>>,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Self, String> {
+        let var = |name: &str| env::var(name).map_err(|e| format!("{}: {}", name, e));
+        Ok(Credentials {
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            expires: None,
+        })
+    }
+
+    /// Whether these should be renewed before signing a request at `dt`.
+    fn expiring(&self, dt: DateTime<UTC>) -> bool {
+        self.expires
+            .as_ref()
+            .map(|e| e.clone() - Duration::minutes(RENEW_MINUTES) <= dt)
+            .unwrap_or(false)
+    }
 }
 
 fn new_client() -> Client {
@@ -44,27 +87,105 @@ impl Clone for S3Storage {
         S3Storage {
             bucket: self.bucket.clone(),
             prefix: self.prefix.clone(),
-            access_key: self.access_key.clone(),
-            secret_key: self.secret_key.clone(),
+            credentials: self.credentials.clone(),
+            role_arn: self.role_arn.clone(),
+            assumed: self.assumed.clone(),
             client: new_client(),
         }
     }
 }
 
 impl S3Storage {
-    pub fn new(config: EngineConfig) -> Self {
-        S3Storage {
-            bucket: config.bucket().map(|s| s.to_string()).expect("S3 bucket"),
+    /// Fails when there is no bucket configured or no credentials in the environment.
+    pub fn new(config: EngineConfig) -> Result<Self, String> {
+        Ok(S3Storage {
+            bucket: config.bucket()
+                .map(|s| s.to_string())
+                .ok_or_else(|| "s3 storage needs a bucket".to_string())?,
             prefix: config.prefix().map(|s| s.to_string()).unwrap_or(String::new()),
-            access_key: env::var("AWS_ACCESS_KEY_ID")
-                .expect("AWS_ACCESS_KEY_ID")
-                .into(),
-            secret_key: env::var("AWS_SECRET_ACCESS_KEY")
-                .expect("AWS_SECRET_ACCESS_KEY")
-                .into(),
+            credentials: Credentials::from_env()?,
+            role_arn: config.aws_role_arn().map(|s| s.to_string()),
+            assumed: Arc::new(Mutex::new(None)),
             client: new_client(),
+        })
+    }
+
+    /// Credentials to sign a request at `dt` with, assuming the configured role again when
+    /// the last credentials it gave are about to expire.
+    fn credentials(&self, dt: DateTime<UTC>) -> Result<Credentials, String> {
+        let role_arn = match self.role_arn {
+            Some(ref role_arn) => role_arn,
+            None => return Ok(self.credentials.clone()),
+        };
+        let mut assumed = self.assumed.lock().expect("assumed lock");
+        let fresh = assumed.as_ref().map(|c| !c.expiring(dt)).unwrap_or(false);
+        if !fresh {
+            info!("Assuming role {}", role_arn);
+            *assumed = Some(assume_role(&self.client, &self.credentials, role_arn, dt)?);
+        }
+        Ok((*assumed).clone().expect("assumed credentials"))
+    }
+}
+
+/// Temporary credentials for `role_arn` from STS, asked for with `credentials`.
+fn assume_role(client: &Client,
+               credentials: &Credentials,
+               role_arn: &str,
+               dt: DateTime<UTC>)
+               -> Result<Credentials, String> {
+    let url = format!("https://sts.amazonaws.com/?Action=AssumeRole&DurationSeconds={}&\
+                       RoleArn={}&RoleSessionName=haumaru&Version=2011-06-15",
+                      ASSUMED_ROLE_DURATION,
+                      uri_encode(role_arn))
+        .parse()
+        .expect("URL");
+    let mut result = AmazonRequest::new(credentials, "sts", Method::Get, url)
+        .with_region("us-east-1")
+        .send(client, dt, None)
+        .map_err(|e| format!("Failed to assume role {}: {}", role_arn, e))?;
+
+    let mut response_body = String::new();
+    result.read_to_string(&mut response_body)
+        .map_err(|e| format!("Failed to read assumed role: {}", e))?;
+    if result.status != hyper::Ok {
+        return Err(format!("Failed to assume role {}: {}\n{}",
+                           role_arn,
+                           result.status,
+                           response_body));
+    }
+    parse_assumed_role(&response_body)
+}
+
+fn parse_assumed_role(xml: &str) -> Result<Credentials, String> {
+    let value = |tag: &str| {
+        let re = Regex::new(&format!("<{0}>([^<]*)</{0}>", tag)).expect("tag regex");
+        re.captures(xml)
+            .and_then(|c| c.at(1).map(|s| s.to_string()))
+            .ok_or(format!("Assumed role response has no {}", tag))
+    };
+    let expires = value("Expiration")?;
+    Ok(Credentials {
+        access_key: value("AccessKeyId")?,
+        secret_key: value("SecretAccessKey")?,
+        session_token: Some(value("SessionToken")?),
+        expires: Some(DateTime::parse_from_rfc3339(&expires)
+            .map_err(|e| format!("Bad expiration {}: {}", expires, e))?
+            .with_timezone(&UTC)),
+    })
+}
+
+/// `s` percent encoded the way signatures expect query values.
+fn uri_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
         }
     }
+    encoded
 }
 
 struct AwsSignature {
@@ -238,9 +359,55 @@ fn test_put_signature() {
     assert_eq!(headers, calcd_headers);
 }
 
+#[test]
+fn test_session_token_signature() {
+    let credentials = Credentials {
+        access_key: "SOME_RANDOM_ACCESS_KEY".to_string(),
+        secret_key: "SOME_RANDOM_SECRET_KEY".to_string(),
+        session_token: Some("SOME_SESSION_TOKEN".to_string()),
+        expires: None,
+    };
+    let url = "https://haumaru-test2.s3.amazonaws.com/".parse().expect("URL");
+    let dt = UTC.ymd(2016, 8, 30).and_hms(2, 25, 34);
+    let headers = AmazonRequest::new(&credentials, "s3", Method::Get, url)
+        .signature(dt)
+        .unwrap()
+        .signed_headers();
+
+    assert_eq!(Some(&[b"SOME_SESSION_TOKEN".to_vec()][..]),
+               headers.get_raw("X-Amz-Security-Token"));
+    let authorization = String::from_utf8(headers.get_raw("Authorization").unwrap()[0].clone())
+        .unwrap();
+    assert!(authorization.contains("SignedHeaders=host;x-amz-date;x-amz-security-token,"));
+}
+
+#[test]
+fn test_parse_assumed_role() {
+    let xml = "<AssumeRoleResponse><AssumeRoleResult><Credentials>\
+               <AccessKeyId>ASIAEXAMPLE</AccessKeyId>\
+               <SecretAccessKey>secret</SecretAccessKey>\
+               <SessionToken>token</SessionToken>\
+               <Expiration>2016-08-30T03:25:34Z</Expiration>\
+               </Credentials></AssumeRoleResult></AssumeRoleResponse>";
+    let credentials = parse_assumed_role(xml).unwrap();
+    assert_eq!("ASIAEXAMPLE", credentials.access_key);
+    assert_eq!(Some("token".to_string()), credentials.session_token);
+
+    let expires = UTC.ymd(2016, 8, 30).and_hms(3, 25, 34);
+    assert_eq!(Some(expires), credentials.expires);
+    assert!(!credentials.expiring(expires - Duration::minutes(10)));
+    assert!(credentials.expiring(expires - Duration::minutes(1)));
+    assert!(parse_assumed_role("<Error/>").is_err());
+}
+
+#[test]
+fn test_uri_encode() {
+    assert_eq!("arn%3Aaws%3Aiam%3A%3A123%3Arole%2Fbackup-role",
+               uri_encode("arn:aws:iam::123:role/backup-role"));
+}
+
 struct AmazonRequest {
-    access_key: String,
-    secret_key: String,
+    credentials: Credentials,
     service: String,
     method: Method,
     url: Url,
@@ -250,10 +417,9 @@ struct AmazonRequest {
 }
 
 impl AmazonRequest {
-    fn new(access_key: &str, secret_key: &str, service: &str, method: Method, url: Url) -> Self {
+    fn new(credentials: &Credentials, service: &str, method: Method, url: Url) -> Self {
         AmazonRequest {
-            access_key: access_key.into(),
-            secret_key: secret_key.into(),
+            credentials: credentials.clone(),
             service: service.into(),
             method: method,
             url: url,
@@ -270,11 +436,11 @@ impl AmazonRequest {
         self.payload_hash = sha256.into();
         self
     }
-    fn send<'a>(self,
-                client: &'a Client,
-                dt: DateTime<UTC>,
-                body: Option<Body<'a>>)
-                -> Result<Response, String> {
+    fn with_region(mut self, region: &str) -> Self {
+        self.region = region.into();
+        self
+    }
+    fn signature(&self, dt: DateTime<UTC>) -> Result<AwsSignature, String> {
         let amzdate = dt.format("%Y%m%dT%H%M%SZ").to_string();
         let datestamp = dt.format("%Y%m%d").to_string();
 
@@ -290,9 +456,15 @@ impl AmazonRequest {
             Some(s) => s.to_string(),
         };
 
-        let sig = AwsSignature {
-            access_key: self.access_key.clone(),
-            secret_key: self.secret_key.clone(),
+        // temporary credentials only work with their session token, which is signed too
+        let mut headers = self.headers.clone();
+        if let Some(ref token) = self.credentials.session_token {
+            headers.insert("X-Amz-Security-Token".into(), token.clone());
+        }
+
+        Ok(AwsSignature {
+            access_key: self.credentials.access_key.clone(),
+            secret_key: self.credentials.secret_key.clone(),
             method: self.method.as_ref().into(),
             service: self.service.clone(),
             host: host.clone(),
@@ -302,9 +474,15 @@ impl AmazonRequest {
             canonical_uri: canonical_uri.clone(),
             canonical_querystring: canonical_querystring.clone(),
             payload_hash: self.payload_hash.clone(),
-            headers: self.headers.clone(),
-        };
-        let headers = sig.signed_headers();
+            headers: headers,
+        })
+    }
+    fn send<'a>(self,
+                client: &'a Client,
+                dt: DateTime<UTC>,
+                body: Option<Body<'a>>)
+                -> Result<Response, String> {
+        let headers = self.signature(dt)?.signed_headers();
 
         let mut res = client.request(self.method.clone(), self.url.clone())
             .headers(headers.clone());
@@ -331,8 +509,7 @@ impl S3Storage {
         let url_str = format!("https://{}?{}", host, query);
 
         let url = url_str.parse().expect("URL");
-        let aws_req =
-            AmazonRequest::new(&self.access_key, &self.secret_key, "s3", Method::Get, url);
+        let aws_req = AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Get, url);
         let mut result = aws_req.send(&self.client, dt.clone(), None)
             .map_err(|e| format!("Failed to check S3 key exists: {}", e))?;
 
@@ -360,22 +537,44 @@ impl S3Storage {
         })
     }
 
-    fn key_from_sha256(&self, hash: &str) -> String {
-        format!("{}/{}/{}/{}", self.prefix, &hash[0..1], &hash[1..2], &hash)
+    fn key_from_hash(&self, algorithm: HashAlgorithm, hash: &str) -> String {
+        match algorithm {
+            HashAlgorithm::Sha256 => {
+                format!("{}/{}/{}/{}", self.prefix, &hash[0..1], &hash[1..2], &hash)
+            }
+            a => format!("{}/{}/{}/{}/{}", self.prefix, a.tag(), &hash[0..1], &hash[1..2], &hash),
+        }
+    }
+
+    fn index_url(&self, namespace: &str) -> Url {
+        format!("https://{}.s3.amazonaws.com/{}/indexes/{}.idx",
+                self.bucket,
+                self.prefix,
+                namespace)
+            .parse()
+            .expect("URL")
     }
 }
 
 impl Storage for S3Storage {
-    fn send(&self, req: &mut SendRequest) -> Result<(), Box<Error>> {
-        let &mut SendRequest { ref md5, sha256: ref hash, node: ref _node, ref mut reader, size } = req;
+    fn send(&self, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>> {
+        let &mut SendRequest { ref md5,
+                               ref hash,
+                               ref node,
+                               ref mut reader,
+                               size,
+                               encoded,
+                               .. } = req;
+        let algorithm = node.hash_algorithm();
         let hex = hash.to_hex();
-        let key = self.key_from_sha256(&hex);
+        let key = self.key_from_hash(algorithm, &hex);
 
         debug!("Using s3://{}/{}", self.bucket, key);
 
         if self.key_exists(UTC::now(), &key)? {
             debug!("Storage already contains {}", key);
-            return Ok(());
+            metrics::incr("storage.s3.blobs_existing");
+            return Ok(SendReceipt::existing());
         }
 
         debug!("Uploading s3://{}/{} ({} bytes)", self.bucket, key, size);
@@ -384,9 +583,17 @@ impl Storage for S3Storage {
         let host = format!("{}.s3.amazonaws.com", self.bucket);
         let url_str = format!("https://{}/{}", host, key);
         let url = url_str.parse().expect("URL");
+
+        // the signature needs the SHA256 of the body, which only the content hash gives us
+        // when it is SHA256 too and the body is not compressed
+        let payload_hash = match algorithm {
+            HashAlgorithm::Sha256 if !encoded => hex.clone(),
+            _ => "UNSIGNED-PAYLOAD".to_string(),
+        };
+
         let aws_req =
-            AmazonRequest::new(&self.access_key, &self.secret_key, "s3", Method::Put, url)
-                .with_header("x-amz-storage-clas", "STANDARD_IA")
+            AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Put, url)
+                .with_header("x-amz-storage-class", DEFAULT_STORAGE_CLASS)
                 .with_header("Content-MD5",
                              &md5.to_base64(base64::Config {
                                  char_set: CharacterSet::Standard,
@@ -394,7 +601,7 @@ impl Storage for S3Storage {
                                  pad: true,
                                  line_length: None,
                              }))
-                .with_payload_hash(&hash.to_hex());
+                .with_payload_hash(&payload_hash);
         let mut result = aws_req.send(&self.client,
                   dt.clone(),
                   Some(Body::SizedBody(reader, size)))
@@ -404,6 +611,7 @@ impl Storage for S3Storage {
             let mut response_body = String::new();
             result.read_to_string(&mut response_body).expect("read_to_string");
             warn!("Failed upload result body:\n{:?}", response_body);
+            metrics::incr("storage.s3.errors");
             return Err(format!("Failed failed to upload key: {}. {}\n{}",
                                result.status,
                                url_str,
@@ -411,15 +619,62 @@ impl Storage for S3Storage {
                 .into());
         }
 
-        Ok(())
+        metrics::incr("storage.s3.blobs_written");
+        metrics::count("storage.s3.bytes_written", size as i64);
+        Ok(SendReceipt::stored(size))
+    }
+    fn retrieve(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<Option<Blob>, Box<Error>> {
+        let key = self.key_from_hash(algorithm, &hash.to_hex());
+        let url = format!("https://{}.s3.amazonaws.com/{}", self.bucket, key).parse().expect("URL");
+        let dt = UTC::now();
+        let aws_req = AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Get, url);
+        let mut result = aws_req.send(&self.client, dt, None)
+            .map_err(|e| format!("Failed to download {} from S3: {}", key, e))?;
+        match result.status {
+            hyper::Ok => {
+                let size = match result.headers.get::<ContentLength>() {
+                    Some(&ContentLength(size)) => size,
+                    None => return Err(format!("S3 sent {} without a length", key).into()),
+                };
+                Ok(Some(decode(Blob::new(size, box result))?))
+            }
+            hyper::NotFound => Ok(None),
+            status => {
+                let mut response_body = String::new();
+                result.read_to_string(&mut response_body).expect("read_to_string");
+                Err(format!("Failed to download {}: {}\n{}", key, status, response_body).into())
+            }
+        }
+    }
+    /// S3 answers a delete the same whether or not it had the key.
+    fn delete(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<bool, Box<Error>> {
+        let key = self.key_from_hash(algorithm, &hash.to_hex());
+        let url = format!("https://{}.s3.amazonaws.com/{}", self.bucket, key).parse().expect("URL");
+        let dt = UTC::now();
+        let aws_req = AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Delete, url);
+        let mut result = aws_req.send(&self.client, dt, None)
+            .map_err(|e| format!("Failed to delete {} from S3: {}", key, e))?;
+        match result.status {
+            hyper::Ok | hyper::status::StatusCode::NoContent => {
+                metrics::incr("storage.s3.blobs_deleted");
+                Ok(true)
+            }
+            status => {
+                let mut response_body = String::new();
+                result.read_to_string(&mut response_body).expect("read_to_string");
+                metrics::incr("storage.s3.errors");
+                Err(format!("Failed to delete {}: {}\n{}", key, status, response_body).into())
+            }
+        }
     }
-    fn retrieve(&self, _hash: &[u8]) -> Result<Option<Box<Read>>, Box<Error>> {
-        use std::io::Cursor;
-        Ok(Some(box Cursor::new(vec![])))
+    /// Each request spends most of its time waiting on the network.
+    fn max_sends(&self) -> Option<usize> {
+        Some(16)
     }
+
     fn verify(&self, n: Node) -> Result<(Node, bool), Box<Error>> {
         let hex = n.hash().as_ref().expect("hash").to_hex();
-        let key = self.key_from_sha256(&hex);
+        let key = self.key_from_hash(n.hash_algorithm(), &hex);
         if self.key_exists(UTC::now(), &key)? {
             info!("{} OK", key);
             Ok((n, true))
@@ -427,6 +682,82 @@ impl Storage for S3Storage {
             Ok((n, false))
         }
     }
+    fn push_index(&self, namespace: &str, index: &Path) -> Result<(), Box<Error>> {
+        let mut file = File::open(index)?;
+        let size = file.metadata()?.len();
+        let url = self.index_url(namespace);
+        debug!("Uploading index to {}", url);
+        let dt = UTC::now();
+        let aws_req = AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Put, url)
+            .with_payload_hash("UNSIGNED-PAYLOAD");
+        let mut result = aws_req.send(&self.client,
+                  dt,
+                  Some(Body::SizedBody(&mut file, size)))
+            .map_err(|e| format!("Failed to upload index to S3: {}", e))?;
+        if result.status != hyper::Ok {
+            let mut response_body = String::new();
+            result.read_to_string(&mut response_body).expect("read_to_string");
+            return Err(format!("Failed to upload index: {}\n{}", result.status, response_body)
+                .into());
+        }
+        Ok(())
+    }
+    fn fetch_index(&self, namespace: &str) -> Result<Option<Blob>, Box<Error>> {
+        let url = self.index_url(namespace);
+        let dt = UTC::now();
+        let aws_req = AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Get, url);
+        let mut result = aws_req.send(&self.client, dt, None)
+            .map_err(|e| format!("Failed to fetch index from S3: {}", e))?;
+        match result.status {
+            hyper::Ok => {
+                let size = match result.headers.get::<ContentLength>() {
+                    Some(&ContentLength(size)) => size,
+                    None => return Err("S3 sent the index without a length".into()),
+                };
+                Ok(Some(Blob::new(size, box result)))
+            }
+            hyper::NotFound => Ok(None),
+            status => {
+                let mut response_body = String::new();
+                result.read_to_string(&mut response_body).expect("read_to_string");
+                Err(format!("Failed to fetch index: {}\n{}", status, response_body).into())
+            }
+        }
+    }
+    fn namespaces(&self) -> Result<Option<Vec<String>>, Box<Error>> {
+        let prefix = format!("{}/indexes/", self.prefix);
+        let query = format!("list-type=2&prefix={}", prefix).replace("/", "%2F");
+        let url = format!("https://{}.s3.amazonaws.com?{}", self.bucket, query)
+            .parse()
+            .expect("URL");
+        let dt = UTC::now();
+        let aws_req = AmazonRequest::new(&self.credentials(dt)?, "s3", Method::Get, url);
+        let mut result = aws_req.send(&self.client, dt, None)
+            .map_err(|e| format!("Failed to list indexes in S3: {}", e))?;
+        let mut response_body = String::new();
+        result.read_to_string(&mut response_body)?;
+        if result.status != hyper::Ok {
+            return Err(format!("Failed to list indexes: {}\n{}", result.status, response_body)
+                .into());
+        }
+        // a partial listing could leave out a namespace whose blobs are still in use
+        if response_body.contains("<IsTruncated>true</IsTruncated>") {
+            return Ok(None);
+        }
+
+        lazy_static! {
+            static ref KEY: Regex = Regex::new("<Key>([^<]*)</Key>").unwrap();
+        }
+        let mut namespaces = vec![];
+        for caps in KEY.captures_iter(&response_body) {
+            if let Some(key) = caps.at(1) {
+                if key.starts_with(&prefix) && key.ends_with(".idx") {
+                    namespaces.push(key[prefix.len()..key.len() - ".idx".len()].to_string());
+                }
+            }
+        }
+        Ok(Some(namespaces))
+    }
 }
 
 fn get_signature_key(key: String,
@@ -448,7 +779,7 @@ fn sha256(content: &str) -> Vec<u8> {
 }
 
 fn sign(key: Vec<u8>, msg: String) -> Vec<u8> {
-    let mut hmac = Hmac::new(Sha256::new(), &key);
+    let mut hmac = Hmac::<Sha256>::new(&key);
     hmac.input(msg.as_bytes());
     hmac.result().code().to_vec()
 }
@@ -0,0 +1,134 @@
+use {HashAlgorithm, Node, Storage};
+use hyper;
+use hyper::client::{Body, Client, Response};
+use hyper::header::{ContentLength, Headers};
+use hyper::method::Method;
+use metrics;
+use rustc_serialize::hex::ToHex;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use storage::{Blob, SendReceipt, SendRequest};
+
+/// Stores blobs on a `haumaru serve` server, in the namespace of the client its token
+/// belongs to.
+#[derive(Clone)]
+pub struct ServerStorage {
+    url: String,
+    token: String,
+    client: Arc<Client>,
+}
+
+impl ServerStorage {
+    pub fn new(url: &str, token: &str) -> Self {
+        ServerStorage {
+            url: url.trim_right_matches('/').to_string(),
+            token: token.to_string(),
+            client: Arc::new(Client::new()),
+        }
+    }
+
+    fn blob_url(&self, algorithm: HashAlgorithm, hash: &[u8]) -> String {
+        format!("{}/blobs/{}/{}", self.url, algorithm.tag(), hash.to_hex())
+    }
+
+    fn request(&self,
+               method: Method,
+               url: &str,
+               body: Option<Body>)
+               -> Result<Response, Box<Error>> {
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![format!("Bearer {}", self.token).into_bytes()]);
+        let mut req = self.client.request(method, url).headers(headers);
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        let res = req.send().map_err(|e| format!("Request to {} failed: {}", url, e))?;
+        if res.status == hyper::status::StatusCode::Unauthorized {
+            return Err(format!("{} refused the server token", self.url).into());
+        }
+        Ok(res)
+    }
+
+    fn exists(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<bool, Box<Error>> {
+        let url = self.blob_url(algorithm, hash);
+        let res = self.request(Method::Head, &url, None)?;
+        match res.status {
+            hyper::Ok => Ok(true),
+            hyper::NotFound => Ok(false),
+            status => Err(format!("Checking {} failed: {}", url, status).into()),
+        }
+    }
+}
+
+impl Storage for ServerStorage {
+    fn send(&self, req: &mut SendRequest) -> Result<SendReceipt, Box<Error>> {
+        let &mut SendRequest { ref hash, ref node, ref mut reader, size, .. } = req;
+        let algorithm = node.hash_algorithm();
+        if self.exists(algorithm, hash)? {
+            debug!("Server already has {}", hash.to_hex());
+            metrics::incr("storage.server.blobs_existing");
+            return Ok(SendReceipt::existing());
+        }
+
+        let url = self.blob_url(algorithm, hash);
+        let res = self.request(Method::Put, &url, Some(Body::SizedBody(reader, size)))?;
+        match res.status {
+            hyper::Ok => Ok(SendReceipt::existing()),
+            hyper::status::StatusCode::Created => {
+                metrics::incr("storage.server.blobs_written");
+                metrics::count("storage.server.bytes_written", size as i64);
+                Ok(SendReceipt::stored(size))
+            }
+            status => Err(format!("Upload to {} failed: {}", url, status).into()),
+        }
+    }
+
+    fn retrieve(&self, algorithm: HashAlgorithm, hash: &[u8]) -> Result<Option<Blob>, Box<Error>> {
+        let url = self.blob_url(algorithm, hash);
+        let res = self.request(Method::Get, &url, None)?;
+        match res.status {
+            hyper::Ok => {
+                let size = match res.headers.get::<ContentLength>() {
+                    Some(&ContentLength(size)) => size,
+                    None => return Err(format!("{} sent no length", url).into()),
+                };
+                Ok(Some(Blob::new(size, box res)))
+            }
+            hyper::NotFound => Ok(None),
+            status => Err(format!("Download from {} failed: {}", url, status).into()),
+        }
+    }
+
+    /// The server checks content hashes as blobs arrive, so this only checks they are there.
+    fn verify(&self, node: Node) -> Result<(Node, bool), Box<Error>> {
+        let hash = node.hash().clone().expect("can not validate without hash");
+        let exists = self.exists(node.hash_algorithm(), &hash)?;
+        Ok((node, exists))
+    }
+
+    /// The server keeps the index in the namespace of the client the token belongs to, so
+    /// `_namespace` is not sent.
+    fn push_index(&self, _namespace: &str, index: &Path) -> Result<(), Box<Error>> {
+        let mut file = File::open(index)?;
+        let size = file.metadata()?.len();
+        let url = format!("{}/index", self.url);
+        let mut res = self.request(Method::Put, &url, Some(Body::SizedBody(&mut file, size)))?;
+        if res.status != hyper::Ok {
+            let mut body = String::new();
+            let _ = res.read_to_string(&mut body);
+            return Err(format!("Pushing index to {} failed: {}: {}", url, res.status, body)
+                .into());
+        }
+        Ok(())
+    }
+
+    fn fetch_index(&self, namespace: &str) -> Result<Option<Blob>, Box<Error>> {
+        Err(format!("{} keeps clients apart, so the index of {} can not be read from here",
+                    self.url,
+                    namespace)
+            .into())
+    }
+}
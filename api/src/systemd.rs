@@ -0,0 +1,132 @@
+//! Minimal sd_notify(3) support so systemd can supervise the daemon with `Type=notify` and
+//! `WatchdogSec=`. Everything here is a no-op when not started by systemd.
+
+use libc;
+use std::env;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Send `state` to the socket named by `$NOTIFY_SOCKET`. Returns false when not running
+/// under systemd or the message could not be delivered.
+pub fn notify(state: &str) -> bool {
+    let socket = match env::var("NOTIFY_SOCKET") {
+        Ok(socket) => socket,
+        Err(_) => return false,
+    };
+
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = socket.as_bytes();
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        warn!("Ignoring invalid NOTIFY_SOCKET {:?}", socket);
+        return false;
+    }
+    for (i, b) in path.iter().enumerate() {
+        addr.sun_path[i] = *b as libc::c_char;
+    }
+    // a leading @ names a socket in the abstract namespace
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let addr_len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return false;
+        }
+        let sent = libc::sendto(fd,
+                                state.as_ptr() as *const libc::c_void,
+                                state.len(),
+                                0,
+                                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                                addr_len as libc::socklen_t);
+        libc::close(fd);
+        sent >= 0
+    }
+}
+
+/// Tell systemd start up is finished.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Free form status shown by `systemctl status`.
+pub fn status(status: &str) {
+    trace!("Status: {}", status);
+    notify(&format!("STATUS={}", status.replace('\n', " ")));
+}
+
+/// How often systemd expects a watchdog ping, if at all.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<i32>().ok() != Some(unsafe { libc::getpid() }) {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .and_then(|usec| parse_watchdog_usec(usec))
+}
+
+fn parse_watchdog_usec(usec: u64) -> Option<Duration> {
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::new(usec / 1_000_000, ((usec % 1_000_000) * 1000) as u32))
+}
+
+/// Pings the systemd watchdog at half the configured interval from its own thread, so long
+/// scans and uploads do not starve it. Pinging stops once this is dropped.
+pub struct Watchdog {
+    alive: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn start() -> Self {
+        let alive = Arc::new(AtomicBool::new(true));
+        if let Some(interval) = watchdog_interval().map(|i| i / 2) {
+            debug!("Pinging systemd watchdog every {:?}", interval);
+            let alive = alive.clone();
+            thread::spawn(move || {
+                while alive.load(Ordering::SeqCst) {
+                    notify("WATCHDOG=1");
+                    thread::sleep(interval);
+                }
+            });
+        }
+        Watchdog { alive: alive }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+    use super::parse_watchdog_usec;
+
+    #[test]
+    fn watchdog_usec() {
+        assert_eq!(None, parse_watchdog_usec(0));
+        assert_eq!(Some(Duration::new(30, 0)), parse_watchdog_usec(30_000_000));
+        assert_eq!(Some(Duration::new(1, 500_000_000)),
+                   parse_watchdog_usec(1_500_000));
+    }
+
+    #[test]
+    fn not_under_systemd() {
+        if ::std::env::var("NOTIFY_SOCKET").is_err() {
+            assert!(!notify("READY=1"));
+        }
+    }
+}
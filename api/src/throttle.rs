@@ -0,0 +1,119 @@
+use std::cmp::min;
+use std::io::{Read, Result as IoResult};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting throughput to `rate` bytes per second, with up to one second of burst.
+/// Clones share the same bucket, so a single throttle can limit a pool of workers.
+#[derive(Clone)]
+pub struct Throttle {
+    rate: u64,
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+struct ThrottleState {
+    available: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "throttle rate must be positive");
+        Throttle {
+            rate: rate,
+            state: Arc::new(Mutex::new(ThrottleState {
+                available: rate as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Account for `amount` bytes, blocking until they fit within the rate.
+    pub fn take(&self, amount: u64) {
+        let wait = {
+            let mut state = self.state.lock().expect("throttle lock");
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last);
+            state.last = now;
+
+            let refill = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9) *
+                         self.rate as f64;
+            state.available = (state.available + refill).min(self.rate as f64);
+            state.available -= amount as f64;
+
+            if state.available >= 0.0 {
+                None
+            } else {
+                Some(-state.available / self.rate as f64)
+            }
+        };
+
+        if let Some(secs) = wait {
+            thread::sleep(Duration::new(secs as u64, (secs.fract() * 1e9) as u32));
+        }
+    }
+}
+
+/// Reader that draws every byte read from a `Throttle`.
+pub struct ThrottledRead<R: Read> {
+    inner: R,
+    throttle: Throttle,
+}
+
+impl<R: Read> ThrottledRead<R> {
+    pub fn new(inner: R, throttle: Throttle) -> Self {
+        ThrottledRead {
+            inner: inner,
+            throttle: throttle,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        // never ask for more than a second's worth at once
+        let len = min(buf.len() as u64, self.throttle.rate()) as usize;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.throttle.take(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+    use std::time::Instant;
+    use super::*;
+
+    #[test]
+    fn burst_is_not_delayed() {
+        let throttle = Throttle::new(1000);
+        let start = Instant::now();
+        throttle.take(1000);
+        assert_eq!(0, start.elapsed().as_secs());
+    }
+
+    #[test]
+    fn delays_beyond_rate() {
+        let throttle = Throttle::new(1000);
+        throttle.take(1000);
+        let start = Instant::now();
+        throttle.take(500);
+        let elapsed = start.elapsed();
+        assert!(elapsed.subsec_nanos() >= 400_000_000 || elapsed.as_secs() > 0);
+    }
+
+    #[test]
+    fn throttled_read_reads_everything() {
+        let content = vec![7u8; 3000];
+        let mut reader = ThrottledRead::new(Cursor::new(content.clone()), Throttle::new(1000000));
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).expect("read_to_end");
+        assert_eq!(content, buf);
+    }
+}
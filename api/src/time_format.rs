@@ -0,0 +1,110 @@
+//! How times are shown in command output, picked with `--time-format` and `--utc`.
+
+use estimate::format_duration;
+use std::str::FromStr;
+use time::{Timespec, at, at_utc, get_time, strftime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TimeFormat {
+    /// ISO-8601 in UTC, such as `2016-12-31T05:00:00Z`.
+    Iso,
+    /// How long ago, such as `2d 3h 0m ago`.
+    Relative,
+    /// Date, time and UTC offset, such as `2016-12-31 18:00:00 +1300`.
+    Local,
+    /// Month, day and time, as plain `ls` shows them.
+    Short,
+}
+
+impl FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iso" => Ok(TimeFormat::Iso),
+            "relative" => Ok(TimeFormat::Relative),
+            "local" => Ok(TimeFormat::Local),
+            "short" => Ok(TimeFormat::Short),
+            _ => {
+                Err(format!("Unknown time format {:?}, expected iso, relative, local or short",
+                            s))
+            }
+        }
+    }
+}
+
+/// The time format and timezone picked for output. Each view falls back to its own format
+/// when none was picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TimeStyle {
+    format: Option<TimeFormat>,
+    utc: bool,
+}
+
+impl TimeStyle {
+    pub fn new() -> Self {
+        TimeStyle::default()
+    }
+    pub fn with_format(mut self, format: TimeFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+    /// Show times in UTC instead of the local timezone.
+    pub fn with_utc(mut self, utc: bool) -> Self {
+        self.utc = utc;
+        self
+    }
+
+    /// `t` in the picked format, or in `default` when none was picked.
+    pub fn show(&self, t: Timespec, default: TimeFormat) -> String {
+        let tm = if self.utc { at_utc(t) } else { at(t) };
+        match self.format.unwrap_or(default) {
+            TimeFormat::Iso => strftime("%Y-%m-%dT%H:%M:%SZ", &at_utc(t)).expect("time format"),
+            TimeFormat::Relative => relative(t.sec, get_time().sec),
+            TimeFormat::Local => strftime("%Y-%m-%d %H:%M:%S %z", &tm).expect("time format"),
+            TimeFormat::Short => strftime("%b %e %H:%M", &tm).expect("time format"),
+        }
+    }
+}
+
+/// How far `t` is from `now`, both in seconds since the epoch.
+pub fn relative(t: i64, now: i64) -> String {
+    if t > now {
+        format!("in {}", format_duration((t - now) as u64))
+    } else {
+        format!("{} ago", format_duration((now - t) as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::Timespec;
+
+    #[test]
+    fn formats() {
+        let t = Timespec::new(1480000000, 0);
+        let utc = TimeStyle::new().with_utc(true);
+        assert_eq!("2016-11-24T15:06:40Z", utc.show(t, TimeFormat::Iso));
+        assert_eq!("2016-11-24 15:06:40 +0000", utc.show(t, TimeFormat::Local));
+        assert_eq!("Nov 24 15:06", utc.show(t, TimeFormat::Short));
+        assert_eq!("2016-11-24T15:06:40Z",
+                   utc.with_format(TimeFormat::Iso).show(t, TimeFormat::Short));
+        assert_eq!("2016-11-24T15:06:40Z",
+                   TimeStyle::new().show(t, TimeFormat::Iso));
+    }
+
+    #[test]
+    fn relative_times() {
+        assert_eq!("2d 3h 0m ago", relative(100, 100 + 2 * 86400 + 3 * 3600));
+        assert_eq!("0s ago", relative(100, 100));
+        assert_eq!("in 5m 0s", relative(400, 100));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(TimeFormat::Iso), "iso".parse());
+        assert_eq!(Ok(TimeFormat::Relative), "relative".parse());
+        assert!("utc".parse::<TimeFormat>().is_err());
+    }
+}
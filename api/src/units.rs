@@ -0,0 +1,69 @@
+/// Human readable byte counts, in binary units.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&'static str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Parse a byte count with an optional binary unit suffix, eg "512", "100M" or "2GiB".
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    let value = s[..digits].parse::<u64>().map_err(|_| format!("Invalid size: {:?}", s))?;
+    let unit = s[digits..].trim().to_uppercase();
+    let shift = match unit.trim_right_matches("IB").trim_right_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("Invalid size unit: {:?}", s)),
+    };
+    value.checked_mul(1 << shift).ok_or_else(|| format!("Size too large: {:?}", s))
+}
+
+/// `numerator / denominator` as a ratio, eg "2.50x". Zero when there is nothing to divide.
+pub fn format_ratio(numerator: u64, denominator: u64) -> String {
+    if denominator == 0 {
+        return "0.00x".to_string();
+    }
+    format!("{:.2}x", numerator as f64 / denominator as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        assert_eq!("0 B", format_bytes(0));
+        assert_eq!("1023 B", format_bytes(1023));
+        assert_eq!("1.0 KiB", format_bytes(1024));
+        assert_eq!("1.5 MiB", format_bytes(1024 * 1024 * 3 / 2));
+        assert_eq!("2.0 GiB", format_bytes(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(512), parse_bytes("512"));
+        assert_eq!(Ok(100 * 1024 * 1024), parse_bytes("100M"));
+        assert_eq!(Ok(2 * 1024 * 1024 * 1024), parse_bytes("2GiB"));
+        assert_eq!(Ok(1024), parse_bytes("1kb"));
+        assert!(parse_bytes("M").is_err());
+        assert!(parse_bytes("10Q").is_err());
+    }
+
+    #[test]
+    fn ratio() {
+        assert_eq!("0.00x", format_ratio(10, 0));
+        assert_eq!("2.50x", format_ratio(5, 2));
+    }
+}
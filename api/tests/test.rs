@@ -3,9 +3,10 @@ extern crate log;
 extern crate env_logger;
 extern crate haumaru_api;
 extern crate rusqlite;
+extern crate time;
 
 use env_logger::LogBuilder;
-use haumaru_api::{Engine, Index, NodeKind, Record};
+use haumaru_api::{Engine, Haumaru, Index, NodeKind, Record};
 use haumaru_api::engine::*;
 use haumaru_api::filesystem::Change;
 use haumaru_api::index::SqlLightIndex;
@@ -14,9 +15,10 @@ use log::{LogLevel, LogLevelFilter, LogRecord};
 use rusqlite::Connection;
 use std::collections::HashSet;
 use std::env;
-use std::fs::{File, create_dir_all, remove_dir, remove_dir_all, remove_file};
+use std::fs::{File, create_dir_all, remove_dir, remove_dir_all, remove_file, rename};
 use std::io::{Cursor, Read, Write};
 use std::path::PathBuf;
+use time::Timespec;
 
 fn setup_logging(default_log_str: &str) {
 
@@ -403,6 +405,64 @@ fn restore_file_from_root() {
     });
 }
 
+#[test]
+fn restore_limited() {
+    let name = "restore_limited";
+    test_change(name, |engine, path| {
+        write_file(path.clone(), "a", "abcdef");
+        engine.scan_as_backup_set(5).unwrap();
+
+        let mut restore_path = path.clone();
+        restore_path.push("restore");
+        create_dir_all(&restore_path).expect("mkdir restore");
+        let restore_path_str = &restore_path.to_str().expect("Path to_str");
+
+        engine.restore_limited("a", None, restore_path_str, Some(4)).expect("engine restore");
+
+        let mut restored_file = restore_path.clone();
+        restored_file.push("a");
+
+        let mut f = File::open(restored_file).expect("open a");
+        let mut content = String::new();
+        f.read_to_string(&mut content).expect("read_to_string");
+        assert_eq!(content, "abcdef");
+    });
+}
+
+#[test]
+fn undelete() {
+    let name = "undelete";
+    test_change(name, |engine, path| {
+        let mut dir = path.clone();
+        dir.push("dir");
+        create_dir_all(dir.clone()).unwrap();
+        let a = write_file(dir.clone(), "a", "abc");
+        let b = write_file(dir.clone(), "b", "def");
+        engine.scan_as_backup_set(5).unwrap();
+
+        let since = time::now_utc().to_timespec();
+        remove_file(a).unwrap();
+        remove_file(b).unwrap();
+        engine.scan_as_backup_set(10).unwrap();
+        write_file(dir.clone(), "b", "new");
+
+        let restore_path_str = path.to_str().expect("Path to_str");
+        let later = Timespec::new(since.sec + 3600, 0);
+        assert!(engine.undelete("dir", later, restore_path_str).expect("undelete").is_empty());
+
+        let restored = engine.undelete("dir", since, restore_path_str).expect("undelete");
+        let paths: Vec<&str> = restored.iter().map(|&(ref n, _)| n.path()).collect();
+        assert_eq!(vec!["dir/a"], paths);
+
+        let mut content = String::new();
+        File::open(dir.join("a")).expect("open a").read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abc");
+        content.clear();
+        File::open(dir.join("b")).expect("open b").read_to_string(&mut content).unwrap();
+        assert_eq!(content, "new");
+    });
+}
+
 #[test]
 fn restore_file_from_dir() {
     let name = "restore_file_from_dir";
@@ -545,3 +605,378 @@ fn full_restore() {
 
     });
 }
+
+#[test]
+fn process_change_renamed_file() {
+    let name = "process_change_renamed_file";
+
+    let dump = test_change(name, |engine, path| {
+        let from = write_file(path.clone(), "a", "abc");
+        engine.process_changes(3, vec![Change::new(from.clone())]).unwrap();
+
+        let mut to = path.clone();
+        to.push("b");
+        rename(&from, &to).unwrap();
+        engine.process_changes(4,
+                             vec![Change::new(from.clone()), Change::renamed(to, from)])
+            .unwrap();
+    });
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::File, "a".into(), 3, 420),
+                              Record::new(NodeKind::File, "a".into(), 0, 0).deleted(),
+                              Record::new(NodeKind::File, "b".into(), 3, 420)];
+    assert_eq!(v, dump);
+}
+
+#[test]
+fn process_change_chmod_file() {
+    use std::fs::{Permissions, set_permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let name = "process_change_chmod_file";
+
+    let dump = test_change(name, |engine, path| {
+        let filename = write_file(path.clone(), "a", "abc");
+        set_permissions(&filename, Permissions::from_mode(0o644)).unwrap();
+        engine.process_changes(3, vec![Change::new(filename.clone())]).unwrap();
+
+        set_permissions(&filename, Permissions::from_mode(0o600)).unwrap();
+        engine.process_changes(4, vec![Change::metadata(filename.clone())]).unwrap();
+    });
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::File, "a".into(), 3, 420),
+                              Record::new(NodeKind::File, "a".into(), 3, 384)];
+    assert_eq!(v, dump);
+}
+
+#[test]
+fn process_change_chmod_and_write_file() {
+    use std::fs::{Permissions, set_permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let name = "process_change_chmod_and_write_file";
+
+    let dump = test_change(name, |engine, path| {
+        let filename = write_file(path.clone(), "a", "abc");
+        set_permissions(&filename, Permissions::from_mode(0o644)).unwrap();
+        engine.process_changes(3, vec![Change::new(filename.clone())]).unwrap();
+
+        // a write coalesced with the attribute event still changes the content
+        write_file(path.clone(), "a", "abcdef");
+        set_permissions(&filename, Permissions::from_mode(0o600)).unwrap();
+        engine.process_changes(4, vec![Change::metadata(filename.clone())]).unwrap();
+        engine.wait_for_queue_drain();
+
+        let mut content = vec![];
+        engine.cat("a", None, &mut content).unwrap();
+        assert_eq!(b"abcdef".to_vec(), content);
+    });
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::File, "a".into(), 3, 420),
+                              Record::new(NodeKind::File, "a".into(), 6, 384)];
+    assert_eq!(v, dump);
+}
+
+#[test]
+fn run_with_scripted_changes() {
+    use haumaru_api::filesystem::ScriptedChangeSource;
+    use std::time::Duration;
+
+    setup_logging("off");
+    let name = "run_with_scripted_changes";
+
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).unwrap();
+
+    let test_dir = format!("target/test/{}", name);
+    let _ = remove_dir_all(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+    let path = PathBuf::from(test_dir.clone()).canonicalize().unwrap();
+
+    let mut working_path = path.clone();
+    working_path.push("working");
+
+    let mut files_path = path.clone();
+    files_path.push("files");
+    create_dir_all(&files_path).unwrap();
+
+    write_file(files_path.clone(), "a", "abc");
+
+    let config = EngineConfig::new(working_path.to_str().unwrap())
+        .with_path(files_path.to_str().unwrap().to_string())
+        .with_period(1);
+    let store = LocalStorage::new(&config).unwrap();
+
+    let mut b = files_path.clone();
+    b.push("b");
+    let script_files_path = files_path.clone();
+    let source = ScriptedChangeSource::new()
+        .wait(Duration::from_millis(500))
+        .run(move || {
+            write_file(script_files_path.clone(), "b", "1234");
+        })
+        .emit(Change::new(b));
+
+    {
+        let mut engine = DefaultEngine::new(config, HashSet::new(), index.clone(), store)
+            .unwrap()
+            .with_change_source(source);
+        engine.run().unwrap();
+    }
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::File, "a".into(), 3, 420),
+                              Record::new(NodeKind::File, "b".into(), 4, 420)];
+    assert_eq!(v, index.dump());
+}
+
+#[test]
+fn builder_with_custom_backends() {
+    setup_logging("off");
+
+    let name = "builder_with_custom_backends";
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).unwrap();
+
+    let test_dir = format!("target/test/{}", name);
+    let _ = remove_dir_all(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+    let path = PathBuf::from(test_dir.clone()).canonicalize().unwrap();
+
+    let mut working_path = path.clone();
+    working_path.push("working");
+
+    let mut files_path = path.clone();
+    files_path.push("files");
+    create_dir_all(&files_path).unwrap();
+
+    write_file(files_path.clone(), "a", "abc");
+
+    let config = EngineConfig::new(working_path.to_str().unwrap())
+        .with_path(files_path.to_str().unwrap().to_string());
+    let store = LocalStorage::new(&config).unwrap();
+
+    assert!(Haumaru::builder().index(index.clone()).storage(store.clone()).build().is_err());
+
+    {
+        let mut engine = Haumaru::builder()
+            .config(config)
+            .index(index.clone())
+            .storage(store)
+            .build()
+            .unwrap();
+        engine.backup_once().unwrap();
+        engine.flush().unwrap();
+        engine.shutdown().unwrap();
+    }
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::File, "a".into(), 3, 420)];
+    assert_eq!(v, index.dump());
+}
+
+#[test]
+fn generated_sources() {
+    setup_logging("off");
+
+    let name = "generated_sources";
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).unwrap();
+
+    let test_dir = format!("target/test/{}", name);
+    let _ = remove_dir_all(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+    let path = PathBuf::from(test_dir.clone()).canonicalize().unwrap();
+
+    let mut working_path = path.clone();
+    working_path.push("working");
+
+    let mut files_path = path.clone();
+    files_path.push("files");
+    create_dir_all(&files_path).unwrap();
+
+    write_file(files_path.clone(), "a", "abc");
+
+    let config = EngineConfig::new(working_path.to_str().unwrap())
+        .with_path(files_path.to_str().unwrap().to_string())
+        .with_generated_sources(parse_generated_sources("dump=printf hello").unwrap());
+    let store = LocalStorage::new(&config).unwrap();
+
+    {
+        let mut engine = Haumaru::builder()
+            .config(config)
+            .index(index.clone())
+            .storage(store)
+            .build()
+            .unwrap();
+        engine.backup_once().unwrap();
+        // the next full scan must not mark the generated directory deleted
+        engine.backup_once().unwrap();
+    }
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::Dir, ".haumaru-generated".into(), 0, 448),
+                              Record::new(NodeKind::File, ".haumaru-generated/dump".into(), 5, 384),
+                              Record::new(NodeKind::Dir, ".haumaru-generated".into(), 0, 448),
+                              Record::new(NodeKind::File, ".haumaru-generated/dump".into(), 5, 384),
+                              Record::new(NodeKind::File, "a".into(), 3, 420)];
+    assert_eq!(v, index.dump());
+}
+
+#[test]
+fn scan_from_snapshot() {
+    setup_logging("off");
+
+    let name = "scan_from_snapshot";
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).unwrap();
+
+    let test_dir = format!("target/test/{}", name);
+    let _ = remove_dir_all(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+    let path = PathBuf::from(test_dir.clone()).canonicalize().unwrap();
+
+    let mut working_path = path.clone();
+    working_path.push("working");
+
+    let mut files_path = path.clone();
+    files_path.push("files");
+    create_dir_all(&files_path).unwrap();
+
+    let mut snapshot_path = path.clone();
+    snapshot_path.push("snapshot");
+
+    write_file(files_path.clone(), "a", "abc");
+
+    // the live file changes as soon as the snapshot is taken
+    let files = files_path.to_str().unwrap();
+    let snap = snapshot_path.to_str().unwrap();
+    let snapshot = Snapshot::new(&format!("cp -R {} {} && printf wxyz > {}/a", files, snap, files),
+                                 snap)
+        .with_release(&format!("rm -rf {}", snap));
+
+    let config = EngineConfig::new(working_path.to_str().unwrap())
+        .with_path(files.to_string())
+        .with_snapshot(Some(snapshot));
+    let store = LocalStorage::new(&config).unwrap();
+
+    {
+        let mut engine = Haumaru::builder()
+            .config(config)
+            .index(index.clone())
+            .storage(store)
+            .build()
+            .unwrap();
+        engine.backup_once().unwrap();
+    }
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::File, "a".into(), 3, 420)];
+    assert_eq!(v, index.dump());
+    assert!(!snapshot_path.exists());
+}
+
+#[test]
+fn put_stream() {
+    setup_logging("off");
+
+    let name = "put_stream";
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).unwrap();
+
+    let test_dir = format!("target/test/{}", name);
+    let _ = remove_dir_all(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+    let path = PathBuf::from(test_dir.clone()).canonicalize().unwrap();
+
+    let mut working_path = path.clone();
+    working_path.push("working");
+
+    let mut files_path = path.clone();
+    files_path.push("files");
+    create_dir_all(&files_path).unwrap();
+
+    write_file(files_path.clone(), "a", "abc");
+
+    let config = EngineConfig::new(working_path.to_str().unwrap())
+        .with_path(files_path.to_str().unwrap().to_string());
+    let store = LocalStorage::new(&config).unwrap();
+
+    {
+        let mut engine = Haumaru::builder()
+            .config(config)
+            .index(index.clone())
+            .storage(store)
+            .build()
+            .unwrap();
+        engine.backup_once().unwrap();
+        engine.put("db/dump", true, &mut Cursor::new(b"hello".to_vec())).unwrap();
+        assert!(engine.put("../dump", false, &mut Cursor::new(vec![])).is_err());
+        // the next full scan must not mark the stream deleted
+        engine.backup_once().unwrap();
+    }
+
+    let v: Vec<Record> = vec![Record::new(NodeKind::Dir, ".haumaru-virtual".into(), 0, 448),
+                              Record::new(NodeKind::Dir, ".haumaru-virtual/db".into(), 0, 448),
+                              Record::new(NodeKind::File,
+                                          ".haumaru-virtual/db/dump".into(),
+                                          5,
+                                          384),
+                              Record::new(NodeKind::File, "a".into(), 3, 420)];
+    assert_eq!(v, index.dump());
+
+    // --current added the stream to the set the first backup closed
+    let a = index.clone().get("a".to_string(), None).unwrap().unwrap();
+    let dump = index.clone().get(".haumaru-virtual/db/dump".to_string(), None).unwrap().unwrap();
+    assert_eq!(a.backup_set(), dump.backup_set());
+}
+
+#[test]
+fn virtual_namespace() {
+    setup_logging("off");
+
+    let name = "virtual_namespace";
+    let conn = Connection::open_in_memory().expect("conn");
+    let index = SqlLightIndex::new(conn).unwrap();
+
+    let test_dir = format!("target/test/{}", name);
+    let _ = remove_dir_all(&test_dir);
+    create_dir_all(&test_dir).unwrap();
+    let path = PathBuf::from(test_dir.clone()).canonicalize().unwrap();
+
+    let mut working_path = path.clone();
+    working_path.push("working");
+
+    let mut files_path = path.clone();
+    files_path.push("files");
+    create_dir_all(&files_path).unwrap();
+
+    write_file(files_path.clone(), "a", "abc");
+    // reserved for streams, never scanned
+    write_file(files_path.clone(), ".haumaru-virtual", "xyz");
+
+    let config = EngineConfig::new(working_path.to_str().unwrap())
+        .with_path(files_path.to_str().unwrap().to_string());
+    let store = LocalStorage::new(&config).unwrap();
+
+    let mut engine = Haumaru::builder()
+        .config(config)
+        .index(index.clone())
+        .storage(store)
+        .build()
+        .unwrap();
+    engine.backup_once().unwrap();
+    engine.put("virtual:dump", false, &mut Cursor::new(b"hello".to_vec())).unwrap();
+
+    let mut listed = vec![];
+    engine.list("", None, &mut listed).unwrap();
+    let listed = String::from_utf8(listed).unwrap();
+    assert!(listed.contains("a"));
+    assert!(!listed.contains(".haumaru-virtual"));
+
+    let mut out = vec![];
+    engine.cat(&resolve_key("virtual:dump"), None, &mut out).unwrap();
+    assert_eq!(b"hello".to_vec(), out);
+    assert!(engine.cat(&resolve_key("virtual:"), None, &mut vec![]).is_err());
+
+    // a deleted file is not there to read, as in the web UI
+    remove_file(files_path.join("a")).unwrap();
+    engine.backup_once().unwrap();
+    assert!(engine.cat("a", None, &mut vec![]).is_err());
+}
@@ -5,10 +5,12 @@ extern crate haumaru;
 extern crate haumaru_api;
 extern crate clap;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, Arg, Shell, SubCommand};
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::path;
+use std::process::exit;
 
 #[derive(Debug)]
 enum CliError {
@@ -45,8 +47,76 @@ fn app<'a, 'b>(default_path: &'a str,
             .help("Backup config")
             .default_value(default_config_file)
             .takes_value(true))
+        .arg(Arg::with_name("job")
+            .long("job")
+            .short("j")
+            .global(true)
+            .value_name("NAME")
+            .help("Job from the jobs: of the config to work on, all of them for backup")
+            .takes_value(true))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .global(true)
+            .help("Print results as JSON, one object per line"))
+        .arg(Arg::with_name("time-format")
+            .long("time-format")
+            .global(true)
+            .value_name("FORMAT")
+            .possible_values(&["iso", "relative", "local", "short"])
+            .help("Show times as ISO-8601 in UTC, how long ago, local date and time, or briefly")
+            .takes_value(true))
+        .arg(Arg::with_name("utc")
+            .long("utc")
+            .global(true)
+            .help("Show times in UTC instead of the local timezone"))
         .subcommand(SubCommand::with_name("backup")
             .about("Start backup service")
+            .arg(Arg::with_name("once")
+                .long("once")
+                .help("Perform a single full backup and exit"))
+            .arg(Arg::with_name("path")
+                .long("path")
+                .short("p")
+                .value_name("PATH")
+                .help("Path to backup")
+                .default_value(default_path)
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Serve a central repository to other machines")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .short("l")
+                .value_name("ADDR")
+                .help("Address to listen on, overriding serve_listen")
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("put")
+            .about("Back up stdin under a virtual key")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .value_name("KEY")
+                .help("Key to store stdin under")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("current")
+                .long("current")
+                .help("Add to the latest backup set instead of starting a new one"))
             .arg(Arg::with_name("path")
                 .long("path")
                 .short("p")
@@ -64,7 +134,6 @@ fn app<'a, 'b>(default_path: &'a str,
                 .required(true)))
         .subcommand(SubCommand::with_name("verify")
             .about("Verify backup integrity")
-            .setting(AppSettings::TrailingVarArg)
             .arg(Arg::with_name("working")
                 .long("working")
                 .short("w")
@@ -73,17 +142,294 @@ fn app<'a, 'b>(default_path: &'a str,
                 .default_value(default_working)
                 .takes_value(true)
                 .required(true))
-            .arg(Arg::with_name("like").multiple(true)))
+            .arg(Arg::with_name("like")
+                .long("like")
+                .value_name("PATTERN")
+                .help("SQL LIKE pattern of keys to verify, from before globs")
+                .takes_value(true)
+                .conflicts_with("glob"))
+            .arg(Arg::with_name("glob")
+                .value_name("GLOB")
+                .help("Keys to verify, a path prefix or a glob like 'photos/2023/**'")))
+        .subcommand(SubCommand::with_name("status")
+            .about("Show what the backup daemon is doing")
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Show repository totals")
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("snapshots")
+            .about("List backup sets")
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("report")
+            .about("Show where the time of a backup run went")
+            .arg(Arg::with_name("set")
+                .value_name("SET")
+                .help("Backup set id, as listed by snapshots")
+                .required(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("undelete")
+            .about("Restore files deleted since a given time to where they were")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .value_name("KEY")
+                .help("Directory or file to undelete under")
+                .default_value("")
+                .takes_value(true))
+            .arg(Arg::with_name("since")
+                .long("since")
+                .value_name("TIME")
+                .help("Only files deleted at or after this unix timestamp or YYYY-MM-DD date")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("target")
+                .long("target")
+                .short("t")
+                .value_name("PATH")
+                .help("Directory to restore under, the backup path by default")
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("skipped")
+            .about("Show the paths a backup run left out, and why")
+            .arg(Arg::with_name("set")
+                .value_name("SET")
+                .help("Backup set id, as listed by snapshots")
+                .required(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("du")
+            .about("Show stored bytes per directory")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .value_name("KEY")
+                .help("Directory to break down. Format: [<path>][@<utc_unix_ts>]")
+                .default_value("")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("depth")
+                .long("depth")
+                .short("d")
+                .value_name("N")
+                .help("Directory levels to group by")
+                .default_value("1")
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("browse")
+            .about("Browse backups interactively and restore what is marked")
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("find")
+            .about("Find backed up files")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .value_name("KEY")
+                .help("Directory to search. Format: [virtual:][<path>][@<utc_unix_ts>]")
+                .default_value("")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .value_name("PATTERN")
+                .help("Names matching a wildcard pattern, eg '*.psd'")
+                .takes_value(true))
+            .arg(Arg::with_name("larger-than")
+                .long("larger-than")
+                .value_name("SIZE")
+                .help("Files larger than SIZE, eg 100M")
+                .takes_value(true))
+            .arg(Arg::with_name("modified-after")
+                .long("modified-after")
+                .value_name("TIME")
+                .help("Modified after a unix timestamp or YYYY-MM-DD date")
+                .takes_value(true))
+            .arg(Arg::with_name("deleted")
+                .long("deleted")
+                .help("Only deleted files, as their last version"))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("cost")
+            .about("Estimate the monthly S3 cost of the repository and how it will grow")
+            .arg(Arg::with_name("class")
+                .long("class")
+                .value_name("CLASS")
+                .help("S3 storage class to price, STANDARD_IA unless given")
+                .possible_values(&["STANDARD", "STANDARD_IA", "GLACIER"])
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("prune")
+            .about("Remove versions past their retention")
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("estimate")
+            .about("Scan the backup path without uploading and estimate a first backup")
+            .arg(Arg::with_name("bandwidth")
+                .long("bandwidth")
+                .short("b")
+                .value_name("SIZE")
+                .help("Upload bandwidth per second to project the duration at, such as 2M")
+                .default_value("1M")
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("doctor")
+            .about("Check the environment for problems that would stop backups working")
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("compare")
+            .about("Show files on disk that are new, changed or missing from the latest backup")
+            .arg(Arg::with_name("hash")
+                .long("hash")
+                .help("Hash files whose size and mtime match to check their content too"))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
         .subcommand(SubCommand::with_name("ls")
             .about("List file(s)")
             .arg(Arg::with_name("key")
                 .long("key")
                 .short("k")
                 .value_name("KEY")
-                .help("List file(s) on key. Format: [<path>][@<utc_unix_ts>]")
+                .help("List file(s) on key. Format: [virtual:][<path>][@<utc_unix_ts>]")
                 .default_value("")
                 .takes_value(true)
                 .required(true))
+            .arg(Arg::with_name("recursive")
+                .short("R")
+                .help("List directories recursively"))
+            .arg(Arg::with_name("tree")
+                .long("tree")
+                .help("Show the listing as an indented tree"))
+            .arg(Arg::with_name("long")
+                .short("l")
+                .help("Show the hash, backup set and UTC time of each entry"))
+            .arg(Arg::with_name("sort")
+                .long("sort")
+                .value_name("KEY")
+                .help("Order entries by size, mtime or name")
+                .possible_values(&["size", "mtime", "name"])
+                .takes_value(true))
+            .arg(Arg::with_name("reverse")
+                .long("reverse")
+                .short("r")
+                .help("Reverse the order of entries"))
+            .arg(Arg::with_name("deleted")
+                .long("deleted")
+                .help("Include deleted entries, with when they were deleted"))
+            .arg(Arg::with_name("namespace")
+                .long("namespace")
+                .short("n")
+                .value_name("NAME")
+                .help("Read the backups of another client sharing the storage")
+                .takes_value(true))
+            .arg(Arg::with_name("working")
+                .long("working")
+                .short("w")
+                .value_name("PATH")
+                .help("Working path for haumaru")
+                .default_value(default_working)
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("cat")
+            .about("Write a file to stdout")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .short("k")
+                .value_name("KEY")
+                .help("File to write. Format: [virtual:]<path>[@<utc_unix_ts>]")
+                .takes_value(true)
+                .required(true))
             .arg(Arg::with_name("working")
                 .long("working")
                 .short("w")
@@ -98,7 +444,7 @@ fn app<'a, 'b>(default_path: &'a str,
                 .long("key")
                 .short("k")
                 .value_name("KEY")
-                .help("Restore file(s) on key. Format: [<path>][@<utc_unix_ts>]")
+                .help("Restore file(s) on key. Format: [virtual:][<path>][@<utc_unix_ts>]")
                 .default_value("")
                 .takes_value(true)
                 .required(true))
@@ -110,6 +456,20 @@ fn app<'a, 'b>(default_path: &'a str,
                 .default_value(".")
                 .takes_value(true)
                 .required(true))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Show what would be restored without writing anything"))
+            .arg(Arg::with_name("limit-rate")
+                .long("limit-rate")
+                .value_name("SIZE")
+                .help("Download at most this much per second, such as 512K")
+                .takes_value(true))
+            .arg(Arg::with_name("namespace")
+                .long("namespace")
+                .short("n")
+                .value_name("NAME")
+                .help("Read the backups of another client sharing the storage")
+                .takes_value(true))
             .arg(Arg::with_name("working")
                 .long("working")
                 .short("w")
@@ -117,6 +477,12 @@ fn app<'a, 'b>(default_path: &'a str,
                 .help("Working path for haumaru")
                 .default_value(default_working)
                 .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("completions")
+            .about("Print a shell completion script")
+            .arg(Arg::with_name("shell")
+                .possible_values(&["bash", "zsh", "fish"])
+                .help("Shell to complete in")
                 .required(true)));
 
 }
@@ -179,6 +545,22 @@ fn config_with_args(config: haumaru_api::Config,
         }
     }
 
+    if cmd.is_present("json") {
+        config.set_output("json".to_string());
+    }
+
+    if let Some(time_format) = cmd.value_of("time-format") {
+        config.set_time_format(time_format.to_string());
+    }
+
+    if cmd.is_present("utc") {
+        config.set_utc(true);
+    }
+
+    if let Some(job) = cmd.value_of("job") {
+        config = config.job(job)?;
+    }
+
     info!("{:?}", config);
     Ok(config)
 }
@@ -208,6 +590,18 @@ fn run() -> Result<i64, Box<Error>> {
                       default_config_file.as_str())
         .get_matches();
 
+    // completions need no config, so can be set up before there is one
+    if let Some(cmd) = matches.subcommand_matches("completions") {
+        let shell = cmd.value_of("shell")
+            .ok_or(CliError::Missing("shell".to_string()))?
+            .parse::<Shell>()?;
+        app(default_path.as_str(),
+            default_working.as_str(),
+            default_config_file.as_str())
+            .gen_completions_to("haumaru", shell, &mut io::stdout());
+        return Ok(0);
+    }
+
     use std::fs::File;
     use haumaru_api::AsConfig;
 
@@ -220,25 +614,129 @@ fn run() -> Result<i64, Box<Error>> {
             .map_err(|e| format!("Failed to load config from {}: {}", config, e))?;
     debug!("{:?}", user_config);
 
+    if let Some(target) = user_config.log_target() {
+        haumaru::set_log_target(target.parse::<haumaru::LogTarget>()?);
+    }
+
     if let Some(cmd) = matches.subcommand_matches("backup") {
-        haumaru_api::run(config_with_args(user_config, &cmd)?)?;
+        if cmd.is_present("once") {
+            haumaru_api::backup_once(config_with_args(user_config, &cmd)?)?;
+        } else {
+            haumaru_api::run(config_with_args(user_config, &cmd)?)?;
+        }
+
+    } else if let Some(cmd) = matches.subcommand_matches("serve") {
+        haumaru_api::serve(config_with_args(user_config, &cmd)?, cmd.value_of("listen"))?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("put") {
+        let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
+        haumaru_api::put(config_with_args(user_config, &cmd)?, key, cmd.is_present("current"))?;
 
     } else if let Some(cmd) = matches.subcommand_matches("verify") {
-        let mut like = "%".to_owned();
-        let like_arg = cmd.value_of("like");
-        if let Some(has_like_arg) = like_arg {
-            like = has_like_arg.to_owned();
+        let scope = match cmd.value_of("like") {
+            Some(like) => haumaru_api::KeyScope::like(like),
+            None => haumaru_api::KeyScope::glob(cmd.value_of("glob").unwrap_or("")),
+        };
+        haumaru_api::verify(config_with_args(user_config, &cmd)?, scope)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("status") {
+        haumaru_api::status(config_with_args(user_config, &cmd)?)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("stats") {
+        haumaru_api::stats(config_with_args(user_config, &cmd)?)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("snapshots") {
+        haumaru_api::snapshots(config_with_args(user_config, &cmd)?)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("report") {
+        let set = cmd.value_of("set")
+            .ok_or(CliError::Missing("set".to_string()))?
+            .parse::<u64>()?;
+        haumaru_api::report(config_with_args(user_config, &cmd)?, set)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("undelete") {
+        let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
+        let since = cmd.value_of("since").ok_or(CliError::Missing("since".to_string()))?;
+        haumaru_api::undelete(config_with_args(user_config, &cmd)?,
+                              key,
+                              since,
+                              cmd.value_of("target"))?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("skipped") {
+        let set = cmd.value_of("set")
+            .ok_or(CliError::Missing("set".to_string()))?
+            .parse::<u64>()?;
+        haumaru_api::skipped(config_with_args(user_config, &cmd)?, set)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("du") {
+        let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
+        let depth = cmd.value_of("depth")
+            .ok_or(CliError::Missing("depth".to_string()))?
+            .parse::<usize>()?;
+        haumaru_api::du(config_with_args(user_config, &cmd)?, key, depth)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("browse") {
+        haumaru_api::browse(config_with_args(user_config, &cmd)?)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("find") {
+        let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
+        let mut query = haumaru_api::FindQuery::new().with_deleted(cmd.is_present("deleted"));
+        if let Some(name) = cmd.value_of("name") {
+            query = query.with_name(name);
+        }
+        if let Some(size) = cmd.value_of("larger-than") {
+            query = query.with_larger_than(size)?;
         }
-        haumaru_api::verify(config_with_args(user_config, &cmd)?, like)?;
+        if let Some(time) = cmd.value_of("modified-after") {
+            query = query.with_modified_after(time)?;
+        }
+        haumaru_api::find(config_with_args(user_config, &cmd)?, key, query)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("cost") {
+        haumaru_api::cost(config_with_args(user_config, &cmd)?, cmd.value_of("class"))?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("prune") {
+        haumaru_api::prune(config_with_args(user_config, &cmd)?)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("estimate") {
+        let bandwidth = cmd.value_of("bandwidth").unwrap_or("1M");
+        haumaru_api::estimate(config_with_args(user_config, &cmd)?, bandwidth)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("doctor") {
+        haumaru_api::doctor(config_with_args(user_config, &cmd)?)?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("compare") {
+        haumaru_api::compare(config_with_args(user_config, &cmd)?, cmd.is_present("hash"))?;
 
     } else if let Some(cmd) = matches.subcommand_matches("ls") {
         let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
-        haumaru_api::list(config_with_args(user_config, &cmd)?, key)?;
+        let mut options = haumaru_api::ListOptions::new()
+            .with_recursive(cmd.is_present("recursive"))
+            .with_tree(cmd.is_present("tree"))
+            .with_long(cmd.is_present("long"))
+            .with_reverse(cmd.is_present("reverse"))
+            .with_deleted(cmd.is_present("deleted"));
+        if let Some(sort) = cmd.value_of("sort") {
+            options = options.with_sort(sort.parse::<haumaru_api::SortKey>()?);
+        }
+        haumaru_api::list(config_with_args(user_config, &cmd)?,
+                          key,
+                          options,
+                          cmd.value_of("namespace"))?;
+
+    } else if let Some(cmd) = matches.subcommand_matches("cat") {
+        let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
+        haumaru_api::cat(config_with_args(user_config, &cmd)?, key)?;
 
     } else if let Some(cmd) = matches.subcommand_matches("restore") {
         let key = cmd.value_of("key").ok_or(CliError::Missing("key".to_string()))?;
         let target = cmd.value_of("target").ok_or(CliError::Missing("target".to_string()))?;
-        haumaru_api::restore(config_with_args(user_config, &cmd)?, key, target)?;
+        haumaru_api::restore(config_with_args(user_config, &cmd)?,
+                             key,
+                             target,
+                             cmd.is_present("dry-run"),
+                             cmd.value_of("namespace"),
+                             cmd.value_of("limit-rate"))?;
 
     } else {
         app(default_path.as_str(),
@@ -260,7 +758,7 @@ fn main() {
         Err(e) => {
             error!("{}", e);
             debug!("{:?}", e);
-            return;
+            exit(1);
         }
         Ok(_) => (),
     };
@@ -5,4 +5,4 @@ extern crate time;
 
 mod logging;
 
-pub use logging::setup_logging;
+pub use logging::{LogTarget, set_log_target, setup_logging};
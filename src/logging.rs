@@ -1,9 +1,131 @@
 use std::io::{Write, Cursor};
-use log::{LogRecord, LogLevelFilter, LogLevel};
-use env_logger::LogBuilder;
+use log::{self, Log, LogMetadata, LogRecord, LogLevelFilter, LogLevel};
+use env_logger::{LogBuilder, Logger};
 use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 use time;
 
+const SYSLOG_SOCKET: &'static str = "/dev/log";
+const JOURNALD_SOCKET: &'static str = "/run/systemd/journal/socket";
+
+/// Where log records are written. Selectable at runtime, so the config file can choose it
+/// after logging is already set up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogTarget {
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(format!("Unknown log target {} (expected stderr, syslog or journald)", s)),
+        }
+    }
+}
+
+static TARGET: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn set_log_target(target: LogTarget) {
+    let n = match target {
+        LogTarget::Stderr => 0,
+        LogTarget::Syslog => 1,
+        LogTarget::Journald => 2,
+    };
+    TARGET.store(n, Ordering::SeqCst);
+}
+
+fn log_target() -> LogTarget {
+    match TARGET.load(Ordering::SeqCst) {
+        1 => LogTarget::Syslog,
+        2 => LogTarget::Journald,
+        _ => LogTarget::Stderr,
+    }
+}
+
+/// syslog severity of a level.
+fn severity(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug | LogLevel::Trace => 7,
+    }
+}
+
+/// RFC 3164 style message for the local syslog socket, using the daemon facility.
+fn syslog_message(record: &LogRecord) -> String {
+    format!("<{}>haumaru: {} {}",
+            3 * 8 + severity(record.level()),
+            record.location().module_path(),
+            record.args())
+}
+
+/// Message in the journald native protocol. Values containing newlines are sent length
+/// prefixed.
+fn journald_message(record: &LogRecord) -> Vec<u8> {
+    let mut buf = vec![];
+    let fields = vec![("PRIORITY", severity(record.level()).to_string()),
+                      ("SYSLOG_IDENTIFIER", "haumaru".to_string()),
+                      ("CODE_MODULE", record.location().module_path().to_string()),
+                      ("MESSAGE", format!("{}", record.args()))];
+    for (key, value) in fields {
+        if value.contains('\n') {
+            buf.extend_from_slice(key.as_bytes());
+            buf.push(b'\n');
+            let len = value.len() as u64;
+            for i in 0..8 {
+                buf.push((len >> (i * 8)) as u8);
+            }
+            buf.extend_from_slice(value.as_bytes());
+            buf.push(b'\n');
+        } else {
+            buf.extend_from_slice(format!("{}={}\n", key, value).as_bytes());
+        }
+    }
+    buf
+}
+
+struct HaumaruLogger {
+    stderr: Logger,
+}
+
+impl HaumaruLogger {
+    fn send(&self, socket: &str, message: &[u8], record: &LogRecord) {
+        let sent = UnixDatagram::unbound().and_then(|s| s.send_to(message, socket));
+        if sent.is_err() {
+            // the log daemon is unavailable, don't lose the record
+            self.stderr.log(record);
+        }
+    }
+}
+
+impl Log for HaumaruLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match log_target() {
+            LogTarget::Stderr => self.stderr.log(record),
+            LogTarget::Syslog => {
+                self.send(SYSLOG_SOCKET, syslog_message(record).as_bytes(), record)
+            }
+            LogTarget::Journald => self.send(JOURNALD_SOCKET, &journald_message(record), record),
+        }
+    }
+}
+
 pub fn setup_logging(default_log_str: &str) {
 
     let format = |record: &LogRecord| {
@@ -47,5 +169,10 @@ pub fn setup_logging(default_log_str: &str) {
         builder.parse(default_log_str);
     }
 
-    builder.init().unwrap();
-}
\ No newline at end of file
+    let stderr = builder.build();
+    log::set_logger(|max_level| {
+            max_level.set(stderr.filter());
+            Box::new(HaumaruLogger { stderr: stderr })
+        })
+        .unwrap();
+}